@@ -2,7 +2,7 @@ use criterion::{criterion_group, criterion_main, Criterion};
 use martin::pg::composite_source::CompositeSource;
 use martin::pg::dev::make_pool;
 use martin::pg::function_source::FunctionSource;
-use martin::pg::table_source::TableSource;
+use martin::pg::table_source::{Buffer, TableSource};
 use martin::source::{Source, Xyz};
 use std::collections::HashMap;
 
@@ -11,18 +11,12 @@ fn mock_table_source(schema: &str, table: &str) -> TableSource {
         id: format!("{schema}.{table}"),
         schema: schema.to_owned(),
         table: table.to_owned(),
-        id_column: None,
         geometry_column: "geom".to_owned(),
-        minzoom: None,
-        maxzoom: None,
-        bounds: None,
         srid: 3857,
         extent: Some(4096),
-        buffer: Some(64),
+        buffer: Some(Buffer::Value(64)),
         clip_geom: Some(true),
-        geometry_type: None,
-        properties: HashMap::new(),
-        unrecognized: HashMap::new(),
+        ..Default::default()
     }
 }
 
@@ -34,6 +28,14 @@ fn mock_function_source(schema: &str, function: &str) -> FunctionSource {
         minzoom: None,
         maxzoom: None,
         bounds: None,
+        name: None,
+        description: None,
+        attribution: None,
+        query_params: None,
+        vector_layers: None,
+        api_keys: None,
+        empty_tile: None,
+        headers: None,
         unrecognized: HashMap::new(),
     }
 }
@@ -50,7 +52,10 @@ async fn get_table_source_tile() {
     let source = mock_table_source("public", "table_source");
     let xyz = Xyz { z: 0, x: 0, y: 0 };
 
-    let _tile = source.get_tile(&mut connection, &xyz, &None).await.unwrap();
+    let _tile = source
+        .get_tile(Some(&mut connection), &xyz, &None)
+        .await
+        .unwrap();
 }
 
 async fn get_composite_source() {
@@ -60,6 +65,7 @@ async fn get_composite_source() {
     let source = CompositeSource {
         id: "public.points1,public.points2".to_owned(),
         table_sources: vec![points1, points2],
+        layers: None,
     };
 
     let _tilejson = source.get_tilejson();
@@ -75,10 +81,14 @@ async fn get_composite_source_tile() {
     let source = CompositeSource {
         id: "public.points1,public.points2".to_owned(),
         table_sources: vec![points1, points2],
+        layers: None,
     };
 
     let xyz = Xyz { z: 0, x: 0, y: 0 };
-    let _tile = source.get_tile(&mut connection, &xyz, &None).await.unwrap();
+    let _tile = source
+        .get_tile(Some(&mut connection), &xyz, &None)
+        .await
+        .unwrap();
 }
 
 async fn get_function_source() {
@@ -93,7 +103,10 @@ async fn get_function_source_tile() {
     let source = mock_function_source("public", "function_source");
     let xyz = Xyz { z: 0, x: 0, y: 0 };
 
-    let _tile = source.get_tile(&mut connection, &xyz, &None).await.unwrap();
+    let _tile = source
+        .get_tile(Some(&mut connection), &xyz, &None)
+        .await
+        .unwrap();
 }
 
 fn table_source(c: &mut Criterion) {
@@ -1,24 +1,35 @@
+#[cfg(unix)]
+use actix_rt::signal::unix::{signal, SignalKind};
 use actix_web::dev::Server;
 use clap::Parser;
 use log::{error, info, warn};
-use martin::config::{read_config, ConfigBuilder};
+use martin::config::{read_configs, save_config, Config, ConfigBuilder};
+use martin::logging::{init_logger, LOG_FILTER_DEFAULT};
 use martin::pg::config::{PgArgs, PgConfigBuilder};
-use martin::pg::db::configure_db_sources;
+use martin::pg::db::Pool;
 use martin::srv::config::{SrvArgs, SrvConfigBuilder};
+use martin::srv::listen::unix_socket_path;
 use martin::srv::server;
+use martin::srv::server::SharedSources;
+use martin::srv::shutdown::{shutdown, shutdown_signal, InFlight};
+use martin::MartinBuilder;
 use std::collections::HashMap;
+use std::time::Duration;
 use std::{env, io};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(about, version)]
 pub struct Args {
     /// Database connection string
     pub connection: Option<String>,
-    /// Path to config file.
+    /// Path to config file. Repeat it to merge several files, later files overriding earlier ones.
     #[arg(short, long)]
-    pub config: Option<String>,
+    pub config: Vec<String>,
+    /// Save the resolved config, including all discovered sources, to a file and exit. Use "-" to print it to stdout.
+    #[arg(long)]
+    pub save_config: Option<String>,
     /// [Deprecated] Scan for new sources on sources list requests
     #[arg(short, long, hide = true)]
     pub watch: bool,
@@ -47,38 +58,112 @@ impl From<Args> for ConfigBuilder {
     }
 }
 
-async fn start(args: Args) -> io::Result<Server> {
+fn build_config(args: &Args) -> io::Result<Config> {
+    if args.config.is_empty() {
+        info!("Config file is not specified");
+        ConfigBuilder::from(args.clone()).finalize()
+    } else {
+        info!("Using {}", args.config.join(", "));
+        let cfg = read_configs(&args.config)?;
+        let mut builder = ConfigBuilder::from(args.clone());
+        builder.merge(cfg);
+        builder.finalize()
+    }
+}
+
+/// Re-read the config and swap in its sources on SIGHUP, keeping the connection pools
+#[cfg(unix)]
+fn reload_on_sighup(args: Args, sources: SharedSources, pool: Pool, database_pools: Vec<Pool>) {
+    actix_rt::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("Can't listen for SIGHUP, config reloading is disabled: {e}");
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            info!("Reloading config on SIGHUP");
+            let result = match build_config(&args) {
+                Ok(config) => {
+                    server::reload_sources(&sources, &pool, &database_pools, config).await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                error!("Can't reload config, keeping the previous sources: {e}");
+            }
+        }
+    });
+}
+
+/// Drain the in-flight requests and stop the server on SIGTERM or SIGINT
+fn shutdown_on_signal(server: &Server, in_flight: InFlight, timeout: Duration) {
+    let handle = server.handle();
+    actix_rt::spawn(async move {
+        if let Err(e) = shutdown_signal().await {
+            error!("Can't listen for shutdown signals, graceful shutdown is disabled: {e}");
+            return;
+        }
+        shutdown(handle, &in_flight, timeout).await;
+    });
+}
+
+/// Start the server, or return `None` if the config only had to be saved
+async fn start(args: Args) -> io::Result<Option<Server>> {
     info!("Starting Martin v{VERSION}");
 
-    let mut config = if let Some(ref config_file_name) = args.config {
-        info!("Using {config_file_name}");
-        let cfg = read_config(config_file_name)?;
-        let mut builder = ConfigBuilder::from(args);
-        builder.merge(cfg);
-        builder.finalize()?
-    } else {
-        info!("Config file is not specified");
-        ConfigBuilder::from(args).finalize()?
-    };
+    let config = build_config(&args)?;
+    let martin = MartinBuilder::new(config).build().await?;
 
-    let pool = configure_db_sources(&mut config).await?;
-    let listen_addresses = config.srv.listen_addresses.clone();
-    let server = server::new(pool, config);
+    if let Some(file_name) = &args.save_config {
+        save_config(&martin.config, file_name)?;
+        return Ok(None);
+    }
+    let listen_addresses = martin.config.srv.listen_addresses.clone();
+    #[cfg(unix)]
+    reload_on_sighup(
+        args,
+        martin.sources.clone(),
+        martin.pool.clone(),
+        martin.database_pools.clone(),
+    );
+    let shutdown_timeout = Duration::from_secs(martin.config.srv.shutdown_timeout);
+    let in_flight = InFlight::default();
+    let server = server::new(martin, in_flight.clone())?;
+    shutdown_on_signal(&server, in_flight, shutdown_timeout);
 
     info!("Martin has been started on {listen_addresses}.");
-    info!("Use http://{listen_addresses}/index.json to get the list of available sources.");
-    Ok(server)
+    match unix_socket_path(&listen_addresses) {
+        Some(path) => info!(
+            "Use curl --unix-socket {} http://localhost/index.json to get the list of available sources.",
+            path.display()
+        ),
+        None => info!(
+            "Use http://{listen_addresses}/index.json to get the list of available sources."
+        ),
+    }
+    Ok(Some(server))
 }
 
 #[actix_web::main]
 async fn main() -> io::Result<()> {
-    let env = env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "martin=info");
-    env_logger::Builder::from_env(env).init();
-    match start(Args::parse()).await {
-        Ok(server) => server.await,
+    let filters =
+        env::var(env_logger::DEFAULT_FILTER_ENV).unwrap_or_else(|_| LOG_FILTER_DEFAULT.to_owned());
+    init_logger(&filters)?;
+    #[cfg(feature = "otel")]
+    let tracer_provider = martin::srv::otel::init_tracing()?;
+    let result = match start(Args::parse()).await {
+        Ok(Some(server)) => server.await,
+        Ok(None) => Ok(()),
         Err(error) => {
             error!("{error}");
             std::process::exit(-1);
         }
+    };
+    #[cfg(feature = "otel")]
+    if let Err(e) = tracer_provider.shutdown() {
+        warn!("Can't export the last traces: {e}");
     }
+    result
 }
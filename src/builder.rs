@@ -0,0 +1,159 @@
+use crate::cache::TileCache;
+use crate::config::Config;
+use crate::file_tree::{resolve_file_sources, FileSource, FileSources};
+use crate::fonts::FontSources;
+use crate::pg::db::{configure_db_sources, setup_reserved_pool, Pool};
+use crate::source::InMemorySource;
+use crate::sprites::SpriteSources;
+use crate::srv::auth::JwtAuth;
+#[cfg(feature = "observability")]
+use crate::srv::metrics::Metrics;
+use crate::srv::server::{create_state, router, SharedSources, Sources};
+use actix_web::web::{Data, ServiceConfig};
+use arc_swap::ArcSwap;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Connects to the databases of a config and resolves its sources, for serving them
+/// from the martin binary, or from routes registered on another actix app.
+///
+/// ```no_run
+/// use actix_web::{web, App, HttpServer};
+/// use martin::config::read_config;
+/// use martin::MartinBuilder;
+///
+/// #[actix_web::main]
+/// async fn main() -> std::io::Result<()> {
+///     let config = read_config("config.yaml")?.finalize()?;
+///     let martin = MartinBuilder::new(config).build().await?;
+///
+///     HttpServer::new(move || {
+///         let martin = martin.clone();
+///         App::new()
+///             .route("/", web::get().to(|| async { "My app" }))
+///             .service(web::scope("/tiles").configure(move |cfg| martin.configure(cfg)))
+///     })
+///     .bind("127.0.0.1:8080")?
+///     .run()
+///     .await
+/// }
+/// ```
+#[derive(Debug)]
+pub struct MartinBuilder {
+    config: Config,
+    in_memory_sources: Vec<InMemorySource>,
+}
+
+impl MartinBuilder {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            in_memory_sources: Vec::new(),
+        }
+    }
+
+    /// Serve the tiles of an in-memory source at `/file/{id}`, like those of a file source
+    pub fn in_memory_source(mut self, source: InMemorySource) -> Self {
+        self.in_memory_sources.push(source);
+        self
+    }
+
+    /// Connect to the main database, the extra `databases` and the reserved pool,
+    /// and resolve all their sources
+    pub async fn build(self) -> io::Result<Martin> {
+        let mut config = self.config;
+        let (pool, database_pools) = configure_db_sources(&mut config).await?;
+        let reserved_pool = setup_reserved_pool(&config).await?;
+        let sources = Arc::new(ArcSwap::from_pointee(Sources::from(&config)));
+        let tile_cache = TileCache::new(
+            config.srv.tile_cache_size,
+            config.srv.tile_cache_ttl.map(Duration::from_secs),
+            config.srv.tile_cache_max_entry_size,
+        )
+        .map(Arc::new);
+        let fonts = config
+            .srv
+            .fonts
+            .as_ref()
+            .map(FontSources::new)
+            .transpose()?;
+        let sprites = config
+            .srv
+            .sprites
+            .as_ref()
+            .map(SpriteSources::new)
+            .transpose()?;
+        let mut file_sources = config
+            .srv
+            .file_sources
+            .as_ref()
+            .map(resolve_file_sources)
+            .transpose()?;
+        for source in self.in_memory_sources {
+            let file_sources = file_sources.get_or_insert_with(FileSources::new);
+            if file_sources.contains_key(&source.id) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Source {} is both a file source and an in-memory one",
+                        source.id
+                    ),
+                ));
+            }
+            file_sources.insert(source.id.clone(), Box::new(FileSource::InMemory(source)));
+        }
+        let auth = config
+            .srv
+            .auth
+            .as_ref()
+            .map(JwtAuth::new)
+            .transpose()?
+            .map(Arc::new);
+
+        Ok(Martin {
+            config,
+            pool,
+            reserved_pool,
+            database_pools,
+            sources,
+            tile_cache,
+            #[cfg(feature = "observability")]
+            metrics: Arc::new(Metrics::new().map_err(io::Error::other)?),
+            fonts,
+            sprites,
+            file_sources,
+            auth,
+        })
+    }
+}
+
+/// Resolved sources and connection pools, cheap to clone into each server worker
+#[derive(Clone)]
+pub struct Martin {
+    /// The config, including all discovered sources
+    pub config: Config,
+    pub pool: Pool,
+    pub reserved_pool: Option<Pool>,
+    pub database_pools: Vec<Pool>,
+    /// Sources served by the routes, which can be swapped with `reload_sources`
+    pub sources: SharedSources,
+    pub(crate) tile_cache: Option<Arc<TileCache>>,
+    #[cfg(feature = "observability")]
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) fonts: Option<FontSources>,
+    pub(crate) sprites: Option<SpriteSources>,
+    pub(crate) file_sources: Option<FileSources>,
+    pub(crate) auth: Option<Arc<JwtAuth>>,
+}
+
+impl Martin {
+    /// Register the health, sources list, TileJSON, tile, font, sprite and file tree routes, with
+    /// the state they share. Call it in the app factory of every worker, as each one gets its own state.
+    /// The routes check the access to the sources, and take all the paths of the scope they are
+    /// registered in, so register other routes before them or in another scope.
+    pub fn configure(&self, cfg: &mut ServiceConfig) {
+        let state = create_state(self);
+        cfg.app_data(Data::new(state)).configure(router);
+    }
+}
@@ -0,0 +1,155 @@
+use crate::pg::db::Connection;
+use crate::source::{EmptyTile, Source, Tile, TileFormat, UrlQuery, Xyz};
+use async_trait::async_trait;
+use lru::LruCache;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tilejson::TileJSON;
+
+/// Sources generation, source id, tile coordinates and sorted url query params
+type CacheKey = (u64, String, Xyz, Vec<(String, String)>);
+
+/// A size-bounded LRU cache of non-empty tiles, shared by all server workers
+pub struct TileCache {
+    ttl: Option<Duration>,
+    /// Tiles larger than this many bytes are served but not cached
+    max_entry_size: Option<usize>,
+    entries: Mutex<LruCache<CacheKey, (Instant, Tile)>>,
+}
+
+impl TileCache {
+    /// Create a new cache, or `None` if the size is zero
+    pub fn new(size: usize, ttl: Option<Duration>, max_entry_size: Option<usize>) -> Option<Self> {
+        NonZeroUsize::new(size).map(|size| Self {
+            ttl,
+            max_entry_size,
+            entries: Mutex::new(LruCache::new(size)),
+        })
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<Tile> {
+        let mut entries = self.entries.lock().ok()?;
+        let (created, tile) = entries.get(key)?;
+        if self.ttl.is_some_and(|ttl| created.elapsed() >= ttl) {
+            entries.pop(key);
+            return None;
+        }
+        Some(tile.clone())
+    }
+
+    fn insert(&self, key: CacheKey, tile: Tile) {
+        if self.max_entry_size.is_some_and(|max| tile.len() > max) {
+            return;
+        }
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.put(key, (Instant::now(), tile));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().map_or(0, |entries| entries.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Debug for TileCache {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TileCache")
+            .field("ttl", &self.ttl)
+            .field("max_entry_size", &self.max_entry_size)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// A source serving tiles from the cache, and caching the tiles of the inner source.
+/// Errors, empty tiles and tiles above the `max_entry_size` of the cache are not cached.
+#[derive(Debug)]
+pub struct CachedSource {
+    pub inner: Box<dyn Source + Send + Sync>,
+    pub cache: Arc<TileCache>,
+    /// Generation of the sources the inner source belongs to, so the tiles cached
+    /// before a reload are not served for the reloaded sources
+    pub generation: u64,
+}
+
+#[allow(mismatched_lifetime_syntaxes)]
+#[async_trait]
+impl Source for CachedSource {
+    async fn get_id(&self) -> &str {
+        self.inner.get_id().await
+    }
+
+    async fn get_tilejson(&self) -> Result<TileJSON, io::Error> {
+        self.inner.get_tilejson().await
+    }
+
+    fn is_valid_zoom(&self, zoom: i32) -> bool {
+        self.inner.is_valid_zoom(zoom)
+    }
+
+    fn get_format(&self) -> TileFormat {
+        self.inner.get_format()
+    }
+
+    fn get_max_age(&self) -> Option<Duration> {
+        self.inner.get_max_age()
+    }
+
+    fn get_empty_tile(&self) -> EmptyTile {
+        self.inner.get_empty_tile()
+    }
+
+    fn get_headers(&self) -> Option<&HashMap<String, String>> {
+        self.inner.get_headers()
+    }
+
+    fn get_max_feature_count(&self) -> Option<usize> {
+        self.inner.get_max_feature_count()
+    }
+
+    fn is_out_of_bounds(&self, xyz: &Xyz) -> bool {
+        self.inner.is_out_of_bounds(xyz)
+    }
+
+    fn requires_connection(&self) -> bool {
+        self.inner.requires_connection()
+    }
+
+    async fn get_tile(
+        &self,
+        conn: Option<&mut Connection>,
+        xyz: &Xyz,
+        query: &Option<UrlQuery>,
+    ) -> Result<Tile, io::Error> {
+        let mut params: Vec<(String, String)> = query
+            .iter()
+            .flatten()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        params.sort();
+        let key = (
+            self.generation,
+            self.get_id().await.to_owned(),
+            *xyz,
+            params,
+        );
+
+        if let Some(tile) = self.cache.get(&key) {
+            return Ok(tile);
+        }
+
+        let tile = self.inner.get_tile(conn, xyz, query).await?;
+        if !tile.is_empty() {
+            self.cache.insert(key, tile.clone());
+        }
+        Ok(tile)
+    }
+}
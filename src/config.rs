@@ -1,11 +1,14 @@
 use crate::pg::config::{PgConfig, PgConfigBuilder};
 use crate::prettify_error;
 use crate::srv::config::{SrvConfig, SrvConfigBuilder};
-use log::warn;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use serde_yaml::Value;
 use std::collections::HashMap;
+use std::env;
+use std::fs;
 use std::fs::File;
+use std::hash::Hash;
 use std::io;
 use std::io::prelude::*;
 
@@ -34,7 +37,26 @@ pub fn set_option<T>(first: &mut Option<T>, second: Option<T>) {
     }
 }
 
+/// Add the entries of the second map missing from the first one.
+/// The entries of the first map are kept when both have the same key.
+pub fn merge_option_map<K: Eq + Hash, V>(
+    first: &mut Option<HashMap<K, V>>,
+    second: Option<HashMap<K, V>>,
+) {
+    match (first.as_mut(), second) {
+        (Some(first), Some(second)) => {
+            for (key, value) in second {
+                first.entry(key).or_insert(value);
+            }
+        }
+        (None, second) => *first = second,
+        (Some(_), None) => {}
+    }
+}
+
 impl ConfigBuilder {
+    /// Fill the settings missing from this config with the ones of the other config,
+    /// and add the sources of the other config that this config doesn't have
     pub fn merge(&mut self, other: ConfigBuilder) -> &mut Self {
         self.srv.merge(other.srv);
         self.pg.merge(other.pg);
@@ -65,17 +87,94 @@ pub fn read_config(file_name: &str) -> io::Result<ConfigBuilder> {
     let mut contents = String::new();
     file.read_to_string(&mut contents)
         .map_err(|e| prettify_error!(e, "Unable to read config file '{}'", file_name))?;
+    let contents = expand_env_vars(&contents, |name| env::var(name).ok())
+        .map_err(|e| prettify_error!(e, "Error parsing config file '{}'", file_name))?;
     serde_yaml::from_str(contents.as_str())
         .map_err(|e| prettify_error!(e, "Error parsing config file '{}'", file_name))
 }
 
+/// Read the config files and merge them, later files overriding the settings of earlier ones.
+/// The sources of all the files are served, a later source replacing an earlier one with the same id.
+pub fn read_configs(file_names: &[String]) -> io::Result<ConfigBuilder> {
+    let mut configs = file_names
+        .iter()
+        .rev()
+        .map(|file_name| read_config(file_name));
+    let mut builder = configs
+        .next()
+        .ok_or_else(|| io::Error::other("No config file to read"))??;
+    for config in configs {
+        builder.merge(config?);
+    }
+    Ok(builder)
+}
+
+/// Replace `${VAR}` and `${VAR:-default}` references with the values returned by `lookup`.
+/// The default is used when the variable is unset or empty, and `$${` is a literal `${`.
+fn expand_env_vars(contents: &str, lookup: impl Fn(&str) -> Option<String>) -> io::Result<String> {
+    let mut expanded = String::with_capacity(contents.len());
+    let mut rest = contents;
+    while let Some(start) = rest.find("${") {
+        let (before, reference) = rest.split_at(start);
+        if let Some(before) = before.strip_suffix('$') {
+            expanded.push_str(before);
+            expanded.push_str("${");
+            rest = &reference[2..];
+            continue;
+        }
+        expanded.push_str(before);
+
+        let end = reference.find('}').ok_or_else(|| {
+            let line = reference.lines().next().unwrap_or_default();
+            io::Error::other(format!("Unclosed environment variable reference '{line}'"))
+        })?;
+        let (name, default) = match reference[2..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&reference[2..end], None),
+        };
+        let value = match (lookup(name), default) {
+            (Some(value), Some(default)) if value.is_empty() => default.to_owned(),
+            (Some(value), _) => value,
+            (None, Some(default)) => default.to_owned(),
+            (None, None) => {
+                return Err(io::Error::other(format!(
+                    "Environment variable {name} is not set and has no default"
+                )))
+            }
+        };
+        expanded.push_str(&value);
+        rest = &reference[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Write the resolved config to a file, or print it to stdout if the file name is `-`
+pub fn save_config(config: &Config, file_name: &str) -> io::Result<()> {
+    let yaml = serde_yaml::to_string(config)
+        .map_err(|e| prettify_error!(e, "Unable to serialize config"))?;
+    if file_name == "-" {
+        println!("{yaml}");
+        Ok(())
+    } else {
+        fs::write(file_name, yaml)
+            .map_err(|e| prettify_error!(e, "Unable to write config file '{}'", file_name))?;
+        info!("Saved config to {file_name}");
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::pg::config::{IdNormalization, OnMissingSource, PgArgs};
     use crate::pg::function_source::FunctionSource;
-    use crate::pg::table_source::TableSource;
+    use crate::pg::raster_source::RasterSource;
+    use crate::pg::table_source::{Buffer, TableSource};
+    use crate::source::TileScheme;
+    use crate::srv::config::SrvArgs;
     use indoc::indoc;
-    use std::collections::HashMap;
+    use std::collections::{BTreeMap, HashMap};
 
     #[test]
     fn parse_config() {
@@ -100,6 +199,10 @@ mod tests {
                 minzoom: 0
                 maxzoom: 30
                 bounds: [-180.0, -90.0, 180.0, 90.0]
+                name: Table source
+                description: Points of the table source
+                attribution: '© Table source authors'
+                tile_scheme: tms
                 extent: 4096
                 buffer: 64
                 clip_geom: true
@@ -115,6 +218,17 @@ mod tests {
                 minzoom: 0
                 maxzoom: 30
                 bounds: [-180.0, -90.0, 180.0, 90.0]
+                attribution: '© Function source authors'
+
+            raster_sources:
+              public.raster_source:
+                id: public.raster_source
+                schema: public
+                table: raster_source
+                raster_column: rast
+                srid: 3857
+                maxzoom: 12
+                colormap: grayscale
         "};
 
         let config: ConfigBuilder = serde_yaml::from_str(yaml).expect("parse yaml");
@@ -123,15 +237,57 @@ mod tests {
             srv: SrvConfig {
                 keep_alive: 75,
                 listen_addresses: "0.0.0.0:3000".to_string(),
+                dual_stack: false,
                 worker_processes: 8,
+                empty_tile_cache_size: 0,
+                empty_tile_cache_ttl: 60,
+                admin_endpoints: false,
+                debug_endpoints: false,
+                feature_count_header: false,
+                tile_cache_size: 0,
+                tile_cache_ttl: None,
+                tile_cache_max_entry_size: None,
+                tile_compression_level: 6,
+                base_path: String::new(),
+                cache_max_age: None,
+                shutdown_timeout: 30,
+                fonts: None,
+                sprites: None,
+                file_sources: None,
+                auth: None,
+                api_keys: None,
+                max_tile_size: None,
+                tile_timeout: None,
+                max_zoom: None,
+                tls: None,
             },
             pg: PgConfig {
                 connection_string: "postgres://postgres@localhost:5432/db".to_string(),
                 ca_root_file: None,
+                ca_root_dir: None,
+                ssl_cert: None,
+                ssl_key: None,
+                ssl_key_passphrase: None,
                 danger_accept_invalid_certs: false,
                 default_srid: Some(4326),
                 pool_size: 20,
+                pool_acquire_timeout: 30,
+                max_retries: 2,
+                statement_timeout: None,
+                reserved_pool_size: None,
+                reserved_pool_minzoom: 12,
+                partial_composite_tiles: false,
+                prepared_statements: true,
+                normalize_ids: IdNormalization::None,
+                id_conflict_separator: ".".to_string(),
+                fail_on_id_conflict: false,
+                on_missing_source: OnMissingSource::Warn,
+                sample_srid: false,
+                default_extent: None,
+                default_buffer: None,
+                default_clip_geom: None,
                 use_dynamic_sources: false,
+                auto_publish: None,
                 table_sources: HashMap::from([(
                     "public.table_source".to_string(),
                     Box::new(TableSource {
@@ -140,16 +296,19 @@ mod tests {
                         table: "table_source".to_string(),
                         srid: 4326,
                         geometry_column: "geom".to_string(),
-                        id_column: None,
                         minzoom: Some(0),
                         maxzoom: Some(30),
                         bounds: Some([-180, -90, 180, 90].into()),
+                        name: Some("Table source".to_string()),
+                        description: Some("Points of the table source".to_string()),
+                        attribution: Some("© Table source authors".to_string()),
+                        tile_scheme: Some(TileScheme::Tms),
                         extent: Some(4096),
-                        buffer: Some(64),
+                        buffer: Some(Buffer::Value(64)),
                         clip_geom: Some(true),
                         geometry_type: Some("GEOMETRY".to_string()),
                         properties: HashMap::from([("gid".to_string(), "int4".to_string())]),
-                        unrecognized: HashMap::new(),
+                        ..Default::default()
                     }),
                 )]),
                 function_sources: HashMap::from([(
@@ -161,11 +320,630 @@ mod tests {
                         minzoom: Some(0),
                         maxzoom: Some(30),
                         bounds: Some([-180, -90, 180, 90].into()),
+                        name: None,
+                        description: None,
+                        attribution: Some("© Function source authors".to_string()),
+                        query_params: None,
+                        vector_layers: None,
+                        api_keys: None,
+                        empty_tile: None,
+                        headers: None,
+                        unrecognized: HashMap::new(),
+                    }),
+                )]),
+                raster_sources: HashMap::from([(
+                    "public.raster_source".to_string(),
+                    Box::new(RasterSource {
+                        id: "public.raster_source".to_string(),
+                        schema: "public".to_string(),
+                        table: "raster_source".to_string(),
+                        raster_column: "rast".to_string(),
+                        srid: 3857,
+                        minzoom: None,
+                        maxzoom: Some(12),
+                        bounds: None,
+                        name: None,
+                        description: None,
+                        attribution: None,
+                        colormap: Some("grayscale".to_string()),
+                        api_keys: None,
+                        empty_tile: None,
+                        headers: None,
                         unrecognized: HashMap::new(),
                     }),
                 )]),
+                aliases: HashMap::new(),
+                databases: vec![],
             },
         };
         assert_eq!(config, expected);
     }
+
+    #[test]
+    fn parse_config_databases() {
+        let yaml = indoc! {"
+            ---
+            connection_string: 'postgres://postgres@localhost:5432/basemaps'
+            pool_size: 10
+            databases:
+              - connection_string: 'postgres://postgres@localhost:5432/overlays'
+                pool_size: 5
+                function_sources:
+                  public.function_source:
+                    id: public.function_source
+                    schema: public
+                    function: function_source
+              - connection_string: 'postgres://postgres@localhost:5432/rasters'
+        "};
+
+        let config: ConfigBuilder = serde_yaml::from_str(yaml).expect("parse yaml");
+        let config = config.finalize().expect("finalize");
+        assert_eq!(
+            config.pg.connection_string,
+            "postgres://postgres@localhost:5432/basemaps"
+        );
+        assert_eq!(config.pg.pool_size, 10);
+        assert!(config.pg.use_dynamic_sources);
+
+        let [overlays, rasters] = &config.pg.databases[..] else {
+            panic!("expected two databases, got {:?}", config.pg.databases);
+        };
+        assert_eq!(
+            overlays.connection_string,
+            "postgres://postgres@localhost:5432/overlays"
+        );
+        assert_eq!(overlays.pool_size, 5);
+        assert!(!overlays.use_dynamic_sources);
+        assert!(overlays
+            .function_sources
+            .contains_key("public.function_source"));
+        assert_eq!(
+            rasters.connection_string,
+            "postgres://postgres@localhost:5432/rasters"
+        );
+        assert_eq!(rasters.pool_size, 20);
+        assert!(rasters.use_dynamic_sources);
+
+        let yaml = indoc! {"
+            connection_string: 'postgres://postgres@localhost:5432/basemaps'
+            databases:
+              - pool_size: 5
+        "};
+        let config: ConfigBuilder = serde_yaml::from_str(yaml).expect("parse yaml");
+        assert!(config.finalize().is_err());
+
+        let yaml = indoc! {"
+            connection_string: 'postgres://postgres@localhost:5432/basemaps'
+            databases:
+              - connection_string: 'postgres://postgres@localhost:5432/overlays'
+                databases:
+                  - connection_string: 'postgres://postgres@localhost:5432/rasters'
+        "};
+        let config: ConfigBuilder = serde_yaml::from_str(yaml).expect("parse yaml");
+        assert!(config.finalize().is_err());
+    }
+
+    fn expand(contents: &str) -> io::Result<String> {
+        let vars = HashMap::from([
+            ("DATABASE_URL", "postgres://postgres@db:5432/db"),
+            ("EMPTY", ""),
+        ]);
+        expand_env_vars(contents, |name| vars.get(name).map(|v| (*v).to_owned()))
+    }
+
+    #[test]
+    fn expand_env_vars_ok() {
+        assert_eq!(
+            expand("connection_string: '${DATABASE_URL}'").unwrap(),
+            "connection_string: 'postgres://postgres@db:5432/db'"
+        );
+        assert_eq!(
+            expand("listen_addresses: '0.0.0.0:${PORT:-3000}'").unwrap(),
+            "listen_addresses: '0.0.0.0:3000'"
+        );
+        assert_eq!(
+            expand("${DATABASE_URL:-postgres://localhost/db} ${EMPTY:-empty} ${EMPTY}").unwrap(),
+            "postgres://postgres@db:5432/db empty "
+        );
+        assert_eq!(expand("$${PORT} $PORT $").unwrap(), "${PORT} $PORT $");
+
+        let yaml = expand(indoc! {"
+            connection_string: '${DATABASE_URL}'
+            listen_addresses: '0.0.0.0:${PORT:-3000}'
+        "})
+        .unwrap();
+        let config: ConfigBuilder = serde_yaml::from_str(&yaml).expect("parse yaml");
+        let config = config.finalize().expect("finalize");
+        assert_eq!(
+            config.pg.connection_string,
+            "postgres://postgres@db:5432/db"
+        );
+        assert_eq!(config.srv.listen_addresses, "0.0.0.0:3000");
+    }
+
+    #[test]
+    fn expand_env_vars_missing() {
+        let err = expand("connection_string: '${MISSING_DATABASE_URL}'").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Environment variable MISSING_DATABASE_URL is not set and has no default"
+        );
+
+        let err = expand("connection_string: '${DATABASE_URL'").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unclosed environment variable reference '${DATABASE_URL''"
+        );
+    }
+
+    #[test]
+    fn parse_config_base_path() {
+        for (base_path, expected) in [
+            ("/tiles/", "/tiles"),
+            ("tiles", "/tiles"),
+            ("//maps//tiles", "/maps/tiles"),
+            ("/", ""),
+            ("", ""),
+        ] {
+            let yaml = format!(
+                "connection_string: 'postgres://postgres@localhost:5432/db'\nbase_path: '{base_path}'"
+            );
+            let config: ConfigBuilder = serde_yaml::from_str(&yaml).expect("parse yaml");
+            let config = config.finalize().expect("finalize");
+            assert_eq!(config.srv.base_path, expected);
+        }
+    }
+
+    #[test]
+    fn parse_config_source_headers() {
+        let yaml = indoc! {"
+            connection_string: 'postgres://postgres@localhost:5432/db'
+            table_sources:
+              public.table_source:
+                id: public.table_source
+                schema: public
+                table: table_source
+                srid: 4326
+                geometry_column: geom
+                properties:
+                  gid: int4
+                headers:
+                  X-Data-License: CC-BY-4.0
+                  Surrogate-Control: max-age=86400
+                  Content-Type: text/plain
+                  content-encoding: br
+                  'Bad Header': value
+        "};
+        let config: ConfigBuilder = serde_yaml::from_str(yaml).expect("parse yaml");
+        let config = config.finalize().expect("finalize");
+        let headers = config.pg.table_sources["public.table_source"]
+            .headers
+            .clone()
+            .unwrap();
+        assert_eq!(
+            headers,
+            HashMap::from([
+                ("X-Data-License".to_string(), "CC-BY-4.0".to_string()),
+                ("Surrogate-Control".to_string(), "max-age=86400".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_config_auto_publish() {
+        let yaml = indoc! {"
+            connection_string: 'postgres://postgres@localhost:5432/db'
+            auto_publish:
+              schemas: [public, app_*]
+              exclude_schemas: [app_internal]
+              exclude_tables: [tmp_*, public.spatial_ref_sys]
+        "};
+        let config: ConfigBuilder = serde_yaml::from_str(yaml).expect("parse yaml");
+        let config = config.finalize().expect("finalize");
+        assert!(config.pg.use_dynamic_sources);
+        let auto_publish = config.pg.auto_publish.unwrap();
+
+        assert!(auto_publish.is_schema_published("public"));
+        assert!(auto_publish.is_schema_published("app_roads"));
+        assert!(!auto_publish.is_schema_published("app_internal"));
+        assert!(!auto_publish.is_schema_published("topology"));
+
+        assert!(auto_publish.is_table_published("public", "points"));
+        assert!(!auto_publish.is_table_published("public", "tmp_points"));
+        assert!(!auto_publish.is_table_published("app_roads", "tmp_roads"));
+        assert!(!auto_publish.is_table_published("public", "spatial_ref_sys"));
+        assert!(auto_publish.is_table_published("app_roads", "spatial_ref_sys"));
+        assert!(!auto_publish.is_table_published("topology", "points"));
+    }
+
+    #[test]
+    fn parse_config_on_missing_source() {
+        let yaml = indoc! {"
+            connection_string: 'postgres://postgres@localhost:5432/db'
+            on_missing_source: error
+        "};
+        let config: ConfigBuilder = serde_yaml::from_str(yaml).expect("parse yaml");
+        let config = config.finalize().expect("finalize");
+        assert_eq!(config.pg.on_missing_source, OnMissingSource::Error);
+
+        let yaml = "on_missing_source: ignore";
+        assert!(serde_yaml::from_str::<ConfigBuilder>(yaml).is_err());
+    }
+
+    #[test]
+    fn parse_config_aliases() {
+        let yaml = indoc! {"
+            connection_string: 'postgres://postgres@localhost:5432/db'
+            aliases:
+              roads: public.table_source
+        "};
+        let config: ConfigBuilder = serde_yaml::from_str(yaml).expect("parse yaml");
+        let config = config.finalize().expect("finalize");
+        assert_eq!(
+            config.pg.aliases,
+            HashMap::from([("roads".to_owned(), "public.table_source".to_owned())])
+        );
+
+        // Aliases refer to the sources of all databases, so they are only set at the top level
+        let yaml = indoc! {"
+            connection_string: 'postgres://postgres@localhost:5432/db'
+            databases:
+              - connection_string: 'postgres://postgres@localhost:5432/other'
+                aliases:
+                  roads: public.table_source
+        "};
+        let config: ConfigBuilder = serde_yaml::from_str(yaml).expect("parse yaml");
+        assert!(config.finalize().is_err());
+    }
+
+    #[test]
+    fn parse_config_ssl_cert() {
+        let parse = |yaml: &str| {
+            let yaml = format!("connection_string: 'postgres://localhost/db'\n{yaml}");
+            serde_yaml::from_str::<ConfigBuilder>(&yaml)
+                .expect("parse yaml")
+                .finalize()
+        };
+        let config = parse("ssl_cert: client.crt\nssl_key: client.key").unwrap();
+        assert_eq!(config.pg.ssl_cert.as_deref(), Some("client.crt"));
+        assert_eq!(config.pg.ssl_key.as_deref(), Some("client.key"));
+
+        for yaml in ["ssl_cert: client.crt", "ssl_key: client.key"] {
+            let error = parse(yaml).unwrap_err();
+            assert!(
+                error.to_string().contains("set together"),
+                "{yaml}: {error}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_config_table_defaults() {
+        let yaml = indoc! {"
+            connection_string: 'postgres://postgres@localhost:5432/db'
+            default_extent: 8192
+            default_buffer: 256
+            default_clip_geom: false
+        "};
+        let config: ConfigBuilder = serde_yaml::from_str(yaml).expect("parse yaml");
+        let config = config.finalize().expect("finalize");
+        assert_eq!(config.pg.default_extent, Some(8192));
+        assert_eq!(config.pg.default_buffer, Some(256));
+        assert_eq!(config.pg.default_clip_geom, Some(false));
+    }
+
+    #[test]
+    fn parse_config_worker_processes() {
+        let parse = |yaml: &str| {
+            let yaml = format!("connection_string: 'postgres://localhost/db'\n{yaml}");
+            serde_yaml::from_str::<ConfigBuilder>(&yaml).expect("parse yaml")
+        };
+
+        let config = parse("keep_alive: 5").finalize().unwrap();
+        assert_eq!(config.srv.worker_processes, num_cpus::get());
+        assert_eq!(config.srv.keep_alive, 5);
+
+        let config = parse("worker_processes: 4").finalize().unwrap();
+        assert_eq!(config.srv.worker_processes, 4);
+
+        // The command line overrides the config file
+        let mut srv = SrvConfigBuilder::from(SrvArgs {
+            keep_alive: None,
+            listen_addresses: None,
+            workers: Some(2),
+            base_path: None,
+            max_zoom: None,
+        });
+        srv.merge(parse("worker_processes: 4").srv);
+        assert_eq!(srv.finalize().unwrap().worker_processes, 2);
+
+        let error = parse("worker_processes: 0").finalize().unwrap_err();
+        assert_eq!(error.to_string(), "worker_processes must be at least 1");
+    }
+
+    #[test]
+    fn parse_config_reserved_pool_size() {
+        let parse = |yaml: &str| {
+            let yaml = format!("connection_string: 'postgres://localhost/db'\n{yaml}");
+            serde_yaml::from_str::<ConfigBuilder>(&yaml)
+                .expect("parse yaml")
+                .finalize()
+        };
+
+        let config = parse("reserved_pool_size: 4").unwrap();
+        assert_eq!(config.pg.reserved_pool_size, Some(4));
+        assert_eq!(parse("pool_size: 4").unwrap().pg.reserved_pool_size, None);
+
+        // bb8 panics on a pool without connections
+        let error = parse("reserved_pool_size: 0").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "reserved_pool_size must be at least 1, or left out to disable the reserved pool"
+        );
+    }
+
+    #[test]
+    fn parse_config_ambiguous_join() {
+        let yaml = indoc! {"
+            ---
+            connection_string: 'postgres://postgres@localhost:5432/db'
+            table_sources:
+              public.table_source:
+                id: public.table_source
+                schema: public
+                table: table_source
+                srid: 4326
+                geometry_column: geom
+                properties:
+                  gid: int4
+                  name: text
+                join:
+                  table: table_source_attributes
+                  key: gid
+                  columns: [name]
+        "};
+
+        let config: ConfigBuilder = serde_yaml::from_str(yaml).expect("parse yaml");
+        let error = config.finalize().unwrap_err();
+        assert!(error.to_string().contains("ambiguous"));
+    }
+
+    #[test]
+    fn parse_config_buffer_exceeds_extent() {
+        let yaml = indoc! {"
+            ---
+            connection_string: 'postgres://postgres@localhost:5432/db'
+            table_sources:
+              public.table_source:
+                id: public.table_source
+                schema: public
+                table: table_source
+                srid: 4326
+                geometry_column: geom
+                extent: 4096
+                buffer: 8192
+                properties:
+                  gid: int4
+        "};
+
+        let config: ConfigBuilder = serde_yaml::from_str(yaml).expect("parse yaml");
+        let error = config.finalize().unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("buffer 8192 larger than its extent 4096"));
+    }
+
+    #[test]
+    fn parse_config_buffer() {
+        let yaml = indoc! {"
+            ---
+            connection_string: 'postgres://postgres@localhost:5432/db'
+            table_sources:
+              single:
+                id: single
+                schema: public
+                table: table_source
+                srid: 4326
+                geometry_column: geom
+                buffer: 64
+                properties: {}
+              per_zoom:
+                id: per_zoom
+                schema: public
+                table: table_source
+                srid: 4326
+                geometry_column: geom
+                buffer:
+                  0: 256
+                  10: 32
+                properties: {}
+        "};
+
+        let config: ConfigBuilder = serde_yaml::from_str(yaml).expect("parse yaml");
+        let config = config.finalize().unwrap();
+        let table_sources = config.pg.table_sources;
+        assert_eq!(table_sources["single"].buffer, Some(Buffer::Value(64)));
+        assert_eq!(
+            table_sources["per_zoom"].buffer,
+            Some(Buffer::Zooms(BTreeMap::from([(0, 256), (10, 32)])))
+        );
+        assert_eq!(table_sources["per_zoom"].get_buffer(9), 256);
+        assert_eq!(table_sources["per_zoom"].get_buffer(10), 32);
+    }
+
+    #[test]
+    fn parse_config_connection_string_file() {
+        let file = env::temp_dir().join("martin_connection_string_file");
+        fs::write(&file, "postgres://file@localhost:5432/db\n").unwrap();
+        let yaml = format!(
+            "connection_string: 'postgres://config@localhost:5432/db'\nconnection_string_file: '{}'",
+            file.display()
+        );
+
+        // the file takes precedence over the connection string of the config
+        let config: ConfigBuilder = serde_yaml::from_str(&yaml).expect("parse yaml");
+        let config = config.finalize().unwrap();
+        assert_eq!(
+            config.pg.connection_string,
+            "postgres://file@localhost:5432/db"
+        );
+
+        // and over the connection string of the command line or DATABASE_URL
+        let args = PgArgs {
+            connection_string_file: None,
+            ca_root_file: None,
+            ca_root_dir: None,
+            ssl_cert: None,
+            ssl_key: None,
+            danger_accept_invalid_certs: false,
+            default_srid: None,
+            pool_size: None,
+            pool_acquire_timeout: None,
+        };
+        let mut pg = PgConfigBuilder::from((args, Some("postgres://arg@localhost/db".to_owned())));
+        let config: ConfigBuilder = serde_yaml::from_str(&yaml).expect("parse yaml");
+        pg.merge(config.pg);
+        assert_eq!(
+            pg.finalize().unwrap().connection_string,
+            "postgres://file@localhost:5432/db"
+        );
+
+        fs::write(&file, "\n").unwrap();
+        let config: ConfigBuilder = serde_yaml::from_str(&yaml).expect("parse yaml");
+        let error = config.finalize().unwrap_err();
+        assert!(error.to_string().contains("is empty"));
+
+        fs::remove_file(&file).unwrap();
+        let config: ConfigBuilder = serde_yaml::from_str(&yaml).expect("parse yaml");
+        let error = config.finalize().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error
+            .to_string()
+            .contains("Can't read connection_string_file"));
+    }
+
+    #[test]
+    fn save_config_round_trip() {
+        let yaml = indoc! {"
+            ---
+            connection_string: 'postgres://postgres@localhost:5432/db'
+            api_keys: [server-secret]
+            table_sources:
+              public.table_source:
+                id: public.table_source
+                schema: public
+                table: table_source
+                srid: 4326
+                geometry_column: geom
+                bounds: [-180.0, -90.0, 180.0, 90.0]
+                geometry_type: GEOMETRY
+                properties:
+                  gid: int4
+                api_keys: [secret]
+            function_sources:
+              public.function_source:
+                id: public.function_source
+                schema: public
+                function: function_source
+        "};
+
+        let config: ConfigBuilder = serde_yaml::from_str(yaml).expect("parse yaml");
+        let config = config.finalize().expect("finalize");
+
+        let saved = serde_yaml::to_string(&config).expect("serialize config");
+        assert!(saved.contains("server-secret"));
+        let reloaded: ConfigBuilder = serde_yaml::from_str(&saved).expect("parse saved yaml");
+        assert!(reloaded.unrecognized.is_empty());
+        assert_eq!(reloaded.finalize().expect("finalize saved"), config);
+    }
+
+    #[test]
+    fn read_configs_merged() {
+        let base = indoc! {"
+            connection_string: 'postgres://postgres@localhost:5432/db'
+            listen_addresses: '0.0.0.0:3000'
+            keep_alive: 75
+            pool_size: 20
+            table_sources:
+              points:
+                id: points
+                schema: public
+                table: points
+                srid: 4326
+                geometry_column: geom
+                geometry_type: POINT
+                properties:
+                  gid: int4
+              lines:
+                id: lines
+                schema: public
+                table: lines
+                srid: 4326
+                geometry_column: geom
+                geometry_type: LINESTRING
+                properties:
+                  gid: int4
+            function_sources:
+              tiles:
+                id: tiles
+                schema: public
+                function: tiles
+        "};
+        let overlay = indoc! {"
+            keep_alive: 10
+            table_sources:
+              lines:
+                id: lines
+                schema: staging
+                table: lines
+                srid: 3857
+                geometry_column: geom
+                geometry_type: LINESTRING
+                properties:
+                  gid: int4
+              polygons:
+                id: polygons
+                schema: public
+                table: polygons
+                srid: 4326
+                geometry_column: geom
+                geometry_type: POLYGON
+                properties:
+                  gid: int4
+        "};
+        let dir = env::temp_dir();
+        let file_names: Vec<String> = [("base", base), ("overlay", overlay)]
+            .into_iter()
+            .map(|(name, yaml)| {
+                let file = dir.join(format!("martin_{name}_{}.yaml", std::process::id()));
+                fs::write(&file, yaml).unwrap();
+                file.display().to_string()
+            })
+            .collect();
+
+        let config = read_configs(&file_names).unwrap().finalize().unwrap();
+        for file_name in &file_names {
+            fs::remove_file(file_name).unwrap();
+        }
+
+        // Settings of the overlay override the base, the others are kept
+        assert_eq!(config.srv.keep_alive, 10);
+        assert_eq!(config.srv.listen_addresses, "0.0.0.0:3000");
+        assert_eq!(config.pg.pool_size, 20);
+        assert_eq!(
+            config.pg.connection_string,
+            "postgres://postgres@localhost:5432/db"
+        );
+
+        // Sources of both files are served, the overlay replacing the base ones with its ids
+        let mut ids: Vec<_> = config.pg.table_sources.keys().cloned().collect();
+        ids.sort();
+        assert_eq!(ids, vec!["lines", "points", "polygons"]);
+        let lines = &config.pg.table_sources["lines"];
+        assert_eq!((lines.schema.as_str(), lines.srid), ("staging", 3857));
+        assert_eq!(config.pg.table_sources["points"].srid, 4326);
+        assert!(config.pg.function_sources.contains_key("tiles"));
+
+        assert!(read_configs(&[]).is_err());
+    }
 }
@@ -0,0 +1,384 @@
+//! Serving of pre-rendered tiles from a directory tree of `{z}/{x}/{y}.{extension}` files,
+//! and of the features of GeoParquet files.
+
+#[cfg(feature = "geoparquet")]
+use crate::geoparquet::GeoParquetSource;
+use crate::pg::db::Connection;
+use crate::source::{
+    is_valid_zoom, validate_zoom_range, EmptyTile, InMemorySource, Source, Tile, TileFormat,
+    UrlQuery, Xyz, MAX_ZOOM,
+};
+use actix_web::web;
+use async_trait::async_trait;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tilejson::{tilejson, TileJSON};
+
+/// Extensions of the GeoParquet files in the `file_sources` config
+pub const GEOPARQUET_EXTENSIONS: &[&str] = &["parquet", "geoparquet"];
+
+/// A directory of tiles or a GeoParquet file in the `file_sources` config
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FileTreeConfig {
+    /// Root directory of the `{z}/{x}/{y}.{extension}` files, or the GeoParquet file
+    pub path: String,
+    /// Extension of the tile files, `pbf`, `mvt` or `png`,
+    /// or `parquet` or `geoparquet` for a GeoParquet file
+    pub extension: String,
+    /// Lowest zoom level served, instead of the lowest one of the directory tree
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minzoom: Option<u8>,
+    /// Highest zoom level served, instead of the highest one of the directory tree
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maxzoom: Option<u8>,
+}
+
+impl FileTreeConfig {
+    /// Check the configured zoom levels of the source
+    pub fn validate(&self, id: &str) -> io::Result<()> {
+        validate_zoom_range(id, self.minzoom, self.maxzoom)
+    }
+}
+
+pub type FileSources = HashMap<String, Box<FileSource>>;
+
+/// Create the sources of the `file_sources` config
+pub fn resolve_file_sources(config: &HashMap<String, FileTreeConfig>) -> io::Result<FileSources> {
+    config
+        .iter()
+        .map(|(id, source)| {
+            let mut file_source = if GEOPARQUET_EXTENSIONS.contains(&source.extension.as_str()) {
+                new_geoparquet_source(id, &source.path)?
+            } else {
+                FileSource::Tree(FileTreeSource::new(id, &source.path, &source.extension)?)
+            };
+            let tilejson = file_source.tilejson_mut();
+            if source.minzoom.is_some() {
+                tilejson.minzoom = source.minzoom;
+            }
+            if source.maxzoom.is_some() {
+                tilejson.maxzoom = source.maxzoom;
+            }
+            Ok((id.clone(), Box::new(file_source)))
+        })
+        .collect()
+}
+
+#[cfg(feature = "geoparquet")]
+fn new_geoparquet_source(id: &str, path: &str) -> io::Result<FileSource> {
+    GeoParquetSource::new(id, path).map(FileSource::GeoParquet)
+}
+
+#[cfg(not(feature = "geoparquet"))]
+fn new_geoparquet_source(id: &str, path: &str) -> io::Result<FileSource> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("Source {id} is the GeoParquet file {path}, which requires the geoparquet feature"),
+    ))
+}
+
+/// A source of the `file_sources` config, or tiles kept in memory by an application embedding martin
+#[derive(Clone, Debug)]
+pub enum FileSource {
+    Tree(FileTreeSource),
+    #[cfg(feature = "geoparquet")]
+    GeoParquet(GeoParquetSource),
+    InMemory(InMemorySource),
+}
+
+impl FileSource {
+    fn as_source(&self) -> &(dyn Source + Send + Sync) {
+        match self {
+            Self::Tree(source) => source,
+            #[cfg(feature = "geoparquet")]
+            Self::GeoParquet(source) => source,
+            Self::InMemory(source) => source,
+        }
+    }
+
+    /// Check that the files of the source can still be read. In-memory sources always can.
+    pub fn check(&self) -> io::Result<()> {
+        match self {
+            Self::Tree(source) => source.check(),
+            #[cfg(feature = "geoparquet")]
+            Self::GeoParquet(source) => source.check(),
+            Self::InMemory(_) => Ok(()),
+        }
+    }
+
+    fn tilejson_mut(&mut self) -> &mut TileJSON {
+        match self {
+            Self::Tree(source) => &mut source.tilejson,
+            #[cfg(feature = "geoparquet")]
+            Self::GeoParquet(source) => &mut source.tilejson,
+            Self::InMemory(source) => &mut source.tilejson,
+        }
+    }
+}
+
+#[allow(mismatched_lifetime_syntaxes)]
+#[async_trait]
+impl Source for FileSource {
+    async fn get_id(&self) -> &str {
+        self.as_source().get_id().await
+    }
+
+    async fn get_tilejson(&self) -> Result<TileJSON, io::Error> {
+        self.as_source().get_tilejson().await
+    }
+
+    fn is_valid_zoom(&self, zoom: i32) -> bool {
+        self.as_source().is_valid_zoom(zoom)
+    }
+
+    fn get_format(&self) -> TileFormat {
+        self.as_source().get_format()
+    }
+
+    fn get_max_age(&self) -> Option<Duration> {
+        self.as_source().get_max_age()
+    }
+
+    fn get_empty_tile(&self) -> EmptyTile {
+        self.as_source().get_empty_tile()
+    }
+
+    fn get_headers(&self) -> Option<&HashMap<String, String>> {
+        self.as_source().get_headers()
+    }
+
+    fn get_max_feature_count(&self) -> Option<usize> {
+        self.as_source().get_max_feature_count()
+    }
+
+    fn is_out_of_bounds(&self, xyz: &Xyz) -> bool {
+        self.as_source().is_out_of_bounds(xyz)
+    }
+
+    fn requires_connection(&self) -> bool {
+        self.as_source().requires_connection()
+    }
+
+    async fn get_tile(
+        &self,
+        conn: Option<&mut Connection>,
+        xyz: &Xyz,
+        query: &Option<UrlQuery>,
+    ) -> Result<Tile, io::Error> {
+        self.as_source().get_tile(conn, xyz, query).await
+    }
+}
+
+/// A source reading the tiles of a directory tree, e.g. `tiles/{z}/{x}/{y}.pbf`.
+/// Missing tiles are empty.
+#[derive(Clone, Debug)]
+pub struct FileTreeSource {
+    pub id: String,
+    pub root: PathBuf,
+    pub extension: String,
+    pub format: TileFormat,
+    pub tilejson: TileJSON,
+}
+
+impl FileTreeSource {
+    /// Create a source of the tiles under `root` with the file `extension`, whose format it
+    /// determines. The zoom levels are those of the numeric directories under `root`.
+    pub fn new(id: &str, root: impl Into<PathBuf>, extension: &str) -> io::Result<Self> {
+        let root = root.into();
+        let format = [TileFormat::Mvt, TileFormat::Png]
+            .into_iter()
+            .find(|format| format.extensions().contains(&extension))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Unsupported tile file extension '{extension}' of source {id}"),
+                )
+            })?;
+
+        let mut zooms = Vec::new();
+        for entry in read_root(&root)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                let zoom = entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|z| z.parse::<u8>().ok());
+                zooms.extend(zoom.filter(|zoom| *zoom <= MAX_ZOOM));
+            }
+        }
+
+        let mut tilejson = tilejson! {
+            tilejson: "2.2.0".to_string(),
+            tiles: vec![],  // tile source is required, but not yet known
+            name: id.to_owned(),
+        };
+        tilejson.minzoom = zooms.iter().min().copied();
+        tilejson.maxzoom = zooms.iter().max().copied();
+
+        info!(
+            "Serving tiles of source {id} from {} with zoom levels {:?}..{:?}",
+            root.display(),
+            tilejson.minzoom,
+            tilejson.maxzoom
+        );
+        Ok(Self {
+            id: id.to_owned(),
+            root,
+            extension: extension.to_owned(),
+            format,
+            tilejson,
+        })
+    }
+
+    /// Check that the tiles directory can still be read
+    pub fn check(&self) -> io::Result<()> {
+        read_root(&self.root).map(|_| ())
+    }
+
+    /// Read the file of a tile, or an empty tile if there is none
+    pub fn read_tile(&self, xyz: &Xyz) -> io::Result<Tile> {
+        let file = self
+            .root
+            .join(xyz.z.to_string())
+            .join(xyz.x.to_string())
+            .join(format!("{}.{}", xyz.y, self.extension));
+        match fs::read(file) {
+            Ok(data) => Ok(Tile::from(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Tile::new()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn read_root(root: &Path) -> io::Result<fs::ReadDir> {
+    fs::read_dir(root).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Can't read tiles directory {}: {e}", root.display()),
+        )
+    })
+}
+
+#[allow(mismatched_lifetime_syntaxes)]
+#[async_trait]
+impl Source for FileTreeSource {
+    async fn get_id(&self) -> &str {
+        self.id.as_str()
+    }
+
+    async fn get_tilejson(&self) -> Result<TileJSON, io::Error> {
+        Ok(self.tilejson.clone())
+    }
+
+    fn is_valid_zoom(&self, zoom: i32) -> bool {
+        is_valid_zoom(zoom, self.tilejson.minzoom, self.tilejson.maxzoom)
+    }
+
+    fn get_format(&self) -> TileFormat {
+        self.format
+    }
+
+    fn requires_connection(&self) -> bool {
+        false
+    }
+
+    async fn get_tile(
+        &self,
+        _conn: Option<&mut Connection>,
+        xyz: &Xyz,
+        _query: &Option<UrlQuery>,
+    ) -> Result<Tile, io::Error> {
+        let (source, xyz) = (self.clone(), *xyz);
+        web::block(move || source.read_tile(&xyz))
+            .await
+            .map_err(io::Error::other)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tiles");
+
+    #[test]
+    fn test_new_file_tree_source() {
+        let source = FileTreeSource::new("tiles", FIXTURES, "pbf").unwrap();
+        assert_eq!(source.format, TileFormat::Mvt);
+        assert_eq!(source.tilejson.minzoom, Some(0));
+        assert_eq!(source.tilejson.maxzoom, Some(1));
+        assert!(source.is_valid_zoom(1));
+        assert!(!source.is_valid_zoom(2));
+
+        let source = FileTreeSource::new("tiles", FIXTURES, "png").unwrap();
+        assert_eq!(source.format, TileFormat::Png);
+
+        let error = FileTreeSource::new("tiles", FIXTURES, "txt").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        let error = FileTreeSource::new("tiles", format!("{FIXTURES}/missing"), "pbf").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_resolve_file_sources() {
+        let config = HashMap::from([(
+            "tiles".to_owned(),
+            FileTreeConfig {
+                path: FIXTURES.to_owned(),
+                extension: "pbf".to_owned(),
+                minzoom: None,
+                maxzoom: None,
+            },
+        )]);
+        let sources = resolve_file_sources(&config).unwrap();
+        assert!(matches!(
+            sources["tiles"].as_ref(),
+            FileSource::Tree(source) if source.id == "tiles" && source.root == Path::new(FIXTURES)
+        ));
+    }
+
+    #[test]
+    fn test_file_tree_config_zooms() {
+        let mut config = FileTreeConfig {
+            path: FIXTURES.to_owned(),
+            extension: "pbf".to_owned(),
+            minzoom: Some(1),
+            maxzoom: None,
+        };
+        config.validate("tiles").unwrap();
+        let sources =
+            resolve_file_sources(&HashMap::from([("tiles".to_owned(), config.clone())])).unwrap();
+        assert!(!sources["tiles"].is_valid_zoom(0));
+        assert!(sources["tiles"].is_valid_zoom(1));
+
+        config.maxzoom = Some(0);
+        let error = config.validate("tiles").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Source tiles has minzoom 1 greater than its maxzoom 0"
+        );
+
+        config.minzoom = None;
+        config.maxzoom = Some(31);
+        let error = config.validate("tiles").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Source tiles has maxzoom 31, must be between 0 and 30"
+        );
+    }
+
+    #[test]
+    fn test_read_tile() {
+        let source = FileTreeSource::new("tiles", FIXTURES, "pbf").unwrap();
+        let tile = source.read_tile(&Xyz { z: 1, x: 1, y: 0 }).unwrap();
+        assert_eq!(&tile[..2], &[0x1a, 0x0d]);
+
+        let tile = source.read_tile(&Xyz { z: 1, x: 0, y: 0 }).unwrap();
+        assert!(tile.is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+//! Serving of the [glyph ranges](https://github.com/mapbox/glyph-pbf-composite) used by
+//! map styles for their labels, from a directory of `{fontstack}/{start}-{end}.pbf` files.
+
+use crate::mvt::{invalid, write_len_field, Fields, WIRE_LEN, WIRE_VARINT};
+use log::info;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// `Glyphs.stacks` field number
+const GLYPHS_STACKS: u64 = 1;
+/// `Fontstack.name` field number
+const FONTSTACK_NAME: u64 = 1;
+/// `Fontstack.range` field number
+const FONTSTACK_RANGE: u64 = 2;
+/// `Fontstack.glyphs` field number
+const FONTSTACK_GLYPHS: u64 = 3;
+/// `Glyph.id` field number
+const GLYPH_ID: u64 = 1;
+
+/// Number of glyphs in a range file
+pub const GLYPH_RANGE_SIZE: u32 = 256;
+/// Highest glyph id, as glyph ranges cover the Unicode Basic Multilingual Plane
+pub const GLYPH_ID_MAX: u32 = 65535;
+
+/// A directory with a sub-directory of glyph range files for every font
+#[derive(Debug, Clone)]
+pub struct FontSources {
+    path: PathBuf,
+}
+
+impl FontSources {
+    pub fn new(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let sources = Self { path: path.into() };
+        let fonts = sources.get_fonts().map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Can't read fonts directory {}: {e}", sources.path.display()),
+            )
+        })?;
+        info!(
+            "Serving {} fonts from {}",
+            fonts.len(),
+            sources.path.display()
+        );
+        Ok(sources)
+    }
+
+    /// Names of the available fonts, sorted
+    pub fn get_fonts(&self) -> io::Result<Vec<String>> {
+        let mut fonts = Vec::new();
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                if let Some(name) = entry.file_name().to_str() {
+                    fonts.push(name.to_owned());
+                }
+            }
+        }
+        fonts.sort();
+        Ok(fonts)
+    }
+
+    /// Get a glyph range of a comma-separated fontstack, e.g. `Open Sans Regular,Arial Unicode MS Regular`.
+    /// Glyphs missing from a font are taken from the next one, and fonts without the range
+    /// are skipped. Returns `None` if no font of the fontstack has the range.
+    pub fn get_font_range(
+        &self,
+        fontstack: &str,
+        start: u32,
+        end: u32,
+    ) -> io::Result<Option<Vec<u8>>> {
+        if !start.is_multiple_of(GLYPH_RANGE_SIZE)
+            || end != start + GLYPH_RANGE_SIZE - 1
+            || end > GLYPH_ID_MAX
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid glyph range {start}-{end}, ranges are like 0-255 or 256-511"),
+            ));
+        }
+
+        let mut fonts = Vec::new();
+        for font in fontstack.split(',').map(str::trim) {
+            if font.is_empty() || font == "." || font == ".." || font.contains(['/', '\\']) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid font name '{font}'"),
+                ));
+            }
+            let path = self.path.join(font).join(format!("{start}-{end}.pbf"));
+            match fs::read(path) {
+                Ok(data) => fonts.push((font, data)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        match fonts.as_slice() {
+            [] => Ok(None),
+            [(_, data)] => Ok(Some(data.clone())),
+            _ => {
+                let names: Vec<&str> = fonts.iter().map(|(name, _)| *name).collect();
+                let ranges = fonts.iter().map(|(_, data)| data.as_slice());
+                merge_glyphs(&names.join(", "), &format!("{start}-{end}"), ranges).map(Some)
+            }
+        }
+    }
+}
+
+/// Combine the glyphs of several glyph ranges into a single fontstack named `name`.
+/// A glyph found in several ranges is taken from the first one.
+pub fn merge_glyphs<'a>(
+    name: &str,
+    range: &str,
+    ranges: impl IntoIterator<Item = &'a [u8]>,
+) -> io::Result<Vec<u8>> {
+    let mut glyphs = BTreeMap::new();
+    for data in ranges {
+        for field in (Fields { buf: data }) {
+            let (field, wire_type, fontstack) = field?;
+            if field != GLYPHS_STACKS || wire_type != WIRE_LEN {
+                continue;
+            }
+            for field in (Fields { buf: fontstack }) {
+                if let (FONTSTACK_GLYPHS, WIRE_LEN, glyph) = field? {
+                    glyphs.entry(get_glyph_id(glyph)?).or_insert(glyph);
+                }
+            }
+        }
+    }
+
+    let mut fontstack = Vec::new();
+    write_len_field(&mut fontstack, FONTSTACK_NAME, name.as_bytes());
+    write_len_field(&mut fontstack, FONTSTACK_RANGE, range.as_bytes());
+    for glyph in glyphs.values() {
+        write_len_field(&mut fontstack, FONTSTACK_GLYPHS, glyph);
+    }
+    let mut merged = Vec::with_capacity(fontstack.len() + 8);
+    write_len_field(&mut merged, GLYPHS_STACKS, &fontstack);
+    Ok(merged)
+}
+
+fn get_glyph_id(glyph: &[u8]) -> io::Result<u64> {
+    let mut fields = Fields { buf: glyph };
+    while !fields.buf.is_empty() {
+        if let (GLYPH_ID, WIRE_VARINT, _, raw) = fields.read_raw_field()? {
+            let mut id = Fields { buf: raw };
+            id.read_varint()?;
+            return id.read_varint();
+        }
+    }
+    Err(invalid("Glyph without an id"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixtures() -> FontSources {
+        FontSources::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/fonts")).unwrap()
+    }
+
+    /// Glyph ids of a glyph range, with the glyphs
+    fn get_glyphs(data: &[u8]) -> Vec<(u64, Vec<u8>)> {
+        let mut glyphs = Vec::new();
+        for field in (Fields { buf: data }) {
+            let (_, _, fontstack) = field.unwrap();
+            for field in (Fields { buf: fontstack }) {
+                if let (FONTSTACK_GLYPHS, WIRE_LEN, glyph) = field.unwrap() {
+                    glyphs.push((get_glyph_id(glyph).unwrap(), glyph.to_vec()));
+                }
+            }
+        }
+        glyphs
+    }
+
+    #[test]
+    fn test_get_fonts() {
+        assert_eq!(fixtures().get_fonts().unwrap(), vec!["Font A", "Font B"]);
+        assert!(FontSources::new("/nonexistent/fonts").is_err());
+    }
+
+    #[test]
+    fn test_merge_glyphs() {
+        let fonts = fixtures();
+        let font_a = fonts.get_font_range("Font A", 0, 255).unwrap().unwrap();
+        let font_b = fonts.get_font_range("Font B", 0, 255).unwrap().unwrap();
+        let glyphs_a = get_glyphs(&font_a);
+        let glyphs_b = get_glyphs(&font_b);
+        let ids = |glyphs: &[(u64, Vec<u8>)]| glyphs.iter().map(|g| g.0).collect::<Vec<_>>();
+        assert_eq!(ids(&glyphs_a), vec![65, 66]);
+        assert_eq!(ids(&glyphs_b), vec![66, 67]);
+
+        let merged = merge_glyphs("Font A, Font B", "0-255", [&font_a[..], &font_b[..]]).unwrap();
+        let glyphs = get_glyphs(&merged);
+        assert_eq!(ids(&glyphs), vec![65, 66, 67]);
+        // the first font wins
+        assert_eq!(glyphs[1], glyphs_a[1]);
+        assert_eq!(glyphs[2], glyphs_b[1]);
+
+        let merged_fontstack = fonts.get_font_range("Font A,Font B", 0, 255).unwrap();
+        assert_eq!(merged_fontstack, Some(merged));
+
+        let merged = fonts
+            .get_font_range("Font B, Font A", 0, 255)
+            .unwrap()
+            .unwrap();
+        let glyphs = get_glyphs(&merged);
+        assert_eq!(glyphs[1], glyphs_b[0]);
+    }
+
+    #[test]
+    fn test_get_font_range() {
+        let fonts = fixtures();
+        assert_eq!(
+            fonts.get_font_range("Missing,Font A", 0, 255).unwrap(),
+            fonts.get_font_range("Font A", 0, 255).unwrap()
+        );
+        assert_eq!(fonts.get_font_range("Font A", 256, 511).unwrap(), None);
+        assert_eq!(fonts.get_font_range("Missing", 0, 255).unwrap(), None);
+
+        let invalid = |fontstack, start, end| {
+            fonts
+                .get_font_range(fontstack, start, end)
+                .unwrap_err()
+                .kind()
+                == io::ErrorKind::InvalidInput
+        };
+        assert!(invalid("Font A", 0, 100));
+        assert!(invalid("Font A", 1, 256));
+        assert!(invalid("Font A", 65536, 65791));
+        assert!(invalid("..", 0, 255));
+        assert!(invalid("Font A,../fonts/Font B", 0, 255));
+    }
+}
@@ -0,0 +1,476 @@
+//! Serving of vector tiles from the features of a [GeoParquet](https://geoparquet.org) file.
+
+use crate::mvt::{MvtLayerEncoder, MvtValue, TileGeometry, TilePoint};
+use crate::pg::db::Connection;
+use crate::source::{intersects_bounds, Source, Tile, TileMatrixSet, UrlQuery, Xyz};
+use actix_web::web;
+use async_trait::async_trait;
+use log::info;
+use parquet::basic::{ConvertedType, LogicalType, Type as PhysicalType};
+use parquet::file::metadata::RowGroupMetaData;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::file::statistics::Statistics;
+use parquet::record::{Field, Row};
+use postgis::ewkb::{self, EwkbRead};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use tilejson::{tilejson, Bounds, TileJSON, VectorLayer};
+
+/// Extent of the tiles in tile coordinates
+const EXTENT: u32 = 4096;
+/// Tile coordinates kept beyond the tile edges, so that clipped lines and polygons
+/// have no visible edges
+const BUFFER: u32 = 64;
+/// Latitude limit of the Web Mercator projection
+const MAX_LATITUDE: f64 = 85.051_128_779_806_59;
+
+/// The `geo` key-value metadata of a GeoParquet file
+#[derive(Debug, Deserialize)]
+struct GeoMetadata {
+    primary_column: String,
+    columns: HashMap<String, GeoColumn>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GeoColumn {
+    encoding: String,
+    /// PROJJSON of the coordinate reference system, OGC:CRS84 if missing
+    #[serde(default)]
+    crs: Option<serde_json::Value>,
+    /// Bounds of the column as `[xmin, ymin, xmax, ymax]`
+    #[serde(default)]
+    bbox: Option<Vec<f64>>,
+    #[serde(default)]
+    covering: Option<Covering>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Covering {
+    bbox: BboxCovering,
+}
+
+/// Paths of the columns with the bounds of each geometry, e.g. `["bbox", "xmin"]`
+#[derive(Debug, Deserialize)]
+struct BboxCovering {
+    xmin: Vec<String>,
+    ymin: Vec<String>,
+    xmax: Vec<String>,
+    ymax: Vec<String>,
+}
+
+/// A source encoding the features of a GeoParquet file into vector tiles,
+/// with a single layer named after the source. The geometries must be WKB encoded
+/// in longitude and latitude.
+///
+/// Only the row groups intersecting a tile are read, if the file has a `bbox` covering
+/// with statistics. Tiles without features are empty.
+#[derive(Clone, Debug)]
+pub struct GeoParquetSource {
+    pub id: String,
+    pub path: PathBuf,
+    pub tilejson: TileJSON,
+    /// Column of the geometries
+    geometry_column: String,
+    /// Column of the `bbox` covering, which is not a property of the features
+    bbox_column: Option<String>,
+    /// Bounds of each row group from its statistics, or `None` if unknown
+    row_group_bounds: Vec<Option<Bounds>>,
+}
+
+impl GeoParquetSource {
+    /// Create a source of the features of the GeoParquet file at `path`
+    pub fn new(id: &str, path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let reader = open(&path)?;
+        let invalid = |reason: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid GeoParquet file {}: {reason}", path.display()),
+            )
+        };
+
+        let metadata = reader.metadata();
+        let geo = metadata
+            .file_metadata()
+            .key_value_metadata()
+            .and_then(|metadata| metadata.iter().find(|kv| kv.key == "geo"))
+            .and_then(|kv| kv.value.as_deref())
+            .ok_or_else(|| invalid("it has no geo metadata".to_owned()))?;
+        let geo: GeoMetadata =
+            serde_json::from_str(geo).map_err(|e| invalid(format!("bad geo metadata: {e}")))?;
+        let column = geo
+            .columns
+            .get(&geo.primary_column)
+            .ok_or_else(|| invalid(format!("no metadata of column {}", geo.primary_column)))?;
+        if !column.encoding.eq_ignore_ascii_case("WKB") {
+            return Err(invalid(format!(
+                "column {} has the {} encoding, only WKB is supported",
+                geo.primary_column, column.encoding
+            )));
+        }
+        if !column.crs.as_ref().is_none_or(is_lon_lat) {
+            return Err(invalid(format!(
+                "column {} is not in longitude and latitude",
+                geo.primary_column
+            )));
+        }
+
+        let covering = column.covering.as_ref().map(|covering| &covering.bbox);
+        let row_group_bounds: Vec<_> = metadata
+            .row_groups()
+            .iter()
+            .map(|row_group| covering.and_then(|covering| get_bounds(row_group, covering)))
+            .collect();
+
+        let mut tilejson = tilejson! {
+            tilejson: "2.2.0".to_string(),
+            tiles: vec![],  // tile source is required, but not yet known
+            name: id.to_owned(),
+        };
+        tilejson.bounds = match column.bbox.as_deref() {
+            Some([left, bottom, right, top]) => Some(Bounds::new(*left, *bottom, *right, *top)),
+            _ => row_group_bounds
+                .iter()
+                .copied()
+                .reduce(|a, b| Some(a? + b?))
+                .flatten(),
+        };
+        let bbox_column = covering.and_then(|covering| covering.xmin.first().cloned());
+        let fields = metadata
+            .file_metadata()
+            .schema()
+            .get_fields()
+            .iter()
+            .filter(|field| {
+                field.name() != geo.primary_column && Some(field.name()) != bbox_column.as_deref()
+            })
+            .filter_map(|field| Some((field.name().to_owned(), get_field_type(field)?)))
+            .collect();
+        tilejson.vector_layers = Some(vec![VectorLayer::new(id.to_owned(), fields)]);
+
+        info!(
+            "Serving the features of GeoParquet source {id} from {} in {} row groups",
+            path.display(),
+            row_group_bounds.len()
+        );
+        Ok(Self {
+            id: id.to_owned(),
+            path,
+            tilejson,
+            geometry_column: geo.primary_column,
+            bbox_column,
+            row_group_bounds,
+        })
+    }
+
+    /// Check that the file can still be opened and read
+    pub fn check(&self) -> io::Result<()> {
+        open(&self.path).map(|_| ())
+    }
+
+    /// Encode the features intersecting a tile, or an empty tile if there are none
+    pub fn read_tile(&self, xyz: &Xyz) -> io::Result<Tile> {
+        let buffer = f64::from(BUFFER) / f64::from(EXTENT);
+        let bounds = TileMatrixSet::WebMercatorQuad.tile_bounds(xyz, buffer);
+        let reader = open(&self.path)?;
+        let mut layer = MvtLayerEncoder::new(&self.id, EXTENT, BUFFER);
+        for (index, row_group_bounds) in self.row_group_bounds.iter().enumerate() {
+            if row_group_bounds.is_some_and(|row_group| !intersects_bounds(&row_group, &bounds)) {
+                continue;
+            }
+            let row_group = reader.get_row_group(index).map_err(io::Error::other)?;
+            let rows = row_group.get_row_iter(None).map_err(io::Error::other)?;
+            for row in rows {
+                self.add_feature(&mut layer, &row.map_err(io::Error::other)?, xyz)?;
+            }
+        }
+        if layer.is_empty() {
+            Ok(Tile::new())
+        } else {
+            Ok(Tile::from(layer.finish()))
+        }
+    }
+
+    /// Add the feature of a row to the layer, unless it has no geometry in the tile
+    fn add_feature(&self, layer: &mut MvtLayerEncoder, row: &Row, xyz: &Xyz) -> io::Result<()> {
+        let mut wkb = None;
+        let mut properties = Vec::new();
+        for (name, field) in row.get_column_iter() {
+            if *name == self.geometry_column {
+                if let Field::Bytes(bytes) = field {
+                    wkb = Some(bytes.data());
+                }
+            } else if Some(name) != self.bbox_column.as_ref() {
+                properties.extend(to_mvt_value(field).map(|value| (name.as_str(), value)));
+            }
+        }
+        let Some(mut wkb) = wkb else {
+            return Ok(());
+        };
+        let geometry = ewkb::Geometry::read_ewkb(&mut wkb).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid WKB geometry in {}: {e}", self.path.display()),
+            )
+        })?;
+        if let Some(geometry) = to_tile_geometry(&geometry, xyz) {
+            layer.add_feature(&geometry, properties);
+        }
+        Ok(())
+    }
+}
+
+#[allow(mismatched_lifetime_syntaxes)]
+#[async_trait]
+impl Source for GeoParquetSource {
+    async fn get_id(&self) -> &str {
+        self.id.as_str()
+    }
+
+    async fn get_tilejson(&self) -> Result<TileJSON, io::Error> {
+        Ok(self.tilejson.clone())
+    }
+
+    fn is_valid_zoom(&self, zoom: i32) -> bool {
+        crate::source::is_valid_zoom(zoom, self.tilejson.minzoom, self.tilejson.maxzoom)
+    }
+
+    fn requires_connection(&self) -> bool {
+        false
+    }
+
+    async fn get_tile(
+        &self,
+        _conn: Option<&mut Connection>,
+        xyz: &Xyz,
+        _query: &Option<UrlQuery>,
+    ) -> Result<Tile, io::Error> {
+        let (source, xyz) = (self.clone(), *xyz);
+        web::block(move || source.read_tile(&xyz))
+            .await
+            .map_err(io::Error::other)?
+    }
+}
+
+/// Open a Parquet file. Each tile opens its own reader, as readers of a file share its offset.
+fn open(path: &Path) -> io::Result<SerializedFileReader<File>> {
+    let file = File::open(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Can't open GeoParquet file {}: {e}", path.display()),
+        )
+    })?;
+    SerializedFileReader::new(file).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Can't read GeoParquet file {}: {e}", path.display()),
+        )
+    })
+}
+
+/// Whether the PROJJSON of a CRS is OGC:CRS84 or EPSG:4326
+fn is_lon_lat(crs: &serde_json::Value) -> bool {
+    let id = &crs["id"];
+    match (id["authority"].as_str(), &id["code"]) {
+        (Some("OGC"), code) => code == "CRS84",
+        (Some("EPSG"), code) => code == 4326 || code == "4326",
+        _ => false,
+    }
+}
+
+/// Bounds of the features of a row group from the statistics of its `bbox` covering
+fn get_bounds(row_group: &RowGroupMetaData, covering: &BboxCovering) -> Option<Bounds> {
+    let statistic = |path: &[String], is_min: bool| {
+        let column = row_group
+            .columns()
+            .iter()
+            .find(|column| column.column_path().parts() == path)?;
+        match column.statistics()? {
+            Statistics::Double(stats) if is_min => stats.min_opt().copied(),
+            Statistics::Double(stats) => stats.max_opt().copied(),
+            Statistics::Float(stats) if is_min => stats.min_opt().map(|v| f64::from(*v)),
+            Statistics::Float(stats) => stats.max_opt().map(|v| f64::from(*v)),
+            _ => None,
+        }
+    };
+    Some(Bounds::new(
+        statistic(&covering.xmin, true)?,
+        statistic(&covering.ymin, true)?,
+        statistic(&covering.xmax, false)?,
+        statistic(&covering.ymax, false)?,
+    ))
+}
+
+/// TileJSON type of a column which is a feature property, or `None` if it is not one
+fn get_field_type(field: &parquet::schema::types::Type) -> Option<String> {
+    if !field.is_primitive() {
+        return None;
+    }
+    let info = field.get_basic_info();
+    let type_name = match field.get_physical_type() {
+        PhysicalType::BOOLEAN => "Boolean",
+        PhysicalType::INT32 | PhysicalType::INT64 | PhysicalType::FLOAT | PhysicalType::DOUBLE => {
+            "Number"
+        }
+        PhysicalType::BYTE_ARRAY
+            if info.converted_type() == ConvertedType::UTF8
+                || matches!(info.logical_type(), Some(LogicalType::String)) =>
+        {
+            "String"
+        }
+        _ => return None,
+    };
+    Some(type_name.to_owned())
+}
+
+/// The property value of a field, or `None` if it has no vector tile equivalent
+fn to_mvt_value(field: &Field) -> Option<MvtValue> {
+    Some(match field {
+        Field::Bool(value) => MvtValue::Bool(*value),
+        Field::Byte(value) => MvtValue::Int(i64::from(*value)),
+        Field::Short(value) => MvtValue::Int(i64::from(*value)),
+        Field::Int(value) => MvtValue::Int(i64::from(*value)),
+        Field::Long(value) => MvtValue::Int(*value),
+        Field::UByte(value) => MvtValue::UInt(u64::from(*value)),
+        Field::UShort(value) => MvtValue::UInt(u64::from(*value)),
+        Field::UInt(value) => MvtValue::UInt(u64::from(*value)),
+        Field::ULong(value) => MvtValue::UInt(*value),
+        Field::Float16(value) => MvtValue::Float(value.to_f32()),
+        Field::Float(value) => MvtValue::Float(*value),
+        Field::Double(value) => MvtValue::Double(*value),
+        Field::Str(value) => MvtValue::String(value.clone()),
+        Field::Decimal(_)
+        | Field::Date(_)
+        | Field::TimestampMillis(_)
+        | Field::TimestampMicros(_) => MvtValue::String(field.to_string()),
+        _ => return None,
+    })
+}
+
+/// Project a longitude and latitude to the Web Mercator coordinates of a tile
+fn to_tile_point(point: &ewkb::Point, xyz: &Xyz) -> TilePoint {
+    let tiles = f64::from(1_u32 << xyz.z);
+    let x = (point.x + 180.0) / 360.0;
+    let lat = point.y.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+    let y = (1.0 - lat.tan().asinh() / PI) / 2.0;
+    let extent = f64::from(EXTENT);
+    [
+        (x * tiles - f64::from(xyz.x)) * extent,
+        (y * tiles - f64::from(xyz.y)) * extent,
+    ]
+}
+
+/// Project a geometry to the coordinates of a tile, or `None` for a geometry collection,
+/// which has no vector tile equivalent
+fn to_tile_geometry(geometry: &ewkb::Geometry, xyz: &Xyz) -> Option<TileGeometry> {
+    let line = |points: &[ewkb::Point]| -> Vec<TilePoint> {
+        points
+            .iter()
+            .map(|point| to_tile_point(point, xyz))
+            .collect()
+    };
+    let polygon = |polygon: &ewkb::Polygon| -> Vec<Vec<TilePoint>> {
+        polygon
+            .rings
+            .iter()
+            .map(|ring| line(&ring.points))
+            .collect()
+    };
+    Some(match geometry {
+        ewkb::GeometryT::Point(point) => TileGeometry::Points(vec![to_tile_point(point, xyz)]),
+        ewkb::GeometryT::MultiPoint(points) => TileGeometry::Points(line(&points.points)),
+        ewkb::GeometryT::LineString(line_string) => {
+            TileGeometry::Lines(vec![line(&line_string.points)])
+        }
+        ewkb::GeometryT::MultiLineString(lines) => TileGeometry::Lines(
+            lines
+                .lines
+                .iter()
+                .map(|line_string| line(&line_string.points))
+                .collect(),
+        ),
+        ewkb::GeometryT::Polygon(rings) => TileGeometry::Polygons(vec![polygon(rings)]),
+        ewkb::GeometryT::MultiPolygon(polygons) => {
+            TileGeometry::Polygons(polygons.polygons.iter().map(polygon).collect())
+        }
+        ewkb::GeometryT::GeometryCollection(_) => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mvt::summarize_mvt;
+
+    const FIXTURE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/geoparquet/features.parquet"
+    );
+
+    #[test]
+    fn test_new_geoparquet_source() {
+        let source = GeoParquetSource::new("features", FIXTURE).unwrap();
+        assert_eq!(source.geometry_column, "geometry");
+        assert_eq!(source.bbox_column.as_deref(), Some("bbox"));
+        assert_eq!(
+            source.tilejson.bounds,
+            Some(Bounds::new(-118.24, -35.0, 152.0, 52.52))
+        );
+        assert_eq!(source.row_group_bounds.len(), 3);
+        assert_eq!(
+            source.row_group_bounds[1],
+            Some(Bounds::new(-118.24, 34.05, -74.0, 41.88))
+        );
+        let layers = source.tilejson.vector_layers.unwrap();
+        assert_eq!(layers[0].id, "features");
+        assert_eq!(
+            layers[0].fields,
+            HashMap::from([
+                ("name".to_owned(), "String".to_owned()),
+                ("population".to_owned(), "Number".to_owned()),
+            ])
+        );
+
+        let error = GeoParquetSource::new("features", format!("{FIXTURE}.missing")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        let tile = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/tiles/1/1/0.pbf"
+        );
+        let error = GeoParquetSource::new("features", tile).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_read_tile() {
+        let source = GeoParquetSource::new("features", FIXTURE).unwrap();
+        for (xyz, features) in [
+            (Xyz { z: 0, x: 0, y: 0 }, 5),
+            // Paris at 2.35° east is in the buffer of the western tile
+            (Xyz { z: 1, x: 0, y: 0 }, 3),
+            (Xyz { z: 1, x: 1, y: 0 }, 2),
+            (Xyz { z: 1, x: 1, y: 1 }, 1),
+            (Xyz { z: 4, x: 8, y: 5 }, 2),
+            (Xyz { z: 6, x: 34, y: 21 }, 1),
+        ] {
+            let tile = source.read_tile(&xyz).unwrap();
+            let summary = summarize_mvt(&tile).unwrap();
+            assert_eq!(summary.layers.len(), 1, "{xyz:?}");
+            assert_eq!(summary.layers[0].name, "features");
+            assert_eq!(summary.layers[0].features, features, "{xyz:?}");
+            assert_eq!(
+                summary.layers[0].keys,
+                vec!["name", "population"],
+                "{xyz:?}"
+            );
+        }
+
+        // The tile has no features and its bounds intersect no row group
+        let tile = source.read_tile(&Xyz { z: 1, x: 0, y: 1 }).unwrap();
+        assert!(tile.is_empty());
+    }
+}
@@ -2,11 +2,22 @@
 // so all containing types must also derive PartialEq without Eq
 #![allow(clippy::derive_partial_eq_without_eq)]
 
+pub mod builder;
+pub mod cache;
 pub mod config;
+pub mod file_tree;
+pub mod fonts;
+#[cfg(feature = "geoparquet")]
+pub mod geoparquet;
+pub mod logging;
+pub mod mvt;
 pub mod pg;
 pub mod source;
+pub mod sprites;
 pub mod srv;
 
+pub use builder::{Martin, MartinBuilder};
+
 // Ensure README.md contains valid code
 #[cfg(doctest)]
 mod test_readme {
@@ -0,0 +1,159 @@
+use env_logger::filter::{Builder as FilterBuilder, Filter};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::io;
+use std::str::FromStr;
+use std::sync::{OnceLock, RwLock};
+
+pub const LOG_FILTER_DEFAULT: &str = "martin=info";
+
+static LOGGER: OnceLock<&'static ReloadableLogger> = OnceLock::new();
+
+/// A logger whose filter can be replaced at runtime.
+/// Formatting and output are delegated to an unfiltered `env_logger::Logger`.
+pub struct ReloadableLogger {
+    logger: env_logger::Logger,
+    filter: RwLock<Filter>,
+}
+
+impl ReloadableLogger {
+    pub fn new(mut builder: env_logger::Builder, filters: &str) -> io::Result<Self> {
+        Ok(Self {
+            logger: builder.filter_level(LevelFilter::Trace).build(),
+            filter: RwLock::new(parse_filters(filters)?),
+        })
+    }
+
+    /// Replace the current filter, using the same syntax as `RUST_LOG`
+    pub fn set_filter(&self, filters: &str) -> io::Result<()> {
+        let filter = parse_filters(filters)?;
+        log::set_max_level(filter.filter());
+        *self
+            .filter
+            .write()
+            .map_err(|e| io::Error::other(e.to_string()))? = filter;
+        Ok(())
+    }
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter
+            .read()
+            .map(|filter| filter.enabled(metadata))
+            .unwrap_or_default()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.logger.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.logger.flush();
+    }
+}
+
+/// Validate every directive's level, because `env_logger` silently ignores invalid ones
+fn parse_filters(filters: &str) -> io::Result<Filter> {
+    for directive in filters.split(',').map(str::trim) {
+        let is_valid = match directive.split_once('=') {
+            Some((_, level)) => LevelFilter::from_str(level).is_ok(),
+            None => !directive.is_empty(),
+        };
+        if !is_valid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid log filter directive '{directive}'"),
+            ));
+        }
+    }
+    Ok(FilterBuilder::new().parse(filters).build())
+}
+
+/// Install a reloadable logger as the global logger
+pub fn init_logger(filters: &str) -> io::Result<()> {
+    let mut builder = env_logger::Builder::new();
+    if let Ok(style) = std::env::var(env_logger::DEFAULT_WRITE_STYLE_ENV) {
+        builder.parse_write_style(&style);
+    }
+    let logger: &'static ReloadableLogger =
+        Box::leak(Box::new(ReloadableLogger::new(builder, filters)?));
+    log::set_logger(logger).map_err(|e| io::Error::other(e.to_string()))?;
+    log::set_max_level(
+        logger
+            .filter
+            .read()
+            .map(|f| f.filter())
+            .unwrap_or(LevelFilter::Info),
+    );
+    let _ = LOGGER.set(logger);
+    Ok(())
+}
+
+/// Change the filter of the global logger installed with [`init_logger`]
+pub fn set_log_filter(filters: &str) -> io::Result<()> {
+    LOGGER
+        .get()
+        .ok_or_else(|| {
+            io::Error::other("Log filter can't be changed, the logger is not reloadable")
+        })?
+        .set_filter(filters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use env_logger::Target;
+    use log::Level;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn log_debug(logger: &ReloadableLogger, message: &str) {
+        logger.log(
+            &Record::builder()
+                .level(Level::Debug)
+                .target("martin::test")
+                .args(format_args!("{message}"))
+                .build(),
+        );
+    }
+
+    #[test]
+    fn set_filter_enables_debug() {
+        let output = Output::default();
+        let mut builder = env_logger::Builder::new();
+        builder.target(Target::Pipe(Box::new(output.clone())));
+        let logger = ReloadableLogger::new(builder, "martin=info").unwrap();
+
+        log_debug(&logger, "filtered out");
+        assert!(output.0.lock().unwrap().is_empty());
+
+        logger.set_filter("martin=debug").unwrap();
+        log_debug(&logger, "emitted");
+        let written = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert!(written.contains("emitted"));
+        assert!(!written.contains("filtered out"));
+    }
+
+    #[test]
+    fn set_filter_rejects_invalid_level() {
+        let logger = ReloadableLogger::new(env_logger::Builder::new(), "info").unwrap();
+        assert!(logger.set_filter("martin=loud").is_err());
+        assert!(logger.set_filter("").is_err());
+        assert!(logger.set_filter("debug").is_ok());
+        assert!(logger.set_filter("martin").is_ok());
+    }
+}
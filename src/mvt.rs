@@ -0,0 +1,853 @@
+//! Minimal reading of [Mapbox Vector Tiles](https://github.com/mapbox/vector-tile-spec),
+//! just enough to inspect tiles without decoding their geometries,
+//! and encoding of the tiles of sources which are not rendered by PostGIS.
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::io::Read;
+
+/// `Tile.layers` field number
+const TILE_LAYERS: u64 = 3;
+/// `Layer.name` field number
+const LAYER_NAME: u64 = 1;
+/// `Layer.features` field number
+const LAYER_FEATURES: u64 = 2;
+/// `Layer.keys` field number
+const LAYER_KEYS: u64 = 3;
+/// `Layer.values` field number
+const LAYER_VALUES: u64 = 4;
+/// `Layer.extent` field number
+const LAYER_EXTENT: u64 = 5;
+/// `Layer.version` field number
+const LAYER_VERSION: u64 = 15;
+/// `Feature.tags` field number
+const FEATURE_TAGS: u64 = 2;
+/// `Feature.type` field number
+const FEATURE_TYPE: u64 = 3;
+/// `Feature.geometry` field number
+const FEATURE_GEOMETRY: u64 = 4;
+
+const COMMAND_MOVE_TO: u32 = 1;
+const COMMAND_LINE_TO: u32 = 2;
+const COMMAND_CLOSE_PATH: u32 = 7;
+
+pub(crate) const WIRE_VARINT: u64 = 0;
+pub(crate) const WIRE_FIXED64: u64 = 1;
+pub(crate) const WIRE_LEN: u64 = 2;
+pub(crate) const WIRE_FIXED32: u64 = 5;
+
+pub(crate) fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Iterate over the `(field number, wire type, length-delimited payload)` of a protobuf message.
+/// The payload is empty for fields that are not length-delimited.
+pub(crate) struct Fields<'a> {
+    pub(crate) buf: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    pub(crate) fn read_varint(&mut self) -> io::Result<u64> {
+        let mut value = 0;
+        for (i, byte) in self.buf.iter().enumerate().take(10) {
+            value |= u64::from(byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                self.buf = &self.buf[i + 1..];
+                return Ok(value);
+            }
+        }
+        Err(invalid("Invalid varint"))
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.buf.len() {
+            return Err(invalid("Unexpected end of message"));
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn read_field(&mut self) -> io::Result<(u64, u64, &'a [u8])> {
+        let key = self.read_varint()?;
+        let (field, wire_type) = (key >> 3, key & 0x7);
+        let payload = match wire_type {
+            WIRE_VARINT => {
+                self.read_varint()?;
+                &[][..]
+            }
+            WIRE_FIXED64 => {
+                self.take(8)?;
+                &[][..]
+            }
+            WIRE_LEN => {
+                let len = self.read_varint()?;
+                let len = usize::try_from(len).map_err(|_| invalid("Invalid length"))?;
+                self.take(len)?
+            }
+            WIRE_FIXED32 => {
+                self.take(4)?;
+                &[][..]
+            }
+            _ => return Err(invalid("Unsupported wire type")),
+        };
+        Ok((field, wire_type, payload))
+    }
+
+    /// Read a field, also returning its raw encoding to copy it as is
+    pub(crate) fn read_raw_field(&mut self) -> io::Result<(u64, u64, &'a [u8], &'a [u8])> {
+        let start = self.buf;
+        let (field, wire_type, payload) = self.read_field()?;
+        let raw = &start[..start.len() - self.buf.len()];
+        Ok((field, wire_type, payload, raw))
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = io::Result<(u64, u64, &'a [u8])>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            None
+        } else {
+            Some(self.read_field())
+        }
+    }
+}
+
+/// Count the features of all layers of an uncompressed vector tile
+pub fn count_features(tile: &[u8]) -> io::Result<usize> {
+    let mut count = 0;
+    for field in (Fields { buf: tile }) {
+        let (field, wire_type, layer) = field?;
+        if field != TILE_LAYERS || wire_type != WIRE_LEN {
+            continue;
+        }
+        for field in (Fields { buf: layer }) {
+            let (field, wire_type, _) = field?;
+            if field == LAYER_FEATURES && wire_type == WIRE_LEN {
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// Layers of a vector tile, without their geometries and values
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MvtSummary {
+    pub layers: Vec<LayerSummary>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LayerSummary {
+    pub name: String,
+    pub features: usize,
+    /// Property keys used by the layer features
+    pub keys: Vec<String>,
+}
+
+/// Summarize the layers of a vector tile, decompressing gzip or zlib tiles first
+pub fn summarize_mvt(data: &[u8]) -> io::Result<MvtSummary> {
+    let data = decompress_mvt(data)?;
+
+    let mut summary = MvtSummary::default();
+    for field in (Fields { buf: &data }) {
+        let (field, wire_type, layer) = field?;
+        if field != TILE_LAYERS || wire_type != WIRE_LEN {
+            continue;
+        }
+        let mut layer_summary = LayerSummary::default();
+        for field in (Fields { buf: layer }) {
+            match field? {
+                (LAYER_NAME, WIRE_LEN, name) => layer_summary.name = to_string(name)?,
+                (LAYER_FEATURES, WIRE_LEN, _) => layer_summary.features += 1,
+                (LAYER_KEYS, WIRE_LEN, key) => layer_summary.keys.push(to_string(key)?),
+                _ => {}
+            }
+        }
+        summary.layers.push(layer_summary);
+    }
+    Ok(summary)
+}
+
+/// Merge vector tiles into one by concatenating their layers.
+/// Layer names are kept, unless already used by a previous tile,
+/// in which case they are prefixed with the id of their source, e.g. `public.roads.roads`.
+/// Input tiles may be gzip or zlib compressed, the merged tile is not.
+pub fn merge_mvt<'a>(tiles: impl IntoIterator<Item = (&'a str, &'a [u8])>) -> io::Result<Vec<u8>> {
+    let mut merged = Vec::new();
+    let mut names = HashSet::new();
+    for (source_id, tile) in tiles {
+        let tile = decompress_mvt(tile)?;
+        let mut fields = Fields { buf: &tile };
+        while !fields.buf.is_empty() {
+            let (field, wire_type, layer, raw) = fields.read_raw_field()?;
+            if field != TILE_LAYERS || wire_type != WIRE_LEN {
+                continue;
+            }
+            let name = get_layer_name(layer)?;
+            if names.insert(name.clone()) {
+                merged.extend_from_slice(raw);
+            } else {
+                let name = format!("{source_id}.{name}");
+                let layer = rename_layer(layer, &name)?;
+                write_len_field(&mut merged, TILE_LAYERS, &layer);
+                names.insert(name);
+            }
+        }
+    }
+    Ok(merged)
+}
+
+fn get_layer_name(layer: &[u8]) -> io::Result<String> {
+    for field in (Fields { buf: layer }) {
+        if let (LAYER_NAME, WIRE_LEN, name) = field? {
+            return to_string(name);
+        }
+    }
+    Err(invalid("Layer without a name"))
+}
+
+fn rename_layer(layer: &[u8], name: &str) -> io::Result<Vec<u8>> {
+    let mut renamed = Vec::with_capacity(layer.len() + name.len());
+    let mut fields = Fields { buf: layer };
+    while !fields.buf.is_empty() {
+        match fields.read_raw_field()? {
+            (LAYER_NAME, WIRE_LEN, _, _) => {
+                write_len_field(&mut renamed, LAYER_NAME, name.as_bytes())
+            }
+            (_, _, _, raw) => renamed.extend_from_slice(raw),
+        }
+    }
+    Ok(renamed)
+}
+
+pub(crate) fn write_len_field(buf: &mut Vec<u8>, field: u64, payload: &[u8]) {
+    write_varint(buf, field << 3 | WIRE_LEN);
+    write_varint(buf, payload.len() as u64);
+    buf.extend_from_slice(payload);
+}
+
+pub(crate) fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// A point in tile coordinates, from `[0, 0]` at the top-left corner of the tile
+/// to `[extent, extent]` at its bottom-right one
+pub type TilePoint = [f64; 2];
+
+/// A geometry in tile coordinates, which may extend beyond the tile
+#[derive(Clone, Debug, PartialEq)]
+pub enum TileGeometry {
+    Points(Vec<TilePoint>),
+    Lines(Vec<Vec<TilePoint>>),
+    /// Polygons, each an exterior ring followed by its holes
+    Polygons(Vec<Vec<Vec<TilePoint>>>),
+}
+
+/// A property value of a feature
+#[derive(Clone, Debug, PartialEq)]
+pub enum MvtValue {
+    String(String),
+    Float(f32),
+    Double(f64),
+    Int(i64),
+    UInt(u64),
+    Bool(bool),
+}
+
+impl MvtValue {
+    /// Encode the value as a `Layer.values` message
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Self::String(value) => write_len_field(&mut buf, 1, value.as_bytes()),
+            Self::Float(value) => {
+                write_varint(&mut buf, 2 << 3 | WIRE_FIXED32);
+                buf.extend_from_slice(&value.to_le_bytes());
+            }
+            Self::Double(value) => {
+                write_varint(&mut buf, 3 << 3 | WIRE_FIXED64);
+                buf.extend_from_slice(&value.to_le_bytes());
+            }
+            Self::Int(value) => {
+                // sint_value, so that small negative values stay short
+                write_varint(&mut buf, 6 << 3 | WIRE_VARINT);
+                write_varint(&mut buf, ((value << 1) ^ (value >> 63)) as u64);
+            }
+            Self::UInt(value) => {
+                write_varint(&mut buf, 5 << 3 | WIRE_VARINT);
+                write_varint(&mut buf, *value);
+            }
+            Self::Bool(value) => {
+                write_varint(&mut buf, 7 << 3 | WIRE_VARINT);
+                write_varint(&mut buf, u64::from(*value));
+            }
+        }
+        buf
+    }
+}
+
+/// Encoder of a vector tile with a single layer.
+/// Feature geometries are clipped to the tile and its buffer, and the property keys
+/// and values are shared by the features.
+pub struct MvtLayerEncoder {
+    name: String,
+    extent: u32,
+    buffer: u32,
+    features: Vec<Vec<u8>>,
+    keys: Vec<String>,
+    key_ids: HashMap<String, u64>,
+    values: Vec<Vec<u8>>,
+    value_ids: HashMap<Vec<u8>, u64>,
+}
+
+impl MvtLayerEncoder {
+    /// Create an encoder of the layer `name` of tiles of `extent`, e.g. 4096,
+    /// keeping the geometries up to `buffer` beyond the tile edges
+    pub fn new(name: &str, extent: u32, buffer: u32) -> Self {
+        Self {
+            name: name.to_owned(),
+            extent,
+            buffer,
+            features: Vec::new(),
+            keys: Vec::new(),
+            key_ids: HashMap::new(),
+            values: Vec::new(),
+            value_ids: HashMap::new(),
+        }
+    }
+
+    /// Whether no feature has been added
+    pub fn is_empty(&self) -> bool {
+        self.features.is_empty()
+    }
+
+    /// Add a feature, unless its geometry is entirely outside the tile and its buffer.
+    /// Returns whether the feature was added.
+    pub fn add_feature<'a>(
+        &mut self,
+        geometry: &TileGeometry,
+        properties: impl IntoIterator<Item = (&'a str, MvtValue)>,
+    ) -> bool {
+        let Some((geometry_type, geometry)) = self.encode_geometry(geometry) else {
+            return false;
+        };
+
+        let mut tags = Vec::new();
+        for (key, value) in properties {
+            let key_id = match self.key_ids.get(key) {
+                Some(id) => *id,
+                None => {
+                    let id = self.keys.len() as u64;
+                    self.keys.push(key.to_owned());
+                    self.key_ids.insert(key.to_owned(), id);
+                    id
+                }
+            };
+            let value = value.encode();
+            let value_id = match self.value_ids.get(&value) {
+                Some(id) => *id,
+                None => {
+                    let id = self.values.len() as u64;
+                    self.values.push(value.clone());
+                    self.value_ids.insert(value, id);
+                    id
+                }
+            };
+            write_varint(&mut tags, key_id);
+            write_varint(&mut tags, value_id);
+        }
+
+        let mut feature = Vec::new();
+        if !tags.is_empty() {
+            write_len_field(&mut feature, FEATURE_TAGS, &tags);
+        }
+        write_varint(&mut feature, FEATURE_TYPE << 3 | WIRE_VARINT);
+        write_varint(&mut feature, geometry_type);
+        write_len_field(&mut feature, FEATURE_GEOMETRY, &geometry);
+        self.features.push(feature);
+        true
+    }
+
+    /// Encode the tile of the layer
+    pub fn finish(self) -> Vec<u8> {
+        let mut layer = Vec::new();
+        write_varint(&mut layer, LAYER_VERSION << 3 | WIRE_VARINT);
+        write_varint(&mut layer, 2);
+        write_len_field(&mut layer, LAYER_NAME, self.name.as_bytes());
+        for feature in &self.features {
+            write_len_field(&mut layer, LAYER_FEATURES, feature);
+        }
+        for key in &self.keys {
+            write_len_field(&mut layer, LAYER_KEYS, key.as_bytes());
+        }
+        for value in &self.values {
+            write_len_field(&mut layer, LAYER_VALUES, value);
+        }
+        write_varint(&mut layer, LAYER_EXTENT << 3 | WIRE_VARINT);
+        write_varint(&mut layer, u64::from(self.extent));
+
+        let mut tile = Vec::with_capacity(layer.len() + 4);
+        write_len_field(&mut tile, TILE_LAYERS, &layer);
+        tile
+    }
+
+    /// Clip and encode a geometry as its `Feature.type` and `Feature.geometry` commands,
+    /// or `None` if nothing is left of it
+    fn encode_geometry(&self, geometry: &TileGeometry) -> Option<(u64, Vec<u8>)> {
+        let min = -f64::from(self.buffer);
+        let max = f64::from(self.extent + self.buffer);
+        let mut commands = GeometryCommands::default();
+        let geometry_type = match geometry {
+            TileGeometry::Points(points) => {
+                let points: Vec<_> = points
+                    .iter()
+                    .filter(|point| point.iter().all(|v| (min..=max).contains(v)))
+                    .map(round_point)
+                    .collect();
+                if !points.is_empty() {
+                    commands.move_to(&points);
+                }
+                1
+            }
+            TileGeometry::Lines(lines) => {
+                for line in lines {
+                    for part in clip_line(line, min, max) {
+                        let part = round_points(&part);
+                        if part.len() >= 2 {
+                            commands.move_to(&part[..1]);
+                            commands.line_to(&part[1..]);
+                        }
+                    }
+                }
+                2
+            }
+            TileGeometry::Polygons(polygons) => {
+                for polygon in polygons {
+                    let mut rings = polygon
+                        .iter()
+                        .map(|ring| round_ring(&clip_ring(ring, min, max)));
+                    let Some(Some(exterior)) = rings.next() else {
+                        continue;
+                    };
+                    let holes = rings.flatten().map(|ring| (ring, false));
+                    // Exterior rings have a positive area in tile coordinates, holes a negative one
+                    for (mut ring, is_exterior) in std::iter::once((exterior, true)).chain(holes) {
+                        if (ring_area(&ring) > 0) != is_exterior {
+                            ring.reverse();
+                        }
+                        commands.move_to(&ring[..1]);
+                        commands.line_to(&ring[1..]);
+                        commands.close_path();
+                    }
+                }
+                3
+            }
+        };
+        (!commands.buf.is_empty()).then_some((geometry_type, commands.buf))
+    }
+}
+
+/// Commands of a feature geometry, whose coordinates are relative to the previous point
+#[derive(Default)]
+struct GeometryCommands {
+    buf: Vec<u8>,
+    cursor: [i32; 2],
+}
+
+impl GeometryCommands {
+    fn command(&mut self, id: u32, count: usize) {
+        write_varint(&mut self.buf, u64::from(id) | (count as u64) << 3);
+    }
+
+    fn points(&mut self, points: &[[i32; 2]]) {
+        for point in points {
+            for (value, cursor) in point.iter().zip(&mut self.cursor) {
+                let delta = value - *cursor;
+                write_varint(
+                    &mut self.buf,
+                    u64::from(((delta << 1) ^ (delta >> 31)) as u32),
+                );
+                *cursor = *value;
+            }
+        }
+    }
+
+    fn move_to(&mut self, points: &[[i32; 2]]) {
+        self.command(COMMAND_MOVE_TO, points.len());
+        self.points(points);
+    }
+
+    fn line_to(&mut self, points: &[[i32; 2]]) {
+        self.command(COMMAND_LINE_TO, points.len());
+        self.points(points);
+    }
+
+    fn close_path(&mut self) {
+        self.command(COMMAND_CLOSE_PATH, 1);
+    }
+}
+
+/// Round a point clipped to the tile and its buffer to integer tile coordinates
+fn round_point(point: &TilePoint) -> [i32; 2] {
+    [point[0].round() as i32, point[1].round() as i32]
+}
+
+/// Round the points of a line, dropping the repeated ones
+fn round_points(points: &[TilePoint]) -> Vec<[i32; 2]> {
+    let mut rounded: Vec<[i32; 2]> = points.iter().map(round_point).collect();
+    rounded.dedup();
+    rounded
+}
+
+/// Round the points of a ring without repeating its first point at the end,
+/// or `None` if it has no area left
+fn round_ring(ring: &[TilePoint]) -> Option<Vec<[i32; 2]>> {
+    let mut ring = round_points(ring);
+    if ring.len() > 1 && ring.first() == ring.last() {
+        ring.pop();
+    }
+    (ring.len() >= 3 && ring_area(&ring) != 0).then_some(ring)
+}
+
+/// Twice the area of a ring with the surveyor's formula,
+/// positive if it is clockwise in tile coordinates, whose `y` goes down
+fn ring_area(ring: &[[i32; 2]]) -> i64 {
+    ring.iter()
+        .zip(ring.iter().cycle().skip(1))
+        .map(|(a, b)| i64::from(a[0]) * i64::from(b[1]) - i64::from(b[0]) * i64::from(a[1]))
+        .sum()
+}
+
+/// Clip a line to the `min..=max` box, splitting it into the parts inside the box
+fn clip_line(line: &[TilePoint], min: f64, max: f64) -> Vec<Vec<TilePoint>> {
+    let mut parts = Vec::new();
+    let mut part: Vec<TilePoint> = Vec::new();
+    for segment in line.windows(2) {
+        let Some((start, end)) = clip_segment(segment[0], segment[1], min, max) else {
+            continue;
+        };
+        if part.last() != Some(&start) {
+            if part.len() >= 2 {
+                parts.push(std::mem::take(&mut part));
+            }
+            part = vec![start];
+        }
+        part.push(end);
+    }
+    if part.len() >= 2 {
+        parts.push(part);
+    }
+    parts
+}
+
+/// Clip a segment to the `min..=max` box with the Liang-Barsky algorithm
+fn clip_segment(
+    start: TilePoint,
+    end: TilePoint,
+    min: f64,
+    max: f64,
+) -> Option<(TilePoint, TilePoint)> {
+    let delta = [end[0] - start[0], end[1] - start[1]];
+    let (mut t_start, mut t_end) = (0.0_f64, 1.0_f64);
+    for axis in 0..2 {
+        for (p, q) in [
+            (-delta[axis], start[axis] - min),
+            (delta[axis], max - start[axis]),
+        ] {
+            if p == 0.0 {
+                if q < 0.0 {
+                    return None;
+                }
+            } else if p < 0.0 {
+                t_start = t_start.max(q / p);
+            } else {
+                t_end = t_end.min(q / p);
+            }
+        }
+    }
+    if t_start > t_end {
+        return None;
+    }
+    let at = |t: f64| [start[0] + t * delta[0], start[1] + t * delta[1]];
+    let clipped_start = if t_start > 0.0 { at(t_start) } else { start };
+    let clipped_end = if t_end < 1.0 { at(t_end) } else { end };
+    Some((clipped_start, clipped_end))
+}
+
+/// Clip a ring to the `min..=max` box with the Sutherland-Hodgman algorithm
+fn clip_ring(ring: &[TilePoint], min: f64, max: f64) -> Vec<TilePoint> {
+    let mut points = ring.to_vec();
+    for (axis, bound, is_min) in [
+        (0, min, true),
+        (0, max, false),
+        (1, min, true),
+        (1, max, false),
+    ] {
+        let inside = |point: &TilePoint| {
+            if is_min {
+                point[axis] >= bound
+            } else {
+                point[axis] <= bound
+            }
+        };
+        let intersection = |a: &TilePoint, b: &TilePoint| {
+            let t = (bound - a[axis]) / (b[axis] - a[axis]);
+            let mut point = [a[0] + t * (b[0] - a[0]), a[1] + t * (b[1] - a[1])];
+            point[axis] = bound;
+            point
+        };
+        let mut clipped = Vec::with_capacity(points.len() + 4);
+        for (i, current) in points.iter().enumerate() {
+            let previous = &points[(i + points.len() - 1) % points.len()];
+            match (inside(previous), inside(current)) {
+                (true, true) => clipped.push(*current),
+                (true, false) => clipped.push(intersection(previous, current)),
+                (false, true) => {
+                    clipped.push(intersection(previous, current));
+                    clipped.push(*current);
+                }
+                (false, false) => {}
+            }
+        }
+        points = clipped;
+    }
+    points
+}
+
+/// Check if a tile is gzip or zlib compressed.
+/// Uncompressed tiles start with a layer field, which never looks like either header.
+pub fn is_compressed_mvt(data: &[u8]) -> bool {
+    is_gzip(data) || is_zlib(data)
+}
+
+fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&[0x1f, 0x8b])
+}
+
+fn is_zlib(data: &[u8]) -> bool {
+    matches!(data, [cmf, flg, ..] if cmf & 0x0f == 8 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0)
+}
+
+/// Decompress a gzip or zlib compressed tile, or return it as is
+pub fn decompress_mvt(data: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+    if is_gzip(data) {
+        Ok(Cow::Owned(decompress(GzDecoder::new(data))?))
+    } else if is_zlib(data) {
+        Ok(Cow::Owned(decompress(ZlibDecoder::new(data))?))
+    } else {
+        Ok(Cow::Borrowed(data))
+    }
+}
+
+fn decompress(mut decoder: impl Read) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    decoder.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn to_string(data: &[u8]) -> io::Result<String> {
+    String::from_utf8(data.to_vec()).map_err(|_| invalid("Invalid UTF-8 string"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use std::io::Write;
+
+    /// Encode a length-delimited field
+    fn len_field(field: u8, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![field << 3 | 2, payload.len() as u8];
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    fn layer(name: &str, features: usize) -> Vec<u8> {
+        let mut layer = vec![15 << 3, 2]; // version: 2
+        layer.extend(len_field(1, name.as_bytes()));
+        for id in 0..features {
+            // feature with id and a geometry type
+            layer.extend(len_field(2, &[1 << 3, id as u8, 3 << 3, 1]));
+        }
+        if features > 0 {
+            layer.extend(len_field(3, b"gid"));
+            layer.extend(len_field(3, b"name"));
+        }
+        layer.extend(vec![5 << 3, 0x80, 0x20]); // extent: 4096
+        layer
+    }
+
+    #[test]
+    fn test_count_features() {
+        assert_eq!(count_features(&[]).unwrap(), 0);
+
+        let tile = len_field(3, &layer("points", 3));
+        assert_eq!(count_features(&tile).unwrap(), 3);
+
+        let mut tile = len_field(3, &layer("points", 2));
+        tile.extend(len_field(3, &layer("lines", 0)));
+        tile.extend(len_field(3, &layer("polygons", 5)));
+        assert_eq!(count_features(&tile).unwrap(), 7);
+    }
+
+    #[test]
+    fn test_count_features_invalid() {
+        let tile = len_field(3, &layer("points", 3));
+        assert!(count_features(&tile[..tile.len() - 1]).is_err());
+        assert!(count_features(&[0x1f, 0xff]).is_err());
+        assert!(count_features(&[0xff; 11]).is_err());
+    }
+
+    #[test]
+    fn test_summarize_mvt() {
+        let mut tile = len_field(3, &layer("points", 2));
+        tile.extend(len_field(3, &layer("lines", 0)));
+
+        let expected = MvtSummary {
+            layers: vec![
+                LayerSummary {
+                    name: "points".to_owned(),
+                    features: 2,
+                    keys: vec!["gid".to_owned(), "name".to_owned()],
+                },
+                LayerSummary {
+                    name: "lines".to_owned(),
+                    features: 0,
+                    keys: vec![],
+                },
+            ],
+        };
+        assert_eq!(summarize_mvt(&tile).unwrap(), expected);
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&tile).unwrap();
+        assert_eq!(summarize_mvt(&encoder.finish().unwrap()).unwrap(), expected);
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&tile).unwrap();
+        assert_eq!(summarize_mvt(&encoder.finish().unwrap()).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_is_compressed_mvt() {
+        let tile = len_field(3, &layer("points", 2));
+        assert!(!is_compressed_mvt(&tile));
+        assert!(!is_compressed_mvt(&[]));
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&tile).unwrap();
+        assert!(is_compressed_mvt(&encoder.finish().unwrap()));
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&tile).unwrap();
+        assert!(is_compressed_mvt(&encoder.finish().unwrap()));
+    }
+
+    #[test]
+    fn test_merge_mvt() {
+        let mut roads = len_field(3, &layer("roads", 2));
+        roads.extend(len_field(3, &layer("labels", 1)));
+        let pois = len_field(3, &layer("labels", 3));
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&pois).unwrap();
+        let pois = encoder.finish().unwrap();
+
+        let merged = merge_mvt([("public.roads", &roads[..]), ("public.pois", &pois[..])]).unwrap();
+        let layers: Vec<(String, usize)> = summarize_mvt(&merged)
+            .unwrap()
+            .layers
+            .into_iter()
+            .map(|layer| (layer.name, layer.features))
+            .collect();
+        assert_eq!(
+            layers,
+            vec![
+                ("roads".to_owned(), 2),
+                ("labels".to_owned(), 1),
+                ("public.pois.labels".to_owned(), 3),
+            ]
+        );
+
+        assert!(merge_mvt([]).unwrap().is_empty());
+        assert!(merge_mvt([("public.roads", &roads[..roads.len() - 1])]).is_err());
+    }
+    #[test]
+    fn test_mvt_layer_encoder() {
+        let mut layer = MvtLayerEncoder::new("points", 4096, 64);
+        assert!(layer.is_empty());
+        // The point of the vector tile spec example
+        assert!(layer.add_feature(
+            &TileGeometry::Points(vec![[25.0, 17.0]]),
+            [("name", MvtValue::String("a".to_owned()))]
+        ));
+        assert!(!layer.add_feature(&TileGeometry::Points(vec![[-65.0, 17.0]]), []));
+        let tile = layer.finish();
+        let expected_feature = [
+            &len_field(2, &[0, 0])[..],
+            &[3 << 3, 1],
+            &len_field(4, &[9, 50, 34]),
+        ]
+        .concat();
+        let mut expected_layer = vec![15 << 3, 2];
+        expected_layer.extend(len_field(1, b"points"));
+        expected_layer.extend(len_field(2, &expected_feature));
+        expected_layer.extend(len_field(3, b"name"));
+        expected_layer.extend(len_field(4, &len_field(1, b"a")));
+        expected_layer.extend([5 << 3, 0x80, 0x20]);
+        assert_eq!(tile, len_field(3, &expected_layer));
+
+        let mut layer = MvtLayerEncoder::new("shapes", 4096, 64);
+        // A line crossing the tile is clipped to the buffer, one outside of it is dropped
+        assert!(layer.add_feature(
+            &TileGeometry::Lines(vec![vec![[-1000.0, 100.0], [5000.0, 100.0]]]),
+            [("kind", MvtValue::Int(-1))]
+        ));
+        assert!(!layer.add_feature(
+            &TileGeometry::Lines(vec![vec![[-1000.0, -100.0], [5000.0, -100.0]]]),
+            []
+        ));
+        // A counter-clockwise polygon larger than the tile, with a hole
+        assert!(layer.add_feature(
+            &TileGeometry::Polygons(vec![vec![
+                vec![
+                    [-500.0, -500.0],
+                    [-500.0, 5000.0],
+                    [5000.0, 5000.0],
+                    [5000.0, -500.0]
+                ],
+                vec![[10.0, 10.0], [20.0, 10.0], [20.0, 20.0], [10.0, 20.0]],
+            ]]),
+            [("kind", MvtValue::Int(-1)), ("area", MvtValue::Double(1.5))]
+        ));
+        let summary = summarize_mvt(&layer.finish()).unwrap();
+        assert_eq!(summary.layers[0].features, 2);
+        assert_eq!(summary.layers[0].keys, vec!["kind", "area"]);
+
+        let line = clip_line(&[[-1000.0, 100.0], [5000.0, 100.0]], -64.0, 4160.0);
+        assert_eq!(line, vec![vec![[-64.0, 100.0], [4160.0, 100.0]]]);
+        let ring = round_ring(&clip_ring(
+            &[
+                [-500.0, -500.0],
+                [-500.0, 5000.0],
+                [5000.0, 5000.0],
+                [5000.0, -500.0],
+            ],
+            -64.0,
+            4160.0,
+        ))
+        .unwrap();
+        assert_eq!(ring.len(), 4);
+        assert!(ring_area(&ring) < 0);
+        assert_eq!(round_ring(&[[0.0, 0.0], [0.2, 0.0], [0.2, 0.2]]), None);
+    }
+}
@@ -1,16 +1,19 @@
-use crate::pg::db::Connection;
+use crate::pg::db::{query_tile, require_connection, Connection};
 use crate::pg::table_source::TableSource;
-use crate::pg::utils::{get_bounds_cte, get_srid_bounds, prettify_error};
-use crate::source::{Source, Tile, UrlQuery, Xyz};
+use crate::pg::utils::{get_bounds_cte, get_srid_bounds, query_error};
+use crate::source::{EmptyTile, Source, Tile, TileMatrixSet, TileScheme, UrlQuery, Xyz};
 use async_trait::async_trait;
 use itertools::Itertools;
 use std::io;
+use std::time::Duration;
 use tilejson::{tilejson, Bounds, TileJSON};
 
 #[derive(Clone, Debug)]
 pub struct CompositeSource {
     pub id: String,
     pub table_sources: Vec<TableSource>,
+    /// Layers of the tile requested with the `layers` query parameter, all of them if `None`
+    pub layers: Option<Vec<String>>,
 }
 
 impl CompositeSource {
@@ -18,9 +21,9 @@ impl CompositeSource {
         let srid_bounds = self
             .table_sources
             .iter()
-            .map(|source| source.srid)
+            .flat_map(TableSource::get_srids)
             .unique()
-            .map(|srid| get_srid_bounds(srid, xyz))
+            .map(|srid| get_srid_bounds(srid, xyz, self.get_tile_matrix_set()))
             .join(", ");
 
         get_bounds_cte(&srid_bounds)
@@ -30,19 +33,53 @@ impl CompositeSource {
         let tile_query: String = self
             .table_sources
             .iter()
-            .map(|source| format!("({})", source.get_tile_query(xyz)))
+            .map(|source| {
+                format!(
+                    "({})",
+                    source.get_layers_tile_query(xyz, self.layers.as_deref())
+                )
+            })
             .join(" || ");
 
         format!("SELECT {tile_query} AS tile")
     }
 
+    /// Tile scheme shared by all the table sources
+    pub fn get_tile_scheme(&self) -> TileScheme {
+        self.table_sources
+            .first()
+            .and_then(|table_source| table_source.tile_scheme)
+            .unwrap_or_default()
+    }
+
+    /// Tile matrix set shared by all the table sources
+    pub fn get_tile_matrix_set(&self) -> TileMatrixSet {
+        self.table_sources
+            .first()
+            .and_then(|table_source| table_source.tile_matrix_set)
+            .unwrap_or_default()
+    }
+
+    /// Build the tile query for tile coordinates requested in the shared tile scheme
     pub fn build_tile_query(&self, xyz: &Xyz) -> String {
-        let bounds_cte = self.get_bounds_cte(xyz);
-        let tile_query = self.get_tile_query(xyz);
+        let xyz = self.get_tile_scheme().to_xyz(xyz);
+        let bounds_cte = self.get_bounds_cte(&xyz);
+        let tile_query = self.get_tile_query(&xyz);
 
         format!("{bounds_cte} {tile_query}")
     }
 
+    /// The longest statement timeout of the table sources, so no layer is cut short by another.
+    /// `None` if any of them has no timeout.
+    pub fn get_statement_timeout(&self) -> Option<u64> {
+        self.table_sources
+            .iter()
+            .map(|table_source| table_source.statement_timeout)
+            .collect::<Option<Vec<_>>>()?
+            .into_iter()
+            .max()
+    }
+
     pub fn get_minzoom(&self) -> Option<u8> {
         self.table_sources
             .iter()
@@ -65,6 +102,7 @@ impl CompositeSource {
     }
 }
 
+#[allow(mismatched_lifetime_syntaxes)]
 #[async_trait]
 impl Source for CompositeSource {
     async fn get_id(&self) -> &str {
@@ -90,24 +128,68 @@ impl Source for CompositeSource {
             tilejson.bounds = Some(bounds);
         };
 
+        tilejson.scheme = Some(self.get_tile_scheme().as_str().to_owned());
+        self.get_tile_matrix_set().set_tilejson_crs(&mut tilejson);
+
+        // Every layer of the tile needs its attribution shown
+        let attribution = self
+            .table_sources
+            .iter()
+            .filter_map(|table_source| table_source.attribution.as_deref())
+            .unique()
+            .join(", ");
+        if !attribution.is_empty() {
+            tilejson.attribution = Some(attribution);
+        }
+
+        tilejson.vector_layers = Some(
+            self.table_sources
+                .iter()
+                .flat_map(TableSource::get_vector_layers)
+                .collect(),
+        );
+
         // TODO: consider removing - this is not needed per TileJSON spec
         tilejson.set_missing_defaults();
         Ok(tilejson)
     }
 
+    fn is_valid_zoom(&self, zoom: i32) -> bool {
+        self.table_sources
+            .iter()
+            .any(|table_source| table_source.is_valid_zoom(zoom))
+    }
+
+    fn get_max_age(&self) -> Option<Duration> {
+        self.table_sources
+            .iter()
+            .filter_map(TableSource::get_max_age)
+            .min()
+    }
+
+    fn get_empty_tile(&self) -> EmptyTile {
+        EmptyTile::shared(self.table_sources.iter().map(TableSource::get_empty_tile))
+    }
+
+    fn is_out_of_bounds(&self, xyz: &Xyz) -> bool {
+        self.table_sources
+            .iter()
+            .all(|table_source| table_source.is_out_of_bounds(xyz))
+    }
+
     async fn get_tile(
         &self,
-        conn: &mut Connection,
+        conn: Option<&mut Connection>,
         xyz: &Xyz,
         _query: &Option<UrlQuery>,
     ) -> Result<Tile, io::Error> {
+        let conn = require_connection(conn, &self.id)?;
         let tile_query = self.build_tile_query(xyz);
 
-        let tile: Tile = conn
-            .query_one(tile_query.as_str(), &[])
+        let tile = query_tile(conn, &tile_query, &[], self.get_statement_timeout())
             .await
-            .map(|row| row.get("tile"))
-            .map_err(|e| prettify_error!(e, "Can't get composite source tile"))?;
+            .map(|row| row.map_or_else(Tile::new, |row| Tile::from(row.get::<_, Vec<u8>>("tile"))))
+            .map_err(|e| query_error(&e, "Can't get composite source tile"))?;
 
         Ok(tile)
     }
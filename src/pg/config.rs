@@ -1,17 +1,39 @@
-use crate::config::{report_unrecognized_config, set_option};
+use crate::config::{merge_option_map, report_unrecognized_config, set_option};
 use crate::pg::function_source::FunctionSources;
+use crate::pg::raster_source::RasterSources;
 use crate::pg::table_source::TableSources;
+use crate::pg::utils::matches_pattern;
+use crate::source::check_headers;
 use serde::{Deserialize, Serialize};
-use std::{env, io};
+use std::collections::HashMap;
+use std::{env, fs, io};
 
 pub const POOL_SIZE_DEFAULT: u32 = 20;
+pub const RESERVED_POOL_MINZOOM_DEFAULT: u8 = 12;
+pub const POOL_ACQUIRE_TIMEOUT_DEFAULT: u64 = 30;
+pub const ID_CONFLICT_SEPARATOR_DEFAULT: &str = ".";
+pub const MAX_RETRIES_DEFAULT: u32 = 2;
+pub const SSL_CERT_AND_KEY_ERROR: &str =
+    "ssl_cert and ssl_key must be set together, the client certificate and its private key";
 
-#[derive(clap::Args, Debug)]
+#[derive(clap::Args, Debug, Clone)]
 #[command(about, version)]
 pub struct PgArgs {
+    /// Read the database connection string from a file, e.g. a mounted secret. Takes precedence over the connection string argument and DATABASE_URL.
+    #[arg(long)]
+    pub connection_string_file: Option<String>,
     /// Loads trusted root certificates from a file. The file should contain a sequence of PEM-formatted CA certificates.
     #[arg(long)]
     pub ca_root_file: Option<String>,
+    /// Loads trusted root certificates from all the .pem and .crt files of a directory, in addition to the ca-root-file ones.
+    #[arg(long)]
+    pub ca_root_dir: Option<String>,
+    /// Client certificate PEM file, for databases requiring mutual TLS. Requires ssl-key.
+    #[arg(long)]
+    pub ssl_cert: Option<String>,
+    /// Private key PEM file of the client certificate. Its passphrase, if encrypted, is read from SSL_KEY_PASSPHRASE.
+    #[arg(long)]
+    pub ssl_key: Option<String>,
     /// Trust invalid certificates. This introduces significant vulnerabilities, and should only be used as a last resort.
     #[arg(long)]
     pub danger_accept_invalid_certs: bool,
@@ -20,6 +42,90 @@ pub struct PgArgs {
     pub default_srid: Option<i32>,
     #[arg(help = format!("Maximum connections pool size [DEFAULT: {}]", POOL_SIZE_DEFAULT), short, long)]
     pub pool_size: Option<u32>,
+    #[arg(help = format!("Seconds a request waits for a database connection before failing with 503 Service Unavailable [DEFAULT: {}]", POOL_ACQUIRE_TIMEOUT_DEFAULT), long)]
+    pub pool_acquire_timeout: Option<u64>,
+}
+
+/// How source ids are transformed before they are used in routes and TileJSON
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IdNormalization {
+    /// Keep ids as they are
+    #[default]
+    None,
+    /// `MixedCase.MixPoints` -> `mixedcase.mixpoints`
+    Lowercase,
+    /// `MixedCase.MixPoints` -> `mixedcase-mixpoints`
+    Slug,
+}
+
+impl IdNormalization {
+    pub fn apply(self, id: &str) -> String {
+        match self {
+            IdNormalization::None => id.to_owned(),
+            IdNormalization::Lowercase => id.to_lowercase(),
+            IdNormalization::Slug => id
+                .to_lowercase()
+                .split(|c: char| !c.is_alphanumeric())
+                .filter(|part| !part.is_empty())
+                .collect::<Vec<_>>()
+                .join("-"),
+        }
+    }
+}
+
+/// What to do at startup when a source listed in the config is not in the database
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnMissingSource {
+    /// Log a warning and serve the other sources, the tiles of the missing ones fail
+    #[default]
+    Warn,
+    /// Fail listing all the missing sources
+    Error,
+}
+
+/// Which schemas and tables are published when the sources are discovered automatically.
+/// Patterns may use `*` for any number of characters and `?` for a single one, e.g. `app_*`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct AutoPublish {
+    /// Only publish sources in these schemas, all schemas if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schemas: Option<Vec<String>>,
+
+    /// Don't publish sources in these schemas
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_schemas: Vec<String>,
+
+    /// Don't publish these tables, matched against the table name,
+    /// or against `schema.table` if the pattern has a dot
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_tables: Vec<String>,
+}
+
+impl AutoPublish {
+    /// Whether sources in the schema are published, functions are only filtered by schema
+    pub fn is_schema_published(&self, schema: &str) -> bool {
+        let matches = |pattern: &String| matches_pattern(pattern, schema);
+        self.schemas
+            .as_ref()
+            .is_none_or(|schemas| schemas.iter().any(matches))
+            && !self.exclude_schemas.iter().any(matches)
+    }
+
+    /// Whether table and raster sources of the table are published
+    pub fn is_table_published(&self, schema: &str, table: &str) -> bool {
+        let qualified = format!("{schema}.{table}");
+        self.is_schema_published(schema)
+            && !self.exclude_tables.iter().any(|pattern| {
+                let name = if pattern.contains('.') {
+                    &qualified
+                } else {
+                    table
+                };
+                matches_pattern(pattern, name)
+            })
+    }
 }
 
 #[derive(Clone, Debug, Serialize, PartialEq)]
@@ -27,68 +133,245 @@ pub struct PgConfig {
     pub connection_string: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ca_root_file: Option<String>,
+    /// Directory of `.pem` and `.crt` files of trusted root certificates
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_root_dir: Option<String>,
+    /// PEM file of the client certificate, for databases requiring mutual TLS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssl_cert: Option<String>,
+    /// PEM file of the private key of `ssl_cert`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssl_key: Option<String>,
+    /// Passphrase of `ssl_key` if it is encrypted
+    #[serde(skip_serializing)]
+    pub ssl_key_passphrase: Option<String>,
     pub danger_accept_invalid_certs: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub default_srid: Option<i32>,
     pub pool_size: u32,
+    pub pool_acquire_timeout: u64,
+    pub max_retries: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub statement_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reserved_pool_size: Option<u32>,
+    pub reserved_pool_minzoom: u8,
+    pub partial_composite_tiles: bool,
+    pub prepared_statements: bool,
+    pub normalize_ids: IdNormalization,
+    pub id_conflict_separator: String,
+    pub fail_on_id_conflict: bool,
+    pub on_missing_source: OnMissingSource,
+    pub sample_srid: bool,
+    /// Extent of the tables without their own, instead of 4096
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_extent: Option<u32>,
+    /// Buffer of the tables without their own, instead of 64
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_buffer: Option<u32>,
+    /// Clipping of the tables without their own, instead of true
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_clip_geom: Option<bool>,
+    #[serde(skip_serializing)]
     pub use_dynamic_sources: bool,
+    /// Filters of the automatically discovered sources
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auto_publish: Option<AutoPublish>,
     pub table_sources: TableSources,
     pub function_sources: FunctionSources,
+    pub raster_sources: RasterSources,
+    /// Extra ids of the sources of all databases, e.g. `roads: public.roads.geom`,
+    /// each served like the source it refers to
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub aliases: HashMap<String, String>,
+    /// More databases, each with its own connection pool and sources
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub databases: Vec<PgConfig>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PgConfigBuilder {
     pub connection_string: Option<String>,
+    /// File with the connection string, taking precedence over `connection_string`
+    pub connection_string_file: Option<String>,
     pub ca_root_file: Option<String>,
+    pub ca_root_dir: Option<String>,
+    pub ssl_cert: Option<String>,
+    pub ssl_key: Option<String>,
+    pub ssl_key_passphrase: Option<String>,
     pub danger_accept_invalid_certs: Option<bool>,
     pub default_srid: Option<i32>,
     pub pool_size: Option<u32>,
+    pub pool_acquire_timeout: Option<u64>,
+    pub max_retries: Option<u32>,
+    pub statement_timeout: Option<u64>,
+    pub reserved_pool_size: Option<u32>,
+    pub reserved_pool_minzoom: Option<u8>,
+    pub partial_composite_tiles: Option<bool>,
+    /// Prepare tile queries on the server, false for a pooler like PgBouncer in transaction mode
+    pub prepared_statements: Option<bool>,
+    pub normalize_ids: Option<IdNormalization>,
+    pub id_conflict_separator: Option<String>,
+    pub fail_on_id_conflict: Option<bool>,
+    pub on_missing_source: Option<OnMissingSource>,
+    pub sample_srid: Option<bool>,
+    pub default_extent: Option<u32>,
+    pub default_buffer: Option<u32>,
+    pub default_clip_geom: Option<bool>,
+    pub auto_publish: Option<AutoPublish>,
     pub table_sources: Option<TableSources>,
     pub function_sources: Option<FunctionSources>,
+    pub raster_sources: Option<RasterSources>,
+    pub aliases: Option<HashMap<String, String>>,
+    pub databases: Option<Vec<PgConfigBuilder>>,
 }
 
 impl PgConfigBuilder {
     pub fn merge(&mut self, other: PgConfigBuilder) -> &mut Self {
         set_option(&mut self.connection_string, other.connection_string);
+        set_option(
+            &mut self.connection_string_file,
+            other.connection_string_file,
+        );
         set_option(&mut self.ca_root_file, other.ca_root_file);
+        set_option(&mut self.ca_root_dir, other.ca_root_dir);
+        set_option(&mut self.ssl_cert, other.ssl_cert);
+        set_option(&mut self.ssl_key, other.ssl_key);
+        set_option(&mut self.ssl_key_passphrase, other.ssl_key_passphrase);
         set_option(
             &mut self.danger_accept_invalid_certs,
             other.danger_accept_invalid_certs,
         );
         set_option(&mut self.default_srid, other.default_srid);
         set_option(&mut self.pool_size, other.pool_size);
-        set_option(&mut self.table_sources, other.table_sources);
-        set_option(&mut self.function_sources, other.function_sources);
+        set_option(&mut self.pool_acquire_timeout, other.pool_acquire_timeout);
+        set_option(&mut self.max_retries, other.max_retries);
+        set_option(&mut self.statement_timeout, other.statement_timeout);
+        set_option(&mut self.reserved_pool_size, other.reserved_pool_size);
+        set_option(&mut self.reserved_pool_minzoom, other.reserved_pool_minzoom);
+        set_option(
+            &mut self.partial_composite_tiles,
+            other.partial_composite_tiles,
+        );
+        set_option(&mut self.prepared_statements, other.prepared_statements);
+        set_option(&mut self.normalize_ids, other.normalize_ids);
+        set_option(&mut self.id_conflict_separator, other.id_conflict_separator);
+        set_option(&mut self.fail_on_id_conflict, other.fail_on_id_conflict);
+        set_option(&mut self.on_missing_source, other.on_missing_source);
+        set_option(&mut self.sample_srid, other.sample_srid);
+        set_option(&mut self.default_extent, other.default_extent);
+        set_option(&mut self.default_buffer, other.default_buffer);
+        set_option(&mut self.default_clip_geom, other.default_clip_geom);
+        set_option(&mut self.auto_publish, other.auto_publish);
+        merge_option_map(&mut self.table_sources, other.table_sources);
+        merge_option_map(&mut self.function_sources, other.function_sources);
+        merge_option_map(&mut self.raster_sources, other.raster_sources);
+        merge_option_map(&mut self.aliases, other.aliases);
+        set_option(&mut self.databases, other.databases);
         self
     }
 
     /// Apply defaults to the config, and validate if there is a connection string
-    pub fn finalize(self) -> io::Result<PgConfig> {
-        if let Some(ref ts) = self.table_sources {
+    pub fn finalize(mut self) -> io::Result<PgConfig> {
+        if let Some(ref mut ts) = self.table_sources {
             for (k, v) in ts {
                 report_unrecognized_config(&format!("table_sources.{}.", k), &v.unrecognized);
+                check_headers(k, &mut v.headers);
+                v.validate()?;
             }
         }
-        if let Some(ref fs) = self.function_sources {
+        if let Some(ref mut fs) = self.function_sources {
             for (k, v) in fs {
                 report_unrecognized_config(&format!("function_sources.{}.", k), &v.unrecognized);
+                check_headers(k, &mut v.headers);
+                v.validate()?;
             }
         }
-        let connection_string = self.connection_string.ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                "Database connection string is not set",
-            )
-        })?;
+        if let Some(ref mut rs) = self.raster_sources {
+            for (k, v) in rs {
+                report_unrecognized_config(&format!("raster_sources.{}.", k), &v.unrecognized);
+                check_headers(k, &mut v.headers);
+            }
+        }
+        let connection_string = match &self.connection_string_file {
+            Some(path) => read_connection_string_file(path)?,
+            None => self
+                .connection_string
+                .ok_or_else(|| io::Error::other("Database connection string is not set"))?,
+        };
+        if self.ssl_cert.is_some() != self.ssl_key.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                SSL_CERT_AND_KEY_ERROR,
+            ));
+        }
+        let pool_acquire_timeout = self
+            .pool_acquire_timeout
+            .unwrap_or(POOL_ACQUIRE_TIMEOUT_DEFAULT);
+        if pool_acquire_timeout == 0 {
+            return Err(io::Error::other(
+                "pool_acquire_timeout must be at least 1 second",
+            ));
+        }
+        if self.reserved_pool_size == Some(0) {
+            return Err(io::Error::other(
+                "reserved_pool_size must be at least 1, or left out to disable the reserved pool",
+            ));
+        }
+        let databases = self
+            .databases
+            .unwrap_or_default()
+            .into_iter()
+            .map(|database| {
+                if database.databases.is_some() {
+                    return Err(io::Error::other("databases can't be nested"));
+                }
+                if database.aliases.is_some() {
+                    return Err(io::Error::other(
+                        "aliases of the sources of databases must be set at the top level",
+                    ));
+                }
+                database.finalize()
+            })
+            .collect::<io::Result<Vec<_>>>()?;
         Ok(PgConfig {
             connection_string,
             ca_root_file: self.ca_root_file,
+            ca_root_dir: self.ca_root_dir,
+            ssl_cert: self.ssl_cert,
+            ssl_key: self.ssl_key,
+            ssl_key_passphrase: self.ssl_key_passphrase,
             danger_accept_invalid_certs: self.danger_accept_invalid_certs.unwrap_or_default(),
             default_srid: self.default_srid,
             pool_size: self.pool_size.unwrap_or(POOL_SIZE_DEFAULT),
-            use_dynamic_sources: self.table_sources.is_none() && self.function_sources.is_none(),
+            pool_acquire_timeout,
+            max_retries: self.max_retries.unwrap_or(MAX_RETRIES_DEFAULT),
+            statement_timeout: self.statement_timeout,
+            reserved_pool_size: self.reserved_pool_size,
+            reserved_pool_minzoom: self
+                .reserved_pool_minzoom
+                .unwrap_or(RESERVED_POOL_MINZOOM_DEFAULT),
+            partial_composite_tiles: self.partial_composite_tiles.unwrap_or_default(),
+            prepared_statements: self.prepared_statements.unwrap_or(true),
+            normalize_ids: self.normalize_ids.unwrap_or_default(),
+            id_conflict_separator: self
+                .id_conflict_separator
+                .unwrap_or_else(|| ID_CONFLICT_SEPARATOR_DEFAULT.to_owned()),
+            fail_on_id_conflict: self.fail_on_id_conflict.unwrap_or_default(),
+            on_missing_source: self.on_missing_source.unwrap_or_default(),
+            sample_srid: self.sample_srid.unwrap_or_default(),
+            default_extent: self.default_extent,
+            default_buffer: self.default_buffer,
+            default_clip_geom: self.default_clip_geom,
+            use_dynamic_sources: self.table_sources.is_none()
+                && self.function_sources.is_none()
+                && self.raster_sources.is_none(),
+            auto_publish: self.auto_publish,
             table_sources: self.table_sources.unwrap_or_default(),
             function_sources: self.function_sources.unwrap_or_default(),
+            raster_sources: self.raster_sources.unwrap_or_default(),
+            aliases: self.aliases.unwrap_or_default(),
+            databases,
         })
     }
 }
@@ -99,9 +382,21 @@ impl From<(PgArgs, Option<String>)> for PgConfigBuilder {
             connection_string: connection.or_else(|| {
                 env::var_os("DATABASE_URL").and_then(|connection| connection.into_string().ok())
             }),
+            connection_string_file: args.connection_string_file,
             ca_root_file: args.ca_root_file.or_else(|| {
                 env::var_os("CA_ROOT_FILE").and_then(|connection| connection.into_string().ok())
             }),
+            ca_root_dir: args
+                .ca_root_dir
+                .or_else(|| env::var_os("CA_ROOT_DIR").and_then(|dir| dir.into_string().ok())),
+            ssl_cert: args
+                .ssl_cert
+                .or_else(|| env::var_os("SSL_CERT").and_then(|cert| cert.into_string().ok())),
+            ssl_key: args
+                .ssl_key
+                .or_else(|| env::var_os("SSL_KEY").and_then(|key| key.into_string().ok())),
+            ssl_key_passphrase: env::var_os("SSL_KEY_PASSPHRASE")
+                .and_then(|passphrase| passphrase.into_string().ok()),
             danger_accept_invalid_certs: if args.danger_accept_invalid_certs
                 || env::var_os("DANGER_ACCEPT_INVALID_CERTS").is_some()
             {
@@ -117,8 +412,44 @@ impl From<(PgArgs, Option<String>)> for PgConfigBuilder {
                 })
             }),
             pool_size: args.pool_size,
+            pool_acquire_timeout: args.pool_acquire_timeout,
+            max_retries: None,
+            statement_timeout: None,
+            reserved_pool_size: None,
+            reserved_pool_minzoom: None,
+            partial_composite_tiles: None,
+            prepared_statements: None,
+            normalize_ids: None,
+            id_conflict_separator: None,
+            fail_on_id_conflict: None,
+            on_missing_source: None,
+            sample_srid: None,
+            default_extent: None,
+            default_buffer: None,
+            default_clip_geom: None,
+            auto_publish: None,
             table_sources: None,
             function_sources: None,
+            raster_sources: None,
+            aliases: None,
+            databases: None,
         }
     }
 }
+
+/// Read a connection string from a file, without the trailing newline
+fn read_connection_string_file(path: &str) -> io::Result<String> {
+    let content = fs::read_to_string(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Can't read connection_string_file {path}: {e}"),
+        )
+    })?;
+    let connection_string = content.trim_end_matches(['\r', '\n']);
+    if connection_string.trim().is_empty() {
+        return Err(io::Error::other(format!(
+            "connection_string_file {path} is empty"
+        )));
+    }
+    Ok(connection_string.to_owned())
+}
@@ -1,57 +1,256 @@
-use crate::config::Config;
+use crate::config::{set_option, Config};
+use crate::pg::config::{OnMissingSource, PgConfig, SSL_CERT_AND_KEY_ERROR};
 use crate::pg::function_source::get_function_sources;
-use crate::pg::table_source::get_table_sources;
-use crate::pg::utils::prettify_error;
-use bb8::PooledConnection;
+use crate::pg::raster_source::get_raster_sources;
+use crate::pg::table_source::{get_table_sources, Buffer};
+use crate::pg::utils::{normalize_source_ids, prettify_error, rename_conflicting_ids};
+use async_trait::async_trait;
+use bb8::{ManageConnection, PooledConnection, RunError};
+use bb8_postgres::tokio_postgres::types::{ToSql, Type};
+use bb8_postgres::tokio_postgres::{Client, GenericClient, Row};
 use bb8_postgres::{tokio_postgres, PostgresConnectionManager};
-use log::info;
+use log::{info, warn};
+use openssl::error::ErrorStack;
+use openssl::pkey::PKey;
 use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use openssl::x509::store::X509StoreBuilderRef;
+use openssl::x509::X509;
 use postgres_openssl::MakeTlsConnector;
 use semver::{Version, VersionReq};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::future::Future;
 use std::io;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
 use std::str::FromStr;
+use std::time::Duration;
 
-pub type ConnectionManager = PostgresConnectionManager<MakeTlsConnector>;
 pub type Pool = bb8::Pool<ConnectionManager>;
 pub type Connection<'a> = PooledConnection<'a, ConnectionManager>;
 
+/// Opens the connections of a pool
+pub struct ConnectionManager {
+    inner: PostgresConnectionManager<MakeTlsConnector>,
+    prepared_statements: bool,
+}
+
+/// A connection of a pool, dereferencing to its client
+pub struct PgConnection {
+    client: Client,
+    /// Whether tile queries may be prepared statements, which don't work through
+    /// a pooler like PgBouncer in transaction mode
+    pub prepared_statements: bool,
+}
+
+impl Deref for PgConnection {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.client
+    }
+}
+
+impl DerefMut for PgConnection {
+    fn deref_mut(&mut self) -> &mut Client {
+        &mut self.client
+    }
+}
+
+#[async_trait]
+impl ManageConnection for ConnectionManager {
+    type Connection = PgConnection;
+    type Error = tokio_postgres::Error;
+
+    async fn connect(&self) -> Result<PgConnection, Self::Error> {
+        Ok(PgConnection {
+            client: self.inner.connect().await?,
+            prepared_statements: self.prepared_statements,
+        })
+    }
+
+    async fn is_valid(&self, conn: &mut PgConnection) -> Result<(), Self::Error> {
+        self.inner.is_valid(&mut conn.client).await
+    }
+
+    fn has_broken(&self, conn: &mut PgConnection) -> bool {
+        self.inner.has_broken(&mut conn.client)
+    }
+}
+
 const REQUIRED_POSTGIS_VERSION: &str = ">= 2.4.0";
 
-fn make_tls_connector(
-    ca_root_file: &Option<String>,
-    danger_accept_invalid_certs: bool,
-) -> io::Result<MakeTlsConnector> {
+/// Extensions of the certificate files of `ca_root_dir`
+const CA_ROOT_DIR_EXTENSIONS: &[&str] = &["pem", "crt"];
+
+/// Options of the TLS connections to a database
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    pub ca_root_file: Option<String>,
+    pub ca_root_dir: Option<String>,
+    /// PEM file of the client certificate, for databases requiring mutual TLS
+    pub ssl_cert: Option<String>,
+    /// PEM file of the private key of `ssl_cert`
+    pub ssl_key: Option<String>,
+    /// Passphrase of `ssl_key` if it is encrypted
+    pub ssl_key_passphrase: Option<String>,
+    pub danger_accept_invalid_certs: bool,
+}
+
+impl From<&PgConfig> for TlsOptions {
+    fn from(pg: &PgConfig) -> Self {
+        Self {
+            ca_root_file: pg.ca_root_file.clone(),
+            ca_root_dir: pg.ca_root_dir.clone(),
+            ssl_cert: pg.ssl_cert.clone(),
+            ssl_key: pg.ssl_key.clone(),
+            ssl_key_passphrase: pg.ssl_key_passphrase.clone(),
+            danger_accept_invalid_certs: pg.danger_accept_invalid_certs,
+        }
+    }
+}
+
+fn make_tls_connector(tls: &TlsOptions) -> io::Result<MakeTlsConnector> {
+    Ok(MakeTlsConnector::new(make_ssl_connector(tls)?))
+}
+
+fn make_ssl_connector(tls: &TlsOptions) -> io::Result<SslConnector> {
     let mut builder = SslConnector::builder(SslMethod::tls())?;
 
-    if danger_accept_invalid_certs {
+    if tls.danger_accept_invalid_certs {
         builder.set_verify(SslVerifyMode::NONE);
     }
 
-    if let Some(ca_root_file) = ca_root_file {
+    if let Some(ca_root_file) = &tls.ca_root_file {
         info!("Using {ca_root_file} as trusted root certificate");
         builder.set_ca_file(ca_root_file)?;
     }
 
-    let tls_connector = MakeTlsConnector::new(builder.build());
-    Ok(tls_connector)
+    if let Some(ca_root_dir) = &tls.ca_root_dir {
+        let count = add_ca_root_dir(builder.cert_store_mut(), Path::new(ca_root_dir))?;
+        info!("Using {count} trusted root certificates of {ca_root_dir}");
+    }
+
+    match (&tls.ssl_cert, &tls.ssl_key) {
+        (Some(ssl_cert), Some(ssl_key)) => {
+            info!("Using {ssl_cert} as client certificate");
+            builder.set_certificate_chain_file(ssl_cert).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Can't read ssl_cert {ssl_cert}: {e}"),
+                )
+            })?;
+            let key = fs::read(ssl_key).map_err(|e| {
+                io::Error::new(e.kind(), format!("Can't read ssl_key {ssl_key}: {e}"))
+            })?;
+            // An empty passphrase fails on an encrypted key, instead of prompting for it
+            let passphrase = tls.ssl_key_passphrase.as_deref().unwrap_or_default();
+            let key = PKey::private_key_from_pem_passphrase(&key, passphrase.as_bytes()).map_err(
+                |e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Can't decode ssl_key {ssl_key}, is ssl_key_passphrase right? {e}"),
+                    )
+                },
+            )?;
+            let mismatch = |e: ErrorStack| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("ssl_key {ssl_key} is not the key of ssl_cert {ssl_cert}: {e}"),
+                )
+            };
+            builder.set_private_key(&key).map_err(mismatch)?;
+            builder.check_private_key().map_err(mismatch)?;
+        }
+        (None, None) => {}
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                SSL_CERT_AND_KEY_ERROR,
+            ))
+        }
+    }
+
+    Ok(builder.build())
+}
+
+/// Add the certificates of the `.pem` and `.crt` files of a directory to a trust store,
+/// returning how many were added. A directory without any is an error.
+fn add_ca_root_dir(store: &mut X509StoreBuilderRef, dir: &Path) -> io::Result<usize> {
+    let entries = fs::read_dir(dir).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Can't read ca_root_dir {}: {e}", dir.display()),
+        )
+    })?;
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let is_cert = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| {
+                CA_ROOT_DIR_EXTENSIONS.contains(&extension.to_lowercase().as_str())
+            });
+        if is_cert && path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    let mut count = 0;
+    for file in files {
+        let invalid = |reason: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Can't read certificates of {}: {reason}", file.display()),
+            )
+        };
+        let certs = X509::stack_from_pem(&fs::read(&file)?).map_err(|e| invalid(e.to_string()))?;
+        if certs.is_empty() {
+            return Err(invalid("it has no PEM certificate".to_owned()));
+        }
+        for cert in certs {
+            store.add_cert(cert)?;
+            count += 1;
+        }
+    }
+    if count == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "ca_root_dir {} has no .pem or .crt certificate files",
+                dir.display()
+            ),
+        ));
+    }
+    Ok(count)
 }
 
 pub async fn setup_connection_pool(
     connection_string: &str,
-    ca_root_file: &Option<String>,
+    tls: &TlsOptions,
     pool_size: u32,
-    danger_accept_invalid_certs: bool,
+    acquire_timeout: Duration,
+    prepared_statements: bool,
 ) -> io::Result<Pool> {
     let config = tokio_postgres::config::Config::from_str(connection_string)
         .map_err(|e| prettify_error!(e, "Can't parse connection string"))?;
 
-    let tls_connector = make_tls_connector(ca_root_file, danger_accept_invalid_certs)
-        .map_err(|e| prettify_error!(e, "Can't build TLS connection"))?;
+    let tls_connector =
+        make_tls_connector(tls).map_err(|e| prettify_error!(e, "Can't build TLS connection"))?;
 
-    let manager = PostgresConnectionManager::new(config, tls_connector);
+    let manager = ConnectionManager {
+        inner: PostgresConnectionManager::new(config, tls_connector),
+        prepared_statements,
+    };
+    if !prepared_statements {
+        info!("Tile queries are not prepared, for a pooler like PgBouncer in transaction mode");
+    }
 
     let pool = Pool::builder()
         .max_size(pool_size)
+        .connection_timeout(acquire_timeout)
         .build(manager)
         .await
         .map_err(|e| prettify_error!(e, "Can't build connection pool"))?;
@@ -59,11 +258,131 @@ pub async fn setup_connection_pool(
     Ok(pool)
 }
 
+/// Create a separate pool that only serves high-zoom tile requests,
+/// so they are not starved by expensive low-zoom ones
+pub async fn setup_reserved_pool(config: &Config) -> io::Result<Option<Pool>> {
+    let Some(reserved_pool_size) = config.pg.reserved_pool_size else {
+        return Ok(None);
+    };
+
+    info!(
+        "Reserving {reserved_pool_size} connections for tiles at zoom {} and above",
+        config.pg.reserved_pool_minzoom
+    );
+    let pool = setup_connection_pool(
+        &config.pg.connection_string,
+        &TlsOptions::from(&config.pg),
+        reserved_pool_size,
+        Duration::from_secs(config.pg.pool_acquire_timeout),
+        config.pg.prepared_statements,
+    )
+    .await?;
+
+    Ok(Some(pool))
+}
+
+/// Base delay before retrying a query on a lost connection, doubled on every attempt
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(50);
+
+/// Whether an error means the connection to the database was lost or could not be made,
+/// as opposed to a failed query, which would fail again.
+fn is_recoverable(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+/// Run a database operation, retrying it up to `max_retries` times with exponential backoff
+/// if the connection was lost. Other errors, including pool timeouts, are returned right away.
+pub async fn with_retries<T, F, Fut>(max_retries: u32, mut operation: F) -> io::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match operation().await {
+            Err(e) if attempt < max_retries && is_recoverable(&e) => {
+                let delay = RETRY_BASE_DELAY * 2_u32.saturating_pow(attempt);
+                warn!("{e}, retrying in {delay:?}");
+                actix_rt::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Run a tile query returning at most one row. With a `statement_timeout` in milliseconds,
+/// the query runs in its own transaction with that timeout, so PostgreSQL cancels it
+/// if it takes longer.
+#[cfg_attr(feature = "otel", tracing::instrument(name = "query", skip_all))]
+pub async fn query_tile(
+    conn: &mut Connection<'_>,
+    query: &str,
+    params: &[(&(dyn ToSql + Sync), Type)],
+    statement_timeout: Option<u64>,
+) -> Result<Option<Row>, tokio_postgres::Error> {
+    let prepared_statements = conn.prepared_statements;
+    let Some(statement_timeout) = statement_timeout else {
+        let client: &Client = conn;
+        return query_opt(client, prepared_statements, query, params).await;
+    };
+
+    let transaction = conn.transaction().await?;
+    transaction
+        .batch_execute(&format!(
+            "SET LOCAL statement_timeout = {statement_timeout}"
+        ))
+        .await?;
+    let row = query_opt(&transaction, prepared_statements, query, params).await?;
+    transaction.commit().await?;
+    Ok(row)
+}
+
+/// Run a query returning at most one row, as a prepared statement if allowed. Otherwise the query
+/// is sent with the types of its parameters, so no statement outlives it on the server.
+async fn query_opt(
+    client: &impl GenericClient,
+    prepared_statements: bool,
+    query: &str,
+    params: &[(&(dyn ToSql + Sync), Type)],
+) -> Result<Option<Row>, tokio_postgres::Error> {
+    if !prepared_statements {
+        let rows = client.query_typed(query, params).await?;
+        return Ok(rows.into_iter().next());
+    }
+
+    let types: Vec<Type> = params.iter().map(|(_, ty)| ty.clone()).collect();
+    let values: Vec<&(dyn ToSql + Sync)> = params.iter().map(|(value, _)| *value).collect();
+    let statement = client.prepare_typed(query, &types).await?;
+    client.query_opt(&statement, &values).await
+}
+
+/// The connection a database source queries its tiles with, which it can't do without one
+pub fn require_connection<'a, 'b>(
+    conn: Option<&'a mut Connection<'b>>,
+    source_id: &str,
+) -> io::Result<&'a mut Connection<'b>> {
+    conn.ok_or_else(|| io::Error::other(format!("Source {source_id} requires a connection")))
+}
+
+/// Get a connection from the pool. Fails with `TimedOut` if none is available in time.
 pub async fn get_connection(pool: &Pool) -> io::Result<Connection<'_>> {
-    let connection = pool
-        .get()
-        .await
-        .map_err(|e| prettify_error!(e, "Can't retrieve connection from the pool"))?;
+    let connection = pool.get().await.map_err(|e| match e {
+        RunError::User(e) => io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("Can't retrieve connection from the pool: {e}"),
+        ),
+        RunError::TimedOut => io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Timed out waiting for a connection from the pool",
+        ),
+    })?;
 
     Ok(connection)
 }
@@ -89,46 +408,215 @@ async fn validate_postgis_version(pool: &Pool) -> io::Result<()> {
     if req.matches(&version) {
         Ok(())
     } else {
-        Err(io::Error::new(io::ErrorKind::Other, format!("Martin requires PostGIS {REQUIRED_POSTGIS_VERSION}, current version is {postgis_version}")))
+        Err(io::Error::other(format!("Martin requires PostGIS {REQUIRED_POSTGIS_VERSION}, current version is {postgis_version}")))
     }
 }
 
-pub async fn configure_db_sources(mut config: &mut Config) -> io::Result<Pool> {
-    info!("Connecting to database");
+async fn connect(pg: &PgConfig) -> io::Result<Pool> {
     let pool = setup_connection_pool(
-        &config.pg.connection_string,
-        &config.pg.ca_root_file,
-        config.pg.pool_size,
-        config.pg.danger_accept_invalid_certs,
+        &pg.connection_string,
+        &TlsOptions::from(pg),
+        pg.pool_size,
+        Duration::from_secs(pg.pool_acquire_timeout),
+        pg.prepared_statements,
     )
     .await?;
 
     validate_postgis_version(&pool).await?;
+    Ok(pool)
+}
+
+/// Connect to the main database and to the extra `databases`, and resolve the sources of all.
+/// Returns the main pool, and the pools of the extra databases in config order.
+pub async fn configure_db_sources(config: &mut Config) -> io::Result<(Pool, Vec<Pool>)> {
+    info!("Connecting to database");
+    let pool = connect(&config.pg).await?;
+
+    let mut database_pools = Vec::with_capacity(config.pg.databases.len());
+    for (index, database) in config.pg.databases.iter().enumerate() {
+        info!("Connecting to database {index} of databases");
+        database_pools.push(connect(database).await?);
+    }
+
+    resolve_sources(&pool, &database_pools, config).await?;
+    Ok((pool, database_pools))
+}
+
+/// Resolve the sources of the main database and of the extra `databases`, using their pools.
+/// Ids of the extra databases already used by the main database or a previous extra database
+/// are renamed, so all sources can be served together.
+pub async fn resolve_sources(
+    pool: &Pool,
+    database_pools: &[Pool],
+    config: &mut Config,
+) -> io::Result<()> {
+    let pg = &mut config.pg;
+    if pg.databases.len() != database_pools.len() {
+        return Err(io::Error::other(
+            "Databases can't be added or removed without a restart",
+        ));
+    }
+
+    resolve_database_sources(pool, pg).await?;
+
+    let mut used: HashSet<String> = pg
+        .table_sources
+        .keys()
+        .chain(pg.function_sources.keys())
+        .chain(pg.raster_sources.keys())
+        .cloned()
+        .collect();
+    let separator = pg.id_conflict_separator.clone();
+    let fail_on_conflict = pg.fail_on_id_conflict;
+    for (database, pool) in pg.databases.iter_mut().zip(database_pools) {
+        resolve_database_sources(pool, database).await?;
+        rename_conflicting_ids(
+            &mut database.table_sources,
+            &used,
+            &separator,
+            fail_on_conflict,
+            |src| &mut src.id,
+        )?;
+        rename_conflicting_ids(
+            &mut database.function_sources,
+            &used,
+            &separator,
+            fail_on_conflict,
+            |src| &mut src.id,
+        )?;
+        rename_conflicting_ids(
+            &mut database.raster_sources,
+            &used,
+            &separator,
+            fail_on_conflict,
+            |src| &mut src.id,
+        )?;
+        used.extend(
+            database
+                .table_sources
+                .keys()
+                .chain(database.function_sources.keys())
+                .chain(database.raster_sources.keys())
+                .cloned(),
+        );
+    }
+    check_aliases(&pg.aliases, &used)
+}
+
+/// Check that each alias refers to one of the `used` source ids, and is not one itself
+fn check_aliases(aliases: &HashMap<String, String>, used: &HashSet<String>) -> io::Result<()> {
+    let mut aliases: Vec<_> = aliases.iter().collect();
+    aliases.sort();
+    for (alias, id) in aliases {
+        if used.contains(alias) {
+            return Err(io::Error::other(format!(
+                "Alias {alias} of source {id} is already the id of a source"
+            )));
+        }
+        if !used.contains(id) {
+            return Err(io::Error::other(format!(
+                "Alias {alias} refers to {id}, which is not a source"
+            )));
+        }
+    }
+    Ok(())
+}
 
-    let info_prefix = if config.pg.use_dynamic_sources {
-        info!("Automatically detecting table and function sources");
-        let mut connection = get_connection(&pool).await?;
+/// Discover the sources of a database unless they are listed in its config, and normalize their ids
+async fn resolve_database_sources(pool: &Pool, pg: &mut PgConfig) -> io::Result<()> {
+    let info_prefix = if pg.use_dynamic_sources {
+        info!("Automatically detecting table, function and raster sources");
+        let mut connection = get_connection(pool).await?;
 
-        let sources = get_table_sources(&mut connection, config.pg.default_srid).await?;
+        let mut sources =
+            get_table_sources(&mut connection, pg.default_srid, pg.sample_srid).await?;
+        if let Some(auto_publish) = &pg.auto_publish {
+            sources.retain(|_, src| auto_publish.is_table_published(&src.schema, &src.table));
+        }
+        // Discovered tables have no config of their own, so the defaults of the database apply
+        for src in sources.values_mut() {
+            src.extent = pg.default_extent.or(src.extent);
+            src.buffer = pg.default_buffer.map(Buffer::Value).or(src.buffer.take());
+            src.clip_geom = pg.default_clip_geom.or(src.clip_geom);
+        }
         if sources.is_empty() {
             info!("No table sources found");
         } else {
-            config.pg.table_sources = sources;
+            pg.table_sources = sources;
         }
 
-        let sources = get_function_sources(&mut connection).await?;
+        let mut sources = get_function_sources(&mut connection).await?;
+        if let Some(auto_publish) = &pg.auto_publish {
+            sources.retain(|_, src| auto_publish.is_schema_published(&src.schema));
+        }
         if sources.is_empty() {
             info!("No function sources found");
         } else {
-            config.pg.function_sources = sources;
+            pg.function_sources = sources;
+        }
+
+        let mut sources = get_raster_sources(&mut connection).await?;
+        if let Some(auto_publish) = &pg.auto_publish {
+            sources.retain(|_, src| auto_publish.is_table_published(&src.schema, &src.table));
+        }
+        if sources.is_empty() {
+            info!("No raster sources found");
+        } else {
+            pg.raster_sources = sources;
         }
 
         "Found"
     } else {
+        check_missing_sources(pool, pg).await?;
         "Loaded"
     };
 
-    for table_source in config.pg.table_sources.values() {
+    // Tables without their own statement timeout, extent, buffer or clipping get the ones of
+    // their database, which must still fit their own extent or buffer
+    for table_source in pg.table_sources.values_mut() {
+        set_option(&mut table_source.statement_timeout, pg.statement_timeout);
+        set_option(&mut table_source.extent, pg.default_extent);
+        set_option(
+            &mut table_source.buffer,
+            pg.default_buffer.map(Buffer::Value),
+        );
+        set_option(&mut table_source.clip_geom, pg.default_clip_geom);
+        table_source.validate()?;
+    }
+
+    // Feature ids must be integers, other id columns are ignored rather than breaking the tiles
+    if pg.table_sources.values().any(|src| src.id_column.is_some()) {
+        let mut connection = get_connection(pool).await?;
+        for table_source in pg.table_sources.values_mut() {
+            table_source.check_id_column(&mut connection).await?;
+        }
+    }
+
+    let (normalize_ids, separator) = (pg.normalize_ids, pg.id_conflict_separator.as_str());
+    let fail_on_conflict = pg.fail_on_id_conflict;
+    normalize_source_ids(
+        &mut pg.table_sources,
+        normalize_ids,
+        separator,
+        fail_on_conflict,
+        |src| &mut src.id,
+    )?;
+    normalize_source_ids(
+        &mut pg.function_sources,
+        normalize_ids,
+        separator,
+        fail_on_conflict,
+        |src| &mut src.id,
+    )?;
+    normalize_source_ids(
+        &mut pg.raster_sources,
+        normalize_ids,
+        separator,
+        fail_on_conflict,
+        |src| &mut src.id,
+    )?;
+
+    for table_source in pg.table_sources.values() {
         info!(
             r#"{info_prefix} "{}" table source with "{}" column ({}, SRID={})"#,
             table_source.id,
@@ -137,8 +625,265 @@ pub async fn configure_db_sources(mut config: &mut Config) -> io::Result<Pool> {
             table_source.srid
         );
     }
-    for function_source in config.pg.function_sources.values() {
+    for function_source in pg.function_sources.values() {
         info!("{info_prefix} {} function source", function_source.id);
     }
-    Ok(pool)
+    for raster_source in pg.raster_sources.values() {
+        info!(
+            r#"{info_prefix} "{}" raster source with "{}" column (SRID={})"#,
+            raster_source.id, raster_source.raster_column, raster_source.srid
+        );
+    }
+    Ok(())
+}
+
+/// Warn about the sources of the config whose table or function is not in the database,
+/// or fail listing all of them with the `error` policy of `on_missing_source`
+async fn check_missing_sources(pool: &Pool, pg: &PgConfig) -> io::Result<()> {
+    let mut connection = get_connection(pool).await?;
+    let table_exists = include_str!("scripts/table_exists.sql");
+    let function_exists = include_str!("scripts/function_exists.sql");
+    let mut missing = Vec::new();
+    for src in pg.table_sources.values() {
+        if !exists(&mut connection, table_exists, &src.schema, &src.table).await? {
+            missing.push(format!(
+                "table source {} ({}.{})",
+                src.id, src.schema, src.table
+            ));
+        }
+    }
+    for src in pg.function_sources.values() {
+        if !exists(&mut connection, function_exists, &src.schema, &src.function).await? {
+            missing.push(format!(
+                "function source {} ({}.{})",
+                src.id, src.schema, src.function
+            ));
+        }
+    }
+    for src in pg.raster_sources.values() {
+        if !exists(&mut connection, table_exists, &src.schema, &src.table).await? {
+            missing.push(format!(
+                "raster source {} ({}.{})",
+                src.id, src.schema, src.table
+            ));
+        }
+    }
+    missing.sort();
+
+    match pg.on_missing_source {
+        OnMissingSource::Error if !missing.is_empty() => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "Configured sources are not in the database: {}",
+                missing.join(", ")
+            ),
+        )),
+        _ => {
+            for source in missing {
+                warn!("Configured {source} is not in the database, its tiles will fail");
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn exists(
+    connection: &mut Connection<'_>,
+    query: &str,
+    schema: &str,
+    name: &str,
+) -> io::Result<bool> {
+    connection
+        .query_one(query, &[&schema, &name])
+        .await
+        .map(|row| row.get("exists"))
+        .map_err(|e| prettify_error!(e, "Can't check if {}.{} exists", schema, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::Private;
+    use openssl::symm::Cipher;
+    use openssl::x509::X509NameBuilder;
+    use std::cell::Cell;
+
+    #[actix_rt::test]
+    async fn test_with_retries() {
+        // A connection that is lost once, then succeeds
+        let attempts = Cell::new(0);
+        let result = with_retries(2, || async {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() == 1 {
+                Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "connection closed",
+                ))
+            } else {
+                Ok("tile")
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), "tile");
+        assert_eq!(attempts.get(), 2);
+
+        // A connection that is never recovered gives up after the retries
+        let attempts = Cell::new(0);
+        let result: io::Result<()> = with_retries(2, || async {
+            attempts.set(attempts.get() + 1);
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"))
+        })
+        .await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(attempts.get(), 3);
+
+        // SQL errors and pool timeouts are not retried
+        for kind in [io::ErrorKind::Other, io::ErrorKind::TimedOut] {
+            let attempts = Cell::new(0);
+            let result: io::Result<()> = with_retries(2, || async {
+                attempts.set(attempts.get() + 1);
+                Err(io::Error::new(kind, "failed"))
+            })
+            .await;
+            assert_eq!(result.unwrap_err().kind(), kind);
+            assert_eq!(attempts.get(), 1);
+        }
+    }
+
+    /// A self-signed certificate named `name`, and its private key
+    fn make_cert(name: &str) -> (X509, PKey<Private>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap();
+        let subject = subject.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&subject).unwrap();
+        cert.set_issuer_name(&subject).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        (cert.build(), key)
+    }
+
+    #[test]
+    fn test_add_ca_root_dir() {
+        let dir = std::env::temp_dir().join(format!("martin_ca_root_dir_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+
+        let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+        let error = add_ca_root_dir(builder.cert_store_mut(), &dir).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        let error = add_ca_root_dir(builder.cert_store_mut(), &dir.join("missing")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+
+        let (first, second) = (make_cert("first").0, make_cert("second").0);
+        fs::write(dir.join("first.pem"), first.to_pem().unwrap()).unwrap();
+        fs::write(dir.join("second.CRT"), second.to_pem().unwrap()).unwrap();
+        fs::write(dir.join("README"), "not a certificate").unwrap();
+        assert_eq!(add_ca_root_dir(builder.cert_store_mut(), &dir).unwrap(), 2);
+
+        let connector = builder.build();
+        let trusted: Vec<Vec<u8>> = connector
+            .context()
+            .cert_store()
+            .objects()
+            .iter()
+            .filter_map(|object| object.x509())
+            .map(|cert| cert.to_der().unwrap())
+            .collect();
+        for cert in [first, second] {
+            assert!(trusted.contains(&cert.to_der().unwrap()));
+        }
+
+        fs::write(dir.join("broken.pem"), "not a certificate").unwrap();
+        let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+        let error = add_ca_root_dir(builder.cert_store_mut(), &dir).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_make_ssl_connector_client_certificate() {
+        let dir = std::env::temp_dir().join(format!("martin_ssl_cert_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = |name: &str| Some(dir.join(name).to_str().unwrap().to_owned());
+
+        let (cert, key) = make_cert("client");
+        fs::write(dir.join("client.crt"), cert.to_pem().unwrap()).unwrap();
+        let encrypted_key = key
+            .private_key_to_pem_pkcs8_passphrase(Cipher::aes_128_cbc(), b"secret")
+            .unwrap();
+        fs::write(dir.join("client.key"), encrypted_key).unwrap();
+        let (_, other_key) = make_cert("other");
+        let other_key = other_key.private_key_to_pem_pkcs8().unwrap();
+        fs::write(dir.join("other.key"), other_key).unwrap();
+
+        let tls = TlsOptions {
+            ssl_cert: path("client.crt"),
+            ssl_key: path("client.key"),
+            ssl_key_passphrase: Some("secret".to_owned()),
+            ..TlsOptions::default()
+        };
+        let connector = make_ssl_connector(&tls).unwrap();
+        let context = connector.context();
+        assert_eq!(
+            context.certificate().unwrap().to_der().unwrap(),
+            cert.to_der().unwrap()
+        );
+        assert!(context.private_key().unwrap().public_eq(&key));
+
+        for (tls, kind) in [
+            (
+                TlsOptions {
+                    ssl_key_passphrase: Some("wrong".to_owned()),
+                    ..tls.clone()
+                },
+                io::ErrorKind::InvalidData,
+            ),
+            (
+                TlsOptions {
+                    ssl_key_passphrase: None,
+                    ..tls.clone()
+                },
+                io::ErrorKind::InvalidData,
+            ),
+            (
+                TlsOptions {
+                    ssl_key: path("other.key"),
+                    ..tls.clone()
+                },
+                io::ErrorKind::InvalidInput,
+            ),
+            (
+                TlsOptions {
+                    ssl_key: None,
+                    ..tls.clone()
+                },
+                io::ErrorKind::InvalidInput,
+            ),
+            (
+                TlsOptions {
+                    ssl_key: path("missing.key"),
+                    ..tls.clone()
+                },
+                io::ErrorKind::NotFound,
+            ),
+        ] {
+            let error = make_ssl_connector(&tls).unwrap_err();
+            assert_eq!(error.kind(), kind, "{tls:?}: {error}");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
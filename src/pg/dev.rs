@@ -1,10 +1,28 @@
-use crate::pg::db::{setup_connection_pool, Pool};
+use crate::pg::config::{
+    MAX_RETRIES_DEFAULT, POOL_ACQUIRE_TIMEOUT_DEFAULT, RESERVED_POOL_MINZOOM_DEFAULT,
+};
+use crate::pg::db::{setup_connection_pool, Pool, TlsOptions};
 use crate::pg::function_source::{FunctionSource, FunctionSources};
-use crate::pg::table_source::{TableSource, TableSources};
-use crate::srv::server::AppState;
+use crate::pg::raster_source::{RasterSource, RasterSources};
+use crate::pg::table_source::{Buffer, TableSource, TableSources};
+use crate::srv::config::TILE_COMPRESSION_LEVEL_DEFAULT;
+use crate::srv::empty_tiles::EmptyTiles;
+#[cfg(feature = "observability")]
+use crate::srv::metrics::Metrics;
+use crate::srv::server::{AppState, Sources};
+use arc_swap::ArcSwap;
 use log::info;
+use openssl::asn1::Asn1Time;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509};
 use std::collections::HashMap;
 use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tilejson::Bounds;
 
 pub fn mock_table_sources(sources: &[TableSource]) -> TableSources {
@@ -21,79 +39,56 @@ pub fn mock_default_table_sources() -> TableSources {
         id: "public.table_source".to_owned(),
         schema: "public".to_owned(),
         table: "table_source".to_owned(),
-        id_column: None,
         geometry_column: "geom".to_owned(),
         minzoom: Some(0),
         maxzoom: Some(30),
         bounds: Some(Bounds::MAX),
         srid: 4326,
         extent: Some(4096),
-        buffer: Some(64),
+        buffer: Some(Buffer::Value(64)),
         clip_geom: Some(true),
-        geometry_type: None,
-        properties: HashMap::new(),
-        unrecognized: HashMap::new(),
+        ..Default::default()
     };
 
     let table_source_multiple_geom1 = TableSource {
         id: "public.table_source_multiple_geom.geom1".to_owned(),
         schema: "public".to_owned(),
         table: "table_source_multiple_geom".to_owned(),
-        id_column: None,
         geometry_column: "geom1".to_owned(),
-        geometry_type: None,
-        properties: HashMap::new(),
-        unrecognized: HashMap::new(),
-        ..table_source
+        ..table_source.clone()
     };
 
     let table_source_multiple_geom2 = TableSource {
         id: "public.table_source_multiple_geom.geom2".to_owned(),
         schema: "public".to_owned(),
         table: "table_source_multiple_geom".to_owned(),
-        id_column: None,
         geometry_column: "geom2".to_owned(),
-        geometry_type: None,
-        properties: HashMap::new(),
-        unrecognized: HashMap::new(),
-        ..table_source
+        ..table_source.clone()
     };
 
     let table_source1 = TableSource {
         id: "public.points1".to_owned(),
         schema: "public".to_owned(),
         table: "points1".to_owned(),
-        id_column: None,
         geometry_column: "geom".to_owned(),
-        geometry_type: None,
-        properties: HashMap::new(),
-        unrecognized: HashMap::new(),
-        ..table_source
+        ..table_source.clone()
     };
 
     let table_source2 = TableSource {
         id: "public.points2".to_owned(),
         schema: "public".to_owned(),
         table: "points2".to_owned(),
-        id_column: None,
         geometry_column: "geom".to_owned(),
-        geometry_type: None,
-        properties: HashMap::new(),
-        unrecognized: HashMap::new(),
-        ..table_source
+        ..table_source.clone()
     };
 
     let table_source3857 = TableSource {
         id: "public.points3857".to_owned(),
         schema: "public".to_owned(),
         table: "points3857".to_owned(),
-        id_column: None,
         geometry_column: "geom".to_owned(),
         srid: 3857,
-        geometry_type: None,
-        properties: HashMap::new(),
-        unrecognized: HashMap::new(),
-        ..table_source
+        ..table_source.clone()
     };
 
     mock_table_sources(&[
@@ -123,6 +118,14 @@ pub fn mock_default_function_sources() -> FunctionSources {
         minzoom: Some(0),
         maxzoom: Some(30),
         bounds: Some(Bounds::MAX),
+        name: None,
+        description: None,
+        attribution: None,
+        query_params: None,
+        vector_layers: None,
+        api_keys: None,
+        empty_tile: None,
+        headers: None,
         unrecognized: HashMap::new(),
     };
 
@@ -130,6 +133,14 @@ pub fn mock_default_function_sources() -> FunctionSources {
         id: "public.function_source_query_params".to_owned(),
         schema: "public".to_owned(),
         function: "function_source_query_params".to_owned(),
+        name: None,
+        description: None,
+        attribution: None,
+        query_params: None,
+        vector_layers: None,
+        api_keys: None,
+        empty_tile: None,
+        headers: None,
         unrecognized: HashMap::new(),
         ..function_source
     };
@@ -137,27 +148,145 @@ pub fn mock_default_function_sources() -> FunctionSources {
     mock_function_sources(&[function_source, function_source_query_params])
 }
 
+pub fn mock_raster_sources(sources: &[RasterSource]) -> RasterSources {
+    let mut raster_sources: RasterSources = HashMap::new();
+    for source in sources {
+        raster_sources.insert(source.id.clone(), Box::new(source.clone()));
+    }
+
+    raster_sources
+}
+
+pub fn mock_default_raster_sources() -> RasterSources {
+    let raster_source = RasterSource {
+        id: "public.raster_source".to_owned(),
+        schema: "public".to_owned(),
+        table: "raster_source".to_owned(),
+        raster_column: "rast".to_owned(),
+        srid: 3857,
+        minzoom: Some(0),
+        maxzoom: Some(30),
+        bounds: Some(Bounds::MAX),
+        name: None,
+        description: None,
+        attribution: None,
+        colormap: None,
+        api_keys: None,
+        empty_tile: None,
+        headers: None,
+        unrecognized: HashMap::new(),
+    };
+
+    mock_raster_sources(&[raster_source])
+}
+
+/// A self-signed certificate of `localhost` and its private key, both PEM encoded,
+/// for serving over TLS in tests
+pub fn mock_tls_cert() -> (Vec<u8>, Vec<u8>) {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+    let mut subject = X509NameBuilder::new().unwrap();
+    subject
+        .append_entry_by_nid(Nid::COMMONNAME, "localhost")
+        .unwrap();
+    let subject = subject.build();
+
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_subject_name(&subject).unwrap();
+    cert.set_issuer_name(&subject).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    let san = SubjectAlternativeName::new()
+        .dns("localhost")
+        .build(&cert.x509v3_context(None, None))
+        .unwrap();
+    cert.append_extension(san).unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+    (
+        cert.build().to_pem().unwrap(),
+        key.private_key_to_pem_pkcs8().unwrap(),
+    )
+}
+
 pub async fn make_pool() -> Pool {
     let connection_string: String = env::var("DATABASE_URL").unwrap();
     info!("Connecting to {connection_string}");
 
-    let pool = setup_connection_pool(&connection_string, &None, 1, false)
-        .await
-        .unwrap();
+    let pool = setup_connection_pool(
+        &connection_string,
+        &TlsOptions::default(),
+        1,
+        Duration::from_secs(POOL_ACQUIRE_TIMEOUT_DEFAULT),
+        true,
+    )
+    .await
+    .unwrap();
     info!("Connected to {connection_string}");
 
     pool
 }
 
+/// A pool of a database that can't be reached, for serving sources without a database
+pub async fn make_unreachable_pool() -> Pool {
+    setup_connection_pool(
+        "postgres://postgres@127.0.0.1:1/db",
+        &TlsOptions::default(),
+        1,
+        Duration::from_secs(1),
+        true,
+    )
+    .await
+    .unwrap()
+}
+
 pub async fn mock_state(
     table_sources: Option<TableSources>,
     function_sources: Option<FunctionSources>,
 ) -> AppState {
-    let pool = make_pool().await;
+    mock_state_with_pool(make_pool().await, table_sources, function_sources)
+}
 
+pub fn mock_state_with_pool(
+    pool: Pool,
+    table_sources: Option<TableSources>,
+    function_sources: Option<FunctionSources>,
+) -> AppState {
     AppState {
         pool,
-        table_sources: table_sources.unwrap_or_default(),
-        function_sources: function_sources.unwrap_or_default(),
+        reserved_pool: None,
+        reserved_pool_minzoom: RESERVED_POOL_MINZOOM_DEFAULT,
+        database_pools: Vec::new(),
+        sources: Arc::new(ArcSwap::from_pointee(Sources {
+            table_sources: table_sources.unwrap_or_default(),
+            function_sources: function_sources.unwrap_or_default(),
+            raster_sources: RasterSources::default(),
+            databases: HashMap::new(),
+            generation: 0,
+        })),
+        source_versions: Mutex::default(),
+        empty_tiles: EmptyTiles::default(),
+        admin_endpoints: false,
+        debug_endpoints: false,
+        feature_count_header: false,
+        tile_compression_level: TILE_COMPRESSION_LEVEL_DEFAULT,
+        tile_cache: None,
+        #[cfg(feature = "observability")]
+        metrics: Arc::new(Metrics::new().unwrap()),
+        partial_composite_tiles: false,
+        max_retries: MAX_RETRIES_DEFAULT,
+        cache_max_age: None,
+        fonts: None,
+        sprites: None,
+        file_sources: None,
+        auth: None,
+        api_keys: None,
+        max_tile_size: None,
+        tile_timeout: None,
+        max_zoom: None,
+        base_path: String::new(),
     }
 }
@@ -1,14 +1,17 @@
-use crate::pg::db::Connection;
-use crate::pg::utils::{prettify_error, query_to_json};
-use crate::source::{Source, Tile, UrlQuery, Xyz};
+use crate::pg::db::{query_tile, require_connection, Connection};
+use crate::pg::utils::{polygon_to_bbox, prettify_error, query_error, query_to_json};
+use crate::source::{is_valid_zoom, validate_zoom_range, EmptyTile, Source, Tile, UrlQuery, Xyz};
 use async_trait::async_trait;
-use postgres::types::Type;
+use log::warn;
+use postgis::ewkb;
+use postgres::types::Json;
+use postgres::types::{ToSql, Type};
 use postgres_protocol::escape::escape_identifier;
 use serde::{Deserialize, Serialize};
-use serde_yaml::Value;
+use serde_json::Value;
 use std::collections::HashMap;
 use std::io;
-use tilejson::{tilejson, Bounds, TileJSON};
+use tilejson::{tilejson, Bounds, TileJSON, VectorLayer};
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct FunctionSource {
@@ -35,12 +38,147 @@ pub struct FunctionSource {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub bounds: Option<Bounds>,
 
+    /// A name describing the tileset, defaults to the source id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    /// A text description of the tileset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    /// An attribution to be displayed when the map is shown to a user
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attribution: Option<String>,
+
+    /// Types of the query parameters accepted by the function, by name, e.g. `limit: integer`.
+    /// Values of these parameters are checked and passed to the function as JSON of that type.
+    /// Other parameters are passed as numbers when they parse as JSON numbers, and strings otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query_params: Option<HashMap<String, String>>,
+
+    /// Layers of the tiles returned by the function, with their fields, listed as the TileJSON
+    /// `vector_layers`. Martin can't know them, as the function builds the tile.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vector_layers: Option<Vec<VectorLayer>>,
+
+    /// API keys accepted by this source. When set, requests must present one of them
+    /// in the `X-API-Key` header or the `key` query parameter.
+    /// Left out of the sources list, so the keys are not exposed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_keys: Option<Vec<String>>,
+
+    /// How requests for tiles without data are answered: `204` (No Content), `404` (Not Found),
+    /// or `blank`, a tile without data [default: 204]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub empty_tile: Option<EmptyTile>,
+
+    /// Extra HTTP headers of the tile responses, e.g. a license or a CDN cache hint.
+    /// Headers set by martin itself, like `Content-Type`, can't be overridden.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headers: Option<HashMap<String, String>>,
+
     #[serde(flatten, skip_serializing)]
-    pub unrecognized: HashMap<String, Value>,
+    pub unrecognized: HashMap<String, serde_yaml::Value>,
 }
 
 pub type FunctionSources = HashMap<String, Box<FunctionSource>>;
 
+/// PostgreSQL types supported in `query_params`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum QueryParamType {
+    Integer,
+    Float,
+    Boolean,
+    Text,
+    Json,
+}
+
+impl QueryParamType {
+    fn from_pg_type(pg_type: &str) -> Option<Self> {
+        match pg_type.to_lowercase().as_str() {
+            "int2" | "int4" | "int8" | "smallint" | "int" | "integer" | "bigint" => {
+                Some(Self::Integer)
+            }
+            "float4" | "float8" | "real" | "double precision" | "numeric" | "decimal" => {
+                Some(Self::Float)
+            }
+            "bool" | "boolean" => Some(Self::Boolean),
+            "text" | "varchar" | "character varying" => Some(Self::Text),
+            "json" | "jsonb" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    fn parse(self, value: &str) -> Option<serde_json::Value> {
+        match self {
+            Self::Integer => value.parse::<i64>().ok().map(serde_json::Value::from),
+            Self::Float => value
+                .parse::<f64>()
+                .ok()
+                .and_then(serde_json::Number::from_f64)
+                .map(serde_json::Value::Number),
+            Self::Boolean => value.parse::<bool>().ok().map(serde_json::Value::Bool),
+            Self::Text => Some(serde_json::Value::String(value.to_owned())),
+            Self::Json => serde_json::from_str(value).ok(),
+        }
+    }
+}
+
+impl FunctionSource {
+    /// Check the zoom levels, and that the types of `query_params` are supported
+    pub fn validate(&self) -> io::Result<()> {
+        validate_zoom_range(&self.id, self.minzoom, self.maxzoom)?;
+        for (name, pg_type) in self.query_params.iter().flatten() {
+            if QueryParamType::from_pg_type(pg_type).is_none() {
+                return Err(io::Error::other(format!(
+                    r#"Query parameter "{name}" of function source "{}" has unsupported type "{pg_type}", expected one of: integer, bigint, smallint, real, double precision, numeric, boolean, text, varchar, json, jsonb"#,
+                    self.id
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Build the tile query, which expects the following arguments:
+    /// `$1`: x, `$2`: y, `$3`: z, `$4`: the query params as JSON
+    pub fn build_tile_query(&self) -> String {
+        // The schema and function can't be part of a prepared query, so they need to be
+        // escaped by hand. However they come from database introspection so they shall be safe.
+        format!(
+            include_str!("scripts/call_rpc.sql"),
+            schema = escape_identifier(&self.schema),
+            function = escape_identifier(&self.function)
+        )
+    }
+
+    /// Convert the URL query to the JSON passed to the function, with the values of declared
+    /// `query_params` converted to their types. Fails with `InvalidInput` naming the first
+    /// parameter whose value doesn't match its type.
+    pub fn query_to_json(&self, query: &UrlQuery) -> io::Result<Json<HashMap<String, Value>>> {
+        let Some(query_params) = &self.query_params else {
+            return Ok(query_to_json(query));
+        };
+
+        let mut query_json = query_to_json(query);
+        for (name, value) in query {
+            let Some(pg_type) = query_params.get(name) else {
+                continue;
+            };
+            let typed = QueryParamType::from_pg_type(pg_type)
+                .and_then(|param_type| param_type.parse(value))
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Invalid value '{value}' of query parameter '{name}', expected {pg_type}"),
+                    )
+                })?;
+            query_json.0.insert(name.clone(), typed);
+        }
+        Ok(query_json)
+    }
+}
+
+#[allow(mismatched_lifetime_syntaxes)]
 #[async_trait]
 impl Source for FunctionSource {
     async fn get_id(&self) -> &str {
@@ -51,9 +189,12 @@ impl Source for FunctionSource {
         let mut tilejson = tilejson! {
             tilejson: "2.2.0".to_string(),
             tiles: vec![],  // tile source is required, but not yet known
-            name: self.id.to_string(),
+            name: self.name.clone().unwrap_or_else(|| self.id.to_string()),
         };
 
+        tilejson.description = self.description.clone();
+        tilejson.attribution = self.attribution.clone();
+
         if let Some(minzoom) = &self.minzoom {
             tilejson.minzoom = Some(*minzoom);
         };
@@ -62,63 +203,60 @@ impl Source for FunctionSource {
             tilejson.maxzoom = Some(*maxzoom);
         };
 
-        if let Some(bounds) = &self.bounds {
-            tilejson.bounds = Some(*bounds);
-        };
+        // Without bounds, the tiles may cover the whole world
+        tilejson.bounds = Some(self.bounds.unwrap_or_default());
+
+        tilejson.vector_layers = self.vector_layers.clone();
 
         // TODO: consider removing - this is not needed per TileJSON spec
         tilejson.set_missing_defaults();
         Ok(tilejson)
     }
 
+    fn is_valid_zoom(&self, zoom: i32) -> bool {
+        is_valid_zoom(zoom, self.minzoom, self.maxzoom)
+    }
+
+    fn get_empty_tile(&self) -> EmptyTile {
+        self.empty_tile.unwrap_or_default()
+    }
+
+    fn get_headers(&self) -> Option<&HashMap<String, String>> {
+        self.headers.as_ref()
+    }
+
     async fn get_tile(
         &self,
-        conn: &mut Connection,
+        conn: Option<&mut Connection>,
         xyz: &Xyz,
         query: &Option<UrlQuery>,
     ) -> Result<Tile, io::Error> {
+        let conn = require_connection(conn, &self.id)?;
         let empty_query = HashMap::new();
         let query = query.as_ref().unwrap_or(&empty_query);
-        let query_json = query_to_json(query);
-
-        // Query preparation : the schema and function can't be part of a prepared query, so they
-        // need to be escaped by hand.
-        // However schema and function comes from database introspection so they shall be safe.
-        // The query expects the following arguments :
-        // $1 : x
-        // $2 : y
-        // $3 : z
-        // $4 : query_json
-
-        let escaped_schema = escape_identifier(&self.schema);
-        let escaped_function = escape_identifier(&self.function);
-        let raw_query = format!(
-            include_str!("scripts/call_rpc.sql"),
-            schema = escaped_schema,
-            function = escaped_function
-        );
-
-        let query = conn
-            .prepare_typed(
-                &raw_query,
-                &[Type::INT4, Type::INT4, Type::INT4, Type::JSON],
-            )
-            .await
-            .map_err(|e| prettify_error!(e, "Can't create prepared statement for the tile"))?;
+        let query_json = self.query_to_json(query)?;
+        let raw_query = self.build_tile_query();
 
-        let tile = conn
-            .query_one(&query, &[&xyz.x, &xyz.y, &xyz.z, &query_json])
+        let params: [(&(dyn ToSql + Sync), Type); 4] = [
+            (&xyz.x, Type::INT4),
+            (&xyz.y, Type::INT4),
+            (&xyz.z, Type::INT4),
+            (&query_json, Type::JSON),
+        ];
+        let tile = query_tile(conn, &raw_query, &params, None)
             .await
-            .map(|row| row.get(self.function.as_str()))
+            .map(|row| {
+                row.map_or_else(Tile::new, |row| {
+                    Tile::from(row.get::<_, Vec<u8>>(self.function.as_str()))
+                })
+            })
             .map_err(|error| {
-                prettify_error!(
-                    error,
-                    r#"Can't get "{}" tile at {}/{}/{} with {:?} params"#,
-                    self.id,
-                    xyz.z,
-                    xyz.x,
-                    xyz.z,
-                    query_json
+                query_error(
+                    &error,
+                    format_args!(
+                        r#"Can't get "{}" tile at {}/{}/{} with {:?} params"#,
+                        self.id, xyz.z, xyz.x, xyz.z, query_json
+                    ),
                 )
             })?;
 
@@ -138,6 +276,11 @@ pub async fn get_function_sources(conn: &mut Connection<'_>) -> Result<FunctionS
         let schema: String = row.get("specific_schema");
         let function: String = row.get("routine_name");
         let id = format!("{schema}.{function}");
+        let bounds = if row.get("has_bounds_function") {
+            get_function_bounds(conn, &schema, &function).await
+        } else {
+            None
+        };
 
         let source = FunctionSource {
             id: id.clone(),
@@ -145,7 +288,15 @@ pub async fn get_function_sources(conn: &mut Connection<'_>) -> Result<FunctionS
             function,
             minzoom: None,
             maxzoom: None,
-            bounds: None,
+            bounds,
+            name: None,
+            description: None,
+            attribution: None,
+            query_params: None,
+            vector_layers: None,
+            api_keys: None,
+            empty_tile: None,
+            headers: None,
             unrecognized: HashMap::new(),
         };
 
@@ -154,3 +305,152 @@ pub async fn get_function_sources(conn: &mut Connection<'_>) -> Result<FunctionS
 
     Ok(sources)
 }
+
+/// Bounds of the tiles of a function returned by its `{function}_bounds()` companion function.
+/// A companion function that fails is only logged, as the bounds are optional.
+async fn get_function_bounds(
+    conn: &mut Connection<'_>,
+    schema: &str,
+    function: &str,
+) -> Option<Bounds> {
+    let bounds_function = format!("{function}_bounds");
+    let query = format!(
+        include_str!("scripts/get_function_bounds.sql"),
+        schema = escape_identifier(schema),
+        bounds_function = escape_identifier(&bounds_function),
+    );
+    match conn.query_one(query.as_str(), &[]).await {
+        Ok(row) => match row.try_get::<_, Option<ewkb::Polygon>>("bounds") {
+            Ok(polygon) => polygon.as_ref().and_then(polygon_to_bbox),
+            Err(e) => {
+                warn!("Can't read the bounds of function {schema}.{bounds_function}, it must return a polygon: {e}");
+                None
+            }
+        },
+        Err(e) => {
+            warn!("Can't get the bounds of function {schema}.{function}: {e}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_query_to_json() {
+        let source = FunctionSource {
+            id: "public.function_source".to_owned(),
+            schema: "public".to_owned(),
+            function: "function_source".to_owned(),
+            minzoom: None,
+            maxzoom: None,
+            bounds: None,
+            name: None,
+            description: None,
+            attribution: None,
+            query_params: Some(HashMap::from([
+                ("limit".to_owned(), "integer".to_owned()),
+                ("scale".to_owned(), "double precision".to_owned()),
+                ("visible".to_owned(), "boolean".to_owned()),
+                ("label".to_owned(), "text".to_owned()),
+            ])),
+            vector_layers: None,
+            api_keys: None,
+            empty_tile: None,
+            headers: None,
+            unrecognized: HashMap::new(),
+        };
+        assert!(source.validate().is_ok());
+
+        let query = UrlQuery::from([
+            ("limit".to_owned(), "10".to_owned()),
+            ("scale".to_owned(), "0.5".to_owned()),
+            ("visible".to_owned(), "true".to_owned()),
+            ("label".to_owned(), "42".to_owned()),
+            ("other".to_owned(), "42".to_owned()),
+        ]);
+        let Json(query_json) = source.query_to_json(&query).unwrap();
+        assert_eq!(query_json["limit"], json!(10));
+        assert_eq!(query_json["scale"], json!(0.5));
+        assert_eq!(query_json["visible"], json!(true));
+        assert_eq!(query_json["label"], json!("42"));
+        assert_eq!(query_json["other"], json!(42));
+
+        let query = UrlQuery::from([("limit".to_owned(), "ten".to_owned())]);
+        let err = source.query_to_json(&query).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            err.to_string(),
+            "Invalid value 'ten' of query parameter 'limit', expected integer"
+        );
+
+        let mut source = source;
+        source.query_params = Some(HashMap::from([("limit".to_owned(), "geometry".to_owned())]));
+        assert!(source.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_zooms() {
+        let mut source = FunctionSource {
+            id: "public.function_source".to_owned(),
+            schema: "public".to_owned(),
+            function: "function_source".to_owned(),
+            minzoom: Some(10),
+            maxzoom: Some(10),
+            bounds: None,
+            name: None,
+            description: None,
+            attribution: None,
+            query_params: None,
+            vector_layers: None,
+            api_keys: None,
+            empty_tile: None,
+            headers: None,
+            unrecognized: HashMap::new(),
+        };
+        assert!(source.validate().is_ok());
+
+        source.minzoom = Some(14);
+        assert_eq!(
+            source.validate().unwrap_err().to_string(),
+            "Source public.function_source has minzoom 14 greater than its maxzoom 10"
+        );
+
+        source.minzoom = None;
+        source.maxzoom = Some(31);
+        assert_eq!(
+            source.validate().unwrap_err().to_string(),
+            "Source public.function_source has maxzoom 31, must be between 0 and 30"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_tilejson_bounds() {
+        let mut source = FunctionSource {
+            id: "public.function_source".to_owned(),
+            schema: "public".to_owned(),
+            function: "function_source".to_owned(),
+            minzoom: None,
+            maxzoom: None,
+            bounds: Some(Bounds::new(-10.0, -20.0, 30.0, 40.0)),
+            name: None,
+            description: None,
+            attribution: None,
+            query_params: None,
+            vector_layers: None,
+            api_keys: None,
+            empty_tile: None,
+            headers: None,
+            unrecognized: HashMap::new(),
+        };
+        let tilejson = source.get_tilejson().await.unwrap();
+        assert_eq!(tilejson.bounds, Some(Bounds::new(-10.0, -20.0, 30.0, 40.0)));
+
+        source.bounds = None;
+        let tilejson = source.get_tilejson().await.unwrap();
+        assert_eq!(tilejson.bounds, Some(Bounds::MAX_TILED));
+    }
+}
@@ -0,0 +1,71 @@
+use crate::pg::db::{require_connection, Connection};
+use crate::pg::table_source::TableSource;
+use crate::source::{EmptyTile, Source, Tile, TileFormat, UrlQuery, Xyz};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+use tilejson::TileJSON;
+
+/// A table source serving the features of its tiles as GeoJSON instead of vector tiles
+#[derive(Clone, Debug)]
+pub struct GeoJsonSource {
+    /// Id of the table source with a `.geojson` suffix, so its tiles are cached apart
+    pub id: String,
+    pub table_source: TableSource,
+}
+
+impl GeoJsonSource {
+    pub fn new(table_source: TableSource) -> Self {
+        Self {
+            id: format!("{}.geojson", table_source.id),
+            table_source,
+        }
+    }
+}
+
+#[allow(mismatched_lifetime_syntaxes)]
+#[async_trait]
+impl Source for GeoJsonSource {
+    async fn get_id(&self) -> &str {
+        self.id.as_str()
+    }
+
+    async fn get_tilejson(&self) -> Result<TileJSON, io::Error> {
+        self.table_source.get_tilejson().await
+    }
+
+    fn is_valid_zoom(&self, zoom: i32) -> bool {
+        self.table_source.is_valid_zoom(zoom)
+    }
+
+    fn get_format(&self) -> TileFormat {
+        TileFormat::Json
+    }
+
+    fn get_max_age(&self) -> Option<Duration> {
+        self.table_source.get_max_age()
+    }
+
+    fn get_empty_tile(&self) -> EmptyTile {
+        self.table_source.get_empty_tile()
+    }
+
+    fn get_headers(&self) -> Option<&HashMap<String, String>> {
+        self.table_source.get_headers()
+    }
+
+    fn is_out_of_bounds(&self, xyz: &Xyz) -> bool {
+        self.table_source.is_out_of_bounds(xyz)
+    }
+
+    async fn get_tile(
+        &self,
+        conn: Option<&mut Connection>,
+        xyz: &Xyz,
+        _query: &Option<UrlQuery>,
+    ) -> Result<Tile, io::Error> {
+        let conn = require_connection(conn, &self.id)?;
+        self.table_source.get_geojson(conn, xyz).await
+    }
+}
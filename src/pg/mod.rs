@@ -3,5 +3,7 @@ pub mod config;
 pub mod db;
 pub mod dev;
 pub mod function_source;
+pub mod geojson_source;
+pub mod raster_source;
 pub mod table_source;
 pub mod utils;
@@ -0,0 +1,404 @@
+use crate::pg::db::{query_tile, require_connection, Connection};
+use crate::pg::utils::{polygon_to_bbox, prettify_error, query_error, tile_bbox};
+use crate::source::{is_valid_zoom, EmptyTile, Source, Tile, TileFormat, UrlQuery, Xyz};
+use async_trait::async_trait;
+use bytes::Bytes;
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use log::warn;
+use postgis::ewkb;
+use postgres_protocol::escape::{escape_identifier, escape_literal};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+use std::sync::OnceLock;
+use tilejson::{tilejson, Bounds, TileJSON};
+
+/// Width and height of raster tiles in pixels
+pub const RASTER_TILE_SIZE: u32 = 256;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RasterSource {
+    /// Raster source id
+    pub id: String,
+
+    /// Table schema
+    pub schema: String,
+
+    /// Table name
+    pub table: String,
+
+    /// Raster column name
+    pub raster_column: String,
+
+    /// Raster SRID
+    pub srid: u32,
+
+    /// An integer specifying the minimum zoom level
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minzoom: Option<u8>,
+
+    /// An integer specifying the maximum zoom level. MUST be >= minzoom
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maxzoom: Option<u8>,
+
+    /// The maximum extent of available map tiles. Bounds MUST define an area
+    /// covered by all zoom levels. The bounds are represented in WGS:84
+    /// latitude and longitude values, in the order left, bottom, right, top.
+    /// Values may be integers or floating point numbers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bounds: Option<Bounds>,
+
+    /// A name describing the tileset, defaults to the source id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    /// A text description of the tileset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    /// An attribution to be displayed when the map is shown to a user
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attribution: Option<String>,
+
+    /// Color map applied to the first band with `ST_ColorMap`, e.g. `grayscale` or `bluered`.
+    /// Rasters that are not 8 or 16 bit need one to be rendered as PNG.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub colormap: Option<String>,
+
+    /// API keys accepted by this source. When set, requests must present one of them
+    /// in the `X-API-Key` header or the `key` query parameter.
+    /// Left out of the sources list, so the keys are not exposed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_keys: Option<Vec<String>>,
+
+    /// How requests for tiles without data are answered: `204` (No Content), `404` (Not Found),
+    /// or `blank`, a tile without data [default: 204]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub empty_tile: Option<EmptyTile>,
+
+    /// Extra HTTP headers of the tile responses, e.g. a license or a CDN cache hint.
+    /// Headers set by martin itself, like `Content-Type`, can't be overridden.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headers: Option<HashMap<String, String>>,
+
+    #[serde(flatten, skip_serializing)]
+    pub unrecognized: HashMap<String, Value>,
+}
+
+pub type RasterSources = HashMap<String, Box<RasterSource>>;
+
+impl RasterSource {
+    /// Query rendering the tile as a PNG image of `tile_size` pixels
+    pub fn get_tile_query(&self, xyz: &Xyz, tile_size: u32) -> String {
+        let rast = match &self.colormap {
+            Some(colormap) => format!("ST_ColorMap(rast, 1, {})", escape_literal(colormap)),
+            None => "rast".to_owned(),
+        };
+
+        format!(
+            include_str!("scripts/get_raster_tile.sql"),
+            mercator_bounds = tile_bbox(xyz),
+            schema = escape_identifier(&self.schema),
+            table = escape_identifier(&self.table),
+            raster_column = escape_identifier(&self.raster_column),
+            srid = self.srid,
+            tile_size = tile_size,
+            rast = rast,
+        )
+    }
+
+    async fn get_sized_tile(
+        &self,
+        conn: Option<&mut Connection<'_>>,
+        xyz: &Xyz,
+        tile_size: u32,
+    ) -> Result<Tile, io::Error> {
+        let conn = require_connection(conn, &self.id)?;
+        let tile_query = self.get_tile_query(xyz, tile_size);
+
+        let tile: Option<Vec<u8>> = query_tile(conn, &tile_query, &[], None)
+            .await
+            .map(|row| row.and_then(|row| row.get("tile")))
+            .map_err(|error| {
+                query_error(
+                    &error,
+                    format_args!(
+                        r#"Can't get "{}" tile at {}/{}/{}"#,
+                        self.id, xyz.z, xyz.x, xyz.y
+                    ),
+                )
+            })?;
+
+        Ok(tile.map(Tile::from).unwrap_or_default())
+    }
+}
+
+#[allow(mismatched_lifetime_syntaxes)]
+#[async_trait]
+impl Source for RasterSource {
+    async fn get_id(&self) -> &str {
+        self.id.as_str()
+    }
+
+    async fn get_tilejson(&self) -> Result<TileJSON, io::Error> {
+        let mut tilejson = tilejson! {
+            tilejson: "2.2.0".to_string(),
+            tiles: vec![],  // tile source is required, but not yet known
+            name: self.name.clone().unwrap_or_else(|| self.id.to_string()),
+        };
+
+        tilejson.description = self.description.clone();
+        tilejson.attribution = self.attribution.clone();
+
+        if let Some(minzoom) = &self.minzoom {
+            tilejson.minzoom = Some(*minzoom);
+        };
+
+        if let Some(maxzoom) = &self.maxzoom {
+            tilejson.maxzoom = Some(*maxzoom);
+        };
+
+        if let Some(bounds) = &self.bounds {
+            tilejson.bounds = Some(*bounds);
+        };
+
+        // TODO: consider removing - this is not needed per TileJSON spec
+        tilejson.set_missing_defaults();
+        Ok(tilejson)
+    }
+
+    fn is_valid_zoom(&self, zoom: i32) -> bool {
+        is_valid_zoom(zoom, self.minzoom, self.maxzoom)
+    }
+
+    fn get_format(&self) -> TileFormat {
+        TileFormat::Png
+    }
+
+    fn get_empty_tile(&self) -> EmptyTile {
+        self.empty_tile.unwrap_or_default()
+    }
+
+    fn get_headers(&self) -> Option<&HashMap<String, String>> {
+        self.headers.as_ref()
+    }
+
+    async fn get_tile(
+        &self,
+        conn: Option<&mut Connection>,
+        xyz: &Xyz,
+        _query: &Option<UrlQuery>,
+    ) -> Result<Tile, io::Error> {
+        self.get_sized_tile(conn, xyz, RASTER_TILE_SIZE).await
+    }
+}
+
+/// The `@2x` tiles of a raster source for high-DPI screens, rendered at twice the size
+#[derive(Clone, Debug)]
+pub struct HighDpiRasterSource {
+    /// Id of the raster source with an `@2x` suffix, so its tiles are cached apart
+    id: String,
+    source: RasterSource,
+}
+
+impl HighDpiRasterSource {
+    pub fn new(source: RasterSource) -> Self {
+        Self {
+            id: format!("{}@2x", source.id),
+            source,
+        }
+    }
+}
+
+#[allow(mismatched_lifetime_syntaxes)]
+#[async_trait]
+impl Source for HighDpiRasterSource {
+    async fn get_id(&self) -> &str {
+        self.id.as_str()
+    }
+
+    async fn get_tilejson(&self) -> Result<TileJSON, io::Error> {
+        self.source.get_tilejson().await
+    }
+
+    fn is_valid_zoom(&self, zoom: i32) -> bool {
+        self.source.is_valid_zoom(zoom)
+    }
+
+    fn get_format(&self) -> TileFormat {
+        TileFormat::Png
+    }
+
+    fn get_empty_tile(&self) -> EmptyTile {
+        self.source.get_empty_tile()
+    }
+
+    fn get_headers(&self) -> Option<&HashMap<String, String>> {
+        self.source.get_headers()
+    }
+
+    /// Tiles without data are blank images of the same size when the source serves blank tiles
+    async fn get_tile(
+        &self,
+        conn: Option<&mut Connection>,
+        xyz: &Xyz,
+        _query: &Option<UrlQuery>,
+    ) -> Result<Tile, io::Error> {
+        static BLANK_PNG: OnceLock<Tile> = OnceLock::new();
+        let tile_size = 2 * RASTER_TILE_SIZE;
+        let tile = self.source.get_sized_tile(conn, xyz, tile_size).await?;
+        if tile.is_empty() && self.get_empty_tile() == EmptyTile::Blank {
+            return Ok(BLANK_PNG.get_or_init(|| blank_png(tile_size)).clone());
+        }
+        Ok(tile)
+    }
+}
+
+/// Encode a fully transparent square PNG image
+pub fn blank_png(size: u32) -> Bytes {
+    // 8-bit RGBA pixels, each row preceded by its filter type, here none
+    let row_len = 1 + 4 * size as usize;
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    for _ in 0..size {
+        encoder
+            .write_all(&vec![0; row_len])
+            .expect("writing to a Vec can't fail");
+    }
+    let pixels = encoder.finish().expect("writing to a Vec can't fail");
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&size.to_be_bytes());
+    header.extend_from_slice(&size.to_be_bytes());
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    write_png_chunk(&mut png, b"IHDR", &header);
+    write_png_chunk(&mut png, b"IDAT", &pixels);
+    write_png_chunk(&mut png, b"IEND", &[]);
+    Bytes::from(png)
+}
+
+fn write_png_chunk(png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(chunk_type);
+    png.extend_from_slice(data);
+    let mut crc = Crc::new();
+    crc.update(chunk_type);
+    crc.update(data);
+    png.extend_from_slice(&crc.sum().to_be_bytes());
+}
+
+/// Discover the raster columns registered in the `raster_columns` view of PostGIS raster.
+/// Returns no sources if the extension is not installed.
+pub async fn get_raster_sources(conn: &mut Connection<'_>) -> Result<RasterSources, io::Error> {
+    let mut sources = HashMap::new();
+
+    let has_raster_columns: bool = conn
+        .query_one(
+            "SELECT to_regclass('raster_columns') IS NOT NULL AS exists",
+            &[],
+        )
+        .await
+        .map(|row| row.get("exists"))
+        .map_err(|e| prettify_error!(e, "Can't check for PostGIS raster"))?;
+    if !has_raster_columns {
+        return Ok(sources);
+    }
+
+    let rows = conn
+        .query(include_str!("scripts/get_raster_sources.sql"), &[])
+        .await
+        .map_err(|e| prettify_error!(e, "Can't get raster sources"))?;
+
+    for row in &rows {
+        let schema: String = row.get("r_table_schema");
+        let table: String = row.get("r_table_name");
+        let raster_column: String = row.get("r_raster_column");
+        // Tables with several raster columns get the column in the ids of the later ones
+        let mut id = format!("{schema}.{table}");
+        if sources.contains_key(&id) {
+            id = format!("{schema}.{table}.{raster_column}");
+        }
+
+        let srid: i32 = row.get("srid");
+        if srid == 0 {
+            warn!(
+                r#""{id}" has SRID 0, skipping. To use this raster source, you must add raster constraints with AddRasterConstraints, or specify the SRID using the config file"#
+            );
+            continue;
+        }
+
+        // The extent is not a polygon for rasters of a single pixel row or column
+        let bounds = row
+            .try_get::<_, Option<ewkb::Polygon>>("bounds")
+            .ok()
+            .flatten()
+            .and_then(|polygon| polygon_to_bbox(&polygon));
+
+        let source = RasterSource {
+            id: id.clone(),
+            schema,
+            table,
+            raster_column,
+            srid: srid as u32,
+            minzoom: None,
+            maxzoom: None,
+            bounds,
+            name: None,
+            description: None,
+            attribution: None,
+            colormap: None,
+            api_keys: None,
+            empty_tile: None,
+            headers: None,
+            unrecognized: HashMap::new(),
+        };
+
+        sources.insert(id, Box::new(source));
+    }
+
+    Ok(sources)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_tile_query() {
+        let mut source = RasterSource {
+            id: "public.rasters.rast".to_owned(),
+            schema: "public".to_owned(),
+            table: "rasters".to_owned(),
+            raster_column: "rast".to_owned(),
+            srid: 4326,
+            minzoom: None,
+            maxzoom: None,
+            bounds: None,
+            name: None,
+            description: None,
+            attribution: None,
+            colormap: None,
+            api_keys: None,
+            empty_tile: None,
+            headers: None,
+            unrecognized: HashMap::new(),
+        };
+
+        let query = source.get_tile_query(&Xyz { z: 0, x: 0, y: 0 }, RASTER_TILE_SIZE);
+        assert!(query.contains(r#"FROM "public"."rasters""#));
+        assert!(query.contains("ST_Transform(bounds.geom, 4326)"));
+        assert!(query.contains("SELECT ST_AsPNG(rast) AS tile"));
+        assert!(query.contains("bounds.geom, 256, 256,"));
+
+        let query = source.get_tile_query(&Xyz { z: 0, x: 0, y: 0 }, 512);
+        assert!(query.contains("bounds.geom, 512, 512,"));
+
+        source.colormap = Some("it's".to_owned());
+        let query = source.get_tile_query(&Xyz { z: 0, x: 0, y: 0 }, RASTER_TILE_SIZE);
+        assert!(query.contains("ST_AsPNG(ST_ColorMap(rast, 1, 'it''s'))"));
+    }
+}
@@ -0,0 +1,6 @@
+SELECT EXISTS (
+  SELECT 1
+  FROM pg_catalog.pg_proc AS proc
+    JOIN pg_catalog.pg_namespace AS ns ON ns.oid = proc.pronamespace
+  WHERE ns.nspname = $1 AND proc.proname = $2
+) AS exists;
@@ -0,0 +1,8 @@
+SELECT
+  tp.typname AS type_name
+FROM pg_attribute attr
+  JOIN pg_catalog.pg_class AS class ON class.oid = attr.attrelid
+  JOIN pg_catalog.pg_namespace AS ns ON ns.oid = class.relnamespace
+  JOIN pg_catalog.pg_type AS tp ON tp.oid = attr.atttypid
+WHERE NOT attr.attisdropped AND attr.attnum > 0
+  AND ns.nspname = $1 AND class.relname = $2 AND attr.attname = $3;
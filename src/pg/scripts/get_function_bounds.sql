@@ -0,0 +1 @@
+SELECT ST_Transform(ST_Envelope({schema}.{bounds_function}()), 4326) AS bounds;
@@ -1,6 +1,17 @@
 SELECT
   routines.specific_schema,
-  routines.routine_name
+  routines.routine_name,
+  -- A companion function without arguments, e.g. my_tiles_bounds(), returns the geometry covered by the tiles
+  EXISTS (
+    SELECT 1
+    FROM pg_proc
+      JOIN pg_namespace ON pg_namespace.oid = pg_proc.pronamespace
+    WHERE
+      pg_namespace.nspname = routines.specific_schema
+      AND pg_proc.proname = routines.routine_name || '_bounds'
+      AND pg_proc.pronargs = 0
+      AND pg_proc.prorettype = 'geometry'::regtype
+  ) AS has_bounds_function
 FROM information_schema.routines
   LEFT JOIN information_schema.parameters ON routines.specific_name=parameters.specific_name
 WHERE
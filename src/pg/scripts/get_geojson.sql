@@ -0,0 +1,4 @@
+SELECT
+  json_build_object('type', 'FeatureCollection', 'features', json_agg(ST_AsGeoJSON (features.*, 'geom')::json))::text AS geojson
+FROM ({geom_query}) AS features
+HAVING count(*) > 0
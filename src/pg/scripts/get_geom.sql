@@ -1,4 +1,6 @@
 SELECT
-  ST_AsMVTGeom (ST_Transform (ST_CurveToLine("{geometry_column}"), 3857), {mercator_bounds}, {extent}, {buffer}, {clip_geom}) AS geom {properties} FROM {schema}."{table}", bounds
+  {geometry} AS geom {properties} FROM {schema}."{table}" {join}, bounds
   WHERE
-    "{geometry_column}" && bounds.srid_{srid}
+    {geometry_column} && bounds.srid_{srid}
+    {filter}
+  {limit}
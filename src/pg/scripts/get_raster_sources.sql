@@ -0,0 +1,7 @@
+SELECT
+  r_table_schema,
+  r_table_name,
+  r_raster_column,
+  srid,
+  CASE WHEN srid != 0 THEN ST_Envelope(ST_Transform(extent, 4326)) END AS bounds
+FROM raster_columns
@@ -0,0 +1,24 @@
+WITH bounds AS (SELECT {mercator_bounds} AS geom),
+  source AS (
+    SELECT {raster_column} AS rast FROM {schema}.{table}, bounds
+    WHERE {raster_column} && ST_Transform(bounds.geom, {srid})
+  ),
+  template AS (
+    SELECT ST_AsRaster(
+      bounds.geom, {tile_size}, {tile_size},
+      array_agg(band.pixeltype ORDER BY band.bandnum),
+      array_agg(coalesce(band.nodatavalue, 0) ORDER BY band.bandnum),
+      array_agg(coalesce(band.nodatavalue, 0) ORDER BY band.bandnum)
+    ) AS rast
+    FROM bounds, (SELECT rast FROM source LIMIT 1) AS first, ST_BandMetaData(first.rast, ARRAY[]::int[]) AS band
+    GROUP BY bounds.geom
+  ),
+  tile AS (
+    SELECT ST_Union(parts.rast, 'LAST' ORDER BY parts.ord) AS rast FROM (
+      SELECT 0 AS ord, rast FROM template
+      UNION ALL
+      SELECT 1, ST_Clip(ST_Transform(source.rast, template.rast), bounds.geom)
+      FROM source, template, bounds
+    ) AS parts
+  )
+SELECT ST_AsPNG({rast}) AS tile FROM tile
@@ -1 +1 @@
-ST_Transform({mercator_bounds}, {srid}) AS srid_{srid}
\ No newline at end of file
+ST_Transform({tile_bounds}, {srid}) AS srid_{srid}
\ No newline at end of file
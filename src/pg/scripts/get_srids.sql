@@ -0,0 +1,6 @@
+SELECT DISTINCT srid
+FROM (SELECT ST_SRID({geometry_column}) AS srid
+      FROM {schema}.{table}
+      WHERE {geometry_column} IS NOT NULL
+      LIMIT {sample_size}) AS sample
+LIMIT 2;
@@ -0,0 +1,6 @@
+SELECT EXISTS (
+  SELECT 1
+  FROM pg_catalog.pg_class AS class
+    JOIN pg_catalog.pg_namespace AS ns ON ns.oid = class.relnamespace
+  WHERE ns.nspname = $1 AND class.relname = $2
+) AS exists;
@@ -1,18 +1,24 @@
-use crate::pg::db::Connection;
+use crate::pg::db::{query_tile, require_connection, Connection};
 use crate::pg::utils::{
     get_bounds_cte, get_source_bounds, get_srid_bounds, json_to_hashmap, polygon_to_bbox,
-    prettify_error, tile_bbox,
+    prettify_error, query_error, tile_matrix_bbox, tile_matrix_tile_width,
+};
+use crate::source::{
+    intersects_bounds, is_valid_zoom, validate_zoom_range, EmptyTile, Source, Tile, TileFormat,
+    TileMatrixSet, TileScheme, UrlQuery, Xyz,
 };
-use crate::source::{Source, Tile, UrlQuery, Xyz};
 use async_trait::async_trait;
-use log::warn;
+use itertools::Itertools;
+use log::{info, warn};
+use postgres_protocol::escape::escape_identifier;
 use serde::{Deserialize, Serialize};
 use serde_yaml::Value;
-use std::collections::{HashMap, HashSet};
-use std::io;
-use tilejson::{tilejson, Bounds, TileJSON};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+use std::{io, iter};
+use tilejson::{tilejson, Bounds, TileJSON, VectorLayer};
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
 pub struct TableSource {
     /// Table source id
     pub id: String,
@@ -29,7 +35,9 @@ pub struct TableSource {
     /// Geometry column name
     pub geometry_column: String,
 
-    /// Feature id column name
+    /// Feature id column name. MVT feature ids are unsigned 64-bit integers, so it must be
+    /// a `smallint`, `integer` or `bigint` column, others are ignored with a warning.
+    /// Rows with negative or NULL ids are encoded without an id.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub id_column: Option<String>,
 
@@ -48,18 +56,44 @@ pub struct TableSource {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub bounds: Option<Bounds>,
 
+    /// A name describing the tileset, defaults to the source id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    /// A text description of the tileset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    /// An attribution to be displayed when the map is shown to a user
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attribution: Option<String>,
+
+    /// Tile row numbering used in tile URLs, `xyz` (default) or `tms`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tile_scheme: Option<TileScheme>,
+
+    /// Projection and tile grid of the tiles, `WebMercatorQuad` (default)
+    /// or `WorldCRS84Quad` for EPSG:4326 tiles
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tile_matrix_set: Option<TileMatrixSet>,
+
     /// Tile extent in tile coordinate space
     #[serde(skip_serializing_if = "Option::is_none")]
     pub extent: Option<u32>,
 
-    /// Buffer distance in tile coordinate space to optionally clip geometries
+    /// Buffer distance in tile coordinate space to optionally clip geometries,
+    /// either the same at every zoom level or set per zoom level
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub buffer: Option<u32>,
+    pub buffer: Option<Buffer>,
 
     /// Boolean to control if geometries should be clipped or encoded as is
     #[serde(skip_serializing_if = "Option::is_none")]
     pub clip_geom: Option<bool>,
 
+    /// Repair invalid geometries with `ST_MakeValid` before encoding them, at some cost
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub make_valid: Option<bool>,
+
     /// Geometry type
     #[serde(skip_serializing_if = "Option::is_none")]
     pub geometry_type: Option<String>,
@@ -67,46 +101,523 @@ pub struct TableSource {
     /// List of columns, that should be encoded as tile properties
     pub properties: HashMap<String, String>,
 
+    /// Tile property names of some of the `properties` columns, e.g. `col_a: name`.
+    /// The other columns keep their names.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub property_aliases: Option<HashMap<String, String>>,
+
+    /// Join another table to pull extra feature properties from it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub join: Option<TableJoin>,
+
+    /// SQL boolean expression limiting the features served by the source, e.g. `status = 'published'`.
+    /// It is inserted into the tile query as is, so it must come from trusted configuration only.
+    /// Columns of the table may be qualified by the table name to avoid clashes with a `join`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sql_filter: Option<String>,
+
+    /// Milliseconds after which PostgreSQL cancels a tile query of this source, answered
+    /// with 504 Gateway Timeout. Defaults to the `statement_timeout` of the database.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub statement_timeout: Option<u64>,
+
+    /// SQL query returning a single text value that changes whenever the table data changes.
+    /// When set, tiles get an ETag derived from this value, and conditional requests
+    /// are answered without running the tile query.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version_query: Option<String>,
+
+    /// Name of the tile layer, so it can stay the same when the source id changes.
+    /// Defaults to the source id.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub layer_name: Option<String>,
+
+    /// More geometry columns of the table, each encoded as its own layer of the tile
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra_geometry_columns: Option<Vec<GeometryColumn>>,
+
+    /// Simplification of the geometries at some zoom levels, the first matching one applies
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub simplification: Option<Vec<Simplification>>,
+
+    /// Most features of a layer in a tile, ordered by the `id_column` if there is one.
+    /// Tiles that reach it are logged and served with the `X-Tile-Truncated: true` header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_feature_count: Option<u32>,
+
+    /// Answer requests for tiles outside of the `bounds` with an empty tile without querying
+    /// the table. Off by default, as the declared bounds of some sources are wrong.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_out_of_bounds: Option<bool>,
+
+    /// Leave this layer out of composite source tiles when its query fails, instead of failing
+    /// the whole tile. Defaults to the `partial_composite_tiles` of the config.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partial_composite_tiles: Option<bool>,
+
+    /// API keys accepted by this source. When set, requests must present one of them
+    /// in the `X-API-Key` header or the `key` query parameter.
+    /// Left out of the sources list, so the keys are not exposed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_keys: Option<Vec<String>>,
+
+    /// How requests for tiles without data are answered: `204` (No Content), `404` (Not Found),
+    /// or `blank`, a tile without data [default: 204]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub empty_tile: Option<EmptyTile>,
+
+    /// Extra HTTP headers of the tile responses, e.g. a license or a CDN cache hint.
+    /// Headers set by martin itself, like `Content-Type`, can't be overridden.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headers: Option<HashMap<String, String>>,
+
     #[serde(flatten, skip_serializing)]
     pub unrecognized: HashMap<String, Value>,
 }
 
+/// A table joined to a table source to provide extra tile properties
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct TableJoin {
+    /// Joined table schema, defaults to the table source schema
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schema: Option<String>,
+
+    /// Joined table name
+    pub table: String,
+
+    /// Column of the table source used to join
+    pub key: String,
+
+    /// Column of the joined table matching `key`, defaults to the same name as `key`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub join_key: Option<String>,
+
+    /// Columns of the joined table, that should be encoded as tile properties
+    pub columns: Vec<String>,
+}
+
+/// A geometry column encoded as a separate layer of a table source tile
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct GeometryColumn {
+    /// Geometry column name
+    pub column: String,
+
+    /// Geometry column SRID, defaults to the table source SRID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub srid: Option<u32>,
+
+    /// Layer name, defaults to `{source id}.{column}`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub layer: Option<String>,
+}
+
+/// Simplification of the geometries of a table source in a range of zoom levels
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Simplification {
+    /// Lowest zoom level simplified, defaults to 0
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minzoom: Option<u8>,
+
+    /// Highest zoom level simplified, defaults to all higher zoom levels
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maxzoom: Option<u8>,
+
+    /// Tolerance in tile coordinate space, where a tile is `extent` units wide,
+    /// so the simplification is the same on screen at every zoom level
+    pub tolerance: f64,
+
+    /// Use `ST_SimplifyPreserveTopology`, which keeps polygons valid and free of slivers,
+    /// instead of the faster `ST_Simplify` [default: false]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preserve_topology: Option<bool>,
+}
+
+impl Simplification {
+    fn contains(&self, zoom: i32) -> bool {
+        is_valid_zoom(zoom, self.minzoom, self.maxzoom)
+    }
+}
+
+/// Buffer distance of a table source, e.g. `buffer: 64` or `buffer: {0: 256, 10: 64}`
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Buffer {
+    /// Buffer of every zoom level
+    Value(u32),
+    /// Buffers by zoom level, each applying up to the next listed zoom level.
+    /// Zoom levels below the first one get the default buffer.
+    Zooms(BTreeMap<u8, u32>),
+}
+
+impl Buffer {
+    /// Buffer of a zoom level
+    pub fn get(&self, zoom: i32) -> u32 {
+        match self {
+            Buffer::Value(buffer) => *buffer,
+            Buffer::Zooms(buffers) => buffers
+                .iter()
+                .rev()
+                .find(|(z, _)| i32::from(**z) <= zoom)
+                .map_or(DEFAULT_BUFFER, |(_, buffer)| *buffer),
+        }
+    }
+
+    /// Largest buffer of all zoom levels
+    fn max(&self) -> u32 {
+        match self {
+            Buffer::Value(buffer) => *buffer,
+            Buffer::Zooms(buffers) => buffers.values().copied().max().unwrap_or(DEFAULT_BUFFER),
+        }
+    }
+}
+
 pub type TableSources = HashMap<String, Box<TableSource>>;
 
+/// Parse the `extent` query parameter of a tile request, one of [`EXTENT_OVERRIDES`]
+pub fn parse_extent_override(extent: &str) -> io::Result<u32> {
+    extent
+        .parse()
+        .ok()
+        .filter(|extent| EXTENT_OVERRIDES.contains(extent))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid extent '{extent}', expected one of: {}",
+                    EXTENT_OVERRIDES.iter().join(", ")
+                ),
+            )
+        })
+}
+
 impl TableSource {
+    /// Check the source configuration for mistakes that would produce broken tiles
+    pub fn validate(&self) -> io::Result<()> {
+        validate_zoom_range(&self.id, self.minzoom, self.maxzoom)?;
+
+        // ST_AsMVTGeom produces degenerate geometries when the buffer is wider than the tile
+        let extent = self.extent.unwrap_or(DEFAULT_EXTENT);
+        let buffer = self.buffer.as_ref().map_or(DEFAULT_BUFFER, Buffer::max);
+        if buffer > extent {
+            return Err(io::Error::other(format!(
+                "Table source {} has buffer {buffer} larger than its extent {extent}",
+                self.id
+            )));
+        }
+
+        if self
+            .extra_geometry_columns
+            .iter()
+            .flatten()
+            .any(|column| column.column.is_empty())
+        {
+            return Err(io::Error::other(format!(
+                "Table source {} has an empty extra geometry column name",
+                self.id
+            )));
+        }
+
+        if let Some(layer_name) = &self.layer_name {
+            // The name is a string literal of the tile query
+            if layer_name.trim().is_empty() || layer_name.contains('\'') {
+                return Err(io::Error::other(format!(
+                    "Table source {} layer_name {layer_name:?} must be a non-empty name without quotes",
+                    self.id
+                )));
+            }
+        }
+
+        if self.max_feature_count == Some(0) {
+            return Err(io::Error::other(format!(
+                "Table source {} max_feature_count must be positive",
+                self.id
+            )));
+        }
+
+        for simplification in self.simplification.iter().flatten() {
+            if !simplification.tolerance.is_finite() || simplification.tolerance <= 0.0 {
+                return Err(io::Error::other(format!(
+                    "Table source {} simplification tolerance must be a positive number",
+                    self.id
+                )));
+            }
+            if let (Some(minzoom), Some(maxzoom)) = (simplification.minzoom, simplification.maxzoom)
+            {
+                if minzoom > maxzoom {
+                    return Err(io::Error::other(format!(
+                        "Table source {} simplification minzoom {minzoom} is above its maxzoom {maxzoom}",
+                        self.id
+                    )));
+                }
+            }
+        }
+
+        if let Some(sql_filter) = &self.sql_filter {
+            // A statement separator could smuggle a second statement into the tile query
+            if sql_filter.trim().is_empty() || sql_filter.contains(';') {
+                return Err(io::Error::other(format!(
+                    "Table source {} sql_filter must be a non-empty SQL expression without ';'",
+                    self.id
+                )));
+            }
+        }
+
+        if let Some(aliases) = &self.property_aliases {
+            for (column, alias) in aliases.iter().sorted() {
+                if alias.is_empty() || !self.properties.contains_key(column) {
+                    return Err(io::Error::other(format!(
+                        "Table source {} property alias {alias:?} must rename one of its properties, {column} is not",
+                        self.id
+                    )));
+                }
+                if Some(column) == self.id_column.as_ref() {
+                    return Err(io::Error::other(format!(
+                        "Table source {} can't rename its id_column {column}",
+                        self.id
+                    )));
+                }
+            }
+            // The id column is a property of its own name, whether or not it is listed
+            let mut names = HashSet::from(["geom"]);
+            names.extend(self.id_column.as_deref());
+            for column in self
+                .properties
+                .keys()
+                .filter(|column| Some(*column) != self.id_column.as_ref())
+                .sorted()
+            {
+                let name = self.get_property_name(column);
+                if !names.insert(name) {
+                    return Err(io::Error::other(format!(
+                        "Table source {} has more than one tile property named {name}",
+                        self.id
+                    )));
+                }
+            }
+        }
+
+        if let Some(join) = &self.join {
+            let identifiers = [Some(&join.table), Some(&join.key), join.join_key.as_ref()];
+            if identifiers
+                .into_iter()
+                .flatten()
+                .chain(&join.columns)
+                .any(String::is_empty)
+            {
+                return Err(io::Error::other(format!(
+                    "Table source {} has an empty identifier in its join",
+                    self.id
+                )));
+            }
+            for column in &join.columns {
+                let is_property = self
+                    .properties
+                    .keys()
+                    .any(|property| self.get_property_name(property) == column);
+                if is_property || column == "geom" {
+                    return Err(io::Error::other(format!(
+                        "Joined column {column} is ambiguous in table source {}, it is already a tile property",
+                        self.id
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn get_geom_query(&self, xyz: &Xyz) -> String {
-        let mercator_bounds = tile_bbox(xyz);
+        self.get_layer_geom_query(xyz, &self.geometry_column, self.srid, TileFormat::Mvt)
+    }
 
-        let properties = if self.properties.is_empty() {
+    /// Query of the features of a tile, with their geometries encoded for ST_AsMVT,
+    /// or unclipped in WGS84 for GeoJSON
+    fn get_layer_geom_query(
+        &self,
+        xyz: &Xyz,
+        geometry_column: &str,
+        srid: u32,
+        format: TileFormat,
+    ) -> String {
+        let tile_matrix_set = self.tile_matrix_set.unwrap_or_default();
+        let tile_bounds = tile_matrix_bbox(tile_matrix_set, xyz);
+
+        // Columns are qualified with the table name, so they don't clash with the joined table
+        let table = escape_identifier(&self.table);
+        let geometry_column = format!("{table}.{}", escape_identifier(geometry_column));
+
+        let mut properties: Vec<String> = self
+            .properties
+            .keys()
+            .filter(|column| Some(*column) != self.id_column.as_ref())
+            .map(|column| match self.get_property_name(column) {
+                name if name == column => format!("{table}.{}", escape_identifier(column)),
+                name => format!(
+                    "{table}.{} AS {}",
+                    escape_identifier(column),
+                    escape_identifier(name)
+                ),
+            })
+            .collect();
+        // ST_AsMVT takes the feature id from the column, whether or not it is a property
+        if let Some(id_column) = &self.id_column {
+            let id_column = escape_identifier(id_column);
+            properties.push(format!("{table}.{id_column}::int8 AS {id_column}"));
+        }
+
+        let join = if let Some(join) = &self.join {
+            let join_schema = join.schema.as_ref().unwrap_or(&self.schema);
+            let join_key = join.join_key.as_ref().unwrap_or(&join.key);
+            properties.extend(
+                join.columns
+                    .iter()
+                    .map(|column| format!("{JOIN_ALIAS}.{}", escape_identifier(column))),
+            );
+            format!(
+                "LEFT JOIN {}.{} AS {JOIN_ALIAS} ON {JOIN_ALIAS}.{} = {table}.{}",
+                escape_identifier(join_schema),
+                escape_identifier(&join.table),
+                escape_identifier(join_key),
+                escape_identifier(&join.key),
+            )
+        } else {
+            String::new()
+        };
+
+        let properties = if properties.is_empty() {
             String::new()
         } else {
-            let properties = self
-                .properties
-                .keys()
-                .map(|column| format!(r#""{column}""#))
-                .collect::<Vec<String>>()
-                .join(",");
+            format!(", {}", properties.join(","))
+        };
 
-            format!(", {properties}")
+        let mut geometry = format!(
+            "ST_Transform (ST_CurveToLine({geometry_column}), {})",
+            tile_matrix_set.srid()
+        );
+        if let Some(simplification) = self.get_simplification(xyz.z) {
+            // The tolerance is in tile units, convert it to the units of the tile SRID
+            let extent = self.extent.unwrap_or(DEFAULT_EXTENT);
+            let tolerance = simplification.tolerance
+                * tile_matrix_tile_width(tile_matrix_set, xyz.z)
+                / f64::from(extent);
+            let function = if simplification.preserve_topology.unwrap_or_default() {
+                "ST_SimplifyPreserveTopology"
+            } else {
+                "ST_Simplify"
+            };
+            geometry = format!("{function}({geometry}, {tolerance})");
+        }
+        // Repaired last, as simplifying may make a valid geometry invalid
+        if self.make_valid.unwrap_or_default() {
+            geometry = format!("ST_MakeValid({geometry})");
+        }
+        let geometry = if format == TileFormat::Json {
+            format!("ST_Transform ({geometry}, 4326)")
+        } else {
+            format!(
+                "ST_AsMVTGeom ({geometry}, {tile_bounds}, {}, {}, {})",
+                self.extent.unwrap_or(DEFAULT_EXTENT),
+                self.get_buffer(xyz.z),
+                self.clip_geom.unwrap_or(DEFAULT_CLIP_GEOM)
+            )
         };
 
+        // Parenthesized, so an `OR` in the filter can't escape the tile bounds condition
+        let filter = self
+            .sql_filter
+            .as_ref()
+            .map_or(String::new(), |filter| format!("AND ({filter})"));
+
+        // Ordered by the id, so the same features are kept in every tile query
+        let limit = self
+            .max_feature_count
+            .map_or(String::new(), |max| match &self.id_column {
+                Some(id_column) => format!(
+                    "ORDER BY {table}.{} LIMIT {max}",
+                    escape_identifier(id_column)
+                ),
+                None => format!("LIMIT {max}"),
+            });
+
         format!(
             include_str!("scripts/get_geom.sql"),
             schema = self.schema,
             table = self.table,
-            srid = self.srid,
-            geometry_column = self.geometry_column,
-            mercator_bounds = mercator_bounds,
-            extent = self.extent.unwrap_or(DEFAULT_EXTENT),
-            buffer = self.buffer.unwrap_or(DEFAULT_BUFFER),
-            clip_geom = self.clip_geom.unwrap_or(DEFAULT_CLIP_GEOM),
+            srid = srid,
+            geometry_column = geometry_column,
+            geometry = geometry,
+            join = join,
+            filter = filter,
+            limit = limit,
             properties = properties
         )
     }
 
+    /// Name of the tile layer of the main geometry column
+    pub fn get_layer_name(&self) -> &str {
+        self.layer_name.as_deref().unwrap_or(&self.id)
+    }
+
+    /// Name of the tile property of a column, its alias if it has one
+    pub fn get_property_name<'a>(&'a self, column: &'a str) -> &'a str {
+        self.property_aliases
+            .as_ref()
+            .and_then(|aliases| aliases.get(column))
+            .map_or(column, String::as_str)
+    }
+
+    /// Buffer distance of the geometries at a zoom level
+    pub fn get_buffer(&self, zoom: i32) -> u32 {
+        self.buffer
+            .as_ref()
+            .map_or(DEFAULT_BUFFER, |buffer| buffer.get(zoom))
+    }
+
+    /// Simplification of the geometries at a zoom level, if any
+    pub fn get_simplification(&self, zoom: i32) -> Option<&Simplification> {
+        self.simplification
+            .iter()
+            .flatten()
+            .find(|simplification| simplification.contains(zoom))
+    }
+
     pub fn get_tile_query(&self, xyz: &Xyz) -> String {
-        let geom_query = self.get_geom_query(xyz);
+        self.get_layers_tile_query(xyz, None)
+    }
+
+    /// Tile query of the given layers only, or of all of them if `None`.
+    /// Layers of other sources are ignored, and a tile without any layer is empty.
+    pub fn get_layers_tile_query(&self, xyz: &Xyz, layers: Option<&[String]>) -> String {
+        let extra_columns = self.extra_geometry_columns.iter().flatten();
+        let mut layer_queries: Vec<String> = self
+            .get_layer_names()
+            .into_iter()
+            .zip(
+                iter::once((self.geometry_column.as_str(), self.srid)).chain(
+                    extra_columns
+                        .map(|column| (column.column.as_str(), column.srid.unwrap_or(self.srid))),
+                ),
+            )
+            .filter(|(layer, _)| layers.is_none_or(|layers| layers.contains(layer)))
+            .map(|(layer, (column, srid))| {
+                let geom_query = self.get_layer_geom_query(xyz, column, srid, TileFormat::Mvt);
+                self.get_layer_tile_query(&layer, &geom_query)
+            })
+            .collect();
+
+        match layer_queries.len() {
+            0 => "SELECT ''::bytea AS st_asmvt".to_owned(),
+            1 => layer_queries.remove(0),
+            _ => {
+                let layers = layer_queries
+                    .iter()
+                    .map(|layer_query| format!("({layer_query})"))
+                    .join(" || ");
+                format!("SELECT {layers} AS st_asmvt")
+            }
+        }
+    }
 
+    fn get_layer_tile_query(&self, layer: &str, geom_query: &str) -> String {
         let id_column = self
             .id_column
             .clone()
@@ -114,22 +625,196 @@ impl TableSource {
 
         format!(
             include_str!("scripts/get_tile.sql"),
-            id = self.id,
+            id = layer,
             id_column = id_column,
             geom_query = geom_query,
             extent = self.extent.unwrap_or(DEFAULT_EXTENT),
         )
     }
 
+    /// TileJSON `vector_layers` of the tile layers, with the properties and their column types
+    /// as fields. Columns of a `join` have no known type, so their description is empty.
+    pub fn get_vector_layers(&self) -> Vec<VectorLayer> {
+        let mut fields: HashMap<String, String> = self
+            .properties
+            .iter()
+            .map(|(column, type_name)| {
+                (self.get_property_name(column).to_owned(), type_name.clone())
+            })
+            .collect();
+        for column in self.join.iter().flat_map(|join| &join.columns) {
+            fields.insert(column.clone(), String::new());
+        }
+
+        self.get_layer_names()
+            .into_iter()
+            .map(|layer| VectorLayer::new(layer, fields.clone()))
+            .collect()
+    }
+
+    /// Names of the tile layers, the one of the main geometry column first
+    pub fn get_layer_names(&self) -> Vec<String> {
+        let extra_layers = self.extra_geometry_columns.iter().flatten().map(|column| {
+            column
+                .layer
+                .clone()
+                .unwrap_or_else(|| format!("{}.{}", self.id, column.column))
+        });
+        iter::once(self.get_layer_name().to_owned())
+            .chain(extra_layers)
+            .collect()
+    }
+
+    /// SRIDs of all geometry columns of the source
+    pub fn get_srids(&self) -> impl Iterator<Item = u32> + '_ {
+        iter::once(self.srid)
+            .chain(
+                self.extra_geometry_columns
+                    .iter()
+                    .flatten()
+                    .map(|column| column.srid.unwrap_or(self.srid)),
+            )
+            .unique()
+    }
+
+    /// Build the tile query for tile coordinates requested in the source `tile_scheme`
     pub fn build_tile_query(&self, xyz: &Xyz) -> String {
-        let srid_bounds = get_srid_bounds(self.srid, xyz);
-        let bounds_cte = get_bounds_cte(&srid_bounds);
-        let tile_query = self.get_tile_query(xyz);
+        self.build_layers_tile_query(xyz, None)
+    }
+
+    /// Build the tile query of the given layers only, or of all of them if `None`
+    pub fn build_layers_tile_query(&self, xyz: &Xyz, layers: Option<&[String]>) -> String {
+        let xyz = self.tile_scheme.unwrap_or_default().to_xyz(xyz);
+        let tile_query = self.get_layers_tile_query(&xyz, layers);
+
+        format!("{} {tile_query}", self.get_bounds_cte(&xyz))
+    }
+
+    /// Build the query of a GeoJSON `FeatureCollection` with the features of the tile,
+    /// requested in the source `tile_scheme`. Features of extra geometry columns are left out.
+    pub fn build_geojson_query(&self, xyz: &Xyz) -> String {
+        let xyz = self.tile_scheme.unwrap_or_default().to_xyz(xyz);
+        let geom_query =
+            self.get_layer_geom_query(&xyz, &self.geometry_column, self.srid, TileFormat::Json);
+        let geojson_query = format!(
+            include_str!("scripts/get_geojson.sql"),
+            geom_query = geom_query
+        );
 
-        format!("{bounds_cte} {tile_query}")
+        format!("{} {geojson_query}", self.get_bounds_cte(&xyz))
+    }
+
+    fn get_bounds_cte(&self, xyz: &Xyz) -> String {
+        let srid_bounds = self
+            .get_srids()
+            .map(|srid| get_srid_bounds(srid, xyz, self.tile_matrix_set.unwrap_or_default()))
+            .join(", ");
+        get_bounds_cte(&srid_bounds)
+    }
+
+    /// Get a tile with the given layers only, or with all of them if `None`
+    pub async fn get_layers_tile(
+        &self,
+        conn: &mut Connection<'_>,
+        xyz: &Xyz,
+        layers: Option<&[String]>,
+    ) -> io::Result<Tile> {
+        let tile_query = self.build_layers_tile_query(xyz, layers);
+
+        let tile = query_tile(conn, &tile_query, &[], self.statement_timeout)
+            .await
+            .map(|row| {
+                row.map_or_else(Tile::new, |row| {
+                    Tile::from(row.get::<_, Vec<u8>>("st_asmvt"))
+                })
+            })
+            .map_err(|error| {
+                query_error(
+                    &error,
+                    format_args!(
+                        r#"Can't get "{}" tile at /{}/{}/{}"#,
+                        self.id, xyz.z, xyz.x, xyz.z
+                    ),
+                )
+            })?;
+
+        Ok(tile)
+    }
+
+    /// Get the features of a tile as a GeoJSON `FeatureCollection`, empty if there are none
+    pub async fn get_geojson(&self, conn: &mut Connection<'_>, xyz: &Xyz) -> io::Result<Tile> {
+        let query = self.build_geojson_query(xyz);
+        query_tile(conn, &query, &[], self.statement_timeout)
+            .await
+            .map(|row| {
+                row.map_or_else(Tile::new, |row| Tile::from(row.get::<_, String>("geojson")))
+            })
+            .map_err(|error| {
+                query_error(
+                    &error,
+                    format_args!(
+                        r#"Can't get "{}" GeoJSON tile at /{}/{}/{}"#,
+                        self.id, xyz.z, xyz.x, xyz.y
+                    ),
+                )
+            })
+    }
+
+    /// Look up the catalog type of the `id_column`, and ignore it unless it is an integer column
+    pub async fn check_id_column(&mut self, conn: &mut Connection<'_>) -> io::Result<()> {
+        let Some(id_column) = &self.id_column else {
+            return Ok(());
+        };
+
+        let type_name: Option<String> = conn
+            .query_opt(
+                include_str!("scripts/get_column_type.sql"),
+                &[&self.schema, &self.table, id_column],
+            )
+            .await
+            .map_err(|e| prettify_error!(e, r#"Can't get "{}" id column type"#, self.id))?
+            .map(|row| row.get("type_name"));
+
+        if let Some(warning) = self.get_id_column_warning(type_name.as_deref()) {
+            warn!("{warning}");
+            self.id_column = None;
+        }
+        Ok(())
+    }
+
+    /// Why the `id_column` of the given catalog type can't be used for feature ids, if it can't
+    pub fn get_id_column_warning(&self, type_name: Option<&str>) -> Option<String> {
+        let id_column = self.id_column.as_ref()?;
+        match type_name {
+            Some(type_name) if ID_COLUMN_TYPES.contains(&type_name) => None,
+            Some(type_name) => Some(format!(
+                r#"Table source "{}" id column {id_column} has type {type_name}, but feature ids must be integers. Serving its features without ids"#,
+                self.id
+            )),
+            None => Some(format!(
+                r#"Table source "{}" has no id column {id_column}. Serving its features without ids"#,
+                self.id
+            )),
+        }
+    }
+
+    /// Run `version_query`, if configured, and return the current source version token
+    pub async fn get_version(&self, conn: &mut Connection<'_>) -> io::Result<Option<String>> {
+        let Some(version_query) = &self.version_query else {
+            return Ok(None);
+        };
+
+        let version: Option<String> = conn
+            .query_one(version_query.as_str(), &[])
+            .await
+            .and_then(|row| row.try_get(0))
+            .map_err(|e| prettify_error!(e, r#"Can't get "{}" source version"#, self.id))?;
+
+        Ok(version)
     }
 }
 
+#[allow(mismatched_lifetime_syntaxes)]
 #[async_trait]
 impl Source for TableSource {
     async fn get_id(&self) -> &str {
@@ -140,9 +825,16 @@ impl Source for TableSource {
         let mut tilejson = tilejson! {
             tilejson: "2.2.0".to_string(),
             tiles: vec![],  // tile source is required, but not yet known
-            name: self.id.to_string(),
+            name: self.name.clone().unwrap_or_else(|| self.id.to_string()),
         };
 
+        tilejson.description = self.description.clone();
+        tilejson.attribution = self.attribution.clone();
+        tilejson.scheme = Some(self.tile_scheme.unwrap_or_default().as_str().to_owned());
+        self.tile_matrix_set
+            .unwrap_or_default()
+            .set_tilejson_crs(&mut tilejson);
+
         if let Some(minzoom) = &self.minzoom {
             tilejson.minzoom = Some(*minzoom);
         };
@@ -155,45 +847,116 @@ impl Source for TableSource {
             tilejson.bounds = Some(*bounds);
         };
 
+        tilejson.vector_layers = Some(self.get_vector_layers());
+
         // TODO: consider removing - this is not needed per TileJSON spec
         tilejson.set_missing_defaults();
         Ok(tilejson)
     }
 
+    fn is_valid_zoom(&self, zoom: i32) -> bool {
+        is_valid_zoom(zoom, self.minzoom, self.maxzoom)
+    }
+
+    /// Tables may change at any time, so their tiles must always be revalidated
+    fn get_max_age(&self) -> Option<Duration> {
+        Some(Duration::ZERO)
+    }
+
+    fn get_empty_tile(&self) -> EmptyTile {
+        self.empty_tile.unwrap_or_default()
+    }
+
+    fn get_headers(&self) -> Option<&HashMap<String, String>> {
+        self.headers.as_ref()
+    }
+
+    fn get_max_feature_count(&self) -> Option<usize> {
+        self.max_feature_count.map(|max| max as usize)
+    }
+
+    fn is_out_of_bounds(&self, xyz: &Xyz) -> bool {
+        let Some(bounds) = self
+            .bounds
+            .filter(|_| self.skip_out_of_bounds == Some(true))
+        else {
+            return false;
+        };
+        let xyz = self.tile_scheme.unwrap_or_default().to_xyz(xyz);
+        let extent = self.extent.unwrap_or(DEFAULT_EXTENT);
+        let buffer = f64::from(self.get_buffer(xyz.z)) / f64::from(extent);
+        let tile_bounds = self
+            .tile_matrix_set
+            .unwrap_or_default()
+            .tile_bounds(&xyz, buffer);
+        !intersects_bounds(&bounds, &tile_bounds)
+    }
+
     async fn get_tile(
         &self,
-        conn: &mut Connection,
+        conn: Option<&mut Connection>,
         xyz: &Xyz,
         _query: &Option<UrlQuery>,
     ) -> Result<Tile, io::Error> {
-        let tile_query = self.build_tile_query(xyz);
+        let conn = require_connection(conn, &self.id)?;
+        self.get_layers_tile(conn, xyz, None).await
+    }
+}
 
-        let tile: Tile = conn
-            .query_one(tile_query.as_str(), &[])
-            .await
-            .map(|row| row.get("st_asmvt"))
-            .map_err(|error| {
-                prettify_error!(
-                    error,
-                    r#"Can't get "{}" tile at /{}/{}/{}"#,
-                    self.id,
-                    xyz.z,
-                    xyz.x,
-                    xyz.z
-                )
-            })?;
+/// Get the SRID shared by a sample of the rows of a table, or 0 if they have none or differ
+async fn get_sampled_srid(
+    conn: &mut Connection<'_>,
+    schema: &str,
+    table: &str,
+    geometry_column: &str,
+) -> i32 {
+    let id = format!("{schema}.{table}");
+    let query = format!(
+        include_str!("scripts/get_srids.sql"),
+        schema = escape_identifier(schema),
+        table = escape_identifier(table),
+        geometry_column = escape_identifier(geometry_column),
+        sample_size = SRID_SAMPLE_SIZE,
+    );
 
-        Ok(tile)
+    let srids: Vec<i32> = match conn.query(query.as_str(), &[]).await {
+        Ok(rows) => rows.iter().map(|row| row.get("srid")).collect(),
+        Err(e) => {
+            warn!(r#"Can't sample the SRID of "{id}": {e}"#);
+            return 0;
+        }
+    };
+
+    match srids.as_slice() {
+        [srid] => {
+            if *srid != 0 {
+                info!(r#""{id}" has SRID 0, using SRID {srid} sampled from its rows"#);
+            }
+            *srid
+        }
+        [] => 0,
+        _ => {
+            warn!(r#""{id}" has SRID 0, and its rows have mixed SRIDs {srids:?}"#);
+            0
+        }
     }
 }
 
+static JOIN_ALIAS: &str = "joined";
 static DEFAULT_EXTENT: u32 = 4096;
 static DEFAULT_BUFFER: u32 = 64;
 static DEFAULT_CLIP_GEOM: bool = true;
+/// Tile extents a request can ask for with the `extent` query parameter, e.g. 8192 for hi-dpi screens
+pub static EXTENT_OVERRIDES: &[u32] = &[2048, 4096, 8192];
+/// Types of the columns that can be encoded as MVT feature ids, cast to `int8` in tile queries
+static ID_COLUMN_TYPES: &[&str] = &["int2", "int4", "int8"];
+/// Number of rows checked when sampling the SRID of a table
+static SRID_SAMPLE_SIZE: u32 = 1000;
 
 pub async fn get_table_sources(
     conn: &mut Connection<'_>,
     default_srid: Option<i32>,
+    sample_srid: bool,
 ) -> Result<TableSources, io::Error> {
     let mut sources = HashMap::new();
     let mut duplicate_source_ids = HashSet::new();
@@ -215,6 +978,9 @@ pub async fn get_table_sources(
         }
 
         let mut srid: i32 = row.get("srid");
+        if srid == 0 && sample_srid {
+            srid = get_sampled_srid(conn, &schema, &table, &geometry_column).await;
+        }
         if srid == 0 {
             if let Some(default_srid) = default_srid {
                 warn!(r#""{id}" has SRID 0, using the provided default SRID {default_srid}"#);
@@ -241,18 +1007,15 @@ pub async fn get_table_sources(
             id: id.clone(),
             schema,
             table,
-            id_column: None,
             geometry_column,
             bounds,
-            minzoom: None,
-            maxzoom: None,
             srid: srid as u32,
             extent: Some(DEFAULT_EXTENT),
-            buffer: Some(DEFAULT_BUFFER),
+            buffer: Some(Buffer::Value(DEFAULT_BUFFER)),
             clip_geom: Some(DEFAULT_CLIP_GEOM),
             geometry_type: row.get("type"),
             properties: json_to_hashmap(&row.get("properties")),
-            unrecognized: HashMap::new(),
+            ..Default::default()
         };
 
         let mut explicit_source = source.clone();
@@ -1,10 +1,17 @@
-use crate::source::{UrlQuery, Xyz};
+use crate::pg::config::IdNormalization;
+use crate::source::{TileMatrixSet, UrlQuery, Xyz};
 use actix_http::header::HeaderValue;
 use actix_web::http::Uri;
+use bb8_postgres::tokio_postgres;
+use bb8_postgres::tokio_postgres::error::SqlState;
+use log::warn;
 use postgis::{ewkb, LineString, Point, Polygon};
 use postgres::types::Json;
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::Display;
+use std::io;
 use tilejson::Bounds;
 
 #[macro_export]
@@ -23,6 +30,24 @@ macro_rules! prettify_error {
 
 pub(crate) use prettify_error;
 
+/// Like `prettify_error!`, but a query that failed because the connection was lost
+/// gets the `ConnectionAborted` kind, so that it can be retried, and a query canceled
+/// by `statement_timeout` gets the `Interrupted` kind. Other SQL errors stay `Other`.
+pub fn query_error(error: &tokio_postgres::Error, info: impl Display) -> io::Error {
+    let lost_connection = error.is_closed()
+        || error
+            .source()
+            .is_some_and(|source| source.is::<io::Error>());
+    let kind = if lost_connection {
+        io::ErrorKind::ConnectionAborted
+    } else if error.code() == Some(&SqlState::QUERY_CANCELED) {
+        io::ErrorKind::Interrupted
+    } else {
+        io::ErrorKind::Other
+    };
+    io::Error::new(kind, format!("{info}: {error}"))
+}
+
 // https://github.com/mapbox/postgis-vt-util/blob/master/src/TileBBox.sql
 pub fn tile_bbox(xyz: &Xyz) -> String {
     let x = xyz.x;
@@ -40,6 +65,35 @@ pub fn tile_bbox(xyz: &Xyz) -> String {
     format!("ST_MakeEnvelope({x_min}, {y_min}, {x_max}, {y_max}, 3857)")
 }
 
+/// Bounds of a tile of the WorldCRS84Quad tile matrix set, in degrees
+pub fn crs84_tile_bbox(xyz: &Xyz) -> String {
+    let res = 180.0 / f64::from(2_i32.pow(xyz.z as u32));
+
+    let x_min = -180.0 + f64::from(xyz.x) * res;
+    let x_max = x_min + res;
+    let y_max = 90.0 - f64::from(xyz.y) * res;
+    let y_min = y_max - res;
+
+    format!("ST_MakeEnvelope({x_min}, {y_min}, {x_max}, {y_max}, 4326)")
+}
+
+/// Bounds of a tile in the SRID of the tile matrix set
+pub fn tile_matrix_bbox(tile_matrix_set: TileMatrixSet, xyz: &Xyz) -> String {
+    match tile_matrix_set {
+        TileMatrixSet::WebMercatorQuad => tile_bbox(xyz),
+        TileMatrixSet::WorldCrs84Quad => crs84_tile_bbox(xyz),
+    }
+}
+
+/// Width of a tile in the units of the SRID of the tile matrix set, meters or degrees
+pub fn tile_matrix_tile_width(tile_matrix_set: TileMatrixSet, zoom: i32) -> f64 {
+    let tiles = f64::from(2_i32.pow(zoom as u32));
+    match tile_matrix_set {
+        TileMatrixSet::WebMercatorQuad => 20_037_508.34 * 2.0 / tiles,
+        TileMatrixSet::WorldCrs84Quad => 180.0 / tiles,
+    }
+}
+
 pub fn json_to_hashmap(value: &serde_json::Value) -> HashMap<String, String> {
     let mut hashmap = HashMap::new();
 
@@ -71,11 +125,11 @@ pub fn get_bounds_cte(srid_bounds: &str) -> String {
     )
 }
 
-pub fn get_srid_bounds(srid: u32, xyz: &Xyz) -> String {
+pub fn get_srid_bounds(srid: u32, xyz: &Xyz, tile_matrix_set: TileMatrixSet) -> String {
     format!(
         include_str!("scripts/get_srid_bounds.sql"),
         srid = srid,
-        mercator_bounds = tile_bbox(xyz),
+        tile_bounds = tile_matrix_bbox(tile_matrix_set, xyz),
     )
 }
 
@@ -111,3 +165,264 @@ pub fn parse_x_rewrite_url(header: &HeaderValue) -> Option<String> {
         .and_then(|header| header.parse::<Uri>().ok())
         .map(|uri| uri.path().trim_end_matches(".json").to_owned())
 }
+
+/// Match a name against a glob pattern, where `*` matches any number of characters
+/// and `?` matches a single one
+pub fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), name.chars().collect());
+    // Position after the last `*`, and the name position it is matched up to
+    let mut star = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Re-key sources by their normalized ids. When several ids normalize to the same value,
+/// the ones after the first (in original id order) get a numeric suffix after the `separator`,
+/// or the conflict is an error if `fail_on_conflict` is set. Ids are processed in sorted order,
+/// so the same sources always get the same suffixes.
+pub fn normalize_source_ids<T>(
+    sources: &mut HashMap<String, Box<T>>,
+    normalization: IdNormalization,
+    separator: &str,
+    fail_on_conflict: bool,
+    id: fn(&mut T) -> &mut String,
+) -> io::Result<()> {
+    if normalization == IdNormalization::None {
+        return Ok(());
+    }
+
+    let mut ids: Vec<String> = sources.keys().cloned().collect();
+    ids.sort();
+
+    let mut normalized = HashMap::with_capacity(sources.len());
+    for old_id in ids {
+        let mut source = sources.remove(&old_id).unwrap();
+        let base_id = normalization.apply(&old_id);
+        if fail_on_conflict && normalized.contains_key(&base_id) {
+            return Err(io::Error::other(format!(
+                "Source id {old_id} normalizes to the already used {base_id}"
+            )));
+        }
+        let mut new_id = base_id.clone();
+        let mut suffix = 2;
+        while normalized.contains_key(&new_id) {
+            new_id = format!("{base_id}{separator}{suffix}");
+            suffix += 1;
+        }
+        if new_id != base_id {
+            warn!("Source id {old_id} normalizes to the already used {base_id}, using {new_id}");
+        }
+        *id(&mut source) = new_id.clone();
+        normalized.insert(new_id, source);
+    }
+    *sources = normalized;
+    Ok(())
+}
+
+/// Rename the sources whose ids are already `used` by another database. Each gets the first
+/// numeric suffix after the `separator` that is free, or the conflict is an error
+/// if `fail_on_conflict` is set.
+pub fn rename_conflicting_ids<T>(
+    sources: &mut HashMap<String, Box<T>>,
+    used: &HashSet<String>,
+    separator: &str,
+    fail_on_conflict: bool,
+    id: fn(&mut T) -> &mut String,
+) -> io::Result<()> {
+    let mut conflicting: Vec<String> = sources
+        .keys()
+        .filter(|id| used.contains(*id))
+        .cloned()
+        .collect();
+    conflicting.sort();
+
+    for old_id in conflicting {
+        if fail_on_conflict {
+            return Err(io::Error::other(format!(
+                "Source id {old_id} is already used by another database"
+            )));
+        }
+        let mut suffix = 2;
+        let mut new_id = format!("{old_id}{separator}{suffix}");
+        while used.contains(&new_id) || sources.contains_key(&new_id) {
+            suffix += 1;
+            new_id = format!("{old_id}{separator}{suffix}");
+        }
+        warn!("Source id {old_id} is already used by another database, using {new_id}");
+        let mut source = sources.remove(&old_id).unwrap();
+        *id(&mut source) = new_id.clone();
+        sources.insert(new_id, source);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern("app_*", "app_points"));
+        assert!(matches_pattern("app_*", "app_"));
+        assert!(!matches_pattern("app_*", "my_app_points"));
+        assert!(matches_pattern("*_tmp", "points_tmp"));
+        assert!(matches_pattern("a*b*c", "aXbYbZc"));
+        assert!(matches_pattern("points?", "points1"));
+        assert!(!matches_pattern("points?", "points"));
+        assert!(matches_pattern("public", "public"));
+        assert!(!matches_pattern("public", "public2"));
+        assert!(matches_pattern("*", ""));
+    }
+
+    #[test]
+    fn test_crs84_tile_bbox() {
+        assert_eq!(
+            crs84_tile_bbox(&Xyz { z: 0, x: 0, y: 0 }),
+            "ST_MakeEnvelope(-180, -90, 0, 90, 4326)"
+        );
+        assert_eq!(
+            crs84_tile_bbox(&Xyz { z: 0, x: 1, y: 0 }),
+            "ST_MakeEnvelope(0, -90, 180, 90, 4326)"
+        );
+        assert_eq!(
+            crs84_tile_bbox(&Xyz { z: 1, x: 3, y: 1 }),
+            "ST_MakeEnvelope(90, -90, 180, 0, 4326)"
+        );
+        assert_eq!(
+            crs84_tile_bbox(&Xyz { z: 3, x: 9, y: 2 }),
+            "ST_MakeEnvelope(22.5, 22.5, 45, 45, 4326)"
+        );
+        assert_eq!(
+            tile_matrix_bbox(TileMatrixSet::WebMercatorQuad, &Xyz { z: 0, x: 0, y: 0 }),
+            tile_bbox(&Xyz { z: 0, x: 0, y: 0 })
+        );
+    }
+
+    struct MockSource {
+        id: String,
+    }
+
+    fn sources(ids: &[&str]) -> HashMap<String, Box<MockSource>> {
+        ids.iter()
+            .map(|id| {
+                let id = (*id).to_owned();
+                (id.clone(), Box::new(MockSource { id }))
+            })
+            .collect()
+    }
+
+    fn normalized_ids(ids: &[&str], separator: &str) -> Vec<(String, String)> {
+        let mut sources = sources(ids);
+        normalize_source_ids(
+            &mut sources,
+            IdNormalization::Slug,
+            separator,
+            false,
+            |src| &mut src.id,
+        )
+        .unwrap();
+        let mut ids: Vec<(String, String)> = sources
+            .into_iter()
+            .map(|(key, src)| (key, src.id))
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_normalize_source_ids_conflicts() {
+        let ids = [
+            "Public.Points",
+            "public.points",
+            "public_points",
+            "public.lines",
+        ];
+        let expected = vec![
+            ("public-lines".to_owned(), "public-lines".to_owned()),
+            ("public-points".to_owned(), "public-points".to_owned()),
+            ("public-points.2".to_owned(), "public-points.2".to_owned()),
+            ("public-points.3".to_owned(), "public-points.3".to_owned()),
+        ];
+        assert_eq!(normalized_ids(&ids, "."), expected);
+
+        // The suffixes don't depend on the order the sources were discovered in
+        for _ in 0..10 {
+            let mut reversed = ids;
+            reversed.reverse();
+            assert_eq!(normalized_ids(&reversed, "."), expected);
+        }
+
+        assert_eq!(
+            normalized_ids(&ids, "-")[2],
+            ("public-points-2".to_owned(), "public-points-2".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_normalize_source_ids_fail_on_conflict() {
+        let mut ids = sources(&["Public.Points", "public.lines"]);
+        normalize_source_ids(&mut ids, IdNormalization::Lowercase, "-", true, |src| {
+            &mut src.id
+        })
+        .unwrap();
+        assert!(ids.contains_key("public.points"));
+
+        let mut ids = sources(&["Public.Points", "public.points"]);
+        let err = normalize_source_ids(&mut ids, IdNormalization::Lowercase, "-", true, |src| {
+            &mut src.id
+        })
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Source id public.points normalizes to the already used public.points"
+        );
+    }
+
+    #[test]
+    fn test_rename_conflicting_ids() {
+        let used = HashSet::from(["public.points".to_owned(), "public.points-2".to_owned()]);
+
+        let mut ids = sources(&["public.points", "public.points-3", "public.lines"]);
+        rename_conflicting_ids(&mut ids, &used, "-", false, |src| &mut src.id).unwrap();
+        let mut ids: Vec<(String, String)> =
+            ids.into_iter().map(|(key, src)| (key, src.id)).collect();
+        ids.sort();
+        assert_eq!(
+            ids,
+            vec![
+                ("public.lines".to_owned(), "public.lines".to_owned()),
+                ("public.points-3".to_owned(), "public.points-3".to_owned()),
+                ("public.points-4".to_owned(), "public.points-4".to_owned()),
+            ]
+        );
+
+        let mut ids = sources(&["public.points"]);
+        let err =
+            rename_conflicting_ids(&mut ids, &used, "-", true, |src| &mut src.id).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Source id public.points is already used by another database"
+        );
+    }
+}
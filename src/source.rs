@@ -1,30 +1,632 @@
 use crate::pg::db::Connection;
+use crate::pg::raster_source::{blank_png, RASTER_TILE_SIZE};
+use actix_web::http::header::{HeaderName, HeaderValue};
 use async_trait::async_trait;
+use bytes::Bytes;
+use log::warn;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::HashMap;
+use std::f64::consts::PI;
 use std::fmt::Debug;
 use std::io;
-use tilejson::TileJSON;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tilejson::{tilejson, Bounds, TileJSON};
 
-pub type Tile = Vec<u8>;
+pub type Tile = Bytes;
 pub type UrlQuery = HashMap<String, String>;
 
-#[derive(Copy, Clone)]
+/// Headers of tile responses set by martin itself, which source `headers` can't override
+const RESERVED_HEADERS: [&str; 6] = [
+    "content-type",
+    "content-encoding",
+    "content-length",
+    "transfer-encoding",
+    "etag",
+    "vary",
+];
+
+pub fn is_reserved_header(name: &str) -> bool {
+    RESERVED_HEADERS
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(name))
+}
+
+/// Drop the `headers` of a source that martin sets itself, or that are not valid HTTP headers,
+/// with a warning
+pub fn check_headers(source_id: &str, headers: &mut Option<HashMap<String, String>>) {
+    if let Some(headers) = headers {
+        headers.retain(|name, value| {
+            if is_reserved_header(name) {
+                warn!("Source {source_id} can't override the {name} header, ignoring it");
+                false
+            } else if HeaderName::try_from(name.as_str()).is_err()
+                || HeaderValue::try_from(value.as_str()).is_err()
+            {
+                warn!("Source {source_id} has an invalid header {name}: {value}, ignoring it");
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+/// The highest zoom level at which all tile coordinates fit into `i32`
+pub const MAX_ZOOM: u8 = 30;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Xyz {
     pub z: i32,
     pub x: i32,
     pub y: i32,
 }
 
+impl Xyz {
+    /// Parse tile coordinates from URL path segments. Zoom must not exceed [`MAX_ZOOM`],
+    /// and `x` and `y` must be non-negative and less than `2^z`.
+    pub fn from_path(z: &str, x: &str, y: &str) -> io::Result<Self> {
+        Self::from_path_in(z, x, y, TileMatrixSet::default())
+    }
+
+    /// Parse tile coordinates from URL path segments, where `x` and `y` must be within
+    /// the tile matrix of the zoom level in `tile_matrix_set`.
+    pub fn from_path_in(
+        z: &str,
+        x: &str,
+        y: &str,
+        tile_matrix_set: TileMatrixSet,
+    ) -> io::Result<Self> {
+        let zoom = z
+            .parse::<u8>()
+            .ok()
+            .filter(|zoom| *zoom <= MAX_ZOOM)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid zoom level '{z}', must be between 0 and {MAX_ZOOM}"),
+                )
+            })?;
+
+        let (width, height) = tile_matrix_set.matrix_size(zoom);
+        let parse_coordinate = |name: &str, value: &str, tiles_count: u64| {
+            value
+                .parse::<u32>()
+                .ok()
+                .filter(|value| u64::from(*value) < tiles_count)
+                .map(|value| value as i32)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "Invalid {name} coordinate '{value}' at zoom {zoom}, must be between 0 and {}",
+                            tiles_count - 1
+                        ),
+                    )
+                })
+        };
+
+        Ok(Self {
+            z: i32::from(zoom),
+            x: parse_coordinate("x", x, width)?,
+            y: parse_coordinate("y", y, height)?,
+        })
+    }
+}
+
+/// Tile row numbering of a source
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TileScheme {
+    /// Rows numbered from the north, as used by most web maps
+    #[default]
+    Xyz,
+    /// Rows numbered from the south
+    Tms,
+}
+
+impl TileScheme {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TileScheme::Xyz => "xyz",
+            TileScheme::Tms => "tms",
+        }
+    }
+
+    /// Convert tile coordinates requested in this scheme to XYZ ones
+    pub fn to_xyz(self, xyz: &Xyz) -> Xyz {
+        match self {
+            TileScheme::Xyz => *xyz,
+            TileScheme::Tms => Xyz {
+                y: (1 << xyz.z) - 1 - xyz.y,
+                ..*xyz
+            },
+        }
+    }
+}
+
+/// Format of the tiles served by a source
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TileFormat {
+    /// Mapbox vector tiles
+    #[default]
+    Mvt,
+    /// PNG images, rendered from raster data
+    Png,
+    /// GeoJSON `FeatureCollection` of the features of a tile
+    Json,
+}
+
+impl TileFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            TileFormat::Mvt => "application/x-protobuf",
+            TileFormat::Png => "image/png",
+            TileFormat::Json => "application/geo+json",
+        }
+    }
+
+    /// A tile without data: an empty vector tile, a transparent image generated once,
+    /// or a `FeatureCollection` without features
+    pub fn blank_tile(self) -> Tile {
+        static BLANK_PNG: OnceLock<Tile> = OnceLock::new();
+        match self {
+            TileFormat::Mvt => Tile::new(),
+            TileFormat::Png => BLANK_PNG
+                .get_or_init(|| blank_png(RASTER_TILE_SIZE))
+                .clone(),
+            TileFormat::Json => Tile::from_static(br#"{"type":"FeatureCollection","features":[]}"#),
+        }
+    }
+
+    /// Tile URL extensions of the format, the first one is used in TileJSON
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            TileFormat::Mvt => &["pbf", "mvt"],
+            TileFormat::Png => &["png"],
+            TileFormat::Json => &["geojson"],
+        }
+    }
+}
+
+/// How a source answers requests for tiles without data
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EmptyTile {
+    /// `204 No Content` with an empty body
+    #[default]
+    NoContent,
+    /// `404 Not Found`
+    NotFound,
+    /// `200 OK` with a tile without data: an empty vector tile, or a transparent image
+    Blank,
+}
+
+impl EmptyTile {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EmptyTile::NoContent => "204",
+            EmptyTile::NotFound => "404",
+            EmptyTile::Blank => "blank",
+        }
+    }
+
+    /// The policy of several sources served together, if they all share one, otherwise the default
+    pub fn shared(policies: impl IntoIterator<Item = EmptyTile>) -> EmptyTile {
+        let mut policies = policies.into_iter();
+        match policies.next() {
+            Some(first) if policies.all(|policy| policy == first) => first,
+            _ => EmptyTile::default(),
+        }
+    }
+}
+
+impl FromStr for EmptyTile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "204" => Ok(EmptyTile::NoContent),
+            "404" => Ok(EmptyTile::NotFound),
+            "blank" => Ok(EmptyTile::Blank),
+            _ => Err(format!(
+                "Invalid empty_tile '{s}', expected '204', '404' or 'blank'"
+            )),
+        }
+    }
+}
+
+impl Serialize for EmptyTile {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for EmptyTile {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Status codes may be written as numbers
+        match serde_yaml::Value::deserialize(deserializer)? {
+            serde_yaml::Value::Number(number) => number.to_string().parse(),
+            serde_yaml::Value::String(value) => value.parse(),
+            value => Err(format!("Invalid empty_tile {value:?}")),
+        }
+        .map_err(D::Error::custom)
+    }
+}
+
+/// Projection and tile grid of a source, named as in the OGC Two Dimensional Tile Matrix Set
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TileMatrixSet {
+    /// Web Mercator (EPSG:3857) tiles, with a single tile at zoom 0
+    #[default]
+    WebMercatorQuad,
+    /// WGS84 (EPSG:4326) tiles, with two tiles side by side at zoom 0
+    #[serde(rename = "WorldCRS84Quad")]
+    WorldCrs84Quad,
+}
+
+impl TileMatrixSet {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TileMatrixSet::WebMercatorQuad => "WebMercatorQuad",
+            TileMatrixSet::WorldCrs84Quad => "WorldCRS84Quad",
+        }
+    }
+
+    /// SRID of the tile geometries
+    pub fn srid(self) -> u32 {
+        match self {
+            TileMatrixSet::WebMercatorQuad => 3857,
+            TileMatrixSet::WorldCrs84Quad => 4326,
+        }
+    }
+
+    /// Add the projection to TileJSON, which has no field for it, if it's not Web Mercator
+    pub fn set_tilejson_crs(self, tilejson: &mut TileJSON) {
+        if self != TileMatrixSet::WebMercatorQuad {
+            tilejson.other.insert(
+                "crs".to_owned(),
+                serde_json::Value::String(format!("EPSG:{}", self.srid())),
+            );
+        }
+    }
+
+    /// Number of tile columns and rows at a zoom level
+    pub fn matrix_size(self, zoom: u8) -> (u64, u64) {
+        let rows = 1_u64 << zoom;
+        match self {
+            TileMatrixSet::WebMercatorQuad => (rows, rows),
+            TileMatrixSet::WorldCrs84Quad => (rows * 2, rows),
+        }
+    }
+
+    /// Bounds in longitude and latitude of a tile, widened by a `buffer` in tile widths,
+    /// e.g. `64.0 / 4096.0` for the default buffer of vector tiles
+    pub fn tile_bounds(self, xyz: &Xyz, buffer: f64) -> Bounds {
+        let (x, y) = (f64::from(xyz.x), f64::from(xyz.y));
+        match self {
+            TileMatrixSet::WebMercatorQuad => {
+                let tiles = f64::from(1_u32 << xyz.z);
+                let lon = |x: f64| x / tiles * 360.0 - 180.0;
+                let lat = |y: f64| (PI * (1.0 - 2.0 * y / tiles)).sinh().atan().to_degrees();
+                Bounds::new(
+                    lon(x - buffer),
+                    lat(y + 1.0 + buffer),
+                    lon(x + 1.0 + buffer),
+                    lat(y - buffer),
+                )
+            }
+            TileMatrixSet::WorldCrs84Quad => {
+                let width = 180.0 / f64::from(1_u32 << xyz.z);
+                Bounds::new(
+                    -180.0 + (x - buffer) * width,
+                    90.0 - (y + 1.0 + buffer) * width,
+                    -180.0 + (x + 1.0 + buffer) * width,
+                    90.0 - (y - buffer) * width,
+                )
+            }
+        }
+    }
+}
+
+/// Whether the bounds of a tile intersect the bounds of a source. Source bounds crossing
+/// the antimeridian have a `left` greater than their `right`, e.g. `[170, -20, -170, 20]`.
+pub fn intersects_bounds(bounds: &Bounds, tile: &Bounds) -> bool {
+    let latitudes = bounds.bottom <= tile.top && tile.bottom <= bounds.top;
+    let longitudes = if bounds.left <= bounds.right {
+        bounds.left <= tile.right && tile.left <= bounds.right
+    } else {
+        // The bounds are [left, 180] and [-180, right]
+        bounds.left <= tile.right || tile.left <= bounds.right
+    };
+    latitudes && longitudes
+}
+
+pub fn is_valid_zoom(zoom: i32, minzoom: Option<u8>, maxzoom: Option<u8>) -> bool {
+    let gte_minzoom = minzoom.is_none_or(|minzoom| zoom >= minzoom.into());
+
+    let lte_maxzoom = maxzoom.is_none_or(|maxzoom| zoom <= maxzoom.into());
+
+    gte_minzoom && lte_maxzoom
+}
+
+/// Check that the configured zoom levels of a source are at most [`MAX_ZOOM`],
+/// and that its `minzoom` is not greater than its `maxzoom`
+pub fn validate_zoom_range(
+    source_id: &str,
+    minzoom: Option<u8>,
+    maxzoom: Option<u8>,
+) -> io::Result<()> {
+    for (name, zoom) in [("minzoom", minzoom), ("maxzoom", maxzoom)] {
+        if let Some(zoom) = zoom.filter(|zoom| *zoom > MAX_ZOOM) {
+            return Err(io::Error::other(format!(
+                "Source {source_id} has {name} {zoom}, must be between 0 and {MAX_ZOOM}"
+            )));
+        }
+    }
+    if let (Some(minzoom), Some(maxzoom)) = (minzoom, maxzoom) {
+        if minzoom > maxzoom {
+            return Err(io::Error::other(format!(
+                "Source {source_id} has minzoom {minzoom} greater than its maxzoom {maxzoom}"
+            )));
+        }
+    }
+    Ok(())
+}
+
+// async_trait expands `get_id` with an elided lifetime of `&self`
+#[allow(mismatched_lifetime_syntaxes)]
 #[async_trait]
 pub trait Source: Debug {
     async fn get_id(&self) -> &str;
 
     async fn get_tilejson(&self) -> Result<TileJSON, io::Error>;
 
+    /// Check if the source has tiles at the given zoom level
+    fn is_valid_zoom(&self, zoom: i32) -> bool;
+
+    /// Format of the tiles returned by [`Source::get_tile`]
+    fn get_format(&self) -> TileFormat {
+        TileFormat::Mvt
+    }
+
+    /// How long clients and caches may reuse tiles of the source without checking for changes.
+    /// `None` leaves it to them.
+    fn get_max_age(&self) -> Option<Duration> {
+        None
+    }
+
+    /// How requests for tiles without data are answered
+    fn get_empty_tile(&self) -> EmptyTile {
+        EmptyTile::default()
+    }
+
+    /// Extra HTTP headers of the tile responses
+    fn get_headers(&self) -> Option<&HashMap<String, String>> {
+        None
+    }
+
+    /// Most features of a layer in a tile, if the source caps them
+    fn get_max_feature_count(&self) -> Option<usize> {
+        None
+    }
+
+    /// Whether the tile is outside the declared bounds of a source opting in with
+    /// `skip_out_of_bounds`, so it is empty without querying the source
+    fn is_out_of_bounds(&self, _xyz: &Xyz) -> bool {
+        false
+    }
+
+    /// Whether [`Source::get_tile`] queries a database, and needs a connection from the pool
+    fn requires_connection(&self) -> bool {
+        true
+    }
+
+    /// Get a tile, with a connection from the pool if the source [requires one](Source::requires_connection)
     async fn get_tile(
         &self,
-        conn: &mut Connection,
+        conn: Option<&mut Connection>,
         xyz: &Xyz,
         query: &Option<UrlQuery>,
     ) -> Result<Tile, io::Error>;
 }
+
+/// A source serving tiles kept in memory, for tests and for embedding martin
+/// to serve tiles generated by the host application. Missing tiles are empty.
+#[derive(Clone, Debug)]
+pub struct InMemorySource {
+    pub id: String,
+    pub tilejson: TileJSON,
+    pub tiles: HashMap<Xyz, Tile>,
+}
+
+impl InMemorySource {
+    pub fn new(id: &str) -> Self {
+        Self {
+            id: id.to_owned(),
+            tilejson: tilejson! {
+                tilejson: "2.2.0".to_string(),
+                tiles: vec![],  // tile source is required, but not yet known
+                name: id.to_owned(),
+            },
+            tiles: HashMap::new(),
+        }
+    }
+
+    pub fn set_tilejson(&mut self, tilejson: TileJSON) -> &mut Self {
+        self.tilejson = tilejson;
+        self
+    }
+
+    /// Add or replace a tile, returning the replaced one
+    pub fn insert(&mut self, xyz: Xyz, tile: Tile) -> Option<Tile> {
+        self.tiles.insert(xyz, tile)
+    }
+}
+
+#[allow(mismatched_lifetime_syntaxes)]
+#[async_trait]
+impl Source for InMemorySource {
+    async fn get_id(&self) -> &str {
+        self.id.as_str()
+    }
+
+    async fn get_tilejson(&self) -> Result<TileJSON, io::Error> {
+        Ok(self.tilejson.clone())
+    }
+
+    fn is_valid_zoom(&self, zoom: i32) -> bool {
+        is_valid_zoom(zoom, self.tilejson.minzoom, self.tilejson.maxzoom)
+    }
+
+    fn requires_connection(&self) -> bool {
+        false
+    }
+
+    async fn get_tile(
+        &self,
+        _conn: Option<&mut Connection>,
+        xyz: &Xyz,
+        _query: &Option<UrlQuery>,
+    ) -> Result<Tile, io::Error> {
+        Ok(self.tiles.get(xyz).cloned().unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn xyz_from_path() {
+        let xyz = Xyz::from_path("0", "0", "0").unwrap();
+        assert_eq!(xyz, Xyz { z: 0, x: 0, y: 0 });
+        let xyz = Xyz::from_path("12", "2476", "1280").unwrap();
+        assert_eq!(
+            xyz,
+            Xyz {
+                z: 12,
+                x: 2476,
+                y: 1280
+            }
+        );
+        let xyz = Xyz::from_path("30", "1073741823", "0").unwrap();
+        assert_eq!(xyz.x, 1_073_741_823);
+    }
+
+    #[test]
+    fn xyz_from_path_negative() {
+        assert!(Xyz::from_path("-1", "0", "0").is_err());
+        assert!(Xyz::from_path("1", "-1", "0").is_err());
+        assert!(Xyz::from_path("1", "0", "-1").is_err());
+    }
+
+    #[test]
+    fn tile_bounds_intersection() {
+        let world = TileMatrixSet::WebMercatorQuad.tile_bounds(&Xyz { z: 0, x: 0, y: 0 }, 0.0);
+        assert_eq!((world.left, world.right), (-180.0, 180.0));
+        assert!((world.top - 85.051_128_78).abs() < 1e-6);
+        let crs84 = TileMatrixSet::WorldCrs84Quad.tile_bounds(&Xyz { z: 0, x: 1, y: 0 }, 0.0);
+        assert_eq!(crs84, Bounds::new(0.0, -90.0, 180.0, 90.0));
+
+        // Tile 2/0/1 spans longitudes -180..-90, and 2/3/1 spans 90..180
+        let west = TileMatrixSet::WebMercatorQuad.tile_bounds(&Xyz { z: 2, x: 0, y: 1 }, 0.0);
+        let east = TileMatrixSet::WebMercatorQuad.tile_bounds(&Xyz { z: 2, x: 3, y: 1 }, 0.0);
+        let europe = Bounds::new(-10.0, 35.0, 30.0, 70.0);
+        assert!(!intersects_bounds(&europe, &west));
+        assert!(!intersects_bounds(&europe, &east));
+        let fiji = Bounds::new(177.0, -19.0, -178.0, -16.0);
+        assert!(!intersects_bounds(&fiji, &west));
+        let pacific = Bounds::new(170.0, 0.0, -170.0, 60.0);
+        assert!(intersects_bounds(&pacific, &west));
+        assert!(intersects_bounds(&pacific, &east));
+
+        // A buffer reaches features just outside of the tile
+        let tile = Xyz { z: 2, x: 2, y: 1 };
+        let outside = Bounds::new(90.5, 10.0, 91.0, 20.0);
+        let bounds = TileMatrixSet::WebMercatorQuad.tile_bounds(&tile, 0.0);
+        assert!(!intersects_bounds(&outside, &bounds));
+        let bounds = TileMatrixSet::WebMercatorQuad.tile_bounds(&tile, 64.0 / 4096.0);
+        assert!(intersects_bounds(&outside, &bounds));
+    }
+
+    #[test]
+    fn tile_scheme_to_xyz() {
+        let xyz = Xyz { z: 2, x: 1, y: 0 };
+        assert_eq!(TileScheme::Xyz.to_xyz(&xyz), xyz);
+        assert_eq!(TileScheme::Tms.to_xyz(&xyz), Xyz { z: 2, x: 1, y: 3 });
+        assert_eq!(TileScheme::Tms.to_xyz(&TileScheme::Tms.to_xyz(&xyz)), xyz);
+
+        let xyz = Xyz { z: 0, x: 0, y: 0 };
+        assert_eq!(TileScheme::Tms.to_xyz(&xyz), xyz);
+    }
+
+    #[test]
+    fn xyz_from_path_in_crs84() {
+        let crs84 = TileMatrixSet::WorldCrs84Quad;
+        let xyz = Xyz::from_path_in("0", "1", "0", crs84).unwrap();
+        assert_eq!(xyz, Xyz { z: 0, x: 1, y: 0 });
+        assert!(Xyz::from_path_in("0", "2", "0", crs84).is_err());
+        assert!(Xyz::from_path_in("0", "0", "1", crs84).is_err());
+        let xyz = Xyz::from_path_in("30", "2147483647", "1073741823", crs84).unwrap();
+        assert_eq!(xyz.x, i32::MAX);
+        assert!(Xyz::from_path("0", "1", "0").is_err());
+    }
+
+    #[test]
+    fn xyz_from_path_overflow() {
+        assert!(Xyz::from_path("31", "0", "0").is_err());
+        assert!(Xyz::from_path("256", "0", "0").is_err());
+        assert!(Xyz::from_path("0", "1", "0").is_err());
+        assert!(Xyz::from_path("30", "0", "4294967296").is_err());
+        assert!(Xyz::from_path("3", "a", "0").is_err());
+    }
+
+    #[test]
+    fn empty_tile_from_yaml() {
+        let parse = |yaml| serde_yaml::from_str::<EmptyTile>(yaml);
+        assert_eq!(parse("204").unwrap(), EmptyTile::NoContent);
+        assert_eq!(parse("'404'").unwrap(), EmptyTile::NotFound);
+        assert_eq!(parse("blank").unwrap(), EmptyTile::Blank);
+        assert!(parse("200").is_err());
+        assert!(parse("[404]").is_err());
+        assert_eq!(
+            serde_yaml::to_string(&EmptyTile::NotFound).unwrap(),
+            "'404'\n"
+        );
+
+        let shared = EmptyTile::shared([EmptyTile::Blank, EmptyTile::Blank]);
+        assert_eq!(shared, EmptyTile::Blank);
+        let shared = EmptyTile::shared([EmptyTile::Blank, EmptyTile::NotFound]);
+        assert_eq!(shared, EmptyTile::NoContent);
+    }
+
+    #[actix_rt::test]
+    async fn in_memory_source_ok() {
+        let mut source = InMemorySource::new("in_memory");
+        source.insert(Xyz { z: 1, x: 0, y: 1 }, Bytes::from_static(&[1, 2, 3]));
+        source.set_tilejson(tilejson! {
+            tilejson: "2.2.0".to_string(),
+            tiles: vec![],
+            name: "In memory".to_string(),
+            minzoom: 1,
+        });
+
+        assert_eq!(source.get_id().await, "in_memory");
+        let tilejson = source.get_tilejson().await.unwrap();
+        assert_eq!(tilejson.name, Some("In memory".to_owned()));
+        assert_eq!(tilejson.minzoom, Some(1));
+        assert!(!source.is_valid_zoom(0));
+        assert!(!source.requires_connection());
+
+        let xyz = Xyz { z: 1, x: 0, y: 1 };
+        let tile = source.get_tile(None, &xyz, &None).await.unwrap();
+        assert_eq!(tile, Bytes::from_static(&[1, 2, 3]));
+
+        let xyz = Xyz { z: 1, x: 1, y: 1 };
+        let tile = source.get_tile(None, &xyz, &None).await.unwrap();
+        assert!(tile.is_empty());
+    }
+}
@@ -0,0 +1,126 @@
+//! Serving of the [sprites](https://maplibre.org/maplibre-style-spec/sprite/) used by map styles
+//! for their icons, from directories with a `sprite.json` index and a `sprite.png` image,
+//! and optionally their `sprite@2x` variants for high resolution screens.
+
+use log::info;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// File extension, and content type, of a sprite file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpriteFormat {
+    Json,
+    Png,
+}
+
+impl SpriteFormat {
+    pub fn parse(extension: &str) -> Option<Self> {
+        match extension {
+            "json" => Some(Self::Json),
+            "png" => Some(Self::Png),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Png => "png",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Png => "image/png",
+        }
+    }
+}
+
+/// Sprite directories by sprite id
+#[derive(Debug, Clone, Default)]
+pub struct SpriteSources {
+    sprites: HashMap<String, PathBuf>,
+}
+
+impl SpriteSources {
+    /// Check that every directory has at least a `sprite.json` and a `sprite.png`
+    pub fn new(sprites: &HashMap<String, String>) -> io::Result<Self> {
+        let mut sources = Self::default();
+        for (id, path) in sprites {
+            let path = PathBuf::from(path);
+            for format in [SpriteFormat::Json, SpriteFormat::Png] {
+                let file = path.join(format!("sprite.{}", format.extension()));
+                if !file.is_file() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("Sprite '{id}' has no {}", file.display()),
+                    ));
+                }
+            }
+            sources.sprites.insert(id.clone(), path);
+        }
+        info!("Serving {} sprites", sources.sprites.len());
+        Ok(sources)
+    }
+
+    /// Ids of the sprites, sorted
+    pub fn get_ids(&self) -> Vec<&str> {
+        let mut ids: Vec<&str> = self.sprites.keys().map(String::as_str).collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Read a sprite file, the `@2x` variant if `retina` is set.
+    /// Returns `None` for unknown sprites and missing `@2x` variants.
+    pub fn get_sprite(
+        &self,
+        id: &str,
+        retina: bool,
+        format: SpriteFormat,
+    ) -> io::Result<Option<Vec<u8>>> {
+        let Some(path) = self.sprites.get(id) else {
+            return Ok(None);
+        };
+        let scale = if retina { "@2x" } else { "" };
+        let file = path.join(format!("sprite{scale}.{}", format.extension()));
+        match fs::read(file) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_sprite_sources() {
+        let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/sprites");
+        let sprites = SpriteSources::new(&HashMap::from([
+            ("basic".to_owned(), format!("{fixtures}/basic")),
+            ("icons".to_owned(), format!("{fixtures}/icons")),
+        ]))
+        .unwrap();
+        assert_eq!(sprites.get_ids(), vec!["basic", "icons"]);
+
+        // icons has no @2x variant
+        let icons = sprites.get_sprite("icons", false, SpriteFormat::Png);
+        assert!(icons.unwrap().is_some());
+        let icons = sprites.get_sprite("icons", true, SpriteFormat::Png);
+        assert!(icons.unwrap().is_none());
+        let missing = sprites.get_sprite("missing", false, SpriteFormat::Json);
+        assert!(missing.unwrap().is_none());
+
+        let error = SpriteSources::new(&HashMap::from([(
+            "fonts".to_owned(),
+            concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/fonts").to_owned(),
+        )]))
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,133 @@
+//! Access control of the source routes, checked by a middleware of [`router`](super::server::router)
+//! before the request reaches a handler.
+
+use crate::srv::api_key::{check_api_key, check_server_api_key};
+use crate::srv::server::{AppState, Sources};
+use actix_web::{HttpRequest, Result};
+use percent_encoding::percent_decode_str;
+
+/// Kind of the sources addressed by a route
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SourceKind {
+    Table,
+    Function,
+    Raster,
+    File,
+    /// Table or function sources, of the merged tiles and of the debug routes
+    Vector,
+}
+
+/// Kind and comma-separated ids of the sources addressed by the TileJSON or tile route
+/// of a path relative to the routes, e.g. `/rpc/public.points/0/0/0.pbf`
+fn requested_sources(path: &str) -> Option<(SourceKind, &str)> {
+    let path = path.strip_prefix('/')?;
+    let (kind, rest) = match path.split_once('/') {
+        Some(("rpc", rest)) => (SourceKind::Function, rest),
+        Some(("raster", rest)) => (SourceKind::Raster, rest),
+        Some(("file", rest)) => (SourceKind::File, rest),
+        Some(("comp", rest)) => (SourceKind::Vector, rest),
+        Some(("debug", rest)) => (SourceKind::Vector, rest.strip_prefix("sql/")?),
+        _ => (SourceKind::Table, path),
+    };
+
+    let segments: Vec<&str> = rest.split('/').collect();
+    match segments[..] {
+        [ids, _z, _x, _y] => Some((kind, ids)),
+        [tilejson] if tilejson != "index.json" => Some((kind, tilejson.strip_suffix(".json")?)),
+        _ => None,
+    }
+}
+
+/// Canonical id and API keys of a source, which may be requested by an alias
+fn get_source<'a>(
+    state: &'a AppState,
+    sources: &'a Sources,
+    kind: SourceKind,
+    id: &str,
+) -> Option<(&'a str, Option<&'a Vec<String>>)> {
+    let table = || {
+        sources
+            .table_sources
+            .get(id)
+            .map(|source| (source.id.as_str(), source.api_keys.as_ref()))
+    };
+    let function = || {
+        sources
+            .function_sources
+            .get(id)
+            .map(|source| (source.id.as_str(), source.api_keys.as_ref()))
+    };
+    match kind {
+        SourceKind::Table => table(),
+        SourceKind::Function => function(),
+        SourceKind::Raster => sources
+            .raster_sources
+            .get(id)
+            .map(|source| (source.id.as_str(), source.api_keys.as_ref())),
+        SourceKind::File => state
+            .file_sources
+            .as_ref()
+            .and_then(|file_sources| file_sources.get_key_value(id))
+            .map(|(id, _)| (id.as_str(), None)),
+        SourceKind::Vector => table().or_else(function),
+    }
+}
+
+/// Check that the request presents the server-wide API keys, and the API keys and the JWT scopes
+/// of the sources it addresses. `path` is relative to the routes, and the scopes are those of
+/// the canonical source ids, whichever alias is requested. Unknown sources are left to
+/// the handlers to reject.
+pub fn check_access(req: &HttpRequest, state: &AppState, path: &str) -> Result<()> {
+    check_server_api_key(req, state.api_keys.as_ref(), path)?;
+
+    let Some((kind, ids)) = requested_sources(path) else {
+        return Ok(());
+    };
+
+    let sources = state.sources.load();
+    for id in ids.split(',') {
+        let id = percent_decode_str(id).decode_utf8_lossy();
+        let Some((source_id, api_keys)) = get_source(state, &sources, kind, &id) else {
+            continue;
+        };
+        check_api_key(req, api_keys)?;
+        if let Some(auth) = &state.auth {
+            auth.check(req, source_id)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requested_sources() {
+        use SourceKind::{File, Function, Raster, Table, Vector};
+        let requested = requested_sources;
+
+        assert_eq!(requested("/points.json"), Some((Table, "points")));
+        assert_eq!(requested("/a,b/0/0/0.pbf"), Some((Table, "a,b")));
+        assert_eq!(requested("/rpc/fn.json"), Some((Function, "fn")));
+        assert_eq!(requested("/rpc/fn/1/2/3"), Some((Function, "fn")));
+        assert_eq!(requested("/raster/dem/0/0/0.png"), Some((Raster, "dem")));
+        assert_eq!(requested("/file/tiles/0/0/0.pbf"), Some((File, "tiles")));
+        assert_eq!(requested("/comp/a,fn/0/0/0"), Some((Vector, "a,fn")));
+        assert_eq!(requested("/debug/sql/a/0/0/0"), Some((Vector, "a")));
+
+        for path in [
+            "/index.json",
+            "/rpc/index.json",
+            "/file/index.json",
+            "/health",
+            "/catalog",
+            "/font/Font%20A/0-255.pbf",
+            "/sprite/basic@2x.png",
+            "/debug/other/a/0/0/0",
+            "",
+        ] {
+            assert_eq!(requested(path), None, "{path}");
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use actix_web::web::Query;
+use actix_web::{error, HttpRequest, Result};
+use std::collections::HashMap;
+
+/// Request header carrying the API key
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Query parameter carrying the API key, for clients that can't set headers
+pub const API_KEY_PARAM: &str = "key";
+
+/// Health probes, which stay open when the whole server requires an API key
+const PUBLIC_PATHS: [&str; 3] = ["/health", "/healthz", "/ready"];
+
+/// Get the API key presented by the request, preferring the header over the query parameter
+fn get_api_key(req: &HttpRequest) -> Option<String> {
+    if let Some(key) = req.headers().get(API_KEY_HEADER) {
+        return key.to_str().ok().map(str::to_owned);
+    }
+
+    Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().remove(API_KEY_PARAM))
+}
+
+/// Check that the request presents one of the source `api_keys`.
+/// Sources without keys are public. Returns 401 if no key was presented, and 403 if it is wrong.
+pub fn check_api_key(req: &HttpRequest, api_keys: Option<&Vec<String>>) -> Result<()> {
+    let Some(api_keys) = api_keys else {
+        return Ok(());
+    };
+
+    let key = get_api_key(req).ok_or_else(|| error::ErrorUnauthorized("API key required"))?;
+
+    // Check every key, so the response time doesn't reveal which one came close
+    let valid = api_keys.iter().fold(false, |valid, api_key| {
+        constant_time_eq(api_key, &key) | valid
+    });
+
+    if valid {
+        Ok(())
+    } else {
+        Err(error::ErrorForbidden("Invalid API key"))
+    }
+}
+
+/// Check that a request to the server presents one of the server-wide `api_keys`.
+/// Health probes are always allowed, `path` being relative to the routes.
+pub fn check_server_api_key(
+    req: &HttpRequest,
+    api_keys: Option<&Vec<String>>,
+    path: &str,
+) -> Result<()> {
+    if PUBLIC_PATHS.contains(&path) {
+        return Ok(());
+    }
+    check_api_key(req, api_keys)
+}
+
+/// Compare strings in time that depends only on their length
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.bytes()
+        .zip(b.bytes())
+        .fold(0, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq("secret", "secret"));
+        assert!(constant_time_eq("", ""));
+        assert!(!constant_time_eq("secret", "secreT"));
+        assert!(!constant_time_eq("secret", "secret2"));
+    }
+
+    #[test]
+    fn test_check_api_key() {
+        let keys = vec!["one".to_owned(), "two".to_owned()];
+
+        let req = TestRequest::default().to_http_request();
+        assert!(check_api_key(&req, None).is_ok());
+        let err = check_api_key(&req, Some(&keys)).unwrap_err();
+        assert_eq!(err.as_response_error().status_code(), 401);
+
+        let req = TestRequest::default()
+            .insert_header((API_KEY_HEADER, "two"))
+            .to_http_request();
+        assert!(check_api_key(&req, Some(&keys)).is_ok());
+
+        let req = TestRequest::with_uri("/?key=one").to_http_request();
+        assert!(check_api_key(&req, Some(&keys)).is_ok());
+
+        let req = TestRequest::with_uri("/?key=three").to_http_request();
+        let err = check_api_key(&req, Some(&keys)).unwrap_err();
+        assert_eq!(err.as_response_error().status_code(), 403);
+    }
+
+    #[test]
+    fn test_check_server_api_key() {
+        let keys = vec!["one".to_owned()];
+
+        let req = TestRequest::with_uri("/tiles/health").to_http_request();
+        assert!(check_server_api_key(&req, Some(&keys), "/health").is_ok());
+        let err = check_server_api_key(&req, Some(&keys), "/tiles/health").unwrap_err();
+        assert_eq!(err.as_response_error().status_code(), 401);
+
+        let req = TestRequest::with_uri("/tiles/index.json").to_http_request();
+        let err = check_server_api_key(&req, Some(&keys), "/index.json").unwrap_err();
+        assert_eq!(err.as_response_error().status_code(), 401);
+        assert!(check_server_api_key(&req, None, "/index.json").is_ok());
+
+        let req = TestRequest::with_uri("/tiles/index.json?key=one").to_http_request();
+        assert!(check_server_api_key(&req, Some(&keys), "/index.json").is_ok());
+    }
+}
@@ -0,0 +1,235 @@
+use crate::prettify_error;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{error, HttpRequest, Result};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::{fs, io};
+
+/// Algorithm used to sign the JWT access tokens
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum JwtAlgorithm {
+    /// HMAC with SHA-256, verified with the shared `secret`
+    HS256,
+    /// RSA with SHA-256, verified with the `public_key` of the token issuer
+    RS256,
+}
+
+/// JWT access control of the `auth` config
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct AuthConfig {
+    pub algorithm: JwtAlgorithm,
+    /// Shared secret of `HS256` tokens
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    /// Path to the PEM file with the RSA public key of `RS256` tokens
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    /// Scopes a token must have to access each protected source.
+    /// Sources that are not listed stay public.
+    #[serde(default)]
+    pub sources: HashMap<String, Vec<String>>,
+}
+
+impl AuthConfig {
+    /// Check that the key of the algorithm is set
+    pub fn validate(&self) -> io::Result<()> {
+        match (self.algorithm, &self.secret, &self.public_key) {
+            (JwtAlgorithm::HS256, Some(_), _) | (JwtAlgorithm::RS256, _, Some(_)) => Ok(()),
+            (JwtAlgorithm::HS256, None, _) => Err(io::Error::other(
+                "auth with HS256 algorithm requires a secret",
+            )),
+            (JwtAlgorithm::RS256, _, None) => Err(io::Error::other(
+                "auth with RS256 algorithm requires a public_key",
+            )),
+        }
+    }
+}
+
+/// Scopes granted by a token, as a space-separated string like OAuth2, or as a list
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Scopes {
+    Text(String),
+    List(Vec<String>),
+}
+
+impl Scopes {
+    fn contains(&self, scope: &str) -> bool {
+        match self {
+            Scopes::Text(scopes) => scopes.split_whitespace().any(|s| s == scope),
+            Scopes::List(scopes) => scopes.iter().any(|s| s == scope),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    scope: Option<Scopes>,
+}
+
+/// Verifies the `Authorization: Bearer` tokens of requests for protected sources
+pub struct JwtAuth {
+    key: DecodingKey,
+    validation: Validation,
+    sources: HashMap<String, Vec<String>>,
+}
+
+impl JwtAuth {
+    pub fn new(config: &AuthConfig) -> io::Result<Self> {
+        config.validate()?;
+        let (key, algorithm) = match config.algorithm {
+            JwtAlgorithm::HS256 => {
+                let secret = config.secret.as_deref().unwrap_or_default();
+                (
+                    DecodingKey::from_secret(secret.as_bytes()),
+                    Algorithm::HS256,
+                )
+            }
+            JwtAlgorithm::RS256 => {
+                let path = config.public_key.as_deref().unwrap_or_default();
+                let pem = fs::read(path)
+                    .map_err(|e| prettify_error!(e, "Unable to read auth public_key '{}'", path))?;
+                let key = DecodingKey::from_rsa_pem(&pem)
+                    .map_err(|e| prettify_error!(e, "Invalid auth public_key '{}'", path))?;
+                (key, Algorithm::RS256)
+            }
+        };
+
+        Ok(Self {
+            key,
+            validation: Validation::new(algorithm),
+            sources: config.sources.clone(),
+        })
+    }
+
+    /// Check that the request has a valid token with the scopes required by the source.
+    /// Returns 401 if the token is missing, invalid or expired, and 403 if it lacks a scope.
+    pub fn check(&self, req: &HttpRequest, source_id: &str) -> Result<()> {
+        let Some(required) = self.sources.get(source_id) else {
+            return Ok(());
+        };
+
+        let token = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| error::ErrorUnauthorized("Bearer token required"))?;
+
+        let claims = decode::<Claims>(token.trim(), &self.key, &self.validation)
+            .map_err(|e| match e.kind() {
+                ErrorKind::ExpiredSignature => error::ErrorUnauthorized("Token expired"),
+                _ => error::ErrorUnauthorized(format!("Invalid token: {e}")),
+            })?
+            .claims;
+
+        let missing = required.iter().find(|scope| {
+            claims
+                .scope
+                .as_ref()
+                .is_none_or(|scopes| !scopes.contains(scope))
+        });
+        match missing {
+            Some(scope) => Err(error::ErrorForbidden(format!(
+                "Token lacks the '{scope}' scope of source {source_id}"
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use jsonwebtoken::{encode, get_current_timestamp, EncodingKey, Header};
+    use serde_json::json;
+
+    const SECRET: &str = "secret";
+
+    fn auth() -> JwtAuth {
+        JwtAuth::new(&AuthConfig {
+            algorithm: JwtAlgorithm::HS256,
+            secret: Some(SECRET.to_owned()),
+            public_key: None,
+            sources: HashMap::from([
+                ("private".to_owned(), vec!["tiles:read".to_owned()]),
+                ("signed_in".to_owned(), vec![]),
+            ]),
+        })
+        .unwrap()
+    }
+
+    fn request(claims: &serde_json::Value) -> HttpRequest {
+        let key = EncodingKey::from_secret(SECRET.as_bytes());
+        let token = encode(&Header::default(), claims, &key).unwrap();
+        TestRequest::default()
+            .insert_header((AUTHORIZATION, format!("Bearer {token}")))
+            .to_http_request()
+    }
+
+    fn status(result: Result<()>) -> u16 {
+        result
+            .unwrap_err()
+            .as_response_error()
+            .status_code()
+            .as_u16()
+    }
+
+    #[test]
+    fn test_valid_token() {
+        let exp = get_current_timestamp() + 3600;
+        let req = request(&json!({ "exp": exp, "scope": "profile tiles:read" }));
+        assert!(auth().check(&req, "private").is_ok());
+        assert!(auth().check(&req, "signed_in").is_ok());
+        assert!(auth().check(&req, "public").is_ok());
+
+        let req = request(&json!({ "exp": exp, "scope": ["tiles:read"] }));
+        assert!(auth().check(&req, "private").is_ok());
+    }
+
+    #[test]
+    fn test_missing_token() {
+        let req = TestRequest::default().to_http_request();
+        assert!(auth().check(&req, "public").is_ok());
+        assert_eq!(status(auth().check(&req, "private")), 401);
+        assert_eq!(status(auth().check(&req, "signed_in")), 401);
+
+        let req = TestRequest::default()
+            .insert_header((AUTHORIZATION, "Bearer not-a-token"))
+            .to_http_request();
+        assert_eq!(status(auth().check(&req, "private")), 401);
+    }
+
+    #[test]
+    fn test_expired_token() {
+        let exp = get_current_timestamp() - 3600;
+        let req = request(&json!({ "exp": exp, "scope": "tiles:read" }));
+        assert_eq!(status(auth().check(&req, "private")), 401);
+    }
+
+    #[test]
+    fn test_insufficient_scope() {
+        let exp = get_current_timestamp() + 3600;
+        let req = request(&json!({ "exp": exp, "scope": "profile" }));
+        assert_eq!(status(auth().check(&req, "private")), 403);
+        assert!(auth().check(&req, "signed_in").is_ok());
+
+        let req = request(&json!({ "exp": exp }));
+        assert_eq!(status(auth().check(&req, "private")), 403);
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = AuthConfig {
+            algorithm: JwtAlgorithm::RS256,
+            secret: Some(SECRET.to_owned()),
+            public_key: None,
+            sources: HashMap::new(),
+        };
+        assert!(config.validate().is_err());
+        assert!(JwtAuth::new(&config).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+use crate::source::Tile;
+use actix_web::http::header::{AcceptEncoding, Encoding};
+use actix_web::{HttpMessage, HttpRequest};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io;
+use std::io::Write;
+
+/// Brotli window size, as recommended by the brotli library
+const BROTLI_LGWIN: u32 = 22;
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+/// Pick the encoding of an uncompressed tile: brotli or gzip if the client accepts them,
+/// in the order of its preference, otherwise identity
+pub fn negotiate_encoding(req: &HttpRequest) -> Encoding {
+    let supported = [Encoding::brotli(), Encoding::gzip(), Encoding::identity()];
+    req.get_header::<AcceptEncoding>()
+        .and_then(|accept| accept.negotiate(supported.iter()))
+        .unwrap_or_else(Encoding::identity)
+}
+
+/// Compress a tile with the given encoding and level (0-9).
+/// Returns `None` for encodings that are not supported.
+pub fn compress_tile(tile: &[u8], encoding: &Encoding, level: u32) -> io::Result<Option<Tile>> {
+    let compressed = if *encoding == Encoding::gzip() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+        encoder.write_all(tile)?;
+        encoder.finish()?
+    } else if *encoding == Encoding::brotli() {
+        let mut encoder =
+            brotli::CompressorWriter::new(Vec::new(), BROTLI_BUFFER_SIZE, level, BROTLI_LGWIN);
+        encoder.write_all(tile)?;
+        encoder.flush()?;
+        encoder.into_inner()
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(Tile::from(compressed)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::ACCEPT_ENCODING;
+    use actix_web::test::TestRequest;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn negotiate(accept_encoding: Option<&str>) -> Encoding {
+        let mut req = TestRequest::default();
+        if let Some(accept_encoding) = accept_encoding {
+            req = req.insert_header((ACCEPT_ENCODING, accept_encoding));
+        }
+        negotiate_encoding(&req.to_http_request())
+    }
+
+    #[test]
+    fn test_negotiate_encoding() {
+        assert_eq!(negotiate(None), Encoding::identity());
+        assert_eq!(negotiate(Some("identity")), Encoding::identity());
+        assert_eq!(negotiate(Some("deflate")), Encoding::identity());
+        assert_eq!(negotiate(Some("gzip")), Encoding::gzip());
+        assert_eq!(negotiate(Some("br, gzip")), Encoding::brotli());
+        assert_eq!(negotiate(Some("br;q=0.5, gzip")), Encoding::gzip());
+    }
+
+    #[test]
+    fn test_compress_tile() {
+        let tile = [0x1a; 1000];
+
+        let gzip = compress_tile(&tile, &Encoding::gzip(), 6).unwrap().unwrap();
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&gzip[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, tile);
+
+        let brotli = compress_tile(&tile, &Encoding::brotli(), 6)
+            .unwrap()
+            .unwrap();
+        let mut decompressed = Vec::new();
+        brotli::Decompressor::new(&brotli[..], BROTLI_BUFFER_SIZE)
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, tile);
+
+        assert!(compress_tile(&tile, &Encoding::identity(), 6)
+            .unwrap()
+            .is_none());
+    }
+}
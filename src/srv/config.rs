@@ -1,55 +1,227 @@
 use crate::config::set_option;
+use crate::file_tree::FileTreeConfig;
+use crate::source::MAX_ZOOM;
+use crate::srv::auth::AuthConfig;
+use crate::srv::tls::TlsConfig;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::io;
 
 pub const KEEP_ALIVE_DEFAULT: usize = 75;
+pub const SHUTDOWN_TIMEOUT_DEFAULT: u64 = 30;
 pub const LISTEN_ADDRESSES_DEFAULT: &str = "0.0.0.0:3000";
+pub const EMPTY_TILE_CACHE_SIZE_DEFAULT: usize = 0;
+pub const EMPTY_TILE_CACHE_TTL_DEFAULT: u64 = 60;
+pub const TILE_COMPRESSION_LEVEL_DEFAULT: u32 = 6;
+pub const TILE_COMPRESSION_LEVEL_MAX: u32 = 9;
 
-#[derive(clap::Args, Debug)]
+#[derive(clap::Args, Debug, Clone)]
 #[command(about, version)]
 pub struct SrvArgs {
     #[arg(help = format!("Connection keep alive timeout. [DEFAULT: {}]", KEEP_ALIVE_DEFAULT), short, long)]
     pub keep_alive: Option<usize>,
-    #[arg(help = format!("The socket address to bind. [DEFAULT: {}]", LISTEN_ADDRESSES_DEFAULT), short, long)]
+    #[arg(help = format!("The socket address to bind, or unix:/path/to.sock to listen on a Unix domain socket. [DEFAULT: {}]", LISTEN_ADDRESSES_DEFAULT), short, long)]
     pub listen_addresses: Option<String>,
-    /// Number of web server workers
+    /// Number of web server workers. [DEFAULT: number of CPUs]
     #[arg(short = 'W', long)]
     pub workers: Option<usize>,
+    /// Path prefix of all routes, for serving martin under a path of a reverse proxy, e.g. /tiles
+    #[arg(long)]
+    pub base_path: Option<String>,
+    /// Highest zoom level served by any source, requests for higher zoom levels fail with 400 Bad Request
+    #[arg(long)]
+    pub max_zoom: Option<u8>,
 }
 
 #[derive(Debug, Clone, Serialize, PartialEq)]
 pub struct SrvConfig {
     pub keep_alive: usize,
     pub listen_addresses: String,
+    pub dual_stack: bool,
     pub worker_processes: usize,
+    pub empty_tile_cache_size: usize,
+    pub empty_tile_cache_ttl: u64,
+    pub admin_endpoints: bool,
+    pub debug_endpoints: bool,
+    pub feature_count_header: bool,
+    pub tile_cache_size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tile_cache_ttl: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tile_cache_max_entry_size: Option<usize>,
+    pub tile_compression_level: u32,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub base_path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_max_age: Option<u64>,
+    pub shutdown_timeout: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fonts: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sprites: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_sources: Option<HashMap<String, FileTreeConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<AuthConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_keys: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tile_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tile_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_zoom: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SrvConfigBuilder {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub keep_alive: Option<usize>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(skip_serializing_if = "Option::is_none", alias = "listen")]
     pub listen_addresses: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub dual_stack: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub worker_processes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub empty_tile_cache_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub empty_tile_cache_ttl: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_endpoints: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debug_endpoints: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub feature_count_header: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tile_cache_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tile_cache_ttl: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tile_cache_max_entry_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tile_compression_level: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_max_age: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shutdown_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fonts: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sprites: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_sources: Option<HashMap<String, FileTreeConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<AuthConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_keys: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tile_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tile_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_zoom: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
 }
 
 impl SrvConfigBuilder {
     pub fn merge(&mut self, other: SrvConfigBuilder) -> &mut Self {
         set_option(&mut self.keep_alive, other.keep_alive);
         set_option(&mut self.listen_addresses, other.listen_addresses);
+        set_option(&mut self.dual_stack, other.dual_stack);
         set_option(&mut self.worker_processes, other.worker_processes);
+        set_option(&mut self.empty_tile_cache_size, other.empty_tile_cache_size);
+        set_option(&mut self.empty_tile_cache_ttl, other.empty_tile_cache_ttl);
+        set_option(&mut self.admin_endpoints, other.admin_endpoints);
+        set_option(&mut self.debug_endpoints, other.debug_endpoints);
+        set_option(&mut self.feature_count_header, other.feature_count_header);
+        set_option(&mut self.tile_cache_size, other.tile_cache_size);
+        set_option(&mut self.tile_cache_ttl, other.tile_cache_ttl);
+        set_option(
+            &mut self.tile_cache_max_entry_size,
+            other.tile_cache_max_entry_size,
+        );
+        set_option(
+            &mut self.tile_compression_level,
+            other.tile_compression_level,
+        );
+        set_option(&mut self.base_path, other.base_path);
+        set_option(&mut self.cache_max_age, other.cache_max_age);
+        set_option(&mut self.shutdown_timeout, other.shutdown_timeout);
+        set_option(&mut self.fonts, other.fonts);
+        set_option(&mut self.sprites, other.sprites);
+        set_option(&mut self.file_sources, other.file_sources);
+        set_option(&mut self.auth, other.auth);
+        set_option(&mut self.api_keys, other.api_keys);
+        set_option(&mut self.max_tile_size, other.max_tile_size);
+        set_option(&mut self.tile_timeout, other.tile_timeout);
+        set_option(&mut self.max_zoom, other.max_zoom);
+        set_option(&mut self.tls, other.tls);
         self
     }
 
     /// Apply defaults to the config, and validate if there is a connection string
     pub fn finalize(self) -> io::Result<SrvConfig> {
+        let tile_compression_level = self
+            .tile_compression_level
+            .unwrap_or(TILE_COMPRESSION_LEVEL_DEFAULT);
+        if tile_compression_level > TILE_COMPRESSION_LEVEL_MAX {
+            return Err(io::Error::other(format!(
+                "tile_compression_level must be between 0 and {TILE_COMPRESSION_LEVEL_MAX}, got {tile_compression_level}"
+            )));
+        }
+        if let Some(auth) = &self.auth {
+            auth.validate()?;
+        }
+        if self.worker_processes == Some(0) {
+            return Err(io::Error::other("worker_processes must be at least 1"));
+        }
+        for (id, source) in self.file_sources.iter().flatten() {
+            source.validate(id)?;
+        }
+        if let Some(max_zoom) = self.max_zoom.filter(|zoom| *zoom > MAX_ZOOM) {
+            return Err(io::Error::other(format!(
+                "max_zoom must be between 0 and {MAX_ZOOM}, got {max_zoom}"
+            )));
+        }
+
         Ok(SrvConfig {
             keep_alive: self.keep_alive.unwrap_or(KEEP_ALIVE_DEFAULT),
             listen_addresses: self
                 .listen_addresses
                 .unwrap_or_else(|| LISTEN_ADDRESSES_DEFAULT.to_owned()),
+            dual_stack: self.dual_stack.unwrap_or_default(),
             worker_processes: self.worker_processes.unwrap_or_else(num_cpus::get),
+            empty_tile_cache_size: self
+                .empty_tile_cache_size
+                .unwrap_or(EMPTY_TILE_CACHE_SIZE_DEFAULT),
+            empty_tile_cache_ttl: self
+                .empty_tile_cache_ttl
+                .unwrap_or(EMPTY_TILE_CACHE_TTL_DEFAULT),
+            admin_endpoints: self.admin_endpoints.unwrap_or_default(),
+            debug_endpoints: self.debug_endpoints.unwrap_or_default(),
+            feature_count_header: self.feature_count_header.unwrap_or_default(),
+            tile_cache_size: self.tile_cache_size.unwrap_or_default(),
+            tile_cache_ttl: self.tile_cache_ttl,
+            tile_cache_max_entry_size: self.tile_cache_max_entry_size,
+            tile_compression_level,
+            base_path: normalize_base_path(self.base_path.as_deref().unwrap_or_default()),
+            cache_max_age: self.cache_max_age,
+            shutdown_timeout: self.shutdown_timeout.unwrap_or(SHUTDOWN_TIMEOUT_DEFAULT),
+            fonts: self.fonts,
+            sprites: self.sprites,
+            file_sources: self.file_sources,
+            auth: self.auth,
+            api_keys: self.api_keys,
+            max_tile_size: self.max_tile_size,
+            tile_timeout: self.tile_timeout,
+            max_zoom: self.max_zoom,
+            tls: self.tls,
         })
     }
 }
@@ -59,7 +231,39 @@ impl From<SrvArgs> for SrvConfigBuilder {
         SrvConfigBuilder {
             keep_alive: args.keep_alive,
             listen_addresses: args.listen_addresses,
+            dual_stack: None,
             worker_processes: args.workers,
+            empty_tile_cache_size: None,
+            empty_tile_cache_ttl: None,
+            admin_endpoints: None,
+            debug_endpoints: None,
+            feature_count_header: None,
+            tile_cache_size: None,
+            tile_cache_ttl: None,
+            tile_cache_max_entry_size: None,
+            tile_compression_level: None,
+            base_path: args.base_path,
+            cache_max_age: None,
+            shutdown_timeout: None,
+            fonts: None,
+            sprites: None,
+            file_sources: None,
+            auth: None,
+            api_keys: None,
+            max_tile_size: None,
+            tile_timeout: None,
+            max_zoom: args.max_zoom,
+            tls: None,
         }
     }
 }
+
+/// Make a base path an empty string for the root, or a path with a leading slash
+/// and no trailing one, e.g. `tiles/` -> `/tiles`
+fn normalize_base_path(base_path: &str) -> String {
+    base_path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| format!("/{segment}"))
+        .collect()
+}
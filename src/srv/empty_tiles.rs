@@ -0,0 +1,143 @@
+use crate::source::Xyz;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+type EmptyTileKey = (String, Xyz);
+
+/// Known empty tiles of a generation of the sources, with the time they were found empty
+#[derive(Debug, Default)]
+struct Entries {
+    generation: u64,
+    found: HashMap<EmptyTileKey, Instant>,
+    order: VecDeque<EmptyTileKey>,
+}
+
+impl Entries {
+    /// Forget the tiles of older sources generations. Returns false for an older generation,
+    /// whose tiles must not be remembered.
+    fn sync(&mut self, generation: u64) -> bool {
+        if generation > self.generation {
+            self.generation = generation;
+            self.found.clear();
+            self.order.clear();
+        }
+        generation == self.generation
+    }
+}
+
+/// A bounded set of tiles known to be empty, used to skip database queries
+/// for repeated requests. The oldest entries are evicted first, and entries expire
+/// after the TTL or when the sources are reloaded.
+#[derive(Debug, Default)]
+pub struct EmptyTiles {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<Entries>,
+}
+
+impl EmptyTiles {
+    /// Create a new cache. A capacity of zero disables it.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            entries: Mutex::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn contains(&self, generation: u64, source_id: &str, xyz: &Xyz) -> bool {
+        self.is_enabled()
+            && self
+                .entries
+                .lock()
+                .map(|mut entries| {
+                    entries.sync(generation)
+                        && entries
+                            .found
+                            .get(&(source_id.to_string(), *xyz))
+                            .is_some_and(|found| found.elapsed() < self.ttl)
+                })
+                .unwrap_or_default()
+    }
+
+    pub fn insert(&self, generation: u64, source_id: &str, xyz: &Xyz) {
+        if !self.is_enabled() {
+            return;
+        }
+        if let Ok(mut entries) = self.entries.lock() {
+            if !entries.sync(generation) {
+                return;
+            }
+            let Entries { found, order, .. } = &mut *entries;
+            let key = (source_id.to_string(), *xyz);
+            // An expired entry is renewed in place
+            if found.insert(key.clone(), Instant::now()).is_none() {
+                order.push_back(key);
+            }
+            while order.len() > self.capacity {
+                if let Some(oldest) = order.pop_front() {
+                    found.remove(&oldest);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn empty_tiles_evict_oldest() {
+        let cache = EmptyTiles::new(2, TTL);
+        let xyz = |z| Xyz { z, x: 0, y: 0 };
+
+        cache.insert(0, "src", &xyz(0));
+        cache.insert(0, "src", &xyz(1));
+        cache.insert(0, "src", &xyz(1));
+        assert!(cache.contains(0, "src", &xyz(0)));
+        assert!(!cache.contains(0, "other", &xyz(0)));
+
+        cache.insert(0, "src", &xyz(2));
+        assert!(!cache.contains(0, "src", &xyz(0)));
+        assert!(cache.contains(0, "src", &xyz(1)));
+        assert!(cache.contains(0, "src", &xyz(2)));
+    }
+
+    #[test]
+    fn empty_tiles_expire() {
+        let cache = EmptyTiles::new(2, Duration::ZERO);
+        cache.insert(0, "src", &Xyz { z: 0, x: 0, y: 0 });
+        assert!(!cache.contains(0, "src", &Xyz { z: 0, x: 0, y: 0 }));
+    }
+
+    #[test]
+    fn empty_tiles_of_reloaded_sources() {
+        let cache = EmptyTiles::new(2, TTL);
+        let xyz = Xyz { z: 0, x: 0, y: 0 };
+
+        cache.insert(0, "src", &xyz);
+        assert!(!cache.contains(1, "src", &xyz));
+        // The tiles of the previous sources are forgotten, and not remembered anymore
+        assert!(!cache.contains(0, "src", &xyz));
+        cache.insert(0, "src", &xyz);
+        assert!(!cache.contains(1, "src", &xyz));
+
+        cache.insert(1, "src", &xyz);
+        assert!(cache.contains(1, "src", &xyz));
+    }
+
+    #[test]
+    fn empty_tiles_disabled() {
+        let cache = EmptyTiles::new(0, TTL);
+        cache.insert(0, "src", &Xyz { z: 0, x: 0, y: 0 });
+        assert!(!cache.contains(0, "src", &Xyz { z: 0, x: 0, y: 0 }));
+    }
+}
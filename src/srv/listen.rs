@@ -0,0 +1,266 @@
+//! Binding of the server to TCP addresses, a dual-stack socket or a Unix domain socket.
+
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr, TcpListener, ToSocketAddrs};
+use std::path::Path;
+
+/// Prefix of a `listen_addresses` value that is the path of a Unix domain socket,
+/// e.g. `unix:/run/martin/martin.sock`
+pub const UNIX_SOCKET_PREFIX: &str = "unix:";
+
+/// Permissions of the socket file, so that a reverse proxy running as another user
+/// can connect to it, like it could to a TCP address
+#[cfg(unix)]
+const UNIX_SOCKET_MODE: u32 = 0o666;
+
+/// Path of the Unix domain socket to listen on, if `listen_addresses` is one
+pub fn unix_socket_path(listen_addresses: &str) -> Option<&Path> {
+    listen_addresses
+        .strip_prefix(UNIX_SOCKET_PREFIX)
+        .map(Path::new)
+}
+
+/// Resolve the TCP `listen_addresses`, e.g. `0.0.0.0:3000`, `[::]:3000` or `localhost:3000`
+pub fn parse_listen_addresses(listen_addresses: &str) -> io::Result<Vec<SocketAddr>> {
+    let invalid = |reason: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid listen_addresses '{listen_addresses}', expected host:port like 0.0.0.0:3000 or [::]:3000: {reason}"),
+        )
+    };
+    let addrs: Vec<_> = listen_addresses
+        .to_socket_addrs()
+        .map_err(|e| invalid(e.to_string()))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(invalid("it does not resolve to any address".to_owned()));
+    }
+    Ok(addrs)
+}
+
+/// Port of the `listen_addresses` of a dual-stack server, which must listen on all interfaces,
+/// e.g. `0.0.0.0:3000` or `[::]:3000`
+pub fn dual_stack_port(listen_addresses: &str) -> io::Result<u16> {
+    match listen_addresses.parse::<SocketAddr>() {
+        Ok(addr) if addr.ip().is_unspecified() => Ok(addr.port()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("dual_stack requires listen_addresses on all interfaces like 0.0.0.0:3000 or [::]:3000, got '{listen_addresses}'"),
+        )),
+    }
+}
+
+/// Listen on all IPv4 and IPv6 interfaces at `port` with a single IPv6 socket
+/// that also accepts IPv4 connections
+pub fn bind_dual_stack(port: u16) -> io::Result<TcpListener> {
+    let addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+    let bind = || {
+        let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_only_v6(false)?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        Ok(socket.into())
+    };
+    bind().map_err(|e: io::Error| {
+        io::Error::new(
+            e.kind(),
+            format!("Can't bind a dual-stack socket to {addr}: {e}"),
+        )
+    })
+}
+
+#[cfg(unix)]
+pub use unix::bind_unix_socket;
+
+#[cfg(unix)]
+mod unix {
+    use super::UNIX_SOCKET_MODE;
+    use log::info;
+    use std::fs::{self, Permissions};
+    use std::io;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::Path;
+
+    /// Listen on the socket at `path`, replacing the socket file left by a server
+    /// that was not shut down cleanly
+    pub fn bind_unix_socket(path: &Path) -> io::Result<UnixListener> {
+        remove_stale_socket(path)?;
+        let listener = UnixListener::bind(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Can't bind to unix socket {}: {e}", path.display()),
+            )
+        })?;
+        fs::set_permissions(path, Permissions::from_mode(UNIX_SOCKET_MODE))?;
+        Ok(listener)
+    }
+
+    /// Remove the socket file at `path` if no server is listening on it anymore.
+    /// Fails if the path is not a socket, or if another server is still listening on it.
+    fn remove_stale_socket(path: &Path) -> io::Result<()> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Can't bind to {}, it is not a unix socket", path.display()),
+            ));
+        }
+        match UnixStream::connect(path) {
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("Unix socket {} is used by another server", path.display()),
+            )),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                info!("Removing the stale unix socket {}", path.display());
+                fs::remove_file(path)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, TcpStream};
+
+    #[test]
+    fn test_parse_listen_addresses() {
+        let addrs = parse_listen_addresses("[::1]:3000").unwrap();
+        assert_eq!(addrs, vec![SocketAddr::from((Ipv6Addr::LOCALHOST, 3000))]);
+        let addrs = parse_listen_addresses("0.0.0.0:3000").unwrap();
+        assert_eq!(addrs, vec![SocketAddr::from((Ipv4Addr::UNSPECIFIED, 3000))]);
+
+        for invalid in ["[::1]", "0.0.0.0:port", "0.0.0.0:70000"] {
+            let error = parse_listen_addresses(invalid).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{invalid}");
+            assert!(error.to_string().contains(invalid), "{error}");
+        }
+    }
+
+    #[test]
+    fn test_dual_stack_port() {
+        assert_eq!(dual_stack_port("0.0.0.0:3000").unwrap(), 3000);
+        assert_eq!(dual_stack_port("[::]:3001").unwrap(), 3001);
+        assert!(dual_stack_port("127.0.0.1:3000").is_err());
+        assert!(dual_stack_port("localhost:3000").is_err());
+    }
+
+    #[test]
+    fn test_bind_dual_stack() {
+        let listener = bind_dual_stack(0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accept = std::thread::spawn(move || {
+            (0..2)
+                .map(|_| {
+                    let (mut stream, peer) = listener.accept().unwrap();
+                    stream.write_all(b"ok").unwrap();
+                    peer
+                })
+                .collect::<Vec<_>>()
+        });
+
+        for ip in [Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()] {
+            let mut stream = TcpStream::connect(SocketAddr::new(ip, port)).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert_eq!(response, "ok", "{ip}");
+        }
+        let peers = accept.join().unwrap();
+        assert_eq!(peers.len(), 2);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod unix_tests {
+    use super::*;
+    use actix_web::{web, App, HttpServer};
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::PathBuf;
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("martin_{name}_{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_unix_socket_path() {
+        assert_eq!(
+            unix_socket_path("unix:/run/martin.sock"),
+            Some(Path::new("/run/martin.sock"))
+        );
+        assert_eq!(unix_socket_path("0.0.0.0:3000"), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_serve_on_unix_socket() {
+        let path = socket_path("serve");
+        let listener = bind_unix_socket(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, UNIX_SOCKET_MODE);
+
+        let server = HttpServer::new(|| App::new().route("/", web::get().to(|| async { "ok" })))
+            .listen_uds(listener)
+            .unwrap()
+            .workers(1)
+            .disable_signals()
+            .run();
+        let handle = server.handle();
+        let server = actix_rt::spawn(server);
+
+        let request = std::thread::spawn({
+            let path = path.clone();
+            move || {
+                let mut stream = UnixStream::connect(path).unwrap();
+                stream
+                    .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                    .unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).unwrap();
+                response
+            }
+        });
+        let response = web::block(move || request.join().unwrap()).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("ok"));
+
+        // The server removes the socket file when it stops
+        handle.stop(true).await;
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_bind_replaces_stale_socket() {
+        let path = socket_path("stale");
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let _listener = bind_unix_socket(&path).unwrap();
+
+        let error = bind_unix_socket(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_bind_keeps_other_files() {
+        let path = socket_path("file");
+        fs::write(&path, "not a socket").unwrap();
+        let error = bind_unix_socket(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not a socket");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,96 @@
+use actix_web::{HttpResponse, Result};
+use bb8::State;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::time::Duration;
+
+/// Tile request metrics, shared by all server workers
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    duration: HistogramVec,
+    errors: IntCounterVec,
+    pool_connections: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> prometheus::Result<Self> {
+        let requests = IntCounterVec::new(
+            Opts::new("martin_tile_requests_total", "Number of tile requests"),
+            &["source", "status"],
+        )?;
+        let duration = HistogramVec::new(
+            HistogramOpts::new(
+                "martin_tile_request_duration_seconds",
+                "Tile request duration in seconds",
+            ),
+            &["source"],
+        )?;
+        let errors = IntCounterVec::new(
+            Opts::new(
+                "martin_source_errors_total",
+                "Number of failed tile requests",
+            ),
+            &["source"],
+        )?;
+        let pool_connections = IntGaugeVec::new(
+            Opts::new(
+                "martin_pool_connections",
+                "Number of database connections in the pool",
+            ),
+            &["pool", "state"],
+        )?;
+
+        let registry = Registry::new();
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(duration.clone()))?;
+        registry.register(Box::new(errors.clone()))?;
+        registry.register(Box::new(pool_connections.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests,
+            duration,
+            errors,
+            pool_connections,
+        })
+    }
+
+    /// Record the outcome of a tile request
+    pub fn observe(&self, source_id: &str, result: &Result<HttpResponse>, elapsed: Duration) {
+        let status = match result {
+            Ok(response) => response.status(),
+            Err(e) => e.as_response_error().status_code(),
+        };
+        self.requests
+            .with_label_values(&[source_id, status.as_str()])
+            .inc();
+        self.duration
+            .with_label_values(&[source_id])
+            .observe(elapsed.as_secs_f64());
+        if status.is_server_error() {
+            self.errors.with_label_values(&[source_id]).inc();
+        }
+    }
+
+    /// Record the current idle and active connections of a pool
+    pub fn observe_pool(&self, pool: &str, state: &State) {
+        let idle = i64::from(state.idle_connections);
+        let active = i64::from(state.connections) - idle;
+        self.pool_connections
+            .with_label_values(&[pool, "idle"])
+            .set(idle);
+        self.pool_connections
+            .with_label_values(&[pool, "active"])
+            .set(active);
+    }
+
+    /// Encode all metrics in the Prometheus text format
+    pub fn encode(&self) -> prometheus::Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        String::from_utf8(buffer).map_err(|e| prometheus::Error::Msg(e.to_string()))
+    }
+}
@@ -1,2 +1,15 @@
+pub mod access;
+pub mod api_key;
+pub mod auth;
+pub mod compression;
 pub mod config;
+pub mod empty_tiles;
+pub mod listen;
+#[cfg(feature = "observability")]
+pub mod metrics;
+#[cfg(feature = "otel")]
+pub mod otel;
 pub mod server;
+pub mod shutdown;
+pub mod tls;
+pub mod url;
@@ -0,0 +1,106 @@
+//! OpenTelemetry tracing of the requests, exported with OTLP over HTTP.
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::HeaderMap;
+use log::debug;
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use std::future::Future;
+use std::io;
+use tracing::instrument::Instrumented;
+use tracing::{info_span, Instrument, Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::Registry;
+
+/// Export the spans of the whole process with OTLP, configured by the standard
+/// `OTEL_EXPORTER_OTLP_*` and `OTEL_SERVICE_NAME` environment variables.
+/// The provider must be shut down on exit to flush the last spans.
+pub fn init_tracing() -> io::Result<SdkTracerProvider> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .build()
+        .map_err(io::Error::other)?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .build();
+    tracing::subscriber::set_global_default(subscriber(&provider)).map_err(io::Error::other)?;
+    Ok(provider)
+}
+
+/// Subscriber recording the spans with a tracer of the provider
+pub fn subscriber(provider: &SdkTracerProvider) -> impl Subscriber + Send + Sync {
+    let tracer = provider.tracer("martin");
+    Registry::default().with(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Span of a request, continuing the trace of its `traceparent` header if there is one
+pub fn request_span(req: &ServiceRequest) -> Span {
+    let span = info_span!("request", method = %req.method(), path = req.path());
+    let parent = TraceContextPropagator::new().extract(&HeaderExtractor(req.headers()));
+    if let Err(e) = span.set_parent(parent) {
+        debug!("Can't continue the trace of the request: {e}");
+    }
+    span
+}
+
+/// Handle the request by `call` in the span of the request
+pub fn in_request_span<F: Future>(
+    req: ServiceRequest,
+    call: impl FnOnce(ServiceRequest) -> F,
+) -> Instrumented<F> {
+    let span = request_span(&req);
+    let response = span.in_scope(|| call(req));
+    response.instrument(span)
+}
+
+/// Reads the trace context propagated by the request headers
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use opentelemetry::trace::TraceContextExt;
+    use opentelemetry_sdk::trace::InMemorySpanExporter;
+
+    #[test]
+    fn test_request_span_continues_trace() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+
+        tracing::subscriber::with_default(subscriber(&provider), || {
+            let req = TestRequest::get()
+                .uri("/table_source/0/0/0")
+                .insert_header((
+                    "traceparent",
+                    "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+                ))
+                .to_srv_request();
+            let span = request_span(&req);
+            let trace_id = span.context().span().span_context().trace_id();
+            assert_eq!(trace_id.to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        });
+
+        let spans = exporter.get_finished_spans().unwrap();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].name, "request");
+        assert_eq!(spans[0].parent_span_id.to_string(), "00f067aa0ba902b7");
+        assert!(spans[0].parent_span_is_remote);
+    }
+}
@@ -1,29 +1,208 @@
+use crate::builder::Martin;
+use crate::cache::{CachedSource, TileCache};
 use crate::config::Config;
+use crate::file_tree::FileSources;
+use crate::fonts::FontSources;
+use crate::logging::set_log_filter;
+use crate::mvt::{count_features, is_compressed_mvt, merge_mvt, summarize_mvt};
 use crate::pg::composite_source::CompositeSource;
-use crate::pg::db::{get_connection, Pool};
+use crate::pg::db::{get_connection, resolve_sources, with_retries, Pool};
 use crate::pg::function_source::FunctionSources;
-use crate::pg::table_source::{TableSource, TableSources};
-use crate::pg::utils::parse_x_rewrite_url;
-use crate::source::{Source, UrlQuery, Xyz};
+use crate::pg::geojson_source::GeoJsonSource;
+use crate::pg::raster_source::{HighDpiRasterSource, RasterSources};
+use crate::pg::table_source::{parse_extent_override, TableSource, TableSources};
+use crate::pg::utils::{parse_x_rewrite_url, prettify_error};
+use crate::source::{
+    is_reserved_header, EmptyTile, Source, Tile, TileFormat, TileMatrixSet, UrlQuery, Xyz,
+};
+use crate::sprites::{SpriteFormat, SpriteSources};
+use crate::srv::access::check_access;
+use crate::srv::api_key::API_KEY_PARAM;
+use crate::srv::auth::JwtAuth;
+use crate::srv::compression::{compress_tile, negotiate_encoding};
+use crate::srv::empty_tiles::EmptyTiles;
+#[cfg(unix)]
+use crate::srv::listen::bind_unix_socket;
+use crate::srv::listen::{
+    bind_dual_stack, dual_stack_port, parse_listen_addresses, unix_socket_path,
+};
+#[cfg(feature = "observability")]
+use crate::srv::metrics::Metrics;
+#[cfg(feature = "otel")]
+use crate::srv::otel;
+use crate::srv::shutdown::InFlight;
+use crate::srv::tls::load_tls_config;
+use crate::srv::url::{public_origin, public_url, route_path};
 use actix_cors::Cors;
-use actix_web::dev::Server;
-use actix_web::http::header::CACHE_CONTROL;
+use actix_rt::time::timeout;
+use actix_web::dev::{Server, Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{
+    CacheControl, CacheDirective, Encoding, EntityTag, HeaderName, HeaderValue, IfNoneMatch,
+    TryIntoHeaderValue, ACCEPT, CACHE_CONTROL, CONTENT_ENCODING, ETAG, VARY,
+};
 use actix_web::http::Uri;
 use actix_web::middleware::TrailingSlash;
-use actix_web::web::{Data, Path, Query, ServiceConfig};
+use actix_web::web::{self, Data, Path, Query, ServiceConfig};
 use actix_web::{
-    error, middleware, route, App, Error, HttpRequest, HttpResponse, HttpServer, Responder, Result,
+    error, middleware, route, App, Error, HttpMessage, HttpRequest, HttpResponse, HttpServer,
+    Responder, Result,
 };
-use log::error;
-use serde::Deserialize;
-use std::collections::HashMap;
+use arc_swap::ArcSwap;
+use bytes::BytesMut;
+use futures::future::{join, join_all, try_join_all};
+use itertools::Itertools;
+use log::{debug, error, info, warn};
+use openssl::sha::Sha256;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::ops::Deref;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-pub struct AppState {
-    pub pool: Pool,
+/// Response header listing the composite source layers that failed and were left out of the tile
+pub const PARTIAL_TILE_HEADER: &str = "x-tile-partial";
+
+/// Query parameter of table source tile requests overriding the extent of their sources
+pub const EXTENT_PARAM: &str = "extent";
+
+/// Query parameter of table source tile requests keeping only some of their layers,
+/// e.g. `?layers=roads,rivers`
+pub const LAYERS_PARAM: &str = "layers";
+
+/// Response header listing the requested layers that none of the sources has, so were ignored
+pub const UNKNOWN_LAYERS_HEADER: &str = "x-tile-unknown-layers";
+
+/// Response header with the number of features in the tile
+pub const FEATURE_COUNT_HEADER: &str = "x-feature-count";
+
+/// Response header of tiles with a layer capped by the `max_feature_count` of its source
+pub const TRUNCATED_TILE_HEADER: &str = "x-tile-truncated";
+
+/// How long the readiness probe waits for each check
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long a table source version token is reused before its `version_query` runs again
+const SOURCE_VERSION_TTL: Duration = Duration::from_secs(5);
+
+/// Sources served by the server, replaced as a whole when the config is reloaded
+#[derive(Debug, Default)]
+pub struct Sources {
     pub table_sources: TableSources,
     pub function_sources: FunctionSources,
+    pub raster_sources: RasterSources,
+    /// Index in the config `databases` of the extra database serving each source.
+    /// Sources of the main database are not listed.
+    pub databases: HashMap<String, usize>,
+    /// Incremented by every reload. Cached tiles and version tokens are only reused
+    /// by the generation of the sources they were fetched with.
+    pub generation: u64,
+}
+
+impl Sources {
+    /// Index of the extra database serving the source, or `None` for the main database
+    fn get_database(&self, source_id: &str) -> Option<usize> {
+        self.databases.get(source_id).copied()
+    }
+}
+
+pub type SharedSources = Arc<ArcSwap<Sources>>;
+
+/// Generation of the sources a version token was fetched for, when, and the token
+pub type SourceVersion = (u64, Instant, Option<String>);
+
+impl From<&Config> for Sources {
+    fn from(config: &Config) -> Self {
+        let mut sources = Self {
+            table_sources: config.pg.table_sources.clone(),
+            function_sources: config.pg.function_sources.clone(),
+            raster_sources: config.pg.raster_sources.clone(),
+            databases: HashMap::new(),
+            generation: 0,
+        };
+        for (index, database) in config.pg.databases.iter().enumerate() {
+            for (id, source) in &database.table_sources {
+                sources.table_sources.insert(id.clone(), source.clone());
+                sources.databases.insert(id.clone(), index);
+            }
+            for (id, source) in &database.function_sources {
+                sources.function_sources.insert(id.clone(), source.clone());
+                sources.databases.insert(id.clone(), index);
+            }
+            for (id, source) in &database.raster_sources {
+                sources.raster_sources.insert(id.clone(), source.clone());
+                sources.databases.insert(id.clone(), index);
+            }
+        }
+        for (alias, id) in &config.pg.aliases {
+            if let Some(source) = sources.table_sources.get(id).cloned() {
+                sources.table_sources.insert(alias.clone(), source);
+            }
+            if let Some(source) = sources.function_sources.get(id).cloned() {
+                sources.function_sources.insert(alias.clone(), source);
+            }
+            if let Some(source) = sources.raster_sources.get(id).cloned() {
+                sources.raster_sources.insert(alias.clone(), source);
+            }
+            if let Some(index) = sources.get_database(id) {
+                sources.databases.insert(alias.clone(), index);
+            }
+        }
+        sources
+    }
+}
+
+pub struct AppState {
+    pub pool: Pool,
+    /// Pool used only by tile requests at or above `reserved_pool_minzoom`
+    pub reserved_pool: Option<Pool>,
+    pub reserved_pool_minzoom: u8,
+    /// Pools of the extra `databases`, in config order
+    pub database_pools: Vec<Pool>,
+    pub sources: SharedSources,
+    /// Recently fetched table source version tokens, keyed by source id
+    pub source_versions: Mutex<HashMap<String, SourceVersion>>,
+    /// Tiles recently found to be empty, served without querying the database
+    pub empty_tiles: EmptyTiles,
+    /// Enable endpoints that change the server at runtime, like `PUT /log-level`
+    pub admin_endpoints: bool,
+    /// Enable endpoints showing how sources are served, like `GET /debug/sql`
+    pub debug_endpoints: bool,
+    /// Recently served tiles, shared by all workers
+    pub tile_cache: Option<Arc<TileCache>>,
+    #[cfg(feature = "observability")]
+    pub metrics: Arc<Metrics>,
+    /// Add the `X-Feature-Count` header to tile responses
+    pub feature_count_header: bool,
+    /// Compression level of uncompressed tiles served with gzip or brotli
+    pub tile_compression_level: u32,
+    /// Leave out failed layers from composite tiles instead of failing the whole tile,
+    /// unless their table source sets `partial_composite_tiles`
+    pub partial_composite_tiles: bool,
+    /// How many times a tile query is retried when the database connection was lost
+    pub max_retries: u32,
+    /// Cache max-age of all tiles, overriding the one suggested by their sources
+    pub cache_max_age: Option<Duration>,
+    /// Directory of the glyph ranges served by the font routes
+    pub fonts: Option<FontSources>,
+    /// Sprites served by the sprite routes
+    pub sprites: Option<SpriteSources>,
+    /// Sources of the `file_sources` directory trees and GeoParquet files, served by the file routes
+    pub file_sources: Option<FileSources>,
+    /// JWT verification of the sources protected by the `auth` config
+    pub auth: Option<Arc<JwtAuth>>,
+    /// API keys required by every route but the health probes. When set,
+    /// the `key` query parameter is not for functions.
+    pub api_keys: Option<Vec<String>>,
+    /// Largest tile in bytes served by the tile routes, larger ones fail with 500
+    pub max_tile_size: Option<usize>,
+    /// Longest time to serve a tile, including waiting for a connection, longer ones fail with 504
+    pub tile_timeout: Option<Duration>,
+    /// Highest zoom level of the tile requests, higher ones fail with 400 whatever the source
+    pub max_zoom: Option<u8>,
+    /// Path prefix of all routes, included in the URLs of the responses
+    pub base_path: String,
 }
 
 #[derive(Deserialize)]
@@ -39,21 +218,19 @@ struct CompositeSourceRequest {
 #[derive(Deserialize)]
 struct TileRequest {
     source_id: String,
-    z: i32,
-    x: i32,
-    y: i32,
-    #[allow(dead_code)]
-    format: String,
+    z: String,
+    x: String,
+    /// Tile row, with an optional format extension, e.g. `3` or `3.pbf`
+    y: String,
 }
 
 #[derive(Deserialize)]
 struct CompositeTileRequest {
     source_ids: String,
-    z: i32,
-    x: i32,
-    y: i32,
-    #[allow(dead_code)]
-    format: String,
+    z: String,
+    x: String,
+    /// Tile row, with an optional format extension, e.g. `3` or `3.pbf`
+    y: String,
 }
 
 fn map_internal_error<T: std::fmt::Display>(e: T) -> Error {
@@ -61,6 +238,188 @@ fn map_internal_error<T: std::fmt::Display>(e: T) -> Error {
     error::ErrorInternalServerError(e.to_string())
 }
 
+/// Report a saturated pool as 503, so clients and load balancers can back off, and a query
+/// canceled by its `statement_timeout` as 504. Other errors, including queries that failed
+/// after retries, are 500
+fn map_connection_error(e: io::Error) -> Error {
+    error!("{e}");
+    match e.kind() {
+        io::ErrorKind::TimedOut => error::ErrorServiceUnavailable(e.to_string()),
+        io::ErrorKind::Interrupted => error::ErrorGatewayTimeout(e.to_string()),
+        _ => error::ErrorInternalServerError(e.to_string()),
+    }
+}
+
+/// Fail with 504 Gateway Timeout when serving a tile takes longer than `tile_timeout`
+pub async fn with_tile_timeout<T>(
+    tile_timeout: Option<Duration>,
+    source_id: &str,
+    xyz: &Xyz,
+    future: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    let Some(tile_timeout) = tile_timeout else {
+        return future.await;
+    };
+    timeout(tile_timeout, future).await.unwrap_or_else(|_| {
+        warn!(
+            "Tile {}/{}/{} of source {source_id} took longer than tile_timeout {}ms",
+            xyz.z,
+            xyz.x,
+            xyz.y,
+            tile_timeout.as_millis()
+        );
+        Err(error::ErrorGatewayTimeout(format!(
+            "Tile of source {source_id} timed out"
+        )))
+    })
+}
+
+/// Refuse to serve a tile larger than `max_tile_size`, which is likely a bug of its source
+fn check_tile_size(state: &AppState, source_id: &str, xyz: &Xyz, tile: &Tile) -> Result<()> {
+    match state.max_tile_size {
+        Some(max_tile_size) if tile.len() > max_tile_size => {
+            warn!(
+                "Tile {}/{}/{} of source {source_id} is {} bytes, larger than max_tile_size {max_tile_size}",
+                xyz.z,
+                xyz.x,
+                xyz.y,
+                tile.len()
+            );
+            Err(error::ErrorInternalServerError(format!(
+                "Tile of source {source_id} exceeds max_tile_size"
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Parse tile coordinates, where `y` may end with the extension of the requested format.
+/// Any format other than the one served by the source is a bad request.
+fn parse_xyz(
+    z: &str,
+    x: &str,
+    y: &str,
+    format: TileFormat,
+    tile_matrix_set: TileMatrixSet,
+    max_zoom: Option<u8>,
+) -> Result<Xyz> {
+    let y = match y.split_once('.') {
+        Some((y, extension)) if format.extensions().contains(&extension) => y,
+        Some((_, extension)) => {
+            return Err(error::ErrorBadRequest(format!(
+                "Unsupported tile format '{extension}', expected one of: {}",
+                format.extensions().join(", ")
+            )));
+        }
+        None => y,
+    };
+    let xyz = Xyz::from_path_in(z, x, y, tile_matrix_set)
+        .map_err(|e| error::ErrorBadRequest(e.to_string()))?;
+    match max_zoom {
+        Some(max_zoom) if xyz.z > i32::from(max_zoom) => Err(error::ErrorBadRequest(format!(
+            "Zoom level {} is above the max_zoom {max_zoom} of the server",
+            xyz.z
+        ))),
+        _ => Ok(xyz),
+    }
+}
+
+/// Table source tiles are served as GeoJSON when requested with the `.geojson` extension,
+/// or without an extension and with an `Accept: application/geo+json` header
+fn get_table_tile_format(req: &HttpRequest, y: &str) -> TileFormat {
+    let geojson = match y.split_once('.') {
+        Some((_, extension)) => TileFormat::Json.extensions().contains(&extension),
+        None => req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| {
+                accept.split(',').any(|media_type| {
+                    media_type.split(';').next().unwrap_or_default().trim()
+                        == TileFormat::Json.content_type()
+                })
+            }),
+    };
+    if geojson {
+        TileFormat::Json
+    } else {
+        TileFormat::Mvt
+    }
+}
+
+/// Tile matrix set of table sources combined into a composite source, which must share
+/// their tile scheme and tile matrix set
+fn get_shared_tile_matrix_set(sources: &[TableSource]) -> Result<TileMatrixSet> {
+    if !sources
+        .iter()
+        .map(|src| src.tile_scheme.unwrap_or_default())
+        .all_equal()
+    {
+        return Err(error::ErrorBadRequest(
+            "Can't combine table sources with different tile schemes",
+        ));
+    }
+    let tile_matrix_set = sources
+        .first()
+        .and_then(|src| src.tile_matrix_set)
+        .unwrap_or_default();
+    if sources
+        .iter()
+        .any(|src| src.tile_matrix_set.unwrap_or_default() != tile_matrix_set)
+    {
+        return Err(error::ErrorBadRequest(
+            "Can't combine table sources with different tile matrix sets",
+        ));
+    }
+    Ok(tile_matrix_set)
+}
+
+/// Table sources combined into a single query must all come from the same database
+fn get_shared_database(all_sources: &Sources, sources: &[TableSource]) -> Result<Option<usize>> {
+    let database = sources
+        .first()
+        .and_then(|src| all_sources.get_database(&src.id));
+    if sources
+        .iter()
+        .any(|src| all_sources.get_database(&src.id) != database)
+    {
+        return Err(error::ErrorBadRequest(
+            "Can't combine table sources from different databases",
+        ));
+    }
+    Ok(database)
+}
+
+/// Build the tiles URL template of a TileJSON request as seen by the client,
+/// honoring the `X-Rewrite-URL` header and the forwarded headers of a reverse proxy
+fn get_tiles_url(req: &HttpRequest, state: &AppState, format: TileFormat) -> Result<String> {
+    let extension = format.extensions()[0];
+    let query = if req.query_string().is_empty() {
+        String::new()
+    } else {
+        format!("?{}", req.query_string())
+    };
+    let tiles = format!("{{z}}/{{x}}/{{y}}.{extension}{query}");
+
+    // The rewritten URL is the whole path requested by the client, any prefix included
+    let tiles_url = match req
+        .headers()
+        .get("x-rewrite-url")
+        .and_then(parse_x_rewrite_url)
+    {
+        Some(tiles_path) => format!("{}{tiles_path}/{tiles}", public_origin(req)),
+        None => {
+            let tiles_path = route_path(req, &state.base_path).trim_end_matches(".json");
+            public_url(req, &state.base_path, &format!("{tiles_path}/{tiles}"))
+        }
+    };
+
+    tiles_url
+        .parse::<Uri>()
+        .map(|tiles_url| tiles_url.to_string())
+        .map_err(|e| error::ErrorBadRequest(format!("Can't build tiles URL: {e}")))
+}
+
 /// Return 200 OK if healthy. Used for readiness and liveness probes.
 #[route("/healthz", method = "GET", method = "HEAD")]
 async fn get_health() -> impl Responder {
@@ -69,9 +428,221 @@ async fn get_health() -> impl Responder {
         .message_body("OK")
 }
 
+/// Liveness probe: return 200 OK as long as the server responds
+#[route("/health", method = "GET", method = "HEAD")]
+async fn get_liveness() -> impl Responder {
+    HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .json(json!({ "status": "ok" }))
+}
+
+/// Readiness probe: return 200 OK if the databases of the sources can be queried and the files
+/// of the file sources can be read, or 503 Service Unavailable with the ids of the failing sources.
+/// The checks run concurrently, each with a timeout.
+#[route("/ready", method = "GET", method = "HEAD")]
+async fn get_readiness(state: Data<AppState>) -> impl Responder {
+    let sources = state.sources.load();
+    let source_ids = sources
+        .table_sources
+        .keys()
+        .chain(sources.function_sources.keys())
+        .chain(sources.raster_sources.keys());
+
+    // The reserved pool serves the sources of the main database, like the main pool.
+    // Pools without sources are not checked.
+    let mut pools = vec![("pool".to_owned(), &state.pool, None)];
+    if let Some(reserved_pool) = &state.reserved_pool {
+        pools.push(("reserved_pool".to_owned(), reserved_pool, None));
+    }
+    for (index, pool) in state.database_pools.iter().enumerate() {
+        pools.push((format!("databases.{index}"), pool, Some(index)));
+    }
+    let pool_checks = pools.into_iter().map(|(name, pool, database)| {
+        let ids: Vec<&String> = source_ids
+            .clone()
+            .filter(|id| sources.get_database(id) == database)
+            .collect();
+        async move {
+            if ids.is_empty() || is_ready(&name, ping(pool)).await {
+                Vec::new()
+            } else {
+                ids
+            }
+        }
+    });
+    let file_checks = state.file_sources.iter().flatten().map(|(id, source)| {
+        let source = source.clone();
+        let check = async {
+            web::block(move || source.check())
+                .await
+                .map_err(io::Error::other)?
+        };
+        async move { (!is_ready(id, check).await).then_some(id) }
+    });
+    let (pools, files) = join(join_all(pool_checks), join_all(file_checks)).await;
+
+    let failing: Vec<&String> = pools
+        .into_iter()
+        .flatten()
+        .chain(files.into_iter().flatten())
+        .sorted()
+        .dedup()
+        .collect();
+    let mut response = if failing.is_empty() {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    response.insert_header((CACHE_CONTROL, "no-cache"));
+    if failing.is_empty() {
+        response.json(json!({ "status": "ok" }))
+    } else {
+        response.json(json!({ "status": "unavailable", "failing": failing }))
+    }
+}
+
+/// Run a readiness check within the timeout, logging why it failed
+async fn is_ready(name: &str, check: impl std::future::Future<Output = io::Result<()>>) -> bool {
+    match timeout(READY_TIMEOUT, check).await {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            error!("Readiness check of {name} failed: {e}");
+            false
+        }
+        Err(_) => {
+            error!("Readiness check of {name} timed out");
+            false
+        }
+    }
+}
+
+async fn ping(pool: &Pool) -> io::Result<()> {
+    let connection = get_connection(pool).await?;
+    connection
+        .simple_query("SELECT 1")
+        .await
+        .map_err(|e| prettify_error!(e, "Can't query the database"))?;
+    Ok(())
+}
+
+/// Tile request metrics in the Prometheus text format
+#[cfg(feature = "observability")]
+#[route("/metrics", method = "GET")]
+async fn get_metrics(state: Data<AppState>) -> Result<HttpResponse> {
+    state.metrics.observe_pool("pool", &state.pool.state());
+    if let Some(reserved_pool) = &state.reserved_pool {
+        state
+            .metrics
+            .observe_pool("reserved_pool", &reserved_pool.state());
+    }
+    for (index, pool) in state.database_pools.iter().enumerate() {
+        state
+            .metrics
+            .observe_pool(&format!("databases.{index}"), &pool.state());
+    }
+    let metrics = state.metrics.encode().map_err(map_internal_error)?;
+    Ok(HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(metrics))
+}
+
+/// Change the log filter at runtime, using the same syntax as `RUST_LOG`, e.g. `martin=debug`
+#[route("/log-level", method = "PUT")]
+async fn put_log_level(body: String, state: Data<AppState>) -> Result<HttpResponse> {
+    if !state.admin_endpoints {
+        return Err(error::ErrorNotFound("Admin endpoints are disabled"));
+    }
+
+    let filters = body.trim();
+    set_log_filter(filters).map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidInput => error::ErrorBadRequest(e.to_string()),
+        _ => map_internal_error(e),
+    })?;
+    info!("Log filter changed to {filters}");
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// SQL of a table or function source tile, returned without running it
+#[route("/debug/sql/{source_id}/{z}/{x}/{y}", method = "GET")]
+async fn get_debug_sql(
+    path: Path<TileRequest>,
+    query: Query<HashMap<String, String>>,
+    state: Data<AppState>,
+) -> Result<HttpResponse> {
+    if !state.debug_endpoints {
+        return Err(error::ErrorNotFound("Debug endpoints are disabled"));
+    }
+
+    let all_sources = state.sources.load();
+    let sql = if let Some(source) = all_sources.table_sources.get(&path.source_id) {
+        let xyz = parse_xyz(
+            &path.z,
+            &path.x,
+            &path.y,
+            TileFormat::Mvt,
+            source.tile_matrix_set.unwrap_or_default(),
+            state.max_zoom,
+        )?;
+        source.build_tile_query(&xyz)
+    } else if let Some(source) = all_sources.function_sources.get(&path.source_id) {
+        let xyz = parse_xyz(
+            &path.z,
+            &path.x,
+            &path.y,
+            TileFormat::Mvt,
+            TileMatrixSet::default(),
+            state.max_zoom,
+        )?;
+        let mut query = query.into_inner();
+        if source.api_keys.is_some() || state.api_keys.is_some() {
+            query.remove(API_KEY_PARAM);
+        }
+        let query_json = source
+            .query_to_json(&query)
+            .map_err(|e| error::ErrorBadRequest(e.to_string()))?;
+        // The arguments are bound by the prepared query, so they are listed after it
+        format!(
+            "{}\n-- $1 = {}, $2 = {}, $3 = {}, $4 = '{}'",
+            source.build_tile_query(),
+            xyz.x,
+            xyz.y,
+            xyz.z,
+            json!(query_json.0)
+        )
+    } else {
+        return Err(error::ErrorNotFound(format!(
+            "Table or function source '{}' not found",
+            path.source_id
+        )));
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body(sql))
+}
+
+/// Copy the sources for a sources list, leaving out their API keys
+fn hide_api_keys<S: Clone>(
+    sources: &HashMap<String, Box<S>>,
+    hide: impl Fn(&mut S),
+) -> HashMap<&str, S> {
+    sources
+        .iter()
+        .map(|(id, source)| {
+            let mut source = source.deref().clone();
+            hide(&mut source);
+            (id.as_str(), source)
+        })
+        .collect()
+}
+
 #[route("/index.json", method = "GET", method = "HEAD")]
 async fn get_table_sources(state: Data<AppState>) -> impl Responder {
-    HttpResponse::Ok().json(&state.table_sources)
+    let sources = state.sources.load();
+    HttpResponse::Ok().json(hide_api_keys(&sources.table_sources, |source| {
+        source.api_keys = None;
+    }))
 }
 
 #[route("/{source_ids}.json", method = "GET", method = "HEAD")]
@@ -80,24 +651,28 @@ async fn get_composite_source(
     path: Path<CompositeSourceRequest>,
     state: Data<AppState>,
 ) -> impl Responder {
-    if state.table_sources.is_empty() {
+    let all_sources = state.sources.load();
+    if all_sources.table_sources.is_empty() {
         return Err(error::ErrorNotFound("There is no table sources"));
     }
 
     let sources: Vec<TableSource> = path
         .source_ids
         .split(',')
-        .filter_map(|source_id| state.table_sources.get(source_id))
+        .filter_map(|source_id| all_sources.table_sources.get(source_id))
         .map(|source| source.deref().clone())
         .collect();
 
     if sources.is_empty() {
         return Err(error::ErrorNotFound("There is no such table sources"));
     }
+    get_shared_tile_matrix_set(&sources)?;
+    get_shared_database(&all_sources, &sources)?;
 
     let source = CompositeSource {
         id: path.source_ids.clone(),
         table_sources: sources,
+        layers: None,
     };
 
     let mut tilejson = source
@@ -105,64 +680,266 @@ async fn get_composite_source(
         .await
         .map_err(|e| error::ErrorBadRequest(format!("Can't build TileJSON: {e}")))?;
 
-    let tiles_path = req
-        .headers()
-        .get("x-rewrite-url")
-        .and_then(parse_x_rewrite_url)
-        .unwrap_or_else(|| req.path().trim_end_matches(".json").to_owned());
-
-    let connection_info = req.connection_info();
-
-    let path_and_query = if req.query_string().is_empty() {
-        format!("{tiles_path}/{{z}}/{{x}}/{{y}}.pbf")
-    } else {
-        format!("{tiles_path}/{{z}}/{{x}}/{{y}}.pbf?{}", req.query_string())
-    };
-
-    let tiles_url = Uri::builder()
-        .scheme(connection_info.scheme())
-        .authority(connection_info.host())
-        .path_and_query(path_and_query)
-        .build()
-        .map(|tiles_url| tiles_url.to_string())
-        .map_err(|e| error::ErrorBadRequest(format!("Can't build tiles URL: {e}")))?;
-
-    tilejson.tiles = vec![tiles_url];
+    tilejson.tiles = vec![get_tiles_url(&req, &state, TileFormat::Mvt)?];
     Ok(HttpResponse::Ok().json(tilejson))
 }
 
-#[route("/{source_ids}/{z}/{x}/{y}.{format}", method = "GET", method = "HEAD")]
+#[route("/{source_ids}/{z}/{x}/{y}", method = "GET", method = "HEAD")]
 async fn get_composite_source_tile(
+    req: HttpRequest,
     path: Path<CompositeTileRequest>,
+    query: Query<HashMap<String, String>>,
     state: Data<AppState>,
 ) -> impl Responder {
-    if state.table_sources.is_empty() {
+    let all_sources = state.sources.load();
+    if all_sources.table_sources.is_empty() {
         return Err(error::ErrorNotFound("There is no table sources"));
     }
 
     let sources: Vec<TableSource> = path
         .source_ids
         .split(',')
-        .filter_map(|source_id| state.table_sources.get(source_id))
+        .filter_map(|source_id| all_sources.table_sources.get(source_id))
         .map(|source| source.deref().clone())
-        .filter(|src| is_valid_zoom(path.z, src.minzoom, src.maxzoom))
         .collect();
 
     if sources.is_empty() {
         return Err(error::ErrorNotFound("There is no such table sources"));
     }
+    let tile_matrix_set = get_shared_tile_matrix_set(&sources)?;
+    let database = get_shared_database(&all_sources, &sources)?;
+    let format = get_table_tile_format(&req, &path.y);
+    let xyz = parse_xyz(
+        &path.z,
+        &path.x,
+        &path.y,
+        format,
+        tile_matrix_set,
+        state.max_zoom,
+    )?;
+    if format == TileFormat::Json {
+        let [source] = <[TableSource; 1]>::try_from(sources).map_err(|_| {
+            error::ErrorBadRequest("GeoJSON tiles are served for a single table source")
+        })?;
+        let source = GeoJsonSource::new(source);
+        let mut response = get_tile(
+            &req,
+            &state,
+            all_sources.generation,
+            xyz,
+            None,
+            Box::new(source),
+            database,
+        )
+        .await?;
+        // The same tile URL may be served as GeoJSON or as a vector tile by the Accept header
+        response
+            .headers_mut()
+            .append(VARY, HeaderValue::from_static("accept"));
+        return Ok(response);
+    }
+    let extent = query
+        .get(EXTENT_PARAM)
+        .map(|extent| parse_extent_override(extent))
+        .transpose()
+        .map_err(|e| error::ErrorBadRequest(e.to_string()))?;
+    let (layers, unknown_layers) = match query.get(LAYERS_PARAM) {
+        Some(layers) => {
+            let (layers, unknown_layers) = get_requested_layers(&sources, layers);
+            (Some(layers), unknown_layers)
+        }
+        None => (None, Vec::new()),
+    };
+    let max_age = get_max_age(&state, sources.iter().map(Source::get_max_age));
+    let empty_tile = EmptyTile::shared(sources.iter().map(Source::get_empty_tile));
+    let headers = merge_source_headers(sources.iter().map(Source::get_headers));
+    let finish = |response| {
+        let response = set_source_headers(set_max_age(response, max_age), headers.as_ref());
+        set_unknown_layers_header(response, &unknown_layers)
+    };
+
+    // Layers without tiles at this zoom or location, or not requested, are left out
+    let sources: Vec<TableSource> = sources
+        .into_iter()
+        .filter(|src| src.is_valid_zoom(xyz.z))
+        .filter(|src| {
+            layers.as_ref().is_none_or(|layers| {
+                src.get_layer_names()
+                    .iter()
+                    .any(|layer| layers.contains(layer))
+            })
+        })
+        .map(|src| TableSource {
+            extent: extent.or(src.extent),
+            ..src
+        })
+        .filter(|src| !src.is_out_of_bounds(&xyz))
+        .collect();
+    if sources.is_empty() {
+        let response = tile_response(&req, &state, Tile::new(), TileFormat::Mvt, empty_tile);
+        return Ok(finish(response));
+    }
+
+    let generation = all_sources.generation;
+    let etag = get_versioned_etag(
+        &state,
+        generation,
+        &sources,
+        layers.as_deref(),
+        database,
+        &xyz,
+    )
+    .await?;
+    if let Some(etag) = &etag {
+        if is_not_modified(&req, etag) {
+            return Ok(finish(not_modified_response(etag)));
+        }
+    }
 
     let source = CompositeSource {
         id: path.source_ids.clone(),
         table_sources: sources,
+        layers: layers.clone(),
     };
 
-    get_tile(&state, path.z, path.x, path.y, None, Box::new(source)).await
+    // The extent and the layers change the tiles, so they are a query of the tile caches
+    let mut query = UrlQuery::new();
+    if let Some(extent) = extent {
+        query.insert(EXTENT_PARAM.to_owned(), extent.to_string());
+    }
+    if let Some(layers) = &layers {
+        query.insert(LAYERS_PARAM.to_owned(), layers.join(","));
+    }
+    let query = (!query.is_empty()).then_some(query);
+
+    let is_partial = source.table_sources.len() > 1
+        && source
+            .table_sources
+            .iter()
+            .any(|src| is_partial_layer(&state, src));
+    let mut response = if is_partial {
+        let fetch = fetch_partial_tile(&req, &state, generation, xyz, query, &source, database);
+        let response = observe_tile(&state, &source.id, &xyz, fetch).await?;
+        // A tile without some of its layers has no version
+        if response.headers().contains_key(PARTIAL_TILE_HEADER) {
+            return Ok(finish(response));
+        }
+        finish(response)
+    } else {
+        let response = get_tile(
+            &req,
+            &state,
+            generation,
+            xyz,
+            query,
+            Box::new(source),
+            database,
+        )
+        .await?;
+        let response = set_source_headers(response, headers.as_ref());
+        set_unknown_layers_header(response, &unknown_layers)
+    };
+
+    if let Some(etag) = etag {
+        if response.status().is_success() {
+            response
+                .headers_mut()
+                .insert(ETAG, etag.to_string().parse().map_err(map_internal_error)?);
+        }
+    }
+    Ok(response)
+}
+
+/// Derive a tile ETag from the version tokens of the given table sources and the requested layers.
+/// Returns `None` unless every source has a `version_query` that produced a value.
+async fn get_versioned_etag(
+    state: &AppState,
+    generation: u64,
+    sources: &[TableSource],
+    layers: Option<&[String]>,
+    database: Option<usize>,
+    xyz: &Xyz,
+) -> Result<Option<EntityTag>> {
+    if sources.iter().any(|src| src.version_query.is_none()) {
+        return Ok(None);
+    }
+
+    let mut hasher = Sha256::new();
+    for source in sources {
+        let cached = state
+            .source_versions
+            .lock()
+            .map_err(map_internal_error)?
+            .get(&source.id)
+            .filter(|(fetched_generation, fetched, _)| {
+                *fetched_generation == generation && fetched.elapsed() < SOURCE_VERSION_TTL
+            })
+            .map(|(_, _, version)| version.clone());
+
+        let version = match cached {
+            Some(version) => version,
+            None => {
+                let mut connection = get_connection(get_database_pool(state, database))
+                    .await
+                    .map_err(map_connection_error)?;
+                let version = source
+                    .get_version(&mut connection)
+                    .await
+                    .map_err(map_internal_error)?;
+                state
+                    .source_versions
+                    .lock()
+                    .map_err(map_internal_error)?
+                    .insert(
+                        source.id.clone(),
+                        (generation, Instant::now(), version.clone()),
+                    );
+                version
+            }
+        };
+
+        let Some(version) = version else {
+            return Ok(None);
+        };
+        let extent = source.extent.map(|extent| extent.to_string());
+        for part in [&source.id, &version, extent.as_deref().unwrap_or_default()] {
+            hash_part(&mut hasher, part.as_bytes());
+        }
+    }
+    let layers = layers.map(|layers| layers.join(","));
+    hash_part(
+        &mut hasher,
+        layers.as_deref().unwrap_or_default().as_bytes(),
+    );
+    hash_part(
+        &mut hasher,
+        format!("{}/{}/{}", xyz.z, xyz.x, xyz.y).as_bytes(),
+    );
+
+    Ok(Some(EntityTag::new_strong(hex_digest(hasher))))
+}
+
+/// Add a length-prefixed part to a hash, so the parts can't run into each other
+fn hash_part(hasher: &mut Sha256, part: &[u8]) {
+    hasher.update(&(part.len() as u64).to_le_bytes());
+    hasher.update(part);
+}
+
+/// Hex of the first 8 bytes of a SHA-256 digest. Unlike `DefaultHasher`, it stays the same
+/// across Rust releases and builds, so ETags survive upgrades and agree between servers.
+fn hex_digest(hasher: Sha256) -> String {
+    hasher.finish()[..8]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
 }
 
 #[route("/rpc/index.json", method = "GET", method = "HEAD")]
 async fn get_function_sources(state: Data<AppState>) -> impl Responder {
-    HttpResponse::Ok().json(&state.function_sources)
+    let sources = state.sources.load();
+    HttpResponse::Ok().json(hide_api_keys(&sources.function_sources, |source| {
+        source.api_keys = None;
+    }))
 }
 
 #[route("/rpc/{source_id}.json", method = "GET", method = "HEAD")]
@@ -171,152 +948,1154 @@ async fn get_function_source(
     path: Path<SourceRequest>,
     state: Data<AppState>,
 ) -> Result<HttpResponse> {
-    if state.function_sources.is_empty() {
+    let all_sources = state.sources.load();
+    if all_sources.function_sources.is_empty() {
         return Err(error::ErrorNotFound("There is no function sources"));
     }
 
-    let source = state.function_sources.get(&path.source_id).ok_or_else(|| {
-        error::ErrorNotFound(format!("Function source '{}' not found", path.source_id))
-    })?;
+    let source = all_sources
+        .function_sources
+        .get(&path.source_id)
+        .ok_or_else(|| {
+            error::ErrorNotFound(format!("Function source '{}' not found", path.source_id))
+        })?;
 
     let mut tilejson = source
         .get_tilejson()
         .await
         .map_err(|e| error::ErrorBadRequest(format!("Can't build TileJSON: {e}")))?;
 
-    let tiles_path = req
-        .headers()
-        .get("x-rewrite-url")
-        .and_then(parse_x_rewrite_url)
-        .unwrap_or_else(|| req.path().trim_end_matches(".json").to_owned());
-
-    let connection_info = req.connection_info();
-
-    let path_and_query = if req.query_string().is_empty() {
-        format!("{tiles_path}/{{z}}/{{x}}/{{y}}.pbf")
-    } else {
-        format!("{tiles_path}/{{z}}/{{x}}/{{y}}.pbf?{}", req.query_string())
-    };
-
-    let tiles_url = Uri::builder()
-        .scheme(connection_info.scheme())
-        .authority(connection_info.host())
-        .path_and_query(path_and_query)
-        .build()
-        .map(|tiles_url| tiles_url.to_string())
-        .map_err(|e| error::ErrorBadRequest(format!("Can't build tiles URL: {e}")))?;
-
-    tilejson.tiles = vec![tiles_url];
+    tilejson.tiles = vec![get_tiles_url(&req, &state, TileFormat::Mvt)?];
     Ok(HttpResponse::Ok().json(tilejson))
 }
 
-#[route(
-    "/rpc/{source_id}/{z}/{x}/{y}.{format}",
-    method = "GET",
-    method = "HEAD"
-)]
+#[route("/rpc/{source_id}/{z}/{x}/{y}", method = "GET", method = "HEAD")]
 async fn get_function_source_tile(
+    req: HttpRequest,
     path: Path<TileRequest>,
     query: Query<HashMap<String, String>>,
     state: Data<AppState>,
 ) -> impl Responder {
-    if state.function_sources.is_empty() {
+    let all_sources = state.sources.load();
+    if all_sources.function_sources.is_empty() {
         return Err(error::ErrorNotFound("There is no function sources"));
     }
 
-    let source = state
+    let xyz = parse_xyz(
+        &path.z,
+        &path.x,
+        &path.y,
+        TileFormat::Mvt,
+        TileMatrixSet::default(),
+        state.max_zoom,
+    )?;
+    let source = all_sources
         .function_sources
         .get(&path.source_id)
-        .filter(|src| is_valid_zoom(path.z, src.minzoom, src.maxzoom))
         .ok_or_else(|| {
             error::ErrorNotFound(format!("Function source '{}' not found", path.source_id))
         })?;
 
+    // The key is for martin, not a parameter of the function
+    let mut query = query.into_inner();
+    if source.api_keys.is_some() || state.api_keys.is_some() {
+        query.remove(API_KEY_PARAM);
+    }
+    // Reject mistyped params here, instead of failing the function call with a 500
+    source
+        .query_to_json(&query)
+        .map_err(|e| error::ErrorBadRequest(e.to_string()))?;
+
+    let database = all_sources.get_database(&path.source_id);
     get_tile(
+        &req,
         &state,
-        path.z,
-        path.x,
-        path.y,
-        Some(query.into_inner()),
+        all_sources.generation,
+        xyz,
+        Some(query),
         source.clone(),
+        database,
+    )
+    .await
+}
+
+#[route("/raster/index.json", method = "GET", method = "HEAD")]
+async fn get_raster_sources(state: Data<AppState>) -> impl Responder {
+    let sources = state.sources.load();
+    HttpResponse::Ok().json(hide_api_keys(&sources.raster_sources, |source| {
+        source.api_keys = None;
+    }))
+}
+
+#[route("/raster/{source_id}.json", method = "GET", method = "HEAD")]
+async fn get_raster_source(
+    req: HttpRequest,
+    path: Path<SourceRequest>,
+    state: Data<AppState>,
+) -> Result<HttpResponse> {
+    let all_sources = state.sources.load();
+    if all_sources.raster_sources.is_empty() {
+        return Err(error::ErrorNotFound("There is no raster sources"));
+    }
+
+    let source = all_sources
+        .raster_sources
+        .get(&path.source_id)
+        .ok_or_else(|| {
+            error::ErrorNotFound(format!("Raster source '{}' not found", path.source_id))
+        })?;
+
+    let mut tilejson = source
+        .get_tilejson()
+        .await
+        .map_err(|e| error::ErrorBadRequest(format!("Can't build TileJSON: {e}")))?;
+
+    tilejson.tiles = vec![get_tiles_url(&req, &state, TileFormat::Png)?];
+    Ok(HttpResponse::Ok().json(tilejson))
+}
+
+/// Raster tiles, or `@2x` tiles of twice the size for high-DPI screens,
+/// e.g. `/raster/dem/0/0/0@2x.png`
+#[route("/raster/{source_id}/{z}/{x}/{y}", method = "GET", method = "HEAD")]
+async fn get_raster_source_tile(
+    req: HttpRequest,
+    path: Path<TileRequest>,
+    state: Data<AppState>,
+) -> impl Responder {
+    let all_sources = state.sources.load();
+    if all_sources.raster_sources.is_empty() {
+        return Err(error::ErrorNotFound("There is no raster sources"));
+    }
+
+    let (y, high_dpi) = match path.y.split_once("@2x") {
+        Some((y, extension)) => (format!("{y}{extension}"), true),
+        None => (path.y.clone(), false),
+    };
+    let xyz = parse_xyz(
+        &path.z,
+        &path.x,
+        &y,
+        TileFormat::Png,
+        TileMatrixSet::default(),
+        state.max_zoom,
+    )?;
+    let source = all_sources
+        .raster_sources
+        .get(&path.source_id)
+        .ok_or_else(|| {
+            error::ErrorNotFound(format!("Raster source '{}' not found", path.source_id))
+        })?;
+
+    let source: Box<dyn Source + Send + Sync> = if high_dpi {
+        Box::new(HighDpiRasterSource::new(source.as_ref().clone()))
+    } else {
+        source.clone()
+    };
+    let database = all_sources.get_database(&path.source_id);
+    get_tile(
+        &req,
+        &state,
+        all_sources.generation,
+        xyz,
+        None,
+        source,
+        database,
     )
     .await
 }
 
-fn is_valid_zoom(zoom: i32, minzoom: Option<u8>, maxzoom: Option<u8>) -> bool {
-    let gte_minzoom = minzoom.map_or(true, |minzoom| zoom >= minzoom.into());
+/// Ids of all the sources, sprites and fonts
+#[derive(Serialize)]
+struct Catalog {
+    tables: Vec<String>,
+    functions: Vec<String>,
+    rasters: Vec<String>,
+    files: Vec<String>,
+    sprites: Vec<String>,
+    fonts: Vec<String>,
+}
+
+fn sorted_ids<'a>(ids: impl Iterator<Item = &'a String>) -> Vec<String> {
+    let mut ids: Vec<String> = ids.cloned().collect();
+    ids.sort_unstable();
+    ids
+}
+
+#[route("/catalog", method = "GET", method = "HEAD")]
+async fn get_catalog(state: Data<AppState>) -> Result<HttpResponse> {
+    let fonts = match state.fonts.clone() {
+        Some(fonts) => web::block(move || fonts.get_fonts())
+            .await
+            .map_err(map_internal_error)?
+            .map_err(map_internal_error)?,
+        None => Vec::new(),
+    };
+    let sources = state.sources.load();
+    let catalog = Catalog {
+        tables: sorted_ids(sources.table_sources.keys()),
+        functions: sorted_ids(sources.function_sources.keys()),
+        rasters: sorted_ids(sources.raster_sources.keys()),
+        files: sorted_ids(state.file_sources.iter().flat_map(|sources| sources.keys())),
+        sprites: state.sprites.as_ref().map_or_else(Vec::new, |sprites| {
+            sprites.get_ids().into_iter().map(str::to_owned).collect()
+        }),
+        fonts,
+    };
+    Ok(HttpResponse::Ok().json(catalog))
+}
+
+#[derive(Deserialize)]
+struct FontRequest {
+    fontstack: String,
+    start: u32,
+    end: u32,
+}
+
+fn get_font_sources(state: &AppState) -> Result<&FontSources> {
+    state
+        .fonts
+        .as_ref()
+        .ok_or_else(|| error::ErrorNotFound("There is no fonts"))
+}
+
+#[route("/font/index.json", method = "GET", method = "HEAD")]
+async fn get_fonts(state: Data<AppState>) -> Result<HttpResponse> {
+    let fonts = get_font_sources(&state)?.clone();
+    let fonts = web::block(move || fonts.get_fonts())
+        .await
+        .map_err(map_internal_error)?
+        .map_err(map_internal_error)?;
+    Ok(HttpResponse::Ok().json(fonts))
+}
+
+/// Serve a glyph range of a comma-separated fontstack, merging the glyphs of its fonts
+#[route(
+    "/font/{fontstack}/{start:\\d+}-{end:\\d+}.pbf",
+    method = "GET",
+    method = "HEAD"
+)]
+async fn get_font(path: Path<FontRequest>, state: Data<AppState>) -> Result<HttpResponse> {
+    let fonts = get_font_sources(&state)?.clone();
+    let FontRequest {
+        fontstack,
+        start,
+        end,
+    } = path.into_inner();
+    let range = web::block(move || fonts.get_font_range(&fontstack, start, end))
+        .await
+        .map_err(map_internal_error)?
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidInput => error::ErrorBadRequest(e.to_string()),
+            _ => map_internal_error(e),
+        })?
+        .ok_or_else(|| error::ErrorNotFound("No font of the fontstack has this glyph range"))?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-protobuf")
+        .body(range))
+}
+
+#[derive(Deserialize)]
+struct SpriteRequest {
+    sprite_id: String,
+    format: String,
+}
+
+fn get_sprite_sources(state: &AppState) -> Result<&SpriteSources> {
+    state
+        .sprites
+        .as_ref()
+        .ok_or_else(|| error::ErrorNotFound("There is no sprites"))
+}
 
-    let lte_maxzoom = maxzoom.map_or(true, |maxzoom| zoom <= maxzoom.into());
+#[route("/sprite/index.json", method = "GET", method = "HEAD")]
+async fn get_sprites(state: Data<AppState>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(get_sprite_sources(&state)?.get_ids()))
+}
 
-    gte_minzoom && lte_maxzoom
+/// Serve the index or the image of a sprite, and of its `@2x` variant, e.g. `/sprite/basic@2x.png`
+#[route(
+    "/sprite/{sprite_id}.{format:json|png}",
+    method = "GET",
+    method = "HEAD"
+)]
+async fn get_sprite(path: Path<SpriteRequest>, state: Data<AppState>) -> Result<HttpResponse> {
+    let sprites = get_sprite_sources(&state)?.clone();
+    let format = SpriteFormat::parse(&path.format)
+        .ok_or_else(|| error::ErrorBadRequest("Sprites are served as json or png"))?;
+    let (id, retina) = match path.sprite_id.strip_suffix("@2x") {
+        Some(id) => (id.to_owned(), true),
+        None => (path.sprite_id.clone(), false),
+    };
+    let sprite = web::block(move || sprites.get_sprite(&id, retina, format))
+        .await
+        .map_err(map_internal_error)?
+        .map_err(map_internal_error)?
+        .ok_or_else(|| error::ErrorNotFound(format!("Sprite '{}' not found", path.sprite_id)))?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .body(sprite))
+}
+
+fn get_file_sources(state: &AppState) -> Result<&FileSources> {
+    state
+        .file_sources
+        .as_ref()
+        .filter(|sources| !sources.is_empty())
+        .ok_or_else(|| error::ErrorNotFound("There is no file sources"))
+}
+
+#[route("/file/index.json", method = "GET", method = "HEAD")]
+async fn get_file_tree_sources(state: Data<AppState>) -> Result<HttpResponse> {
+    let mut ids: Vec<&String> = get_file_sources(&state)?.keys().collect();
+    ids.sort_unstable();
+    Ok(HttpResponse::Ok().json(ids))
+}
+
+#[route("/file/{source_id}.json", method = "GET", method = "HEAD")]
+async fn get_file_tree_source(
+    req: HttpRequest,
+    path: Path<SourceRequest>,
+    state: Data<AppState>,
+) -> Result<HttpResponse> {
+    let source = get_file_sources(&state)?
+        .get(&path.source_id)
+        .ok_or_else(|| {
+            error::ErrorNotFound(format!("File source '{}' not found", path.source_id))
+        })?;
+
+    let mut tilejson = source
+        .get_tilejson()
+        .await
+        .map_err(|e| error::ErrorBadRequest(format!("Can't build TileJSON: {e}")))?;
+
+    tilejson.tiles = vec![get_tiles_url(&req, &state, source.get_format())?];
+    Ok(HttpResponse::Ok().json(tilejson))
+}
+
+#[route("/file/{source_id}/{z}/{x}/{y}", method = "GET", method = "HEAD")]
+async fn get_file_tree_source_tile(
+    req: HttpRequest,
+    path: Path<TileRequest>,
+    state: Data<AppState>,
+) -> Result<HttpResponse> {
+    let source = get_file_sources(&state)?
+        .get(&path.source_id)
+        .ok_or_else(|| {
+            error::ErrorNotFound(format!("File source '{}' not found", path.source_id))
+        })?;
+    let xyz = parse_xyz(
+        &path.z,
+        &path.x,
+        &path.y,
+        source.get_format(),
+        TileMatrixSet::default(),
+        state.max_zoom,
+    )?;
+
+    let generation = state.sources.load().generation;
+    get_tile(&req, &state, generation, xyz, None, source.clone(), None).await
+}
+
+/// Fetch the tiles of any table and function sources concurrently, and merge their layers
+/// into a single tile. Layer names used by more than one source are prefixed with the source id.
+#[route("/comp/{source_ids}/{z}/{x}/{y}", method = "GET", method = "HEAD")]
+async fn get_merged_tile(
+    req: HttpRequest,
+    path: Path<CompositeTileRequest>,
+    query: Query<HashMap<String, String>>,
+    state: Data<AppState>,
+) -> Result<HttpResponse> {
+    let all_sources = state.sources.load();
+    let generation = all_sources.generation;
+    let xyz = parse_xyz(
+        &path.z,
+        &path.x,
+        &path.y,
+        TileFormat::Mvt,
+        TileMatrixSet::default(),
+        state.max_zoom,
+    )?;
+
+    let mut sources: Vec<(Box<dyn Source + Send + Sync>, Option<usize>)> = Vec::new();
+    let mut function_sources = Vec::new();
+    let mut has_api_keys = false;
+    let mut max_ages = Vec::new();
+    let mut empty_tiles = Vec::new();
+    let mut headers = Vec::new();
+    for source_id in path.source_ids.split(',') {
+        let (source, api_keys): (Box<dyn Source + Send + Sync>, _) =
+            if let Some(source) = all_sources.table_sources.get(source_id) {
+                if source.tile_matrix_set.unwrap_or_default() != TileMatrixSet::default() {
+                    return Err(error::ErrorBadRequest(format!(
+                    "Table source '{source_id}' tiles are not in Web Mercator and can't be merged"
+                )));
+                }
+                (source.clone(), source.api_keys.as_ref())
+            } else if let Some(source) = all_sources.function_sources.get(source_id) {
+                function_sources.push(source);
+                (source.clone(), source.api_keys.as_ref())
+            } else if all_sources.raster_sources.contains_key(source_id) {
+                return Err(error::ErrorBadRequest(format!(
+                    "Raster source '{source_id}' can't be merged into a vector tile"
+                )));
+            } else {
+                return Err(error::ErrorNotFound(format!(
+                    "Source '{source_id}' not found"
+                )));
+            };
+        has_api_keys |= api_keys.is_some();
+        max_ages.push(source.get_max_age());
+        empty_tiles.push(source.get_empty_tile());
+        headers.push(source.get_headers().cloned());
+        let database = all_sources.get_database(source_id);
+        sources.push((with_tile_cache(&state, generation, source), database));
+    }
+
+    // The key is for martin, not a parameter of the functions
+    let mut query = query.into_inner();
+    if has_api_keys || state.api_keys.is_some() {
+        query.remove(API_KEY_PARAM);
+    }
+    for source in function_sources {
+        source
+            .query_to_json(&query)
+            .map_err(|e| error::ErrorBadRequest(e.to_string()))?;
+    }
+    let query = Some(query);
+
+    let (xyz, query) = (&xyz, &query);
+    let fetch = async {
+        let tiles = try_join_all(sources.iter().map(|(source, database)| {
+            fetch_source_tile(&state, generation, xyz, query, source.as_ref(), *database)
+        }))
+        .await?;
+        let mut ids = Vec::with_capacity(sources.len());
+        for (source, _) in &sources {
+            ids.push(source.get_id().await);
+        }
+        let tile = merge_mvt(ids.into_iter().zip(tiles.iter().map(|tile| &tile[..])))
+            .map_err(map_internal_error)?;
+        let empty_tile = EmptyTile::shared(empty_tiles);
+        Ok(tile_response(
+            &req,
+            &state,
+            Tile::from(tile),
+            TileFormat::Mvt,
+            empty_tile,
+        ))
+    };
+    let response = observe_tile(&state, &path.source_ids, xyz, fetch).await?;
+    let response = set_max_age(response, get_max_age(&state, max_ages));
+    let headers = merge_source_headers(headers.iter().map(Option::as_ref));
+    Ok(set_source_headers(response, headers.as_ref()))
+}
+
+/// Pool of the extra database with the given index, or the main pool
+fn get_database_pool(state: &AppState, database: Option<usize>) -> &Pool {
+    database.map_or(&state.pool, |index| &state.database_pools[index])
+}
+
+/// High-zoom tiles are cheap and numerous, so they get their own pool when one is reserved.
+/// The reserved pool only serves the main database.
+fn get_tile_pool(state: &AppState, database: Option<usize>, z: i32) -> &Pool {
+    match &state.reserved_pool {
+        Some(pool) if database.is_none() && z >= i32::from(state.reserved_pool_minzoom) => pool,
+        _ => get_database_pool(state, database),
+    }
 }
 
 async fn get_tile(
+    req: &HttpRequest,
     state: &Data<AppState>,
-    z: i32,
-    x: i32,
-    y: i32,
+    generation: u64,
+    xyz: Xyz,
     query: Option<UrlQuery>,
-    source: Box<dyn Source + Send>,
+    source: Box<dyn Source + Send + Sync>,
+    database: Option<usize>,
 ) -> Result<HttpResponse, Error> {
-    let mut connection = get_connection(&state.pool).await?;
-    let tile = source
-        .get_tile(&mut connection, &Xyz { z, x, y }, &query)
+    let source_id = source.get_id().await.to_owned();
+    let max_age = get_max_age(state, [source.get_max_age()]);
+    let headers = source.get_headers().cloned();
+    let fetch = fetch_tile(req, state, generation, xyz, query, source, database);
+    observe_tile(state, &source_id, &xyz, fetch)
         .await
-        .map_err(map_internal_error)?;
+        .map(|response| set_max_age(response, max_age))
+        .map(|response| set_source_headers(response, headers.as_ref()))
+}
+
+/// Serve a tile within the `tile_timeout`, and record the request in the metrics
+async fn observe_tile(
+    state: &AppState,
+    source_id: &str,
+    xyz: &Xyz,
+    fetch: impl std::future::Future<Output = Result<HttpResponse>>,
+) -> Result<HttpResponse> {
+    #[cfg(feature = "observability")]
+    let started = Instant::now();
 
-    match tile.len() {
-        0 => Ok(HttpResponse::NoContent()
-            .content_type("application/x-protobuf")
-            .finish()),
-        _ => Ok(HttpResponse::Ok()
-            .content_type("application/x-protobuf")
-            .body(tile)),
+    let result = with_tile_timeout(state.tile_timeout, source_id, xyz, fetch).await;
+
+    #[cfg(feature = "observability")]
+    state.metrics.observe(source_id, &result, started.elapsed());
+
+    result
+}
+
+async fn fetch_tile(
+    req: &HttpRequest,
+    state: &Data<AppState>,
+    generation: u64,
+    xyz: Xyz,
+    query: Option<UrlQuery>,
+    source: Box<dyn Source + Send + Sync>,
+    database: Option<usize>,
+) -> Result<HttpResponse, Error> {
+    let source = with_tile_cache(state, generation, source);
+    let tile =
+        fetch_source_tile(state, generation, &xyz, &query, source.as_ref(), database).await?;
+
+    let truncated = is_truncated(source.as_ref(), &xyz, &tile).await;
+    let empty_tile = source.get_empty_tile();
+    let mut response = tile_response(req, state, tile, source.get_format(), empty_tile);
+    if truncated {
+        response.headers_mut().insert(
+            HeaderName::from_static(TRUNCATED_TILE_HEADER),
+            HeaderValue::from_static("true"),
+        );
+    }
+    Ok(response)
+}
+
+/// Get a tile of the source with retries, and check its size. Tiles known to be empty,
+/// or out of the zoom levels or the bounds of the source, are empty without querying it.
+async fn fetch_source_tile(
+    state: &AppState,
+    generation: u64,
+    xyz: &Xyz,
+    query: &Option<UrlQuery>,
+    source: &(dyn Source + Send + Sync),
+    database: Option<usize>,
+) -> Result<Tile> {
+    let source_id = source.get_id().await;
+    // Tiles requested with query params may differ per request, so only plain ones are remembered
+    let remember_empty = query.as_ref().is_none_or(HashMap::is_empty);
+    if remember_empty && state.empty_tiles.contains(generation, source_id, xyz) {
+        return Ok(Tile::new());
+    }
+    if !source.is_valid_zoom(xyz.z) || source.is_out_of_bounds(xyz) {
+        return Ok(Tile::new());
+    }
+
+    let pool = get_tile_pool(state, database, xyz.z);
+    let tile = with_retries(state.max_retries, || {
+        get_source_tile(source, pool, xyz, query)
+    })
+    .await
+    .map_err(map_connection_error)?;
+    check_tile_size(state, source_id, xyz, &tile)?;
+
+    if remember_empty && tile.is_empty() {
+        state.empty_tiles.insert(generation, source_id, xyz);
+    }
+    Ok(tile)
+}
+
+/// Whether a failed layer of the table source is left out of the composite tiles
+fn is_partial_layer(state: &AppState, source: &TableSource) -> bool {
+    source
+        .partial_composite_tiles
+        .unwrap_or(state.partial_composite_tiles)
+}
+
+/// Fetch the layers of each table source of a composite tile concurrently, each like a tile
+/// of its own source with a connection of its own. Failed layers of the sources allowing it
+/// are left out and listed in the `X-Tile-Partial` header. Any other failed layer fails
+/// the tile, and so do failures of all the layers.
+async fn fetch_partial_tile(
+    req: &HttpRequest,
+    state: &AppState,
+    generation: u64,
+    xyz: Xyz,
+    query: Option<UrlQuery>,
+    source: &CompositeSource,
+    database: Option<usize>,
+) -> Result<HttpResponse> {
+    let query = &query;
+    let layers =
+        join_all(source.table_sources.iter().map(|table_source| {
+            let layer = CompositeSource {
+                id: table_source.id.clone(),
+                table_sources: vec![table_source.clone()],
+                layers: source.layers.clone(),
+            };
+            let layer = with_tile_cache(state, generation, Box::new(layer));
+            async move {
+                fetch_source_tile(state, generation, &xyz, query, layer.as_ref(), database).await
+            }
+        }))
+        .await;
+
+    let mut tile = BytesMut::new();
+    let mut failed = Vec::new();
+    let mut first_error = None;
+    for (table_source, layer) in source.table_sources.iter().zip(layers) {
+        match layer {
+            Ok(layer) => tile.extend_from_slice(&layer),
+            Err(e) if is_partial_layer(state, table_source) => {
+                warn!(
+                    "Leaving out the layer of {} from composite source {}: {e}",
+                    table_source.id, source.id
+                );
+                failed.push(table_source.id.as_str());
+                first_error.get_or_insert(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if let Some(e) = first_error.filter(|_| failed.len() == source.table_sources.len()) {
+        return Err(e);
+    }
+
+    let tile = tile.freeze();
+    check_tile_size(state, &source.id, &xyz, &tile)?;
+    let mut response = tile_response(req, state, tile, TileFormat::Mvt, source.get_empty_tile());
+    if !failed.is_empty() {
+        response.headers_mut().insert(
+            HeaderName::from_static(PARTIAL_TILE_HEADER),
+            failed.join(",").parse().map_err(map_internal_error)?,
+        );
+    }
+    Ok(response)
+}
+
+/// Whether a layer of the tile reached the `max_feature_count` of its source,
+/// so some of its features may be left out
+async fn is_truncated(source: &(dyn Source + Send + Sync), xyz: &Xyz, tile: &Tile) -> bool {
+    let Some(max_feature_count) = source.get_max_feature_count() else {
+        return false;
+    };
+    let summary = match summarize_mvt(tile) {
+        Ok(summary) => summary,
+        Err(e) => {
+            debug!("Can't count tile features: {e}");
+            return false;
+        }
+    };
+    let truncated: Vec<&str> = summary
+        .layers
+        .iter()
+        .filter(|layer| layer.features >= max_feature_count)
+        .map(|layer| layer.name.as_str())
+        .collect();
+    if truncated.is_empty() {
+        return false;
+    }
+    info!(
+        "Tile {}/{}/{}/{} is truncated to {max_feature_count} features in layers {}",
+        source.get_id().await,
+        xyz.z,
+        xyz.x,
+        xyz.y,
+        truncated.join(", ")
+    );
+    true
+}
+
+/// Get a tile of the source, in a tracing span of its own. A connection is only taken
+/// from the pool if the source queries a database.
+#[cfg_attr(
+    feature = "otel",
+    tracing::instrument(
+        name = "get_tile",
+        skip_all,
+        fields(source = tracing::field::Empty, z = xyz.z, x = xyz.x, y = xyz.y)
+    )
+)]
+async fn get_source_tile(
+    source: &(dyn Source + Send + Sync),
+    pool: &Pool,
+    xyz: &Xyz,
+    query: &Option<UrlQuery>,
+) -> io::Result<Tile> {
+    #[cfg(feature = "otel")]
+    tracing::Span::current().record("source", source.get_id().await);
+    if source.requires_connection() {
+        let mut connection = get_connection(pool).await?;
+        source.get_tile(Some(&mut connection), xyz, query).await
+    } else {
+        source.get_tile(None, xyz, query).await
+    }
+}
+
+/// Handle the request by the next service, in the tracing span of the request
+#[cfg(feature = "otel")]
+fn call_traced<S: Service<ServiceRequest>>(
+    srv: &S,
+    req: ServiceRequest,
+) -> impl std::future::Future<Output = Result<S::Response, S::Error>> {
+    otel::in_request_span(req, |req| srv.call(req))
+}
+
+#[cfg(not(feature = "otel"))]
+fn call_traced<S: Service<ServiceRequest>>(srv: &S, req: ServiceRequest) -> S::Future {
+    srv.call(req)
+}
+
+fn with_tile_cache(
+    state: &AppState,
+    generation: u64,
+    source: Box<dyn Source + Send + Sync>,
+) -> Box<dyn Source + Send + Sync> {
+    match &state.tile_cache {
+        Some(cache) => Box::new(CachedSource {
+            inner: source,
+            cache: cache.clone(),
+            generation,
+        }),
+        None => source,
+    }
+}
+
+/// Cache max-age of a tile of the given sources: the one of the server config if set,
+/// otherwise the shortest one suggested by the sources
+fn get_max_age(
+    state: &AppState,
+    max_ages: impl IntoIterator<Item = Option<Duration>>,
+) -> Option<Duration> {
+    state
+        .cache_max_age
+        .or_else(|| max_ages.into_iter().flatten().min())
+}
+
+/// Add a `Cache-Control` header with the max-age, if there is one
+fn set_max_age(mut response: HttpResponse, max_age: Option<Duration>) -> HttpResponse {
+    if let Some(max_age) = max_age {
+        let seconds = u32::try_from(max_age.as_secs()).unwrap_or(u32::MAX);
+        if let Ok(value) = CacheControl(vec![CacheDirective::MaxAge(seconds)]).try_into_value() {
+            response.headers_mut().insert(CACHE_CONTROL, value);
+        }
+    }
+    response
+}
+
+/// Custom headers of the sources of a composite tile, the first source wins for the same header
+fn merge_source_headers<'a>(
+    headers: impl IntoIterator<Item = Option<&'a HashMap<String, String>>>,
+) -> Option<HashMap<String, String>> {
+    let mut merged: Option<HashMap<String, String>> = None;
+    for (name, value) in headers.into_iter().flatten().flatten() {
+        merged
+            .get_or_insert_with(HashMap::new)
+            .entry(name.clone())
+            .or_insert_with(|| value.clone());
+    }
+    merged
+}
+
+/// Add the custom headers of a source, after `Cache-Control` so they can replace it
+fn set_source_headers(
+    mut response: HttpResponse,
+    headers: Option<&HashMap<String, String>>,
+) -> HttpResponse {
+    for (name, value) in headers.into_iter().flatten() {
+        if is_reserved_header(name) {
+            continue;
+        }
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::try_from(name.as_str()),
+            HeaderValue::try_from(value.as_str()),
+        ) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
+
+/// Requested layers of a `layers` query parameter, sorted and deduplicated, and the ones
+/// that none of the sources has. Those are ignored, so they are only reported.
+fn get_requested_layers(sources: &[TableSource], layers: &str) -> (Vec<String>, Vec<String>) {
+    let known_layers: HashSet<String> = sources
+        .iter()
+        .flat_map(TableSource::get_layer_names)
+        .collect();
+    let (layers, unknown_layers): (Vec<String>, Vec<String>) = layers
+        .split(',')
+        .map(str::trim)
+        .filter(|layer| !layer.is_empty())
+        .map(str::to_owned)
+        .sorted()
+        .dedup()
+        .partition(|layer| known_layers.contains(layer));
+    if !unknown_layers.is_empty() {
+        debug!(
+            "Ignoring unknown layers {} of sources {}",
+            unknown_layers.join(","),
+            sources.iter().map(|source| source.id.as_str()).join(",")
+        );
+    }
+    (layers, unknown_layers)
+}
+
+/// Report the ignored unknown layers of a request in the `X-Tile-Unknown-Layers` header
+fn set_unknown_layers_header(
+    mut response: HttpResponse,
+    unknown_layers: &[String],
+) -> HttpResponse {
+    if unknown_layers.is_empty() {
+        return response;
+    }
+    if let Ok(value) = HeaderValue::try_from(unknown_layers.join(",")) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static(UNKNOWN_LAYERS_HEADER), value);
     }
+    response
 }
 
+/// Whether the `If-None-Match` header of the request matches the ETag
+fn is_not_modified(req: &HttpRequest, etag: &EntityTag) -> bool {
+    match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        None => false,
+    }
+}
+
+fn not_modified_response(etag: &EntityTag) -> HttpResponse {
+    HttpResponse::NotModified()
+        .insert_header((ETAG, etag.to_string()))
+        .finish()
+}
+
+/// ETag of a tile, hashed from its uncompressed bytes. It is weak, because the same tile
+/// is served with different content encodings.
+fn get_tile_etag(tile: &Tile) -> EntityTag {
+    let mut hasher = Sha256::new();
+    hasher.update(tile);
+    EntityTag::new_weak(hex_digest(hasher))
+}
+
+fn tile_response(
+    req: &HttpRequest,
+    state: &AppState,
+    tile: Tile,
+    format: TileFormat,
+    empty_tile: EmptyTile,
+) -> HttpResponse {
+    // A blank tile is served as a tile with data, even if an empty vector tile has no bytes
+    let blank = tile.is_empty() && empty_tile == EmptyTile::Blank;
+    let tile = match empty_tile {
+        EmptyTile::NotFound if tile.is_empty() => return HttpResponse::NotFound().finish(),
+        _ if blank => format.blank_tile(),
+        _ => tile,
+    };
+
+    // Empty tiles have no body to tag
+    let etag = (!tile.is_empty()).then(|| get_tile_etag(&tile));
+    if let Some(etag) = &etag {
+        if is_not_modified(req, etag) {
+            return not_modified_response(etag);
+        }
+    }
+
+    let mut response = if tile.is_empty() && !blank {
+        HttpResponse::NoContent()
+    } else {
+        HttpResponse::Ok()
+    };
+    response.content_type(format.content_type());
+    if let Some(etag) = etag {
+        response.insert_header((ETAG, etag.to_string()));
+    }
+
+    // Images are compressed by their format, and have no features to count
+    if format == TileFormat::Png {
+        response.insert_header((CONTENT_ENCODING, Encoding::identity().to_string()));
+        return response.body(tile);
+    }
+
+    if state.feature_count_header && format == TileFormat::Mvt {
+        // Tiles from function sources may be compressed, those are served without the count
+        match count_features(&tile) {
+            Ok(count) => {
+                response.insert_header((FEATURE_COUNT_HEADER, count));
+            }
+            Err(e) => debug!("Can't count tile features: {e}"),
+        }
+    }
+
+    // Tiles compressed by their source are served as is, and `identity` keeps
+    // the compression middleware from compressing them again
+    let mut encoding = Encoding::identity();
+    let mut tile = tile;
+    if !tile.is_empty() && !is_compressed_mvt(&tile) {
+        response.insert_header((VARY, "accept-encoding"));
+        let accepted = negotiate_encoding(req);
+        match compress_tile(&tile, &accepted, state.tile_compression_level) {
+            Ok(Some(compressed)) if compressed.len() < tile.len() => {
+                encoding = accepted;
+                tile = compressed;
+            }
+            Ok(_) => {}
+            Err(e) => debug!("Can't compress tile: {e}"),
+        }
+    }
+    response.insert_header((CONTENT_ENCODING, encoding.to_string()));
+
+    response.body(tile)
+}
+
+/// Register the routes, behind a middleware checking the access to the sources they serve.
+/// They take all the paths of the scope they are registered in.
 pub fn router(cfg: &mut ServiceConfig) {
-    cfg.service(get_health)
+    let routes = web::scope("")
+        .wrap_fn(|req, srv| {
+            let checked = match req.app_data::<Data<AppState>>() {
+                Some(state) => check_access(req.request(), state, req.match_info().unprocessed()),
+                None => Ok(()),
+            };
+            // Denied requests are answered here, as errors of a middleware are not responses
+            let response = match checked {
+                Ok(()) => Ok(srv.call(req)),
+                Err(e) => Err(req.error_response(e)),
+            };
+            async move {
+                match response {
+                    Ok(response) => response.await.map(ServiceResponse::map_into_left_body),
+                    Err(denied) => Ok(denied.map_into_right_body()),
+                }
+            }
+        })
+        .service(get_health)
+        .service(get_liveness)
+        .service(get_readiness)
+        .service(put_log_level)
+        .service(get_debug_sql)
+        .service(get_catalog)
         .service(get_table_sources)
         .service(get_composite_source)
         .service(get_composite_source_tile)
         .service(get_function_sources)
         .service(get_function_source)
-        .service(get_function_source_tile);
+        .service(get_function_source_tile)
+        .service(get_raster_sources)
+        .service(get_raster_source)
+        .service(get_raster_source_tile)
+        .service(get_fonts)
+        .service(get_font)
+        .service(get_sprites)
+        .service(get_sprite)
+        .service(get_file_tree_sources)
+        .service(get_file_tree_source)
+        .service(get_file_tree_source_tile)
+        .service(get_merged_tile);
+
+    #[cfg(feature = "observability")]
+    let routes = routes.service(get_metrics);
+
+    cfg.service(routes);
 }
 
-fn create_state(pool: Pool, config: Config) -> AppState {
+/// Create the state of a server worker. The pools, sources, tile cache and metrics are shared
+/// with the other workers, the empty tiles cache is not.
+pub(crate) fn create_state(martin: &Martin) -> AppState {
+    let config = &martin.config;
     AppState {
-        pool,
-        table_sources: config.pg.table_sources,
-        function_sources: config.pg.function_sources,
+        pool: martin.pool.clone(),
+        reserved_pool: martin.reserved_pool.clone(),
+        reserved_pool_minzoom: config.pg.reserved_pool_minzoom,
+        database_pools: martin.database_pools.clone(),
+        sources: martin.sources.clone(),
+        source_versions: Mutex::default(),
+        empty_tiles: EmptyTiles::new(
+            config.srv.empty_tile_cache_size,
+            Duration::from_secs(config.srv.empty_tile_cache_ttl),
+        ),
+        admin_endpoints: config.srv.admin_endpoints,
+        debug_endpoints: config.srv.debug_endpoints,
+        tile_cache: martin.tile_cache.clone(),
+        #[cfg(feature = "observability")]
+        metrics: martin.metrics.clone(),
+        feature_count_header: config.srv.feature_count_header,
+        tile_compression_level: config.srv.tile_compression_level,
+        partial_composite_tiles: config.pg.partial_composite_tiles,
+        max_retries: config.pg.max_retries,
+        cache_max_age: config.srv.cache_max_age.map(Duration::from_secs),
+        fonts: martin.fonts.clone(),
+        sprites: martin.sprites.clone(),
+        file_sources: martin.file_sources.clone(),
+        auth: martin.auth.clone(),
+        api_keys: config.srv.api_keys.clone(),
+        max_tile_size: config.srv.max_tile_size,
+        tile_timeout: config.srv.tile_timeout.map(Duration::from_millis),
+        max_zoom: config.srv.max_zoom,
+        base_path: config.srv.base_path.clone(),
     }
 }
 
-pub fn new(pool: Pool, config: Config) -> Server {
-    let keep_alive = config.srv.keep_alive;
-    let worker_processes = config.srv.worker_processes;
-    let listen_addresses = config.srv.listen_addresses.clone();
+/// Resolve the sources of a re-read config using the existing pools, and swap them in.
+/// Requests in flight finish with the previous sources. Only the table, function and raster
+/// sources are reloaded, the other settings need a restart.
+pub async fn reload_sources(
+    sources: &SharedSources,
+    pool: &Pool,
+    database_pools: &[Pool],
+    mut config: Config,
+) -> io::Result<()> {
+    resolve_sources(pool, database_pools, &mut config).await?;
+    let old_sources = sources.load();
+    let new_sources = Sources {
+        generation: old_sources.generation + 1,
+        ..Sources::from(&config)
+    };
 
-    HttpServer::new(move || {
-        let state = create_state(pool.clone(), config.clone());
+    log_source_changes(
+        "table",
+        &old_sources.table_sources,
+        &new_sources.table_sources,
+    );
+    log_source_changes(
+        "function",
+        &old_sources.function_sources,
+        &new_sources.function_sources,
+    );
+    log_source_changes(
+        "raster",
+        &old_sources.raster_sources,
+        &new_sources.raster_sources,
+    );
 
+    sources.store(Arc::new(new_sources));
+    info!(
+        "File sources, fonts, sprites, api_keys and auth are not reloaded, restart to change them"
+    );
+    Ok(())
+}
+
+fn log_source_changes<T: PartialEq>(
+    kind: &str,
+    old: &HashMap<String, Box<T>>,
+    new: &HashMap<String, Box<T>>,
+) {
+    let added = new.keys().filter(|id| !old.contains_key(*id)).sorted();
+    let removed = old.keys().filter(|id| !new.contains_key(*id)).sorted();
+    let changed = new
+        .iter()
+        .filter(|(id, source)| old.get(*id).is_some_and(|old| old != *source))
+        .map(|(id, _)| id)
+        .sorted();
+    info!(
+        "Reloaded {kind} sources, added: [{}], removed: [{}], changed: [{}]",
+        added.format(", "),
+        removed.format(", "),
+        changed.format(", ")
+    );
+}
+
+/// Serve the sources of a built [`Martin`] on the configured address or Unix domain socket,
+/// over HTTPS and HTTP/2 if the `tls` config is set
+pub fn new(martin: Martin, in_flight: InFlight) -> io::Result<Server> {
+    // Loaded before binding, so a bad certificate fails the startup
+    let tls = martin
+        .config
+        .srv
+        .tls
+        .as_ref()
+        .map(load_tls_config)
+        .transpose()?;
+    let keep_alive = martin.config.srv.keep_alive;
+    let worker_processes = martin.config.srv.worker_processes;
+    let listen_addresses = martin.config.srv.listen_addresses.clone();
+    let dual_stack = martin.config.srv.dual_stack;
+    let base_path = martin.config.srv.base_path.clone();
+
+    let server = HttpServer::new(move || {
+        let martin = martin.clone();
         let cors_middleware = Cors::default()
             .allow_any_origin()
             .allowed_methods(vec!["GET"]);
 
         App::new()
-            .app_data(Data::new(state))
             .wrap(cors_middleware)
             .wrap(middleware::NormalizePath::new(TrailingSlash::MergeOnly))
             .wrap(middleware::Logger::default())
             .wrap(middleware::Compress::default())
-            .configure(router)
-    })
-    .bind(listen_addresses.clone())
-    .unwrap_or_else(|_| panic!("Can't bind to {listen_addresses}"))
-    .keep_alive(Duration::from_secs(keep_alive as u64))
-    .shutdown_timeout(0)
-    .workers(worker_processes)
-    .run()
+            .wrap_fn({
+                let in_flight = in_flight.clone();
+                move |req, srv| {
+                    let guard = in_flight.enter();
+                    let response = call_traced(srv, req);
+                    async move {
+                        let response = response.await;
+                        guard.complete();
+                        response
+                    }
+                }
+            })
+            .service(web::scope(&base_path).configure(move |cfg| martin.configure(cfg)))
+    });
+
+    let server = match unix_socket_path(&listen_addresses) {
+        Some(_) if tls.is_some() => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("tls can't be used with the unix socket {listen_addresses}"),
+            ))
+        }
+        Some(_) if dual_stack => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("dual_stack can't be used with the unix socket {listen_addresses}"),
+            ))
+        }
+        #[cfg(unix)]
+        Some(path) => server.listen_uds(bind_unix_socket(path)?)?,
+        #[cfg(not(unix))]
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Can't bind to {listen_addresses}, unix sockets are not supported"),
+            ))
+        }
+        None if dual_stack => {
+            let listener = bind_dual_stack(dual_stack_port(&listen_addresses)?)?;
+            match tls {
+                #[cfg(feature = "tls")]
+                Some(config) => server.listen_rustls(listener, config)?,
+                #[cfg(not(feature = "tls"))]
+                Some(config) => match config {},
+                None => server.listen(listener)?,
+            }
+        }
+        None => {
+            let addrs = parse_listen_addresses(&listen_addresses)?;
+            match tls {
+                #[cfg(feature = "tls")]
+                Some(config) => server.bind_rustls(addrs.as_slice(), config),
+                #[cfg(not(feature = "tls"))]
+                Some(config) => match config {},
+                None => server.bind(addrs.as_slice()),
+            }
+            .map_err(|e| prettify_error!(e, "Can't bind to {}", listen_addresses))?
+        }
+    };
+    Ok(server
+        .keep_alive(Duration::from_secs(keep_alive as u64))
+        .disable_signals()
+        .workers(worker_processes)
+        .run())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tile_etag_is_stable() {
+        // The SHA-256 of the tile, so it must not change with the toolchain
+        let etag = get_tile_etag(&Tile::from_static(b"tile"));
+        assert_eq!(etag, EntityTag::new_weak("8b668b8994aa8451".to_owned()));
+    }
 }
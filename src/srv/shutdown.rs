@@ -0,0 +1,164 @@
+use actix_rt::time::sleep;
+use actix_web::dev::ServerHandle;
+use log::{info, warn};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Counts the requests being served, shared by all server workers,
+/// so the shutdown can report how many of them it has drained
+#[derive(Clone, Debug, Default)]
+pub struct InFlight {
+    active: Arc<AtomicUsize>,
+    completed: Arc<AtomicUsize>,
+}
+
+impl InFlight {
+    /// Number of requests being served
+    pub fn count(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Count a request as in flight until the returned guard is dropped
+    pub fn enter(&self) -> InFlightGuard {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard {
+            in_flight: self.clone(),
+            completed: false,
+        }
+    }
+}
+
+/// A request being served. Requests dropped before [`InFlightGuard::complete`],
+/// e.g. when the shutdown timeout runs out, are not counted as drained.
+pub struct InFlightGuard {
+    in_flight: InFlight,
+    completed: bool,
+}
+
+impl InFlightGuard {
+    pub fn complete(mut self) {
+        self.completed = true;
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.completed {
+            self.in_flight.completed.fetch_add(1, Ordering::SeqCst);
+        }
+        self.in_flight.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Wait for SIGTERM or SIGINT
+#[cfg(unix)]
+pub async fn shutdown_signal() -> io::Result<()> {
+    use actix_rt::signal::unix::{signal, SignalKind};
+    use futures::future::select;
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    select(Box::pin(terminate.recv()), Box::pin(interrupt.recv())).await;
+    Ok(())
+}
+
+/// Wait for Ctrl-C
+#[cfg(not(unix))]
+pub async fn shutdown_signal() -> io::Result<()> {
+    actix_rt::signal::ctrl_c().await
+}
+
+/// Interval between checks of whether the in-flight requests have finished
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Stop accepting connections, wait up to `timeout` for the in-flight requests to finish,
+/// and stop the workers, dropping the connection pools with them.
+/// Requests are drained here rather than by a graceful stop of actix, which can stop
+/// a worker with requests in flight when its connections channel closes first.
+pub async fn shutdown(handle: ServerHandle, in_flight: &InFlight, timeout: Duration) {
+    let pending = in_flight.count();
+    let completed = in_flight.completed.load(Ordering::SeqCst);
+    info!(
+        "Shutting down, waiting up to {}s for {pending} in-flight requests to finish",
+        timeout.as_secs()
+    );
+    handle.pause().await;
+
+    let deadline = Instant::now() + timeout;
+    while in_flight.count() > 0 && Instant::now() < deadline {
+        sleep(DRAIN_POLL_INTERVAL).await;
+    }
+    let remaining = in_flight.count();
+    if remaining > 0 {
+        warn!("Dropping {remaining} requests still running after the shutdown timeout");
+    }
+    let drained = in_flight.completed.load(Ordering::SeqCst) - completed;
+    info!("Drained {drained} requests, closing database connections");
+    handle.stop(false).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::dev::Service;
+    use actix_web::{web, App, HttpServer};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    #[actix_rt::test]
+    async fn test_shutdown_drains_in_flight_requests() {
+        let in_flight = InFlight::default();
+        let counter = in_flight.clone();
+        let server = HttpServer::new(move || {
+            let counter = counter.clone();
+            App::new()
+                .wrap_fn(move |req, srv| {
+                    let guard = counter.enter();
+                    let response = srv.call(req);
+                    async move {
+                        let response = response.await;
+                        guard.complete();
+                        response
+                    }
+                })
+                .route(
+                    "/slow",
+                    web::get().to(|| async {
+                        sleep(Duration::from_millis(500)).await;
+                        "done"
+                    }),
+                )
+        })
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.workers(1).disable_signals().run();
+        let handle = server.handle();
+        let server = actix_rt::spawn(server);
+
+        let request = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+        while in_flight.count() == 0 {
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        shutdown(handle, &in_flight, Duration::from_secs(5)).await;
+        assert_eq!(in_flight.count(), 0);
+        assert_eq!(in_flight.completed.load(Ordering::SeqCst), 1);
+        server.await.unwrap().unwrap();
+
+        let response = request.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("done"));
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+//! TLS termination of the server with rustls, which also serves HTTP/2 to the clients
+//! negotiating it.
+
+use serde::{Deserialize, Serialize};
+use std::io;
+
+/// Certificate of the `tls` config. When set, the server is only reachable over HTTPS.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// Path to the PEM file with the server certificate, followed by its intermediate certificates
+    pub cert: String,
+    /// Path to the PEM file with the private key of the certificate,
+    /// in PKCS#8, PKCS#1 (RSA) or SEC1 (EC) format
+    pub key: String,
+}
+
+#[cfg(feature = "tls")]
+pub use rustls::ServerConfig as TlsServerConfig;
+
+/// Without the `tls` feature, the `tls` config can't be loaded, so there is no server config
+#[cfg(not(feature = "tls"))]
+#[derive(Debug)]
+pub enum TlsServerConfig {}
+
+/// Load the certificate and the private key of the `tls` config, failing with a message
+/// naming the file at fault if they can't be read or don't match
+#[cfg(feature = "tls")]
+pub fn load_tls_config(tls: &TlsConfig) -> io::Result<TlsServerConfig> {
+    use openssl::pkey::PKey;
+    use openssl::x509::X509;
+    use rustls::{Certificate, PrivateKey};
+    use rustls_pemfile::Item;
+    use std::fs::File;
+    use std::io::BufReader;
+
+    let open = |path: &str| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| io::Error::new(e.kind(), format!("Can't read TLS file {path}: {e}")))
+    };
+    let invalid_data = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    let certs = rustls_pemfile::certs(&mut open(&tls.cert)?)
+        .map_err(|e| invalid_data(format!("Can't read TLS cert {}: {e}", tls.cert)))?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!(
+            "TLS cert {} has no PEM certificate",
+            tls.cert
+        )));
+    }
+
+    let key = rustls_pemfile::read_all(&mut open(&tls.key)?)
+        .map_err(|e| invalid_data(format!("Can't read TLS key {}: {e}", tls.key)))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .ok_or_else(|| invalid_data(format!("TLS key {} has no PEM private key", tls.key)))?;
+
+    let mismatch = |e: &dyn std::fmt::Display| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "TLS key {} is not the key of cert {}: {e}",
+                tls.key, tls.cert
+            ),
+        )
+    };
+    // rustls doesn't check that they match, which would only fail the handshakes
+    let cert_key = X509::from_der(&certs[0]).and_then(|cert| cert.public_key());
+    let private_key = PKey::private_key_from_der(&key);
+    match (cert_key, private_key) {
+        (Ok(cert_key), Ok(private_key)) if cert_key.public_eq(&private_key) => {}
+        (Ok(_), Ok(_)) => return Err(mismatch(&"their public keys differ")),
+        (Err(e), _) | (_, Err(e)) => return Err(mismatch(&e)),
+    }
+
+    TlsServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            certs.into_iter().map(Certificate).collect(),
+            PrivateKey(key),
+        )
+        .map_err(|e| mismatch(&e))
+}
+
+#[cfg(not(feature = "tls"))]
+pub fn load_tls_config(tls: &TlsConfig) -> io::Result<TlsServerConfig> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "Serving over TLS with the certificate {} requires the tls feature",
+            tls.cert
+        ),
+    ))
+}
+
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+    use super::*;
+    use crate::pg::dev::mock_tls_cert;
+    use std::fs;
+
+    #[test]
+    fn test_load_tls_config() {
+        let dir = std::env::temp_dir().join(format!("martin_tls_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_owned();
+
+        let (cert, key) = mock_tls_cert();
+        fs::write(path("server.crt"), cert).unwrap();
+        fs::write(path("server.key"), key).unwrap();
+        fs::write(path("other.key"), mock_tls_cert().1).unwrap();
+        fs::write(path("empty.pem"), "not a certificate").unwrap();
+
+        let tls = |cert: &str, key: &str| TlsConfig {
+            cert: path(cert),
+            key: path(key),
+        };
+        load_tls_config(&tls("server.crt", "server.key")).unwrap();
+
+        for (tls, kind) in [
+            (tls("missing.crt", "server.key"), io::ErrorKind::NotFound),
+            (tls("server.crt", "missing.key"), io::ErrorKind::NotFound),
+            (tls("empty.pem", "server.key"), io::ErrorKind::InvalidData),
+            (tls("server.crt", "empty.pem"), io::ErrorKind::InvalidData),
+            (tls("server.crt", "other.key"), io::ErrorKind::InvalidInput),
+        ] {
+            let error = load_tls_config(&tls).unwrap_err();
+            assert_eq!(error.kind(), kind, "{tls:?}: {error}");
+        }
+        let error = load_tls_config(&tls("server.crt", "other.key")).unwrap_err();
+        assert!(error.to_string().contains("other.key"), "{error}");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,134 @@
+//! URLs of the server as seen by its clients, which may be behind a reverse proxy.
+
+use actix_web::HttpRequest;
+
+/// Header of the path prefix a reverse proxy strips before forwarding the requests,
+/// e.g. `/maps` when it serves `https://example.com/maps/{source_id}` from `/{source_id}`
+pub const X_FORWARDED_PREFIX: &str = "x-forwarded-prefix";
+
+/// Scheme and host of the server as seen by the client, e.g. `https://example.com`.
+/// They come from the `Forwarded` or the `X-Forwarded-Proto` and `X-Forwarded-Host` headers
+/// of a reverse proxy, or else from the request itself.
+pub fn public_origin(req: &HttpRequest) -> String {
+    let connection_info = req.connection_info();
+    format!("{}://{}", connection_info.scheme(), connection_info.host())
+}
+
+/// Base URL of the routes as seen by the client, e.g. `https://example.com/maps/tiles`:
+/// the public origin, the `X-Forwarded-Prefix` path stripped by a reverse proxy,
+/// and the `base_path` of the server. It has no trailing slash.
+pub fn public_base_url(req: &HttpRequest, base_path: &str) -> String {
+    let prefix = req
+        .headers()
+        .get(X_FORWARDED_PREFIX)
+        .and_then(|prefix| prefix.to_str().ok())
+        .and_then(|prefix| prefix.split(',').next())
+        .map(normalize_prefix)
+        .unwrap_or_default();
+    format!("{}{prefix}{base_path}", public_origin(req))
+}
+
+/// Public URL of a route, e.g. `/points/{z}/{x}/{y}.pbf`, given relative to the `base_path`
+pub fn public_url(req: &HttpRequest, base_path: &str, route: &str) -> String {
+    format!("{}{route}", public_base_url(req, base_path))
+}
+
+/// Path of the route of the request relative to the `base_path`, e.g. `/points.json`
+pub fn route_path<'a>(req: &'a HttpRequest, base_path: &str) -> &'a str {
+    let path = req.path();
+    path.strip_prefix(base_path)
+        .filter(|route| route.is_empty() || route.starts_with('/'))
+        .unwrap_or(path)
+}
+
+/// A prefix with a leading slash and no trailing one, or empty for the root, e.g. `maps/` -> `/maps`
+fn normalize_prefix(prefix: &str) -> String {
+    prefix
+        .split('/')
+        .map(str::trim)
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| format!("/{segment}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn base_url(headers: &[(&'static str, &'static str)], base_path: &str) -> String {
+        let mut req = TestRequest::get().uri("/tiles/points.json");
+        for header in headers {
+            req = req.insert_header(*header);
+        }
+        public_base_url(&req.to_http_request(), base_path)
+    }
+
+    #[test]
+    fn test_public_base_url_direct() {
+        assert_eq!(
+            base_url(&[("host", "localhost:3000")], ""),
+            "http://localhost:3000"
+        );
+        assert_eq!(
+            base_url(&[("host", "localhost:3000")], "/tiles"),
+            "http://localhost:3000/tiles"
+        );
+    }
+
+    #[test]
+    fn test_public_base_url_forwarded() {
+        let host = ("host", "localhost:3000");
+        let proto = ("x-forwarded-proto", "https");
+        let forwarded_host = ("x-forwarded-host", "example.com");
+        let prefix = ("x-forwarded-prefix", "/maps/");
+
+        for (headers, expected) in [
+            (vec![host, proto], "https://localhost:3000/tiles"),
+            (vec![host, forwarded_host], "http://example.com/tiles"),
+            (vec![host, prefix], "http://localhost:3000/maps/tiles"),
+            (
+                vec![host, proto, forwarded_host],
+                "https://example.com/tiles",
+            ),
+            (
+                vec![host, proto, prefix],
+                "https://localhost:3000/maps/tiles",
+            ),
+            (
+                vec![host, forwarded_host, prefix],
+                "http://example.com/maps/tiles",
+            ),
+            (
+                vec![host, proto, forwarded_host, prefix],
+                "https://example.com/maps/tiles",
+            ),
+            (
+                vec![host, ("forwarded", "proto=https;host=example.com:8443")],
+                "https://example.com:8443/tiles",
+            ),
+        ] {
+            assert_eq!(base_url(&headers, "/tiles"), expected, "{headers:?}");
+        }
+
+        // Only the first of several proxies is used, and an empty prefix is the root
+        let prefix = ("x-forwarded-prefix", "/maps,/other");
+        assert_eq!(base_url(&[host, prefix], ""), "http://localhost:3000/maps");
+        let prefix = ("x-forwarded-prefix", "/");
+        assert_eq!(base_url(&[host, prefix], ""), "http://localhost:3000");
+    }
+
+    #[test]
+    fn test_route_path() {
+        let req = TestRequest::get()
+            .uri("/tiles/points.json")
+            .to_http_request();
+        assert_eq!(route_path(&req, "/tiles"), "/points.json");
+        assert_eq!(route_path(&req, ""), "/tiles/points.json");
+        assert_eq!(route_path(&req, "/til"), "/tiles/points.json");
+        assert_eq!(
+            public_url(&req, "/tiles", "/points/{z}/{x}/{y}.pbf"),
+            "http://localhost:8080/tiles/points/{z}/{x}/{y}.pbf"
+        );
+    }
+}
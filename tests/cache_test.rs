@@ -0,0 +1,145 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use martin::cache::{CachedSource, TileCache};
+use martin::pg::db::Connection;
+use martin::source::{InMemorySource, Source, Tile, UrlQuery, Xyz};
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tilejson::TileJSON;
+
+/// Counts tile requests reaching the in-memory source
+#[derive(Debug)]
+struct CountingSource {
+    inner: InMemorySource,
+    calls: Arc<AtomicUsize>,
+}
+
+#[allow(mismatched_lifetime_syntaxes)]
+#[async_trait]
+impl Source for CountingSource {
+    async fn get_id(&self) -> &str {
+        self.inner.get_id().await
+    }
+
+    async fn get_tilejson(&self) -> Result<TileJSON, io::Error> {
+        self.inner.get_tilejson().await
+    }
+
+    fn is_valid_zoom(&self, zoom: i32) -> bool {
+        self.inner.is_valid_zoom(zoom)
+    }
+
+    fn requires_connection(&self) -> bool {
+        self.inner.requires_connection()
+    }
+
+    async fn get_tile(
+        &self,
+        conn: Option<&mut Connection>,
+        xyz: &Xyz,
+        query: &Option<UrlQuery>,
+    ) -> Result<Tile, io::Error> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        self.inner.get_tile(conn, xyz, query).await
+    }
+}
+
+fn cached_source(cache: &Arc<TileCache>, calls: &Arc<AtomicUsize>) -> CachedSource {
+    let mut inner = InMemorySource::new("in_memory");
+    inner.insert(Xyz { z: 0, x: 0, y: 0 }, Bytes::from_static(&[1, 2, 3]));
+    CachedSource {
+        inner: Box::new(CountingSource {
+            inner,
+            calls: calls.clone(),
+        }),
+        cache: cache.clone(),
+        generation: 0,
+    }
+}
+
+#[actix_rt::test]
+async fn cached_source_ok() {
+    let cache = Arc::new(TileCache::new(10, None, None).unwrap());
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    // Each request builds its own source, the cache is shared
+    let xyz = Xyz { z: 0, x: 0, y: 0 };
+    for _ in 0..2 {
+        let source = cached_source(&cache, &calls);
+        let tile = source.get_tile(None, &xyz, &None).await.unwrap();
+        assert_eq!(tile, Bytes::from_static(&[1, 2, 3]));
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert_eq!(cache.len(), 1);
+
+    // Empty tiles are not cached
+    let source = cached_source(&cache, &calls);
+    let empty = Xyz { z: 1, x: 0, y: 0 };
+    for _ in 0..2 {
+        let tile = source.get_tile(None, &empty, &None).await.unwrap();
+        assert!(tile.is_empty());
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+    // Url query params are part of the key
+    let query = Some(HashMap::from([("a".to_owned(), "1".to_owned())]));
+    for _ in 0..2 {
+        source.get_tile(None, &xyz, &query).await.unwrap();
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 4);
+    assert_eq!(cache.len(), 2);
+
+    // Reloaded sources don't get the tiles of the previous ones
+    let source = CachedSource {
+        generation: 1,
+        ..cached_source(&cache, &calls)
+    };
+    source.get_tile(None, &xyz, &None).await.unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 5);
+}
+
+#[actix_rt::test]
+async fn cached_source_ttl() {
+    let cache = Arc::new(TileCache::new(10, Some(Duration::ZERO), None).unwrap());
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    let source = cached_source(&cache, &calls);
+    let xyz = Xyz { z: 0, x: 0, y: 0 };
+    for _ in 0..2 {
+        source.get_tile(None, &xyz, &None).await.unwrap();
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
+
+#[actix_rt::test]
+async fn cached_source_max_entry_size() {
+    let cache = Arc::new(TileCache::new(10, None, Some(2)).unwrap());
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    // The 3 bytes tile is served every time, but never cached
+    let source = cached_source(&cache, &calls);
+    let xyz = Xyz { z: 0, x: 0, y: 0 };
+    for _ in 0..2 {
+        let tile = source.get_tile(None, &xyz, &None).await.unwrap();
+        assert_eq!(tile, Bytes::from_static(&[1, 2, 3]));
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    assert!(cache.is_empty());
+
+    // Small tiles are still cached
+    let cache = Arc::new(TileCache::new(10, None, Some(3)).unwrap());
+    let source = cached_source(&cache, &calls);
+    for _ in 0..2 {
+        source.get_tile(None, &xyz, &None).await.unwrap();
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+    assert_eq!(cache.len(), 1);
+}
+
+#[test]
+fn tile_cache_disabled() {
+    assert!(TileCache::new(0, None, None).is_none());
+}
@@ -0,0 +1,11 @@
+DROP FUNCTION IF EXISTS public.function_source_static;
+CREATE OR REPLACE FUNCTION public.function_source_static(z integer, x integer, y integer, query_params json) RETURNS bytea AS $$
+  -- A fixed tile with two point features in the 'function_source_static' layer
+  SELECT '\x1a3378020a1666756e6374696f6e5f736f757263655f73746174696312090801180122030914141209080218012203091614288020'::bytea;
+$$ LANGUAGE sql IMMUTABLE STRICT PARALLEL SAFE;
+
+DROP FUNCTION IF EXISTS public.function_source_static_bounds;
+CREATE OR REPLACE FUNCTION public.function_source_static_bounds() RETURNS geometry AS $$
+  -- Bounds of the tiles of function_source_static, found by the source discovery
+  SELECT ST_MakeEnvelope(-2, -1, 2, 1, 4326);
+$$ LANGUAGE sql IMMUTABLE STRICT PARALLEL SAFE;
@@ -0,0 +1,7 @@
+CREATE TABLE points_mixed_srid(gid SERIAL PRIMARY KEY, geom GEOMETRY);
+
+INSERT INTO points_mixed_srid(geom)
+VALUES (ST_SetSRID(ST_MakePoint(30, 10), 4326)),
+       (ST_Transform(ST_SetSRID(ST_MakePoint(-30, -10), 4326), 3857));
+
+CREATE INDEX ON points_mixed_srid USING GIST(geom);
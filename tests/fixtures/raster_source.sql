@@ -0,0 +1,15 @@
+CREATE TABLE raster_source(rid SERIAL PRIMARY KEY, rast RASTER);
+
+-- A single band raster covering the whole world in Web Mercator, with a gradient from west to east
+INSERT INTO raster_source(rast)
+    SELECT
+        ST_MapAlgebra(
+            ST_AddBand(
+                ST_MakeEmptyRaster(100, 100, -20037508.34, 20037508.34, 400748.1668, -400748.1668, 0, 0, 3857),
+                '8BUI'::text, 0, 0
+            ),
+            '8BUI',
+            '[rast.x] * 2'
+        );
+
+SELECT AddRasterConstraints('raster_source'::name, 'rast'::name);
@@ -0,0 +1,5 @@
+DROP TABLE IF EXISTS table_source_ids;
+CREATE TABLE table_source_ids(gid serial PRIMARY KEY, big_id bigint, uid uuid, geom geometry(POINT, 4326));
+
+INSERT INTO table_source_ids(big_id, uid, geom) values (5000000000, 'a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11', GeomFromEWKT('SRID=4326;POINT(0 0)'));
+INSERT INTO table_source_ids(big_id, uid, geom) values (5000000001, 'b0eebc99-9c0b-4ef8-bb6d-6bb9bd380a12', GeomFromEWKT('SRID=4326;POINT(10 10)'));
@@ -0,0 +1,5 @@
+DROP TABLE IF EXISTS table_source_invalid;
+CREATE TABLE table_source_invalid(gid serial PRIMARY KEY, geom geometry(POLYGON, 4326));
+
+-- A self-intersecting "bowtie" polygon, which ST_IsValid rejects
+INSERT INTO table_source_invalid(geom) values (GeomFromEWKT('SRID=4326;POLYGON((0 0, 10 10, 10 0, 0 10, 0 0))'));
@@ -0,0 +1,11 @@
+DROP TABLE IF EXISTS table_source_join;
+CREATE TABLE table_source_join(gid serial PRIMARY KEY, geom geometry(POINT, 4326));
+
+DROP TABLE IF EXISTS table_source_join_attributes;
+CREATE TABLE table_source_join_attributes(gid integer PRIMARY KEY, name text, kind text);
+
+INSERT INTO table_source_join(geom) values (GeomFromEWKT('SRID=4326;POINT(0 0)'));
+INSERT INTO table_source_join(geom) values (GeomFromEWKT('SRID=4326;POINT(10 10)'));
+
+INSERT INTO table_source_join_attributes(gid, name, kind) values (1, 'first', 'a');
+INSERT INTO table_source_join_attributes(gid, name, kind) values (2, 'second', 'b');
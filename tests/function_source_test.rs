@@ -2,6 +2,7 @@ use log::info;
 use martin::pg::dev::make_pool;
 use martin::pg::function_source::get_function_sources;
 use martin::source::{Source, Xyz};
+use tilejson::Bounds;
 
 fn init() {
     let _ = env_logger::builder().is_test(true).try_init();
@@ -26,6 +27,15 @@ async fn get_function_sources_ok() {
     assert_eq!(function_source.minzoom, None);
     assert_eq!(function_source.maxzoom, None);
     assert_eq!(function_source.bounds, None);
+
+    // Bounds of the function_source_static_bounds() companion function
+    let function_source = function_sources
+        .get("public.function_source_static")
+        .unwrap();
+    assert_eq!(
+        function_source.bounds,
+        Some(Bounds::new(-2.0, -1.0, 2.0, 1.0))
+    );
 }
 
 #[actix_rt::test]
@@ -61,7 +71,7 @@ async fn function_source_tile_ok() {
 
     let function_source = function_sources.get("public.function_source").unwrap();
     let tile = function_source
-        .get_tile(&mut connection, &Xyz { x: 0, y: 0, z: 0 }, &None)
+        .get_tile(Some(&mut connection), &Xyz { x: 0, y: 0, z: 0 }, &None)
         .await
         .unwrap();
 
@@ -0,0 +1,51 @@
+use log::info;
+use martin::pg::dev::make_pool;
+use martin::pg::raster_source::get_raster_sources;
+use martin::source::{Source, TileFormat, Xyz};
+
+fn init() {
+    let _ = env_logger::builder().is_test(true).try_init();
+}
+
+#[actix_rt::test]
+async fn get_raster_sources_ok() {
+    init();
+
+    let pool = make_pool().await;
+    let mut connection = pool.get().await.unwrap();
+    let raster_sources = get_raster_sources(&mut connection).await.unwrap();
+
+    info!("raster_sources = {raster_sources:#?}");
+
+    assert!(raster_sources.contains_key("public.raster_source"));
+
+    let raster_source = raster_sources.get("public.raster_source").unwrap();
+    assert_eq!(raster_source.schema, "public");
+    assert_eq!(raster_source.table, "raster_source");
+    assert_eq!(raster_source.raster_column, "rast");
+    assert_eq!(raster_source.srid, 3857);
+    assert_eq!(raster_source.minzoom, None);
+    assert_eq!(raster_source.maxzoom, None);
+
+    let bounds = raster_source.bounds.unwrap();
+    assert!((bounds.left + 180.0).abs() < 0.001);
+    assert!((bounds.right - 180.0).abs() < 0.001);
+}
+
+#[actix_rt::test]
+async fn raster_source_tile_ok() {
+    init();
+
+    let pool = make_pool().await;
+    let mut connection = pool.get().await.unwrap();
+    let raster_sources = get_raster_sources(&mut connection).await.unwrap();
+
+    let raster_source = raster_sources.get("public.raster_source").unwrap();
+    assert_eq!(raster_source.get_format(), TileFormat::Png);
+
+    let tile = raster_source
+        .get_tile(Some(&mut connection), &Xyz { x: 0, y: 0, z: 0 }, &None)
+        .await
+        .unwrap();
+    assert!(tile.starts_with(b"\x89PNG"));
+}
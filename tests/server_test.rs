@@ -1,13 +1,51 @@
 use actix_http::Request;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{
+    ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE, ETAG,
+    IF_NONE_MATCH,
+};
 use actix_web::http::StatusCode;
-use actix_web::test::{call_and_read_body_json, call_service, read_body, TestRequest};
+use actix_web::test::{
+    call_and_read_body_json, call_service, init_service, read_body, TestRequest,
+};
+use actix_web::web::{self, Data};
+use actix_web::App;
+use flate2::read::GzDecoder;
+use indoc::indoc;
+use jsonwebtoken::{encode, get_current_timestamp, EncodingKey, Header};
+use martin::cache::TileCache;
+use martin::config::{read_config, ConfigBuilder};
+use martin::file_tree::{resolve_file_sources, FileSource, FileTreeConfig, FileTreeSource};
+use martin::fonts::{merge_glyphs, FontSources};
+use martin::mvt::{count_features, summarize_mvt};
+use martin::pg::config::IdNormalization;
+use martin::pg::db::{resolve_sources, setup_connection_pool, TlsOptions};
 use martin::pg::dev::{
-    mock_default_function_sources, mock_default_table_sources, mock_function_sources, mock_state,
-    mock_table_sources,
+    make_pool, make_unreachable_pool, mock_default_function_sources, mock_default_raster_sources,
+    mock_default_table_sources, mock_function_sources, mock_raster_sources, mock_state,
+    mock_state_with_pool, mock_table_sources,
 };
 use martin::pg::function_source::{FunctionSource, FunctionSources};
-use martin::pg::table_source::{TableSource, TableSources};
+use martin::pg::raster_source::RasterSource;
+use martin::pg::table_source::{Buffer, GeometryColumn, TableSource, TableSources};
+use martin::pg::utils::{normalize_source_ids, tile_bbox};
+use martin::source::{EmptyTile, InMemorySource, Tile, TileMatrixSet, TileScheme, Xyz};
+use martin::sprites::SpriteSources;
+use martin::srv::api_key::API_KEY_HEADER;
+use martin::srv::auth::{AuthConfig, JwtAlgorithm, JwtAuth};
+use martin::srv::empty_tiles::EmptyTiles;
+use martin::srv::server::{
+    reload_sources, router, with_tile_timeout, Sources, FEATURE_COUNT_HEADER, PARTIAL_TILE_HEADER,
+    UNKNOWN_LAYERS_HEADER,
+};
+use martin::MartinBuilder;
+use serde_json::{json, Value};
 use std::collections::HashMap;
+use std::io::Read;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{env, fs};
 use tilejson::{Bounds, TileJSON};
 
 fn init() {
@@ -28,10 +66,47 @@ macro_rules! create_app {
     }};
 }
 
+/// An app serving the default function and raster sources
+macro_rules! create_raster_app {
+    () => {{
+        init();
+        let state = mock_state(None, None).await;
+        state.sources.store(Arc::new(Sources {
+            function_sources: mock_default_function_sources(),
+            raster_sources: mock_default_raster_sources(),
+            ..Sources::default()
+        }));
+        init_service(App::new().app_data(Data::new(state)).configure(router)).await
+    }};
+}
+
 fn test_get(path: &str) -> Request {
     TestRequest::get().uri(path).to_request()
 }
 
+/// JWT auth with a "secret" HS256 key, requiring the given scopes of the sources
+fn jwt_auth(sources: &[(&str, &str)]) -> Arc<JwtAuth> {
+    let sources = sources
+        .iter()
+        .map(|(id, scope)| ((*id).to_owned(), vec![(*scope).to_owned()]))
+        .collect();
+    let config = AuthConfig {
+        algorithm: JwtAlgorithm::HS256,
+        secret: Some("secret".to_owned()),
+        public_key: None,
+        sources,
+    };
+    Arc::new(JwtAuth::new(&config).unwrap())
+}
+
+/// An `Authorization` header value with a token signed for [`jwt_auth`]
+fn bearer_token(scope: &str) -> String {
+    let claims = json!({ "exp": get_current_timestamp() + 3600, "scope": scope });
+    let key = EncodingKey::from_secret(b"secret");
+    let token = encode(&Header::default(), &claims, &key).unwrap();
+    format!("Bearer {token}")
+}
+
 #[actix_rt::test]
 async fn get_table_sources_ok() {
     let app = create_app!(Some(mock_default_table_sources()), None);
@@ -64,18 +139,15 @@ async fn get_table_source_ok() {
         id: "public.table_source".to_owned(),
         schema: "public".to_owned(),
         table: "table_source".to_owned(),
-        id_column: None,
         geometry_column: "geom".to_owned(),
         bounds: Some(Bounds::MAX),
         minzoom: Some(0),
         maxzoom: Some(30),
         srid: 4326,
         extent: Some(4096),
-        buffer: Some(64),
+        buffer: Some(Buffer::Value(64)),
         clip_geom: Some(true),
-        geometry_type: None,
-        properties: HashMap::new(),
-        unrecognized: HashMap::new(),
+        ..Default::default()
     };
 
     let app = create_app!(Some(mock_table_sources(&[table_source])), None);
@@ -115,6 +187,54 @@ async fn get_table_source_tile_ok() {
     assert!(response.status().is_success());
 }
 
+#[actix_rt::test]
+async fn get_table_source_tile_compressed() {
+    let app = create_app!(Some(mock_default_table_sources()), None);
+
+    let req = TestRequest::get()
+        .uri("/public.points1/0/0/0.pbf")
+        .insert_header((ACCEPT_ENCODING, "identity"))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers().get(CONTENT_ENCODING).unwrap(),
+        "identity"
+    );
+    let raw = read_body(response).await;
+    assert!(count_features(&raw).unwrap() > 0);
+
+    let req = TestRequest::get()
+        .uri("/public.points1/0/0/0.pbf")
+        .insert_header((ACCEPT_ENCODING, "gzip"))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(response.headers().get(CONTENT_ENCODING).unwrap(), "gzip");
+    let gzip = read_body(response).await;
+    assert!(gzip.len() < raw.len());
+    let mut decompressed = Vec::new();
+    GzDecoder::new(&gzip[..])
+        .read_to_end(&mut decompressed)
+        .unwrap();
+    assert_eq!(decompressed, raw);
+
+    let req = TestRequest::get()
+        .uri("/public.points1/0/0/0.pbf")
+        .insert_header((ACCEPT_ENCODING, "gzip, br"))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.headers().get(CONTENT_ENCODING).unwrap(), "gzip");
+
+    let req = TestRequest::get()
+        .uri("/public.points1/0/0/0.pbf")
+        .insert_header((ACCEPT_ENCODING, "br"))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.headers().get(CONTENT_ENCODING).unwrap(), "br");
+    assert!(read_body(response).await.len() < raw.len());
+}
+
 #[actix_rt::test]
 async fn get_table_source_multiple_geom_tile_ok() {
     let app = create_app!(Some(mock_default_table_sources()), None);
@@ -128,6 +248,64 @@ async fn get_table_source_multiple_geom_tile_ok() {
     assert!(response.status().is_success());
 }
 
+#[actix_rt::test]
+async fn get_table_source_tile_layers_subset() {
+    let mut table_sources = mock_default_table_sources();
+    table_sources
+        .get_mut("public.table_source_multiple_geom.geom1")
+        .unwrap()
+        .extra_geometry_columns = Some(vec![GeometryColumn {
+        column: "geom2".to_owned(),
+        srid: None,
+        layer: Some("centroids".to_owned()),
+    }]);
+    let app = create_app!(Some(table_sources), None);
+
+    let path = "/public.table_source_multiple_geom.geom1/0/0/0.pbf";
+    let response = call_service(&app, test_get(path)).await;
+    assert_eq!(
+        get_layer_names(response).await,
+        vec!["public.table_source_multiple_geom.geom1", "centroids"]
+    );
+
+    let response = call_service(&app, test_get(&format!("{path}?layers=centroids"))).await;
+    assert!(response.headers().get(UNKNOWN_LAYERS_HEADER).is_none());
+    assert_eq!(get_layer_names(response).await, vec!["centroids"]);
+
+    // Unknown layers are ignored, and reported
+    let req = test_get(&format!("{path}?layers=rivers,centroids,roads"));
+    let response = call_service(&app, req).await;
+    assert_eq!(
+        response.headers().get(UNKNOWN_LAYERS_HEADER).unwrap(),
+        "rivers,roads"
+    );
+    assert_eq!(get_layer_names(response).await, vec!["centroids"]);
+
+    let response = call_service(&app, test_get(&format!("{path}?layers=rivers"))).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        response.headers().get(UNKNOWN_LAYERS_HEADER).unwrap(),
+        "rivers"
+    );
+}
+
+#[actix_rt::test]
+async fn get_composite_source_tile_layers_subset() {
+    let app = create_app!(Some(mock_default_table_sources()), None);
+
+    let path = "/public.points1,public.points2,public.points3857/0/0/0.pbf";
+    let req = test_get(&format!("{path}?layers=public.points3857,public.points1"));
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(
+        get_layer_names(response).await,
+        vec!["public.points1", "public.points3857"]
+    );
+
+    let response = call_service(&app, test_get(&format!("{path}?layers=public.points2"))).await;
+    assert_eq!(get_layer_names(response).await, vec!["public.points2"]);
+}
+
 #[actix_rt::test]
 async fn get_table_source_tile_minmax_zoom_ok() {
     init();
@@ -136,60 +314,44 @@ async fn get_table_source_tile_minmax_zoom_ok() {
         id: "public.table_source".to_owned(),
         schema: "public".to_owned(),
         table: "table_source".to_owned(),
-        id_column: None,
         geometry_column: "geom".to_owned(),
         bounds: Some(Bounds::MAX),
-        minzoom: None,
         maxzoom: Some(6),
         srid: 4326,
         extent: Some(4096),
-        buffer: Some(64),
+        buffer: Some(Buffer::Value(64)),
         clip_geom: Some(true),
-        geometry_type: None,
-        properties: HashMap::new(),
-        unrecognized: HashMap::new(),
+        ..Default::default()
     };
 
     let points1 = TableSource {
         id: "public.points1".to_owned(),
         schema: "public".to_owned(),
         table: "points1".to_owned(),
-        id_column: None,
         geometry_column: "geom".to_owned(),
         minzoom: Some(6),
         maxzoom: Some(12),
-        geometry_type: None,
-        properties: HashMap::new(),
-        unrecognized: HashMap::new(),
-        ..table_source
+        ..table_source.clone()
     };
 
     let points2 = TableSource {
         id: "public.points2".to_owned(),
         schema: "public".to_owned(),
         table: "points2".to_owned(),
-        id_column: None,
         geometry_column: "geom".to_owned(),
         minzoom: None,
         maxzoom: None,
-        geometry_type: None,
-        properties: HashMap::new(),
-        unrecognized: HashMap::new(),
-        ..table_source
+        ..table_source.clone()
     };
 
     let points3857 = TableSource {
         id: "public.points3857".to_owned(),
         schema: "public".to_owned(),
         table: "points3857".to_owned(),
-        id_column: None,
         geometry_column: "geom".to_owned(),
         minzoom: Some(6),
         maxzoom: None,
-        geometry_type: None,
-        properties: HashMap::new(),
-        unrecognized: HashMap::new(),
-        ..table_source
+        ..table_source.clone()
     };
 
     let tables = &[points1, points2, points3857, table_source];
@@ -198,7 +360,7 @@ async fn get_table_source_tile_minmax_zoom_ok() {
     // zoom = 0 (nothing)
     let req = test_get("/public.points1/0/0/0.pbf");
     let response = call_service(&app, req).await;
-    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
 
     // zoom = 6 (public.points1)
     let req = test_get("/public.points1/6/38/20.pbf");
@@ -213,7 +375,7 @@ async fn get_table_source_tile_minmax_zoom_ok() {
     // zoom = 13 (nothing)
     let req = test_get("/public.points1/13/4952/2560.pbf");
     let response = call_service(&app, req).await;
-    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
 
     // zoom = 0 (public.points2)
     let req = test_get("/public.points2/0/0/0.pbf");
@@ -238,7 +400,7 @@ async fn get_table_source_tile_minmax_zoom_ok() {
     // zoom = 0 (nothing)
     let req = test_get("/public.points3857/0/0/0.pbf");
     let response = call_service(&app, req).await;
-    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
 
     // zoom = 12 (public.points3857)
     let req = test_get("/public.points3857/12/2476/1280.pbf");
@@ -253,260 +415,2529 @@ async fn get_table_source_tile_minmax_zoom_ok() {
     // zoom = 12 (nothing)
     let req = test_get("/public.table_source/12/2476/1280.pbf");
     let response = call_service(&app, req).await;
-    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
 }
 
 #[actix_rt::test]
-async fn get_composite_source_ok() {
-    let app = create_app!(Some(mock_default_table_sources()), None);
-
-    let req = test_get("/public.non_existent1,public.non_existent2.json");
-    let response = call_service(&app, req).await;
-    assert_eq!(response.status(), StatusCode::NOT_FOUND);
-
-    let req = test_get("/public.points1,public.points2,public.points3857.json");
-    let response = call_service(&app, req).await;
+async fn get_table_source_tile_out_of_bounds() {
+    let mut table_sources = mock_default_table_sources();
+    let points1 = table_sources.get_mut("public.points1").unwrap();
+    points1.bounds = Some(Bounds::new(-10.0, 35.0, 40.0, 70.0));
+    points1.skip_out_of_bounds = Some(true);
+    let points2 = table_sources.get_mut("public.points2").unwrap();
+    points2.bounds = Some(Bounds::new(-10.0, 35.0, 40.0, 70.0));
+    points2.skip_out_of_bounds = Some(true);
+    points2.empty_tile = Some(EmptyTile::NotFound);
+    let app = create_app!(Some(table_sources), None);
+
+    // The tile 6/38/20 is within the bounds, 6/10/20 is in the Atlantic
+    let response = call_service(&app, test_get("/public.points1/6/38/20.pbf")).await;
     assert!(response.status().is_success());
-}
-
-#[actix_rt::test]
-async fn get_composite_source_tile_ok() {
-    let app = create_app!(Some(mock_default_table_sources()), None);
-
-    let req = test_get("/public.non_existent1,public.non_existent2/0/0/0.pbf");
-    let response = call_service(&app, req).await;
+    let response = call_service(&app, test_get("/public.points1/6/10/20.pbf")).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = call_service(&app, test_get("/public.points2/6/10/20.pbf")).await;
     assert_eq!(response.status(), StatusCode::NOT_FOUND);
 
-    let req = test_get("/public.points1,public.points2,public.points3857/0/0/0.pbf");
-    let response = call_service(&app, req).await;
-    assert!(response.status().is_success());
+    // Composite tiles leave out the sources with the tile out of their bounds
+    let path = "/public.points1,public.points2/6/10/20.pbf";
+    let response = call_service(&app, test_get(path)).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
 }
 
 #[actix_rt::test]
-async fn get_composite_source_tile_minmax_zoom_ok() {
-    init();
-
-    let public_points1 = TableSource {
-        id: "public.points1".to_owned(),
-        schema: "public".to_owned(),
-        table: "points1".to_owned(),
-        id_column: None,
-        geometry_column: "geom".to_owned(),
-        bounds: Some(Bounds::MAX),
-        minzoom: Some(6),
-        maxzoom: Some(13),
-        srid: 4326,
-        extent: Some(4096),
-        buffer: Some(64),
-        clip_geom: Some(true),
-        geometry_type: None,
-        properties: HashMap::new(),
-        unrecognized: HashMap::new(),
-    };
-
-    let public_points2 = TableSource {
-        id: "public.points2".to_owned(),
+async fn get_table_source_tile_not_modified() {
+    let table_source = TableSource {
+        id: "public.table_source".to_owned(),
         schema: "public".to_owned(),
-        table: "points2".to_owned(),
-        id_column: None,
+        table: "table_source".to_owned(),
         geometry_column: "geom".to_owned(),
         bounds: Some(Bounds::MAX),
-        minzoom: Some(13),
-        maxzoom: Some(20),
+        minzoom: Some(0),
+        maxzoom: Some(30),
         srid: 4326,
         extent: Some(4096),
-        buffer: Some(64),
+        buffer: Some(Buffer::Value(64)),
         clip_geom: Some(true),
-        geometry_type: None,
-        properties: HashMap::new(),
-        unrecognized: HashMap::new(),
+        version_query: Some("SELECT 'v1'".to_owned()),
+        ..Default::default()
     };
 
-    let tables = &[public_points1, public_points2];
-    let app = create_app!(Some(mock_table_sources(tables)), None);
+    let app = create_app!(Some(mock_table_sources(&[table_source])), None);
 
-    // zoom = 0 (nothing)
-    let req = test_get("/public.points1,public.points2/0/0/0.pbf");
+    let req = test_get("/public.table_source/0/0/0.pbf");
     let response = call_service(&app, req).await;
-    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(response.status().is_success());
+    let etag = response.headers().get(ETAG).unwrap().clone();
 
-    // zoom = 6 (public.points1)
-    let req = test_get("/public.points1,public.points2/6/38/20.pbf");
+    let req = TestRequest::get()
+        .uri("/public.table_source/0/0/0.pbf")
+        .insert_header((IF_NONE_MATCH, etag.clone()))
+        .to_request();
     let response = call_service(&app, req).await;
-    assert!(response.status().is_success());
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers().get(ETAG), Some(&etag));
 
-    // zoom = 12 (public.points1)
-    let req = test_get("/public.points1,public.points2/12/2476/1280.pbf");
+    let req = TestRequest::get()
+        .uri("/public.table_source/1/0/0.pbf")
+        .insert_header((IF_NONE_MATCH, etag))
+        .to_request();
     let response = call_service(&app, req).await;
     assert!(response.status().is_success());
+}
 
-    // zoom = 13 (public.points1, public.points2)
-    let req = test_get("/public.points1,public.points2/13/4952/2560.pbf");
+#[actix_rt::test]
+async fn get_table_source_tile_etag() {
+    let app = create_app!(Some(mock_default_table_sources()), None);
+
+    let req = test_get("/public.table_source/0/0/0");
     let response = call_service(&app, req).await;
-    assert!(response.status().is_success());
+    assert_eq!(response.status(), StatusCode::OK);
+    let etag = response.headers().get(ETAG).unwrap().clone();
+    assert!(etag.to_str().unwrap().starts_with("W/\""));
 
-    // zoom = 14 (public.points2)
-    let req = test_get("/public.points1,public.points2/14/9904/5121.pbf");
+    // The tag is the same whatever the content encoding
+    let req = TestRequest::get()
+        .uri("/public.table_source/0/0/0")
+        .insert_header((ACCEPT_ENCODING, "gzip"))
+        .to_request();
     let response = call_service(&app, req).await;
-    assert!(response.status().is_success());
+    assert_eq!(response.headers().get(ETAG), Some(&etag));
 
-    // zoom = 20 (public.points2)
-    let req = test_get("/public.points1,public.points2/20/633856/327787.pbf");
+    let req = TestRequest::get()
+        .uri("/public.table_source/0/0/0")
+        .insert_header((IF_NONE_MATCH, etag.clone()))
+        .to_request();
     let response = call_service(&app, req).await;
-    assert!(response.status().is_success());
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers().get(ETAG), Some(&etag));
+    assert!(read_body(response).await.is_empty());
 
-    // zoom = 21 (nothing)
-    let req = test_get("/public.points1,public.points2/21/1267712/655574.pbf");
+    // Empty tiles are not tagged
+    let req = TestRequest::get()
+        .uri("/public.table_source/20/0/0")
+        .insert_header((IF_NONE_MATCH, etag))
+        .to_request();
     let response = call_service(&app, req).await;
-    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(response.headers().get(ETAG), None);
 }
 
 #[actix_rt::test]
-async fn get_function_sources_ok() {
-    let app = create_app!(None, Some(mock_default_function_sources()));
+async fn get_tile_cache_control() {
+    let app = create_app!(
+        Some(mock_default_table_sources()),
+        Some(mock_default_function_sources())
+    );
 
-    let req = test_get("/rpc/index.json");
-    let response = call_service(&app, req).await;
+    // Tables may change at any time, functions leave it to the client
+    let response = call_service(&app, test_get("/public.table_source/0/0/0")).await;
     assert!(response.status().is_success());
-
-    let body = read_body(response).await;
-    let function_sources: FunctionSources = serde_json::from_slice(&body).unwrap();
-    assert!(function_sources.contains_key("public.function_source"));
+    assert_eq!(response.headers().get(CACHE_CONTROL).unwrap(), "max-age=0");
+    let response = call_service(&app, test_get("/rpc/public.function_source/0/0/0")).await;
+    assert!(response.status().is_success());
+    assert_eq!(response.headers().get(CACHE_CONTROL), None);
+
+    let mut state = mock_state(
+        Some(mock_default_table_sources()),
+        Some(mock_default_function_sources()),
+    )
+    .await;
+    state.cache_max_age = Some(Duration::from_secs(3600));
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    for path in [
+        "/public.table_source/0/0/0",
+        "/rpc/public.function_source/0/0/0",
+        "/comp/public.table_source,public.function_source/0/0/0",
+    ] {
+        let response = call_service(&app, test_get(path)).await;
+        assert!(response.status().is_success(), "{path}");
+        assert_eq!(
+            response.headers().get(CACHE_CONTROL).unwrap(),
+            "max-age=3600",
+            "{path}"
+        );
+    }
 }
 
 #[actix_rt::test]
-async fn get_function_sources_watch_mode_ok() {
-    let app = create_app!(None, Some(mock_default_function_sources()));
+async fn get_table_source_tile_reserved_pool_ok() {
+    init();
 
-    let req = test_get("/rpc/index.json");
+    let mut state = mock_state(Some(mock_default_table_sources()), None).await;
+    state.reserved_pool = Some(make_pool().await);
+    state.reserved_pool_minzoom = 6;
+
+    // Exhaust the main pool, as a flood of low-zoom requests would
+    let pool = state.pool.clone();
+    let _busy = pool.get().await.unwrap();
+
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    let req = test_get("/public.table_source/6/38/20.pbf");
     let response = call_service(&app, req).await;
     assert!(response.status().is_success());
-
-    let body = read_body(response).await;
-    let function_sources: FunctionSources = serde_json::from_slice(&body).unwrap();
-    assert!(function_sources.contains_key("public.function_source"));
 }
 
 #[actix_rt::test]
-async fn get_function_source_ok() {
-    let app = create_app!(None, Some(mock_default_function_sources()));
-
-    let req = test_get("/rpc/public.non_existent.json");
+async fn get_table_source_tile_statement_timeout() {
+    let mut table_sources = mock_default_table_sources();
+    let source = table_sources.get_mut("public.points1").unwrap();
+    source.statement_timeout = Some(100);
+    source.sql_filter = Some("(SELECT true FROM pg_sleep(1))".to_owned());
+    let app = create_app!(Some(table_sources), None);
+
+    let started = Instant::now();
+    let req = test_get("/public.points1/0/0/0");
     let response = call_service(&app, req).await;
-    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    assert!(started.elapsed() < Duration::from_secs(1));
 
-    let req = test_get("/rpc/public.function_source.json");
+    // The timeout only applies to its own query, the connection is reused as is
+    let req = test_get("/public.points2/0/0/0");
     let response = call_service(&app, req).await;
     assert!(response.status().is_success());
-
-    let req = TestRequest::get()
-        .uri("/rpc/public.function_source.json?token=martin")
-        .insert_header((
-            "x-rewrite-url",
-            "/tiles/rpc/public.function_source.json?token=martin",
-        ))
-        .to_request();
-    let result: TileJSON = call_and_read_body_json(&app, req).await;
-    assert_eq!(
-        result.tiles,
-        vec!["http://localhost:8080/tiles/rpc/public.function_source/{z}/{x}/{y}.pbf?token=martin"]
-    );
 }
 
 #[actix_rt::test]
-async fn get_function_source_tile_ok() {
-    let app = create_app!(None, Some(mock_default_function_sources()));
+async fn get_table_source_tile_known_empty() {
+    init();
 
-    let req = test_get("/rpc/public.function_source/0/0/0.pbf");
+    let mut state = mock_state(Some(mock_default_table_sources()), None).await;
+    state.empty_tiles = EmptyTiles::new(10, Duration::from_secs(60));
+    state
+        .empty_tiles
+        .insert(0, "public.table_source", &Xyz { z: 20, x: 0, y: 0 });
+
+    // Drain the pool, so the request would fail if it reached the database
+    let pool = state.pool.clone();
+    let _busy = pool.get().await.unwrap();
+
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    let req = test_get("/public.table_source/20/0/0.pbf");
     let response = call_service(&app, req).await;
-    assert!(response.status().is_success());
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
 }
 
 #[actix_rt::test]
-async fn get_function_source_tile_minmax_zoom_ok() {
-    let function_source1 = FunctionSource {
-        id: "public.function_source1".to_owned(),
-        schema: "public".to_owned(),
-        function: "function_source".to_owned(),
-        minzoom: None,
-        maxzoom: None,
-        bounds: Some(Bounds::MAX),
-        unrecognized: HashMap::new(),
-    };
+async fn get_tile_above_maxzoom() {
+    init();
 
-    let function_source2 = FunctionSource {
-        id: "public.function_source2".to_owned(),
-        schema: "public".to_owned(),
-        function: "function_source".to_owned(),
-        minzoom: Some(6),
-        maxzoom: Some(12),
-        bounds: Some(Bounds::MAX),
-        unrecognized: HashMap::new(),
-    };
+    let mut table_sources = mock_default_table_sources();
+    table_sources
+        .get_mut("public.table_source")
+        .unwrap()
+        .maxzoom = Some(6);
+    let mut function_sources = mock_default_function_sources();
+    function_sources
+        .get_mut("public.function_source")
+        .unwrap()
+        .maxzoom = Some(6);
+    let state = mock_state(Some(table_sources), Some(function_sources)).await;
+
+    // Drain the pool, so the requests would fail if they reached the database
+    let pool = state.pool.clone();
+    let _busy = pool.get().await.unwrap();
+
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    for path in [
+        "/public.table_source/7/0/0.pbf",
+        "/public.table_source/20/0/0.pbf",
+        "/rpc/public.function_source/7/0/0.pbf",
+    ] {
+        let response = call_service(&app, test_get(path)).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT, "{path}");
+    }
+}
 
-    let funcs = &[function_source1, function_source2];
-    let app = create_app!(None, Some(mock_function_sources(funcs)));
+#[actix_rt::test]
+async fn get_tile_invalid_coordinates() {
+    let app = create_app!(
+        Some(mock_default_table_sources()),
+        Some(mock_default_function_sources())
+    );
 
-    // zoom = 0 (public.function_source1)
-    let req = test_get("/rpc/public.function_source1/0/0/0.pbf");
-    let response = call_service(&app, req).await;
-    assert!(response.status().is_success());
+    for path in [
+        "/public.table_source/-1/0/0.pbf",
+        "/public.table_source/0/-1/0.pbf",
+        "/public.table_source/1/0/2.pbf",
+        "/public.table_source/31/0/0.pbf",
+        "/rpc/public.function_source/0/0/4294967296.pbf",
+    ] {
+        let response = call_service(&app, test_get(path)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{path}");
+    }
+}
 
-    // zoom = 6 (public.function_source1)
-    let req = test_get("/rpc/public.function_source1/6/38/20.pbf");
+#[actix_rt::test]
+async fn get_composite_source_ok() {
+    let app = create_app!(Some(mock_default_table_sources()), None);
+
+    let req = test_get("/public.non_existent1,public.non_existent2.json");
     let response = call_service(&app, req).await;
-    assert!(response.status().is_success());
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
 
-    // zoom = 12 (public.function_source1)
-    let req = test_get("/rpc/public.function_source1/12/2476/1280.pbf");
+    let req = test_get("/public.points1,public.points2,public.points3857.json");
     let response = call_service(&app, req).await;
     assert!(response.status().is_success());
+}
 
-    // zoom = 13 (public.function_source1)
-    let req = test_get("/rpc/public.function_source1/13/4952/2560.pbf");
+#[actix_rt::test]
+async fn reload_sources_ok() {
+    init();
+
+    let state = mock_state(Some(mock_default_table_sources()), None).await;
+    let sources = state.sources.clone();
+    let pool = state.pool.clone();
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    let req = test_get("/public.points1.json");
     let response = call_service(&app, req).await;
     assert!(response.status().is_success());
+    let req = test_get("/public.reloaded.json");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let config_file = env::temp_dir().join("martin_reload_sources_ok.yaml");
+    fs::write(
+        &config_file,
+        indoc! {"
+            connection_string: 'postgres://postgres@localhost:5432/db'
+            table_sources:
+              public.reloaded:
+                id: public.reloaded
+                schema: public
+                table: points1
+                srid: 4326
+                geometry_column: geom
+                properties:
+                  gid: int4
+        "},
+    )
+    .unwrap();
+    let config = read_config(config_file.to_str().unwrap())
+        .unwrap()
+        .finalize()
+        .unwrap();
+    reload_sources(&sources, &pool, &[], config).await.unwrap();
+    fs::remove_file(config_file).unwrap();
+
+    let req = test_get("/public.reloaded.json");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let req = test_get("/public.points1.json");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn reload_sources_tile_cache() {
+    init();
+
+    let mut state = mock_state(None, None).await;
+    state.tile_cache = TileCache::new(100, None, None).map(Arc::new);
+    let sources = state.sources.clone();
+    let pool = state.pool.clone();
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    let config_file = env::temp_dir().join("martin_reload_sources_tile_cache.yaml");
+    let mut tiles = Vec::new();
+    for table in ["points1", "points2"] {
+        fs::write(
+            &config_file,
+            format!(
+                indoc! {"
+                    connection_string: 'postgres://postgres@localhost:5432/db'
+                    table_sources:
+                      public.reloaded:
+                        id: public.reloaded
+                        schema: public
+                        table: {table}
+                        srid: 4326
+                        geometry_column: geom
+                        properties:
+                          gid: int4
+                "},
+                table = table
+            ),
+        )
+        .unwrap();
+        let config = read_config(config_file.to_str().unwrap())
+            .unwrap()
+            .finalize()
+            .unwrap();
+        reload_sources(&sources, &pool, &[], config).await.unwrap();
+        let response = call_service(&app, test_get("/public.reloaded/0/0/0")).await;
+        assert_eq!(response.status(), StatusCode::OK, "{table}");
+        tiles.push(read_body(response).await);
+    }
+    fs::remove_file(config_file).unwrap();
+    // The tile cached for the previous source is not served
+    assert_ne!(tiles[0], tiles[1]);
+}
+
+#[actix_rt::test]
+async fn get_sources_from_databases_ok() {
+    init();
+
+    // Both databases are the test database, so the ids of the second one collide with the first
+    let connection_string = env::var("DATABASE_URL").unwrap();
+    let config_file = env::temp_dir().join("martin_get_sources_from_databases_ok.yaml");
+    fs::write(
+        &config_file,
+        format!(
+            indoc! {"
+                connection_string: '{connection_string}'
+                table_sources:
+                  public.points1:
+                    id: public.points1
+                    schema: public
+                    table: points1
+                    srid: 4326
+                    geometry_column: geom
+                    properties:
+                      gid: int4
+                databases:
+                  - connection_string: '{connection_string}'
+                    table_sources:
+                      public.points1:
+                        id: public.points1
+                        schema: public
+                        table: points2
+                        srid: 4326
+                        geometry_column: geom
+                        properties:
+                          gid: int4
+                    function_sources:
+                      public.function_source:
+                        id: public.function_source
+                        schema: public
+                        function: function_source
+            "},
+            connection_string = connection_string
+        ),
+    )
+    .unwrap();
+    let mut config = read_config(config_file.to_str().unwrap())
+        .unwrap()
+        .finalize()
+        .unwrap();
+    fs::remove_file(config_file).unwrap();
+
+    let pool = make_pool().await;
+    let database_pools = vec![make_pool().await];
+    resolve_sources(&pool, &database_pools, &mut config)
+        .await
+        .unwrap();
+
+    let mut state = mock_state(None, None).await;
+    state.database_pools = database_pools;
+    state.sources.store(Arc::new(Sources::from(&config)));
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    let sources: TableSources = call_and_read_body_json(&app, test_get("/index.json")).await;
+    assert_eq!(sources["public.points1"].table, "points1");
+    assert_eq!(sources["public.points1.2"].table, "points2");
+
+    // A composite source is a single query, so it can't span databases
+    let req = test_get("/public.points1,public.points1.2/0/0/0");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    for path in [
+        "/public.points1/0/0/0",
+        "/public.points1.2/0/0/0",
+        "/rpc/public.function_source/0/0/0",
+        "/comp/public.points1,public.points1.2,public.function_source/0/0/0",
+    ] {
+        let response = call_service(&app, test_get(path)).await;
+        assert!(response.status().is_success(), "{path}");
+    }
+}
+
+#[actix_rt::test]
+async fn get_auto_published_sources_ok() {
+    init();
+
+    let connection_string = env::var("DATABASE_URL").unwrap();
+    let resolve = |auto_publish: &str| {
+        let yaml =
+            format!("connection_string: '{connection_string}'\nauto_publish: {auto_publish}");
+        async move {
+            let config: ConfigBuilder = serde_yaml::from_str(&yaml).unwrap();
+            let mut config = config.finalize().unwrap();
+            let pool = make_pool().await;
+            resolve_sources(&pool, &[], &mut config).await.unwrap();
+            config.pg
+        }
+    };
+
+    let pg = resolve("{schemas: [public], exclude_tables: ['points*', 'table_source_*']}").await;
+    assert!(pg.table_sources.contains_key("public.table_source"));
+    assert!(!pg.table_sources.contains_key("public.points1"));
+    assert!(!pg.table_sources.contains_key("public.table_source_join"));
+    assert!(pg.function_sources.contains_key("public.function_source"));
+    assert!(pg.raster_sources.contains_key("public.raster_source"));
+
+    let pg = resolve("{exclude_tables: [public.raster_source]}").await;
+    assert!(pg.table_sources.contains_key("public.points1"));
+    assert!(pg.raster_sources.is_empty());
+
+    let pg = resolve("{exclude_schemas: ['pub*']}").await;
+    assert!(pg.table_sources.is_empty());
+    assert!(pg.function_sources.is_empty());
+    assert!(pg.raster_sources.is_empty());
+
+    let pg = resolve("{schemas: [other]}").await;
+    assert!(pg.table_sources.is_empty());
+    assert!(pg.function_sources.is_empty());
+}
+
+#[actix_rt::test]
+async fn resolve_sources_table_defaults() {
+    init();
+
+    let connection_string = env::var("DATABASE_URL").unwrap();
+    let defaults = "default_extent: 8192\ndefault_buffer: 256\ndefault_clip_geom: false";
+    let resolve = |yaml: String| async move {
+        let config: ConfigBuilder = serde_yaml::from_str(&yaml).unwrap();
+        let mut config = config.finalize().unwrap();
+        let pool = make_pool().await;
+        resolve_sources(&pool, &[], &mut config)
+            .await
+            .map(|_| config.pg)
+    };
+
+    // Discovered tables get the defaults of the database
+    let pg = resolve(format!(
+        "connection_string: '{connection_string}'\n{defaults}"
+    ))
+    .await
+    .unwrap();
+    let source = &pg.table_sources["public.table_source"];
+    assert_eq!(source.extent, Some(8192));
+    assert_eq!(source.buffer, Some(Buffer::Value(256)));
+    assert_eq!(source.clip_geom, Some(false));
+
+    // Configured tables keep their own settings
+    let yaml = format!(
+        indoc! {"
+            connection_string: '{}'
+            {}
+            table_sources:
+              public.table_source:
+                id: public.table_source
+                schema: public
+                table: table_source
+                srid: 4326
+                geometry_column: geom
+                geometry_type: GEOMETRY
+                extent: 2048
+                properties:
+                  gid: int4
+        "},
+        connection_string, defaults
+    );
+    let pg = resolve(yaml).await.unwrap();
+    let source = &pg.table_sources["public.table_source"];
+    assert_eq!(source.extent, Some(2048));
+    assert_eq!(source.buffer, Some(Buffer::Value(256)));
+    assert_eq!(source.clip_geom, Some(false));
+
+    // The default buffer must fit the extent of every table
+    let yaml = format!("connection_string: '{connection_string}'\ndefault_buffer: 5000");
+    assert!(resolve(yaml).await.is_err());
+}
+
+#[actix_rt::test]
+async fn get_alias_tile_ok() {
+    init();
+
+    let connection_string = env::var("DATABASE_URL").unwrap();
+    let resolve = |aliases: &str| {
+        let yaml = format!("connection_string: '{connection_string}'\naliases:\n{aliases}");
+        async move {
+            let config: ConfigBuilder = serde_yaml::from_str(&yaml).unwrap();
+            let mut config = config.finalize().unwrap();
+            let pool = make_pool().await;
+            resolve_sources(&pool, &[], &mut config)
+                .await
+                .map(|_| config)
+        }
+    };
+
+    let config = resolve("  roads: public.table_source\n  tiles: public.function_source")
+        .await
+        .unwrap();
+    let state = mock_state(None, None).await;
+    state.sources.store(Arc::new(Sources::from(&config)));
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    for (original, alias) in [
+        ("/public.table_source/0/0/0", "/roads/0/0/0"),
+        ("/rpc/public.function_source/0/0/0", "/rpc/tiles/0/0/0"),
+    ] {
+        let response = call_service(&app, test_get(original)).await;
+        assert_eq!(response.status(), StatusCode::OK, "{original}");
+        let expected = read_body(response).await;
+        let response = call_service(&app, test_get(alias)).await;
+        assert_eq!(response.status(), StatusCode::OK, "{alias}");
+        assert_eq!(read_body(response).await, expected, "{alias}");
+    }
+    let response = call_service(&app, test_get("/roads.json")).await;
+    assert!(response.status().is_success());
+
+    // Aliases can't shadow a source, nor refer to a missing one
+    let error = resolve("  public.points1: public.table_source")
+        .await
+        .unwrap_err();
+    assert!(
+        error.to_string().contains("already the id of a source"),
+        "{error}"
+    );
+    let error = resolve("  roads: public.missing").await.unwrap_err();
+    assert!(error.to_string().contains("not a source"), "{error}");
+}
+
+#[actix_rt::test]
+async fn get_alias_tile_protected() {
+    init();
+
+    let connection_string = env::var("DATABASE_URL").unwrap();
+    let yaml =
+        format!("connection_string: '{connection_string}'\naliases:\n  roads: public.table_source");
+    let config: ConfigBuilder = serde_yaml::from_str(&yaml).unwrap();
+    let mut config = config.finalize().unwrap();
+    resolve_sources(&make_pool().await, &[], &mut config)
+        .await
+        .unwrap();
+    config
+        .pg
+        .table_sources
+        .get_mut("public.table_source")
+        .unwrap()
+        .api_keys = Some(vec!["key".to_owned()]);
+    let mut state = mock_state(None, None).await;
+    state.sources.store(Arc::new(Sources::from(&config)));
+    // The scopes and the keys of a source protect its aliases too
+    state.auth = Some(jwt_auth(&[("public.table_source", "tiles:read")]));
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    for path in ["/roads.json?key=key", "/roads/0/0/0?key=key"] {
+        let response = call_service(&app, test_get(path)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{path}");
+
+        let req = TestRequest::get()
+            .uri(path)
+            .insert_header((AUTHORIZATION, bearer_token("tiles:read")))
+            .to_request();
+        let response = call_service(&app, req).await;
+        assert!(response.status().is_success(), "{path}");
+    }
+
+    let req = TestRequest::get()
+        .uri("/roads/0/0/0")
+        .insert_header((AUTHORIZATION, bearer_token("tiles:read")))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_rt::test]
+async fn resolve_sources_on_missing_source() {
+    init();
+
+    let connection_string = env::var("DATABASE_URL").unwrap();
+    let resolve = |on_missing_source: &str| {
+        let yaml = format!(
+            indoc! {"
+                connection_string: '{}'
+                on_missing_source: {}
+                function_sources:
+                  public.function_source:
+                    id: public.function_source
+                    schema: public
+                    function: function_source
+                  public.non_existent:
+                    id: public.non_existent
+                    schema: public
+                    function: non_existent
+            "},
+            connection_string, on_missing_source
+        );
+        async move {
+            let config: ConfigBuilder = serde_yaml::from_str(&yaml).unwrap();
+            let mut config = config.finalize().unwrap();
+            let pool = make_pool().await;
+            resolve_sources(&pool, &[], &mut config)
+                .await
+                .map(|_| config.pg)
+        }
+    };
+
+    let pg = resolve("warn").await.unwrap();
+    assert!(pg.function_sources.contains_key("public.function_source"));
+
+    let error = resolve("error").await.unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Configured sources are not in the database: function source public.non_existent (public.non_existent)"
+    );
+}
+
+#[actix_rt::test]
+async fn get_table_source_normalized_id() {
+    let mut table_sources = mock_default_table_sources();
+    for id in ["MixedCase.MixPoints", "mixedcase.mixpoints"] {
+        let mut source = table_sources["public.points1"].as_ref().clone();
+        source.id = id.to_owned();
+        table_sources.insert(id.to_owned(), Box::new(source));
+    }
+    normalize_source_ids(
+        &mut table_sources,
+        IdNormalization::Slug,
+        "-",
+        false,
+        |src| &mut src.id,
+    )
+    .unwrap();
+    assert_eq!(
+        table_sources["mixedcase-mixpoints"].id,
+        "mixedcase-mixpoints"
+    );
+    assert_eq!(
+        table_sources["mixedcase-mixpoints-2"].id,
+        "mixedcase-mixpoints-2"
+    );
+    assert!(table_sources.contains_key("public-points1"));
+
+    let app = create_app!(Some(table_sources), None);
+
+    let req = test_get("/mixedcase-mixpoints.json");
+    let result: TileJSON = call_and_read_body_json(&app, req).await;
+    assert_eq!(result.name, Some("mixedcase-mixpoints".to_owned()));
+
+    let req = test_get("/MixedCase.MixPoints.json");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn get_composite_source_mixed_tile_schemes() {
+    let mut table_sources = mock_default_table_sources();
+    table_sources.get_mut("public.points1").unwrap().tile_scheme = Some(TileScheme::Tms);
+    let app = create_app!(Some(table_sources), None);
+
+    let req = test_get("/public.points1.json");
+    let result: TileJSON = call_and_read_body_json(&app, req).await;
+    assert_eq!(result.scheme, Some("tms".to_owned()));
+
+    let req = test_get("/public.points1,public.points2.json");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let req = test_get("/public.points1,public.points2/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_rt::test]
+async fn get_composite_source_tile_ok() {
+    let app = create_app!(Some(mock_default_table_sources()), None);
+
+    let req = test_get("/public.non_existent1,public.non_existent2/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let req = test_get("/public.points1,public.points2,public.points3857/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+}
+
+#[actix_rt::test]
+async fn get_composite_source_tile_partial() {
+    init();
+
+    let mut table_sources = mock_default_table_sources();
+    let mut broken = table_sources["public.points2"].as_ref().clone();
+    broken.id = "public.broken".to_owned();
+    broken.table = "non_existent".to_owned();
+    table_sources.insert(broken.id.clone(), Box::new(broken.clone()));
+    // Only this one can be left out without the server-wide option
+    broken.id = "public.optional".to_owned();
+    broken.partial_composite_tiles = Some(true);
+    table_sources.insert(broken.id.clone(), Box::new(broken));
+
+    let mut state = mock_state(Some(table_sources.clone()), None).await;
+    state.tile_cache = TileCache::new(100, None, None).map(Arc::new);
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    let req = test_get("/public.points1,public.optional/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers().get(PARTIAL_TILE_HEADER).unwrap(),
+        "public.optional"
+    );
+    assert!(response.headers().get(ETAG).is_none());
+
+    for path in [
+        "/public.points1,public.broken/0/0/0.pbf",
+        "/public.optional,public.broken/0/0/0.pbf",
+        "/public.optional,public.optional/0/0/0.pbf",
+    ] {
+        let response = call_service(&app, test_get(path)).await;
+        assert!(response.status().is_server_error(), "{path}");
+    }
+
+    let mut state = mock_state(Some(table_sources), None).await;
+    state.partial_composite_tiles = true;
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    let req = test_get("/public.points1,public.broken/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers().get(PARTIAL_TILE_HEADER).unwrap(),
+        "public.broken"
+    );
+
+    let req = test_get("/public.points1,public.points2/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert!(response.headers().get(PARTIAL_TILE_HEADER).is_none());
+    let partial = read_body(response).await;
+
+    // The layers are the same as the ones of the tile fetched with a single query
+    let app = create_app!(Some(mock_default_table_sources()), None);
+    let req = test_get("/public.points1,public.points2/0/0/0.pbf");
+    assert_eq!(read_body(call_service(&app, req).await).await, partial);
+}
+
+#[actix_rt::test]
+async fn get_composite_source_tile_minmax_zoom_ok() {
+    init();
+
+    let public_points1 = TableSource {
+        id: "public.points1".to_owned(),
+        schema: "public".to_owned(),
+        table: "points1".to_owned(),
+        geometry_column: "geom".to_owned(),
+        bounds: Some(Bounds::MAX),
+        minzoom: Some(6),
+        maxzoom: Some(13),
+        srid: 4326,
+        extent: Some(4096),
+        buffer: Some(Buffer::Value(64)),
+        clip_geom: Some(true),
+        ..Default::default()
+    };
+
+    let public_points2 = TableSource {
+        id: "public.points2".to_owned(),
+        schema: "public".to_owned(),
+        table: "points2".to_owned(),
+        geometry_column: "geom".to_owned(),
+        bounds: Some(Bounds::MAX),
+        minzoom: Some(13),
+        maxzoom: Some(20),
+        srid: 4326,
+        extent: Some(4096),
+        buffer: Some(Buffer::Value(64)),
+        clip_geom: Some(true),
+        ..Default::default()
+    };
+
+    let tables = &[public_points1, public_points2];
+    let app = create_app!(Some(mock_table_sources(tables)), None);
+
+    // zoom = 0 (nothing)
+    let req = test_get("/public.points1,public.points2/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    // zoom = 6 (public.points1)
+    let req = test_get("/public.points1,public.points2/6/38/20.pbf");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    // zoom = 12 (public.points1)
+    let req = test_get("/public.points1,public.points2/12/2476/1280.pbf");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    // zoom = 13 (public.points1, public.points2)
+    let req = test_get("/public.points1,public.points2/13/4952/2560.pbf");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    // zoom = 14 (public.points2)
+    let req = test_get("/public.points1,public.points2/14/9904/5121.pbf");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    // zoom = 20 (public.points2)
+    let req = test_get("/public.points1,public.points2/20/633856/327787.pbf");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    // zoom = 21 (nothing)
+    let req = test_get("/public.points1,public.points2/21/1267712/655574.pbf");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+}
+
+#[actix_rt::test]
+async fn get_function_sources_ok() {
+    let app = create_app!(None, Some(mock_default_function_sources()));
+
+    let req = test_get("/rpc/index.json");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    let body = read_body(response).await;
+    let function_sources: FunctionSources = serde_json::from_slice(&body).unwrap();
+    assert!(function_sources.contains_key("public.function_source"));
+}
+
+#[actix_rt::test]
+async fn get_function_sources_watch_mode_ok() {
+    let app = create_app!(None, Some(mock_default_function_sources()));
+
+    let req = test_get("/rpc/index.json");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    let body = read_body(response).await;
+    let function_sources: FunctionSources = serde_json::from_slice(&body).unwrap();
+    assert!(function_sources.contains_key("public.function_source"));
+}
+
+#[actix_rt::test]
+async fn get_function_source_tile_feature_count() {
+    init();
+
+    let function_source = FunctionSource {
+        id: "public.function_source_static".to_owned(),
+        schema: "public".to_owned(),
+        function: "function_source_static".to_owned(),
+        minzoom: None,
+        maxzoom: None,
+        bounds: None,
+        name: None,
+        description: None,
+        attribution: None,
+        query_params: None,
+        vector_layers: None,
+        api_keys: None,
+        empty_tile: None,
+        headers: None,
+        unrecognized: HashMap::new(),
+    };
+    let mut state = mock_state(None, Some(mock_function_sources(&[function_source]))).await;
+    state.feature_count_header = true;
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    let req = test_get("/rpc/public.function_source_static/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let count = response
+        .headers()
+        .get(FEATURE_COUNT_HEADER)
+        .unwrap()
+        .clone();
+    let body = read_body(response).await;
+    assert_eq!(count, count_features(&body).unwrap().to_string());
+    assert_eq!(count, "2");
+}
+
+fn static_function_source(id: &str) -> FunctionSource {
+    FunctionSource {
+        id: id.to_owned(),
+        schema: "public".to_owned(),
+        function: "function_source_static".to_owned(),
+        minzoom: None,
+        maxzoom: None,
+        bounds: None,
+        name: None,
+        description: None,
+        attribution: None,
+        query_params: None,
+        vector_layers: None,
+        api_keys: None,
+        empty_tile: None,
+        headers: None,
+        unrecognized: HashMap::new(),
+    }
+}
+
+async fn get_layer_names(response: ServiceResponse) -> Vec<String> {
+    let body = read_body(response).await;
+    summarize_mvt(&body)
+        .unwrap()
+        .layers
+        .into_iter()
+        .map(|layer| layer.name)
+        .collect()
+}
+
+#[actix_rt::test]
+async fn get_merged_tile_ok() {
+    let function_sources =
+        mock_function_sources(&[static_function_source("public.function_source_static")]);
+    let app = create_app!(Some(mock_default_table_sources()), Some(function_sources));
+
+    let req = test_get("/comp/public.points1,public.non_existent/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let req = test_get("/comp/public.points1,public.function_source_static/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(
+        get_layer_names(response).await,
+        vec!["public.points1", "function_source_static"]
+    );
+}
+
+#[actix_rt::test]
+async fn get_merged_tile_layer_collision() {
+    let function_sources = mock_function_sources(&[
+        static_function_source("public.static1"),
+        static_function_source("public.static2"),
+    ]);
+    let app = create_app!(None, Some(function_sources));
+
+    let req = test_get("/comp/public.static1,public.static2/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(
+        get_layer_names(response).await,
+        vec![
+            "function_source_static",
+            "public.static2.function_source_static"
+        ]
+    );
+}
+
+#[actix_rt::test]
+async fn get_function_source_ok() {
+    let app = create_app!(None, Some(mock_default_function_sources()));
+
+    let req = test_get("/rpc/public.non_existent.json");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let req = test_get("/rpc/public.function_source.json");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    let req = TestRequest::get()
+        .uri("/rpc/public.function_source.json?token=martin")
+        .insert_header((
+            "x-rewrite-url",
+            "/tiles/rpc/public.function_source.json?token=martin",
+        ))
+        .to_request();
+    let result: TileJSON = call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        result.tiles,
+        vec!["http://localhost:8080/tiles/rpc/public.function_source/{z}/{x}/{y}.pbf?token=martin"]
+    );
+}
+
+#[actix_rt::test]
+async fn get_function_source_tile_not_compressed_if_larger() {
+    let function_sources =
+        mock_function_sources(&[static_function_source("public.function_source_static")]);
+    let app = create_app!(None, Some(function_sources));
+
+    // The static tile is too small to gain anything from compression
+    let req = TestRequest::get()
+        .uri("/rpc/public.function_source_static/0/0/0.pbf")
+        .insert_header((ACCEPT_ENCODING, "gzip"))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers().get(CONTENT_ENCODING).unwrap(),
+        "identity"
+    );
+    let body = read_body(response).await;
+    assert_eq!(count_features(&body).unwrap(), 2);
+}
+
+#[actix_rt::test]
+async fn get_function_source_tile_extension() {
+    let function_sources =
+        mock_function_sources(&[static_function_source("public.function_source_static")]);
+    let app = create_app!(Some(mock_default_table_sources()), Some(function_sources));
+
+    for path in [
+        "/rpc/public.function_source_static/0/0/0.pbf",
+        "/rpc/public.function_source_static/0/0/0.mvt",
+        "/rpc/public.function_source_static/0/0/0",
+        "/comp/public.function_source_static/0/0/0",
+    ] {
+        let response = call_service(&app, test_get(path)).await;
+        assert_eq!(response.status(), StatusCode::OK, "{path}");
+    }
+
+    for path in [
+        "/rpc/public.function_source_static/0/0/0.png",
+        "/rpc/public.function_source_static/0/0/0.pbf.gz",
+        "/comp/public.function_source_static/0/0/0.jpg",
+        "/public.points1/0/0/0.png",
+    ] {
+        let response = call_service(&app, test_get(path)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{path}");
+    }
+}
+
+#[actix_rt::test]
+async fn get_function_source_tilejson_overrides() {
+    let mut source = static_function_source("public.function_source_static");
+    source.name = Some("Static points".to_owned());
+    source.description = Some("Two points".to_owned());
+    source.attribution = Some("© Martin".to_owned());
+    let plain = static_function_source("public.function_source_plain");
+    let app = create_app!(None, Some(mock_function_sources(&[source, plain])));
+
+    let req = test_get("/rpc/public.function_source_static.json");
+    let result: TileJSON = call_and_read_body_json(&app, req).await;
+    assert_eq!(result.name, Some("Static points".to_owned()));
+    assert_eq!(result.description, Some("Two points".to_owned()));
+    assert_eq!(result.attribution, Some("© Martin".to_owned()));
+
+    let req = test_get("/rpc/public.function_source_plain.json");
+    let result: TileJSON = call_and_read_body_json(&app, req).await;
+    assert_eq!(result.name, Some("public.function_source_plain".to_owned()));
+    assert_eq!(result.description, None);
+    assert_eq!(result.attribution, None);
+}
+
+#[actix_rt::test]
+async fn get_source_api_key() {
+    let mut function_sources = mock_default_function_sources();
+    function_sources
+        .get_mut("public.function_source")
+        .unwrap()
+        .api_keys = Some(vec!["secret".to_owned()]);
+    let mut table_sources = mock_default_table_sources();
+    table_sources
+        .get_mut("public.table_source")
+        .unwrap()
+        .api_keys = Some(vec!["secret".to_owned()]);
+    let app = create_app!(Some(table_sources), Some(function_sources));
+
+    // the sources lists don't expose the keys
+    for path in ["/index.json", "/rpc/index.json"] {
+        let response = call_service(&app, test_get(path)).await;
+        assert!(response.status().is_success());
+        let body = read_body(response).await;
+        assert!(!String::from_utf8_lossy(&body).contains("secret"));
+    }
+
+    // no key required
+    let req = test_get("/rpc/public.function_source_query_params.json");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    // unauthorized
+    let req = test_get("/rpc/public.function_source.json");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let req = test_get("/rpc/public.function_source.json?key=wrong");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let req = test_get("/public.table_source/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let req = test_get("/public.points1,public.table_source/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // authorized
+    let req = test_get("/rpc/public.function_source.json?key=secret");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    let req = TestRequest::get()
+        .uri("/rpc/public.function_source.json")
+        .insert_header((API_KEY_HEADER, "secret"))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+}
+
+#[actix_rt::test]
+async fn get_source_jwt_scopes() {
+    init();
+    let mut state = mock_state(Some(mock_default_table_sources()), None).await;
+    state.auth = Some(jwt_auth(&[("public.points1", "tiles:read")]));
+    // The scopes are checked below the scope the routes are registered in
+    let app = init_service(
+        App::new().service(
+            web::scope("/tiles")
+                .app_data(Data::new(state))
+                .configure(router),
+        ),
+    )
+    .await;
+    for path in [
+        "/tiles/public.points1.json",
+        "/tiles/public.points1/0/0/0",
+        "/tiles/public.points2,public.points1/0/0/0",
+        "/tiles/comp/public.points1/0/0/0",
+    ] {
+        let response = call_service(&app, test_get(path)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{path}");
+
+        let req = TestRequest::get()
+            .uri(path)
+            .insert_header((AUTHORIZATION, bearer_token("profile")))
+            .to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN, "{path}");
+
+        let req = TestRequest::get()
+            .uri(path)
+            .insert_header((AUTHORIZATION, bearer_token("tiles:read")))
+            .to_request();
+        let response = call_service(&app, req).await;
+        assert!(response.status().is_success(), "{path}");
+    }
+
+    // The sources list and the other sources are not protected
+    for path in ["/tiles/index.json", "/tiles/public.points2/0/0/0"] {
+        let response = call_service(&app, test_get(path)).await;
+        assert!(response.status().is_success(), "{path}");
+    }
+}
+
+#[actix_rt::test]
+async fn get_server_api_key() {
+    init();
+    let mut state = mock_state(
+        Some(mock_default_table_sources()),
+        Some(mock_default_function_sources()),
+    )
+    .await;
+    state.api_keys = Some(vec!["secret".to_owned(), "other".to_owned()]);
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    // health probes stay open
+    let req = test_get("/health");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    // missing and bad keys
+    let req = test_get("/index.json");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let req = TestRequest::get()
+        .uri("/public.points1/0/0/0.pbf")
+        .insert_header((API_KEY_HEADER, "wrong"))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // header and query keys
+    let req = TestRequest::get()
+        .uri("/public.points1/0/0/0.pbf")
+        .insert_header((API_KEY_HEADER, "other"))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    let req =
+        test_get("/rpc/public.function_source_query_params/0/0/0.pbf?token=martin&key=secret");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+}
+
+#[actix_rt::test]
+async fn get_server_api_key_embedded() {
+    init();
+
+    let connection_string = env::var("DATABASE_URL").unwrap();
+    let yaml = format!("connection_string: '{connection_string}'\napi_keys: [secret]");
+    let config: ConfigBuilder = serde_yaml::from_str(&yaml).unwrap();
+    let martin = MartinBuilder::new(config.finalize().unwrap())
+        .build()
+        .await
+        .unwrap();
+    // Routes registered by an app embedding martin check the keys too
+    let app = init_service(
+        App::new().service(web::scope("/tiles").configure(move |cfg| martin.configure(cfg))),
+    )
+    .await;
+
+    let response = call_service(&app, test_get("/tiles/health")).await;
+    assert!(response.status().is_success());
+
+    let response = call_service(&app, test_get("/tiles/index.json")).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = call_service(&app, test_get("/tiles/index.json?key=secret")).await;
+    assert!(response.status().is_success());
+}
+
+#[actix_rt::test]
+async fn get_tile_max_tile_size() {
+    init();
+    let mut state = mock_state(Some(mock_default_table_sources()), None).await;
+    state.max_tile_size = Some(1);
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    let req = test_get("/public.points1/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    // empty tiles are never too large
+    let req = test_get("/public.points1/12/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let req = test_get("/comp/public.points1,public.points2/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[actix_rt::test]
+async fn tile_timeout() {
+    let xyz = Xyz { z: 0, x: 0, y: 0 };
+    let slow_tile = || async {
+        actix_rt::time::sleep(Duration::from_millis(100)).await;
+        Ok(Tile::new())
+    };
+
+    let error = with_tile_timeout(Some(Duration::from_millis(10)), "slow", &xyz, slow_tile())
+        .await
+        .unwrap_err();
+    assert_eq!(
+        error.as_response_error().status_code(),
+        StatusCode::GATEWAY_TIMEOUT
+    );
+
+    let tile = with_tile_timeout(Some(Duration::from_secs(1)), "slow", &xyz, slow_tile()).await;
+    assert!(tile.is_ok());
+    let tile = with_tile_timeout(None, "slow", &xyz, slow_tile()).await;
+    assert!(tile.is_ok());
+}
+
+#[actix_rt::test]
+async fn get_tile_above_max_zoom() {
+    init();
+
+    // The sources have tiles up to zoom 30, above the max_zoom of the server
+    let mut state = mock_state(
+        Some(mock_default_table_sources()),
+        Some(mock_default_function_sources()),
+    )
+    .await;
+    state.max_zoom = Some(10);
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    for path in [
+        "/public.table_source/11/0/0",
+        "/rpc/public.function_source/11/0/0",
+        "/comp/public.table_source,public.function_source/11/0/0",
+    ] {
+        let response = call_service(&app, test_get(path)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{path}");
+        let body = read_body(response).await;
+        assert_eq!(
+            body, "Zoom level 11 is above the max_zoom 10 of the server",
+            "{path}"
+        );
+    }
+
+    for path in [
+        "/public.table_source/10/0/0",
+        "/rpc/public.function_source/10/0/0",
+    ] {
+        let response = call_service(&app, test_get(path)).await;
+        assert!(response.status().is_success(), "{path}");
+    }
+}
+
+#[actix_rt::test]
+async fn get_table_source_tile_with_extent() {
+    let app = create_app!(Some(mock_default_table_sources()), None);
+
+    let response = call_service(&app, test_get("/public.table_source/0/0/0?extent=8192")).await;
+    assert!(response.status().is_success());
+    let response = call_service(&app, test_get("/public.table_source/0/0/0?extent=4096")).await;
+    assert!(response.status().is_success());
+
+    let response = call_service(&app, test_get("/public.table_source/0/0/0?extent=100000")).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = read_body(response).await;
+    assert_eq!(
+        body,
+        "Invalid extent '100000', expected one of: 2048, 4096, 8192"
+    );
+}
+
+#[actix_rt::test]
+async fn get_table_source_tile_as_geojson() {
+    let app = create_app!(Some(mock_default_table_sources()), None);
+
+    let requests = [
+        test_get("/public.table_source/0/0/0.geojson"),
+        TestRequest::get()
+            .uri("/public.table_source/0/0/0")
+            .insert_header((ACCEPT, "application/geo+json"))
+            .to_request(),
+    ];
+    for req in requests {
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "application/geo+json"
+        );
+        let geojson: serde_json::Value =
+            serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(geojson["type"], "FeatureCollection");
+        let features = geojson["features"].as_array().unwrap();
+        assert!(!features.is_empty());
+        assert_eq!(features[0]["type"], "Feature");
+        assert!(features[0]["geometry"]["coordinates"].is_array());
+    }
+
+    // Composite sources are served as vector tiles only
+    let req = test_get("/public.table_source,public.points1/0/0/0.geojson");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_rt::test]
+async fn get_function_source_tile_ok() {
+    let app = create_app!(None, Some(mock_default_function_sources()));
+
+    let req = test_get("/rpc/public.function_source/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+}
+
+#[actix_rt::test]
+async fn get_function_source_tile_minmax_zoom_ok() {
+    let function_source1 = FunctionSource {
+        id: "public.function_source1".to_owned(),
+        schema: "public".to_owned(),
+        function: "function_source".to_owned(),
+        minzoom: None,
+        maxzoom: None,
+        bounds: Some(Bounds::MAX),
+        name: None,
+        description: None,
+        attribution: None,
+        query_params: None,
+        vector_layers: None,
+        api_keys: None,
+        empty_tile: None,
+        headers: None,
+        unrecognized: HashMap::new(),
+    };
+
+    let function_source2 = FunctionSource {
+        id: "public.function_source2".to_owned(),
+        schema: "public".to_owned(),
+        function: "function_source".to_owned(),
+        minzoom: Some(6),
+        maxzoom: Some(12),
+        bounds: Some(Bounds::MAX),
+        name: None,
+        description: None,
+        attribution: None,
+        query_params: None,
+        vector_layers: None,
+        api_keys: None,
+        empty_tile: None,
+        headers: None,
+        unrecognized: HashMap::new(),
+    };
+
+    let funcs = &[function_source1, function_source2];
+    let app = create_app!(None, Some(mock_function_sources(funcs)));
+
+    // zoom = 0 (public.function_source1)
+    let req = test_get("/rpc/public.function_source1/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    // zoom = 6 (public.function_source1)
+    let req = test_get("/rpc/public.function_source1/6/38/20.pbf");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    // zoom = 12 (public.function_source1)
+    let req = test_get("/rpc/public.function_source1/12/2476/1280.pbf");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    // zoom = 13 (public.function_source1)
+    let req = test_get("/rpc/public.function_source1/13/4952/2560.pbf");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    // zoom = 0 (nothing)
+    let req = test_get("/rpc/public.function_source2/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    // zoom = 6 (public.function_source2)
+    let req = test_get("/rpc/public.function_source2/6/38/20.pbf");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    // zoom = 12 (public.function_source2)
+    let req = test_get("/rpc/public.function_source2/12/2476/1280.pbf");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    // zoom = 13 (nothing)
+    let req = test_get("/rpc/public.function_source2/13/4952/2560.pbf");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+}
+
+#[actix_rt::test]
+async fn get_function_source_query_params_ok() {
+    let app = create_app!(None, Some(mock_default_function_sources()));
+
+    let req = test_get("/rpc/public.function_source_query_params/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    println!("response.status = {:?}", response.status());
+    assert!(response.status().is_server_error());
+
+    let req = test_get("/rpc/public.function_source_query_params/0/0/0.pbf?token=martin");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+}
+
+#[actix_rt::test]
+async fn get_health_returns_ok() {
+    let app = create_app!(None, Some(mock_default_function_sources()));
+
+    let req = test_get("/healthz");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+}
+
+#[actix_rt::test]
+async fn get_liveness_and_readiness_ok() {
+    let app = create_app!(None, None);
 
-    // zoom = 0 (nothing)
-    let req = test_get("/rpc/public.function_source2/0/0/0.pbf");
+    let req = test_get("/health");
+    let response: serde_json::Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(response, json!({ "status": "ok" }));
+
+    let req = test_get("/ready");
+    let response: serde_json::Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(response, json!({ "status": "ok" }));
+}
+
+#[actix_rt::test]
+async fn get_readiness_failing_pool() {
+    init();
+
+    let table_sources = mock_default_table_sources();
+    let mut failing: Vec<&String> = table_sources.keys().collect();
+    failing.sort();
+    let mut state = mock_state(Some(table_sources.clone()), None).await;
+    state.reserved_pool = Some(make_unreachable_pool().await);
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    let req = test_get("/health");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    // The sources served by the reserved pool are listed
+    let req = test_get("/ready");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body: serde_json::Value = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(body, json!({ "status": "unavailable", "failing": failing }));
+}
+
+#[actix_rt::test]
+async fn get_readiness_failing_sources() {
+    init();
+
+    let tiles_dir = env::temp_dir().join("martin_get_readiness_failing_sources");
+    fs::create_dir_all(&tiles_dir).unwrap();
+    let tree = FileTreeSource::new("tiles", &tiles_dir, "pbf").unwrap();
+    fs::remove_dir(&tiles_dir).unwrap();
+
+    let table_sources = mock_table_sources(&[mock_default_table_sources()
+        .remove("public.table_source")
+        .map(|source| *source)
+        .unwrap()]);
+    let mut state = mock_state_with_pool(make_unreachable_pool().await, Some(table_sources), None);
+    state.file_sources = Some(HashMap::from([
+        ("tiles".to_owned(), Box::new(FileSource::Tree(tree))),
+        (
+            "in_memory".to_owned(),
+            Box::new(FileSource::InMemory(InMemorySource::new("in_memory"))),
+        ),
+    ]));
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    let req = test_get("/ready");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body: serde_json::Value = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(
+        body,
+        json!({ "status": "unavailable", "failing": ["public.table_source", "tiles"] })
+    );
+}
+
+#[actix_rt::test]
+async fn get_tile_pool_exhausted() {
+    init();
+
+    let function_sources =
+        mock_function_sources(&[static_function_source("public.function_source_static")]);
+    let mut state = mock_state(None, Some(function_sources)).await;
+    state.pool = setup_connection_pool(
+        &env::var("DATABASE_URL").unwrap(),
+        &TlsOptions::default(),
+        1,
+        Duration::from_millis(200),
+        true,
+    )
+    .await
+    .unwrap();
+    let pool = state.pool.clone();
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    // Hold the only connection, so the tile request can't get one
+    let connection = pool.get().await.unwrap();
+    let started = Instant::now();
+    let req = test_get("/rpc/public.function_source_static/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    drop(connection);
+    let req = test_get("/rpc/public.function_source_static/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+}
+
+#[actix_rt::test]
+async fn get_tile_without_prepared_statements() {
+    init();
+
+    let mut state = mock_state(
+        Some(mock_default_table_sources()),
+        Some(mock_default_function_sources()),
+    )
+    .await;
+    state.pool = setup_connection_pool(
+        &env::var("DATABASE_URL").unwrap(),
+        &TlsOptions::default(),
+        1,
+        Duration::from_secs(1),
+        false,
+    )
+    .await
+    .unwrap();
+    assert!(!state.pool.get().await.unwrap().prepared_statements);
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    for path in [
+        "/public.points1/0/0/0.pbf",
+        "/comp/public.points1,public.points2/0/0/0.pbf",
+        "/rpc/public.function_source/0/0/0.pbf",
+    ] {
+        let response = call_service(&app, test_get(path)).await;
+        assert!(response.status().is_success(), "{path}");
+    }
+}
+
+#[cfg(feature = "observability")]
+#[actix_rt::test]
+async fn get_metrics_ok() {
+    let mut function_sources = mock_default_function_sources();
+    function_sources
+        .get_mut("public.function_source")
+        .unwrap()
+        .maxzoom = Some(6);
+    let app = create_app!(None, Some(function_sources));
+
+    let req = test_get("/rpc/public.function_source/7/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let req = test_get("/metrics");
+    let body = read_body(call_service(&app, req).await).await;
+    let metrics = String::from_utf8(body.to_vec()).unwrap();
+    assert!(metrics
+        .contains(r#"martin_tile_requests_total{source="public.function_source",status="204"} 1"#));
+    assert!(metrics.contains(
+        r#"martin_tile_request_duration_seconds_count{source="public.function_source"} 1"#
+    ));
+    assert!(metrics.contains(r#"martin_pool_connections{pool="pool",state="idle"}"#));
+    assert!(metrics.contains(r#"martin_pool_connections{pool="pool",state="active"} 0"#));
+}
+
+#[actix_rt::test]
+async fn put_log_level_requires_admin_endpoints() {
+    let app = create_app!(None, None);
+
+    let req = TestRequest::put()
+        .uri("/log-level")
+        .set_payload("martin=debug")
+        .to_request();
     let response = call_service(&app, req).await;
     assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
 
-    // zoom = 6 (public.function_source2)
-    let req = test_get("/rpc/public.function_source2/6/38/20.pbf");
+#[actix_rt::test]
+async fn get_debug_sql_ok() {
+    let app = create_app!(Some(mock_default_table_sources()), None);
+    let response = call_service(&app, test_get("/debug/sql/public.table_source/0/0/0")).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let mut state = mock_state(
+        Some(mock_default_table_sources()),
+        Some(mock_default_function_sources()),
+    )
+    .await;
+    state.debug_endpoints = true;
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    let response = call_service(&app, test_get("/debug/sql/public.table_source/1/1/0")).await;
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers().get(CONTENT_TYPE).unwrap(),
+        "text/plain; charset=utf-8"
+    );
+    let sql = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+    assert!(sql.contains(r#"FROM public."table_source""#), "{sql}");
+    assert!(sql.contains(&tile_bbox(&Xyz { x: 1, y: 0, z: 1 })), "{sql}");
+
+    let response = call_service(&app, test_get("/debug/sql/public.function_source/1/1/0")).await;
+    assert!(response.status().is_success());
+    let sql = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+    assert!(sql.contains(r#""public"."function_source"("#), "{sql}");
+    assert!(sql.contains("$1 = 1, $2 = 0, $3 = 1"), "{sql}");
+
+    let response = call_service(&app, test_get("/debug/sql/public.non_existent/0/0/0")).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn get_raster_source_ok() {
+    let app = create_raster_app!();
+
+    let req = test_get("/raster/index.json");
     let response = call_service(&app, req).await;
     assert!(response.status().is_success());
+    let body = read_body(response).await;
+    let sources: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(sources.get("public.raster_source").is_some());
 
-    // zoom = 12 (public.function_source2)
-    let req = test_get("/rpc/public.function_source2/12/2476/1280.pbf");
+    let req = test_get("/raster/public.non_existent.json");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let req = TestRequest::get()
+        .uri("/raster/public.raster_source.json")
+        .insert_header(("host", "localhost:3000"))
+        .to_request();
+    let tilejson: TileJSON = call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        tilejson.tiles,
+        &["http://localhost:3000/raster/public.raster_source/{z}/{x}/{y}.png"]
+    );
+}
+
+#[actix_rt::test]
+async fn get_raster_source_tile_ok() {
+    let app = create_raster_app!();
+
+    let req = test_get("/raster/public.raster_source/0/0/0.png");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(response.headers().get("content-type").unwrap(), "image/png");
+    let body = read_body(response).await;
+    assert!(body.starts_with(b"\x89PNG"));
+}
+
+#[actix_rt::test]
+async fn get_raster_source_tile_high_dpi() {
+    let app = create_raster_app!();
+
+    // The width and height of a PNG image follow its signature and the IHDR chunk header
+    let png_size = |png: &[u8]| {
+        let width = u32::from_be_bytes(png[16..20].try_into().unwrap());
+        let height = u32::from_be_bytes(png[20..24].try_into().unwrap());
+        (width, height)
+    };
+
+    let req = test_get("/raster/public.raster_source/0/0/0.png");
     let response = call_service(&app, req).await;
     assert!(response.status().is_success());
+    assert_eq!(png_size(&read_body(response).await), (256, 256));
+
+    for path in [
+        "/raster/public.raster_source/0/0/0@2x.png",
+        "/raster/public.raster_source/0/0/0@2x",
+    ] {
+        let response = call_service(&app, test_get(path)).await;
+        assert!(response.status().is_success(), "{path}");
+        assert_eq!(response.headers().get("content-type").unwrap(), "image/png");
+        assert_eq!(png_size(&read_body(response).await), (512, 512), "{path}");
+    }
+
+    // Vector tiles have no high-DPI variant
+    let req = test_get("/rpc/public.function_source/0/0/0@2x.pbf");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
 
-    // zoom = 13 (nothing)
-    let req = test_get("/rpc/public.function_source2/13/4952/2560.pbf");
+#[actix_rt::test]
+async fn get_raster_source_tile_format() {
+    let app = create_raster_app!();
+
+    let req = test_get("/raster/public.raster_source/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let req = test_get("/raster/public.non_existent/0/0/0.png");
     let response = call_service(&app, req).await;
     assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // Raster tiles can't be merged with vector ones
+    let req = test_get("/comp/public.function_source,public.raster_source/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // Vector sources don't serve images
+    let req = test_get("/rpc/public.function_source/0/0/0.png");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
 }
 
 #[actix_rt::test]
-async fn get_function_source_query_params_ok() {
-    let app = create_app!(None, Some(mock_default_function_sources()));
+async fn get_source_with_base_path() {
+    init();
+    let function_sources =
+        mock_function_sources(&[static_function_source("public.function_source_static")]);
+    let state = mock_state(Some(mock_default_table_sources()), Some(function_sources)).await;
+    let app = init_service(
+        App::new()
+            .app_data(Data::new(state))
+            .service(web::scope("/tiles").configure(router)),
+    )
+    .await;
 
-    let req = test_get("/rpc/public.function_source_query_params/0/0/0.pbf");
+    let req = TestRequest::get()
+        .uri("/tiles/public.points1.json")
+        .insert_header(("host", "maps.example.com"))
+        .to_request();
+    let tilejson: TileJSON = call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        tilejson.tiles,
+        &["http://maps.example.com/tiles/public.points1/{z}/{x}/{y}.pbf"]
+    );
+
+    let req = test_get("/tiles/rpc/public.function_source_static/0/0/0.pbf");
     let response = call_service(&app, req).await;
-    println!("response.status = {:?}", response.status());
-    assert!(response.status().is_server_error());
+    assert!(response.status().is_success());
 
-    let req = test_get("/rpc/public.function_source_query_params/0/0/0.pbf?token=martin");
+    let req = test_get("/tiles/health");
     let response = call_service(&app, req).await;
     assert!(response.status().is_success());
+
+    let req = test_get("/public.points1.json");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
 }
 
 #[actix_rt::test]
-async fn get_health_returns_ok() {
-    let app = create_app!(None, Some(mock_default_function_sources()));
+async fn get_tilejson_forwarded_tiles_url() {
+    let app = create_app!(
+        Some(mock_default_table_sources()),
+        Some(mock_default_function_sources())
+    );
 
-    let req = test_get("/healthz");
+    let req = TestRequest::get()
+        .uri("/public.points1.json")
+        .insert_header(("x-forwarded-proto", "https"))
+        .insert_header(("x-forwarded-host", "maps.example.com"))
+        .to_request();
+    let tilejson: TileJSON = call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        tilejson.tiles,
+        &["https://maps.example.com/public.points1/{z}/{x}/{y}.pbf"]
+    );
+
+    let req = TestRequest::get()
+        .uri("/rpc/public.function_source.json")
+        .insert_header(("forwarded", "proto=https;host=maps.example.com:8443"))
+        .to_request();
+    let tilejson: TileJSON = call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        tilejson.tiles,
+        &["https://maps.example.com:8443/rpc/public.function_source/{z}/{x}/{y}.pbf"]
+    );
+
+    let req = TestRequest::get()
+        .uri("/public.points1.json")
+        .insert_header(("x-forwarded-proto", "https"))
+        .insert_header(("x-forwarded-host", "example.com"))
+        .insert_header(("x-forwarded-prefix", "/maps"))
+        .to_request();
+    let tilejson: TileJSON = call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        tilejson.tiles,
+        &["https://example.com/maps/public.points1/{z}/{x}/{y}.pbf"]
+    );
+
+    // Without forwarded headers, the URL is built from the request itself
+    let req = TestRequest::get()
+        .uri("/public.points1.json")
+        .insert_header(("host", "localhost:3000"))
+        .to_request();
+    let tilejson: TileJSON = call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        tilejson.tiles,
+        &["http://localhost:3000/public.points1/{z}/{x}/{y}.pbf"]
+    );
+}
+
+#[actix_rt::test]
+async fn get_function_source_typed_query_params() {
+    let mut function_source = static_function_source("public.function_source_static");
+    function_source.query_params = Some(HashMap::from([
+        ("limit".to_owned(), "integer".to_owned()),
+        ("label".to_owned(), "text".to_owned()),
+    ]));
+    let app = create_app!(None, Some(mock_function_sources(&[function_source])));
+
+    let req = test_get("/rpc/public.function_source_static/0/0/0.pbf?limit=10&label=42");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    // Declared params are optional
+    let req = test_get("/rpc/public.function_source_static/0/0/0.pbf");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    for query in ["limit=ten", "limit=", "limit=1.5"] {
+        let req = test_get(&format!(
+            "/rpc/public.function_source_static/0/0/0.pbf?{query}"
+        ));
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = read_body(response).await;
+        assert!(String::from_utf8_lossy(&body).contains("query parameter 'limit'"));
+    }
+
+    let req = test_get("/comp/public.function_source_static/0/0/0.pbf?limit=ten");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_rt::test]
+async fn get_composite_source_tile_matrix_sets() {
+    let mut table_sources = mock_default_table_sources();
+    let mut crs84_source = table_sources["public.points1"].as_ref().clone();
+    crs84_source.id = "public.points1_crs84".to_owned();
+    crs84_source.tile_matrix_set = Some(TileMatrixSet::WorldCrs84Quad);
+    table_sources.insert(crs84_source.id.clone(), Box::new(crs84_source));
+    let app = create_app!(Some(table_sources), None);
+
+    let req = test_get("/public.points1_crs84.json");
+    let tilejson: TileJSON = call_and_read_body_json(&app, req).await;
+    assert_eq!(tilejson.other.get("crs"), Some(&json!("EPSG:4326")));
+
+    // Zoom 0 of WorldCRS84Quad has two columns, Web Mercator only one
+    let req = test_get("/public.points1_crs84/0/2/0.pbf");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let req = test_get("/public.points1/0/1/0.pbf");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    for path in [
+        "/public.points1,public.points1_crs84.json",
+        "/public.points1,public.points1_crs84/0/0/0.pbf",
+        "/comp/public.points1_crs84/0/0/0.pbf",
+    ] {
+        let response = call_service(&app, test_get(path)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
+
+#[actix_rt::test]
+async fn get_font_ok() {
+    init();
+    let fonts_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/fonts");
+    let mut state = mock_state_with_pool(make_unreachable_pool().await, None, None);
+    state.fonts = Some(FontSources::new(fonts_dir).unwrap());
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    let fonts: Vec<String> = call_and_read_body_json(&app, test_get("/font/index.json")).await;
+    assert_eq!(fonts, vec!["Font A", "Font B"]);
+    let catalog: Value = call_and_read_body_json(&app, test_get("/catalog")).await;
+    assert_eq!(catalog["fonts"], json!(["Font A", "Font B"]));
+
+    let font_a = fs::read(format!("{fonts_dir}/Font A/0-255.pbf")).unwrap();
+    let font_b = fs::read(format!("{fonts_dir}/Font B/0-255.pbf")).unwrap();
+
+    let response = call_service(&app, test_get("/font/Font%20A/0-255.pbf")).await;
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers().get(CONTENT_TYPE).unwrap(),
+        "application/x-protobuf"
+    );
+    assert_eq!(read_body(response).await, font_a);
+
+    // Glyphs of both fonts, those of Font A first
+    let merged = merge_glyphs("Font A, Font B", "0-255", [&font_a[..], &font_b[..]]).unwrap();
+    let response = call_service(&app, test_get("/font/Font%20A,Font%20B/0-255.pbf")).await;
+    assert!(response.status().is_success());
+    assert_eq!(read_body(response).await, merged);
+
+    // Fonts without the range are skipped
+    let response = call_service(&app, test_get("/font/Missing,Font%20B/0-255.pbf")).await;
+    assert_eq!(read_body(response).await, font_b);
+
+    for (path, status) in [
+        ("/font/Missing/0-255.pbf", StatusCode::NOT_FOUND),
+        ("/font/Font%20A/256-511.pbf", StatusCode::NOT_FOUND),
+        ("/font/Font%20A/0-100.pbf", StatusCode::BAD_REQUEST),
+        ("/font/..,Font%20A/0-255.pbf", StatusCode::BAD_REQUEST),
+    ] {
+        let response = call_service(&app, test_get(path)).await;
+        assert_eq!(response.status(), status, "{path}");
+    }
+
+    let state = mock_state_with_pool(make_unreachable_pool().await, None, None);
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+    let response = call_service(&app, test_get("/font/Font%20A/0-255.pbf")).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn get_sprite_ok() {
+    init();
+    let sprites_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/sprites");
+    let mut state = mock_state_with_pool(make_unreachable_pool().await, None, None);
+    state.sprites = Some(
+        SpriteSources::new(&HashMap::from([
+            ("basic".to_owned(), format!("{sprites_dir}/basic")),
+            ("icons".to_owned(), format!("{sprites_dir}/icons")),
+        ]))
+        .unwrap(),
+    );
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    let ids: Vec<String> = call_and_read_body_json(&app, test_get("/sprite/index.json")).await;
+    assert_eq!(ids, vec!["basic", "icons"]);
+    let catalog: Value = call_and_read_body_json(&app, test_get("/catalog")).await;
+    assert_eq!(catalog["sprites"], json!(["basic", "icons"]));
+    assert_eq!(catalog["fonts"], json!([]));
+
+    for (path, file, content_type) in [
+        ("/sprite/basic.json", "sprite.json", "application/json"),
+        ("/sprite/basic.png", "sprite.png", "image/png"),
+        (
+            "/sprite/basic@2x.json",
+            "sprite@2x.json",
+            "application/json",
+        ),
+        ("/sprite/basic@2x.png", "sprite@2x.png", "image/png"),
+    ] {
+        let response = call_service(&app, test_get(path)).await;
+        assert!(response.status().is_success(), "{path}");
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            content_type,
+            "{path}"
+        );
+        let expected = fs::read(format!("{sprites_dir}/basic/{file}")).unwrap();
+        assert_eq!(read_body(response).await, expected, "{path}");
+    }
+
+    for (path, status) in [
+        ("/sprite/icons.png", StatusCode::OK),
+        ("/sprite/icons@2x.png", StatusCode::NOT_FOUND),
+        ("/sprite/missing.json", StatusCode::NOT_FOUND),
+        ("/sprite/basic.svg", StatusCode::NOT_FOUND),
+    ] {
+        let response = call_service(&app, test_get(path)).await;
+        assert_eq!(response.status(), status, "{path}");
+    }
+
+    let state = mock_state_with_pool(make_unreachable_pool().await, None, None);
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+    let response = call_service(&app, test_get("/sprite/basic.json")).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn get_in_memory_source_ok() {
+    init();
+    let mut source = InMemorySource::new("in_memory");
+    source.insert(Xyz { z: 0, x: 0, y: 0 }, Tile::from_static(&[1, 2, 3]));
+    // The database is never queried for the tiles of an in-memory source
+    let mut state = mock_state_with_pool(make_unreachable_pool().await, None, None);
+    state.file_sources = Some(HashMap::from([(
+        "in_memory".to_owned(),
+        Box::new(FileSource::InMemory(source)),
+    )]));
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    let tilejson: TileJSON = call_and_read_body_json(&app, test_get("/file/in_memory.json")).await;
+    assert_eq!(tilejson.name, Some("in_memory".to_owned()));
+
+    let response = call_service(&app, test_get("/file/in_memory/0/0/0.pbf")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let etag = response.headers().get(ETAG).unwrap().clone();
+    assert_eq!(read_body(response).await, Tile::from_static(&[1, 2, 3]));
+
+    let req = TestRequest::get()
+        .uri("/file/in_memory/0/0/0.pbf")
+        .insert_header((IF_NONE_MATCH, etag))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+    let response = call_service(&app, test_get("/file/in_memory/1/0/0.pbf")).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+}
+
+#[actix_rt::test]
+async fn get_file_source_jwt_scopes() {
+    init();
+    let mut source = InMemorySource::new("private");
+    source.insert(Xyz { z: 0, x: 0, y: 0 }, Tile::from_static(&[1, 2, 3]));
+    let mut state = mock_state_with_pool(make_unreachable_pool().await, None, None);
+    state.file_sources = Some(HashMap::from([(
+        "private".to_owned(),
+        Box::new(FileSource::InMemory(source)),
+    )]));
+    state.auth = Some(jwt_auth(&[("private", "tiles:read")]));
+    // The scopes are checked below the scope the routes are registered in
+    let app = init_service(
+        App::new().service(
+            web::scope("/tiles")
+                .app_data(Data::new(state))
+                .configure(router),
+        ),
+    )
+    .await;
+    for path in ["/tiles/file/private.json", "/tiles/file/private/0/0/0.pbf"] {
+        let response = call_service(&app, test_get(path)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{path}");
+
+        let req = TestRequest::get()
+            .uri(path)
+            .insert_header((AUTHORIZATION, bearer_token("profile")))
+            .to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN, "{path}");
+
+        let req = TestRequest::get()
+            .uri(path)
+            .insert_header((AUTHORIZATION, bearer_token("tiles:read")))
+            .to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::OK, "{path}");
+    }
+
+    // The sources list is not protected
+    let response = call_service(&app, test_get("/tiles/file/index.json")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn get_file_tree_source_ok() {
+    init();
+    let tiles_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tiles");
+    // The database is never queried for the tiles of a file source
+    let mut state = mock_state_with_pool(make_unreachable_pool().await, None, None);
+    state.file_sources = Some(
+        resolve_file_sources(&HashMap::from([(
+            "tiles".to_owned(),
+            FileTreeConfig {
+                path: tiles_dir.to_owned(),
+                extension: "pbf".to_owned(),
+                minzoom: None,
+                maxzoom: None,
+            },
+        )]))
+        .unwrap(),
+    );
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    let ids: Vec<String> = call_and_read_body_json(&app, test_get("/file/index.json")).await;
+    assert_eq!(ids, vec!["tiles"]);
+
+    let req = TestRequest::get()
+        .uri("/file/tiles.json")
+        .insert_header(("host", "localhost:3000"))
+        .to_request();
+    let tilejson: TileJSON = call_and_read_body_json(&app, req).await;
+    assert_eq!(tilejson.maxzoom, Some(1));
+    assert_eq!(
+        tilejson.tiles,
+        &["http://localhost:3000/file/tiles/{z}/{x}/{y}.pbf"]
+    );
+
+    let response = call_service(&app, test_get("/file/tiles/1/1/0.pbf")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let expected = fs::read(format!("{tiles_dir}/1/1/0.pbf")).unwrap();
+    assert_eq!(read_body(response).await, expected);
+
+    for (path, status) in [
+        ("/file/tiles/1/0/0.pbf", StatusCode::NO_CONTENT),
+        ("/file/tiles/1/1/0.png", StatusCode::BAD_REQUEST),
+        ("/file/missing/0/0/0", StatusCode::NOT_FOUND),
+    ] {
+        let response = call_service(&app, test_get(path)).await;
+        assert_eq!(response.status(), status, "{path}");
+    }
+}
+
+#[actix_rt::test]
+async fn get_file_tree_source_jwt_scopes() {
+    init();
+    let tiles_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tiles");
+    let mut state = mock_state_with_pool(make_unreachable_pool().await, None, None);
+    state.file_sources = Some(
+        resolve_file_sources(&HashMap::from([(
+            "tiles".to_owned(),
+            FileTreeConfig {
+                path: tiles_dir.to_owned(),
+                extension: "pbf".to_owned(),
+                minzoom: None,
+                maxzoom: None,
+            },
+        )]))
+        .unwrap(),
+    );
+    state.auth = Some(jwt_auth(&[("tiles", "tiles:read")]));
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    for path in ["/file/tiles.json", "/file/tiles/1/1/0.pbf"] {
+        let response = call_service(&app, test_get(path)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{path}");
+
+        let req = TestRequest::get()
+            .uri(path)
+            .insert_header((AUTHORIZATION, bearer_token("tiles:read")))
+            .to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::OK, "{path}");
+    }
+}
+
+#[cfg(feature = "geoparquet")]
+#[actix_rt::test]
+async fn get_geoparquet_source_ok() {
+    init();
+    let path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/geoparquet/features.parquet"
+    );
+    let mut state = mock_state_with_pool(make_unreachable_pool().await, None, None);
+    state.file_sources = Some(
+        resolve_file_sources(&HashMap::from([(
+            "features".to_owned(),
+            FileTreeConfig {
+                path: path.to_owned(),
+                extension: "parquet".to_owned(),
+                minzoom: None,
+                maxzoom: None,
+            },
+        )]))
+        .unwrap(),
+    );
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    let tilejson: TileJSON = call_and_read_body_json(&app, test_get("/file/features.json")).await;
+    assert_eq!(tilejson.vector_layers.unwrap()[0].id, "features");
+
+    let response = call_service(&app, test_get("/file/features/1/1/0.pbf")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let summary = summarize_mvt(&read_body(response).await).unwrap();
+    assert_eq!(summary.layers[0].features, 2);
+
+    let response = call_service(&app, test_get("/file/features/1/0/1.pbf")).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+}
+
+#[cfg(feature = "geoparquet")]
+#[actix_rt::test]
+async fn get_geoparquet_source_jwt_scopes() {
+    init();
+    let path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/geoparquet/features.parquet"
+    );
+    let mut state = mock_state_with_pool(make_unreachable_pool().await, None, None);
+    state.file_sources = Some(
+        resolve_file_sources(&HashMap::from([(
+            "features".to_owned(),
+            FileTreeConfig {
+                path: path.to_owned(),
+                extension: "parquet".to_owned(),
+                minzoom: None,
+                maxzoom: None,
+            },
+        )]))
+        .unwrap(),
+    );
+    state.auth = Some(jwt_auth(&[("features", "tiles:read")]));
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    for path in ["/file/features.json", "/file/features/1/1/0.pbf"] {
+        let response = call_service(&app, test_get(path)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{path}");
+
+        let req = TestRequest::get()
+            .uri(path)
+            .insert_header((AUTHORIZATION, bearer_token("tiles:read")))
+            .to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::OK, "{path}");
+    }
+}
+
+#[cfg(feature = "tls")]
+#[actix_rt::test]
+async fn get_tile_over_tls_and_http2() {
+    use actix_web::HttpServer;
+    use martin::pg::dev::mock_tls_cert;
+    use martin::srv::tls::{load_tls_config, TlsConfig};
+
+    init();
+    let dir = env::temp_dir().join(format!("martin_server_tls_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir(&dir).unwrap();
+    let (cert, key) = mock_tls_cert();
+    fs::write(dir.join("server.crt"), &cert).unwrap();
+    fs::write(dir.join("server.key"), key).unwrap();
+    let tls = load_tls_config(&TlsConfig {
+        cert: dir.join("server.crt").to_str().unwrap().to_owned(),
+        key: dir.join("server.key").to_str().unwrap().to_owned(),
+    })
+    .unwrap();
+
+    let tiles_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tiles");
+    // The database is never queried for the tiles of a file source
+    let mut state = mock_state_with_pool(make_unreachable_pool().await, None, None);
+    state.file_sources = Some(
+        resolve_file_sources(&HashMap::from([(
+            "tiles".to_owned(),
+            FileTreeConfig {
+                path: tiles_dir.to_owned(),
+                extension: "pbf".to_owned(),
+                minzoom: None,
+                maxzoom: None,
+            },
+        )]))
+        .unwrap(),
+    );
+    let state = Data::new(state);
+    let server = HttpServer::new(move || App::new().app_data(state.clone()).configure(router))
+        .workers(1)
+        .disable_signals()
+        .bind_rustls(("127.0.0.1", 0), tls)
+        .unwrap();
+    let port = server.addrs()[0].port();
+    let server = server.run();
+    let handle = server.handle();
+    actix_rt::spawn(server);
+
+    let client = reqwest::Client::builder()
+        .add_root_certificate(reqwest::Certificate::from_pem(&cert).unwrap())
+        .resolve("localhost", ([127, 0, 0, 1], port).into())
+        .build()
+        .unwrap();
+    let response = client
+        .get(format!("https://localhost:{port}/file/tiles/1/1/0.pbf"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.version(), reqwest::Version::HTTP_2);
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let expected = fs::read(format!("{tiles_dir}/1/1/0.pbf")).unwrap();
+    assert_eq!(response.bytes().await.unwrap(), expected);
+
+    handle.stop(true).await;
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[actix_rt::test]
+async fn get_empty_tile_policies() {
+    let table_source = mock_default_table_sources()["public.table_source"]
+        .deref()
+        .clone();
+    let raster_source = mock_default_raster_sources()["public.raster_source"]
+        .deref()
+        .clone();
+    // Tiles below the minzoom are empty without querying the database
+    let mut tables = Vec::new();
+    let mut rasters = Vec::new();
+    for (id, empty_tile) in [
+        ("default", None),
+        ("no_content", Some(EmptyTile::NoContent)),
+        ("not_found", Some(EmptyTile::NotFound)),
+        ("blank", Some(EmptyTile::Blank)),
+    ] {
+        tables.push(TableSource {
+            id: format!("table_{id}"),
+            minzoom: Some(6),
+            empty_tile,
+            ..table_source.clone()
+        });
+        rasters.push(RasterSource {
+            id: format!("raster_{id}"),
+            minzoom: Some(6),
+            empty_tile,
+            ..raster_source.clone()
+        });
+    }
+    let state = mock_state(None, None).await;
+    state.sources.store(Arc::new(Sources {
+        table_sources: mock_table_sources(&tables),
+        raster_sources: mock_raster_sources(&rasters),
+        ..Sources::default()
+    }));
+    let app = init_service(App::new().app_data(Data::new(state)).configure(router)).await;
+
+    for (path, status) in [
+        ("/table_default/0/0/0.pbf", StatusCode::NO_CONTENT),
+        ("/table_no_content/0/0/0.pbf", StatusCode::NO_CONTENT),
+        ("/table_not_found/0/0/0.pbf", StatusCode::NOT_FOUND),
+        ("/table_blank/0/0/0.pbf", StatusCode::OK),
+        ("/raster/raster_default/0/0/0.png", StatusCode::NO_CONTENT),
+        ("/raster/raster_not_found/0/0/0.png", StatusCode::NOT_FOUND),
+        ("/raster/raster_blank/0/0/0.png", StatusCode::OK),
+        // Composite tiles only use a policy shared by all their sources
+        (
+            "/table_blank,table_not_found/0/0/0.pbf",
+            StatusCode::NO_CONTENT,
+        ),
+        (
+            "/table_not_found,table_not_found/0/0/0.pbf",
+            StatusCode::NOT_FOUND,
+        ),
+    ] {
+        let response = call_service(&app, test_get(path)).await;
+        assert_eq!(response.status(), status, "{path}");
+        if status != StatusCode::OK {
+            assert!(read_body(response).await.is_empty(), "{path}");
+        }
+    }
+
+    // A blank vector tile has no layers
+    let response = call_service(&app, test_get("/table_blank/0/0/0.pbf")).await;
+    assert_eq!(
+        response.headers().get(CONTENT_TYPE).unwrap(),
+        "application/x-protobuf"
+    );
+    assert!(read_body(response).await.is_empty());
+
+    let response = call_service(&app, test_get("/raster/raster_blank/0/0/0.png")).await;
+    assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), "image/png");
+    let body = read_body(response).await;
+    assert!(body.starts_with(b"\x89PNG\r\n\x1a\n"));
+    assert_eq!(&body[16..24], &[0, 0, 1, 0, 0, 0, 1, 0], "256x256 image");
+}
+
+#[actix_rt::test]
+async fn get_tile_source_headers() {
+    let table_source = mock_default_table_sources()["public.table_source"]
+        .deref()
+        .clone();
+    // Tiles below the minzoom are empty without querying the database
+    let licensed = TableSource {
+        id: "licensed".to_owned(),
+        minzoom: Some(6),
+        headers: Some(HashMap::from([
+            ("X-Data-License".to_owned(), "CC-BY-4.0".to_owned()),
+            ("Content-Type".to_owned(), "text/plain".to_owned()),
+        ])),
+        ..table_source.clone()
+    };
+    let unlicensed = TableSource {
+        id: "unlicensed".to_owned(),
+        minzoom: Some(6),
+        ..table_source
+    };
+    let app = create_app!(Some(mock_table_sources(&[licensed, unlicensed])), None);
+
+    let response = call_service(&app, test_get("/licensed/0/0/0.pbf")).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        response.headers().get("x-data-license").unwrap(),
+        "CC-BY-4.0"
+    );
+    assert_eq!(
+        response.headers().get(CONTENT_TYPE).unwrap(),
+        "application/x-protobuf"
+    );
+
+    let response = call_service(&app, test_get("/unlicensed/0/0/0.pbf")).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert!(response.headers().get("x-data-license").is_none());
+
+    // Composite tiles get the headers of all their sources
+    let response = call_service(&app, test_get("/unlicensed,licensed/0/0/0.pbf")).await;
+    assert_eq!(
+        response.headers().get("x-data-license").unwrap(),
+        "CC-BY-4.0"
+    );
+}
+
+#[cfg(feature = "otel")]
+#[actix_rt::test]
+async fn get_tile_traced() {
+    use actix_web::dev::Service;
+    use martin::srv::otel::{in_request_span, subscriber};
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+
+    let exporter = InMemorySpanExporter::default();
+    let provider = SdkTracerProvider::builder()
+        .with_simple_exporter(exporter.clone())
+        .build();
+    let _subscriber = tracing::subscriber::set_default(subscriber(&provider));
+
+    init();
+    let state = mock_state(Some(mock_default_table_sources()), None).await;
+    let app = init_service(
+        App::new()
+            .wrap_fn(|req, srv| in_request_span(req, |req| srv.call(req)))
+            .app_data(Data::new(state))
+            .configure(router),
+    )
+    .await;
+
+    let req = test_get("/public.points1/0/0/0.pbf");
     let response = call_service(&app, req).await;
     assert!(response.status().is_success());
+
+    let spans = exporter.get_finished_spans().unwrap();
+    let span = |name: &str| spans.iter().find(|span| span.name == name).unwrap();
+    let (request, get_tile, query) = (span("request"), span("get_tile"), span("query"));
+    assert_eq!(get_tile.parent_span_id, request.span_context.span_id());
+    assert_eq!(query.parent_span_id, get_tile.span_context.span_id());
 }
@@ -1,8 +1,14 @@
 use log::info;
-use martin::pg::dev::make_pool;
-use martin::pg::table_source::get_table_sources;
-use martin::source::{Source, Xyz};
-use std::collections::HashMap;
+use martin::mvt::summarize_mvt;
+use martin::pg::dev::{make_pool, mock_default_table_sources};
+use martin::pg::table_source::{
+    get_table_sources, parse_extent_override, Buffer, GeometryColumn, Simplification, TableJoin,
+};
+use martin::pg::utils::{get_bounds_cte, get_srid_bounds};
+use martin::source::{Source, TileMatrixSet, TileScheme, Xyz};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Deref;
+use tilejson::Bounds;
 
 fn init() {
     let _ = env_logger::builder().is_test(true).try_init();
@@ -14,7 +20,9 @@ async fn get_table_sources_ok() {
 
     let pool = make_pool().await;
     let mut connection = pool.get().await.unwrap();
-    let table_sources = get_table_sources(&mut connection, None).await.unwrap();
+    let table_sources = get_table_sources(&mut connection, None, false)
+        .await
+        .unwrap();
 
     info!("table_sources = {table_sources:#?}");
 
@@ -31,7 +39,7 @@ async fn get_table_sources_ok() {
     assert_eq!(table_source.maxzoom, None);
     assert!(table_source.bounds.is_some());
     assert_eq!(table_source.extent, Some(4096));
-    assert_eq!(table_source.buffer, Some(64));
+    assert_eq!(table_source.buffer, Some(Buffer::Value(64)));
     assert_eq!(table_source.clip_geom, Some(true));
     assert_eq!(table_source.geometry_type, Some("GEOMETRY".to_owned()));
 
@@ -46,7 +54,9 @@ async fn table_source_tilejson_ok() {
 
     let pool = make_pool().await;
     let mut connection = pool.get().await.unwrap();
-    let table_sources = get_table_sources(&mut connection, None).await.unwrap();
+    let table_sources = get_table_sources(&mut connection, None, false)
+        .await
+        .unwrap();
 
     let table_source = table_sources.get("public.table_source").unwrap();
     let tilejson = table_source.get_tilejson().await.unwrap();
@@ -61,6 +71,170 @@ async fn table_source_tilejson_ok() {
     assert_eq!(tilejson.maxzoom, Some(30));
     assert!(tilejson.bounds.is_some());
     assert!(tilejson.tiles.is_empty());
+
+    let vector_layers = tilejson.vector_layers.unwrap();
+    assert_eq!(vector_layers.len(), 1);
+    assert_eq!(vector_layers[0].id, "public.table_source");
+    assert_eq!(
+        vector_layers[0].fields,
+        HashMap::from([("gid".to_owned(), "int4".to_owned())])
+    );
+}
+
+#[test]
+fn table_source_vector_layers() {
+    let mut table_source = mock_default_table_sources()
+        .get("public.table_source")
+        .unwrap()
+        .deref()
+        .clone();
+    table_source.properties = HashMap::from([("gid".to_owned(), "int4".to_owned())]);
+    table_source.join = Some(TableJoin {
+        schema: None,
+        table: "table_source_attributes".to_owned(),
+        key: "gid".to_owned(),
+        join_key: None,
+        columns: vec!["name".to_owned()],
+    });
+    table_source.extra_geometry_columns = Some(vec![GeometryColumn {
+        column: "geom2".to_owned(),
+        srid: None,
+        layer: Some("centroids".to_owned()),
+    }]);
+
+    let vector_layers = table_source.get_vector_layers();
+    let ids: Vec<&str> = vector_layers
+        .iter()
+        .map(|layer| layer.id.as_str())
+        .collect();
+    assert_eq!(ids, vec!["public.table_source", "centroids"]);
+    for layer in vector_layers {
+        assert_eq!(
+            layer.fields,
+            HashMap::from([
+                ("gid".to_owned(), "int4".to_owned()),
+                ("name".to_owned(), String::new()),
+            ])
+        );
+    }
+}
+
+#[test]
+fn table_source_buffer_per_zoom() {
+    let mut table_source = mock_default_table_sources()
+        .get("public.table_source")
+        .unwrap()
+        .deref()
+        .clone();
+    table_source.buffer = Some(Buffer::Zooms(BTreeMap::from([(2, 256), (10, 32)])));
+    table_source.validate().unwrap();
+
+    let buffer_of = |z| {
+        let geom_query = table_source.get_geom_query(&Xyz { x: 0, y: 0, z });
+        let buffer = geom_query.split(", 4096, ").nth(1).unwrap();
+        buffer.split(',').next().unwrap().to_owned()
+    };
+    assert_eq!(buffer_of(0), "64");
+    assert_eq!(buffer_of(2), "256");
+    assert_eq!(buffer_of(9), "256");
+    assert_eq!(buffer_of(14), "32");
+
+    table_source.buffer = Some(Buffer::Zooms(BTreeMap::from([(0, 8192)])));
+    assert!(table_source.validate().is_err());
+}
+
+#[test]
+fn table_source_simplification() {
+    let mut table_source = mock_default_table_sources()
+        .get("public.table_source")
+        .unwrap()
+        .deref()
+        .clone();
+    table_source.simplification = Some(vec![
+        Simplification {
+            minzoom: None,
+            maxzoom: Some(5),
+            tolerance: 1.0,
+            preserve_topology: None,
+        },
+        Simplification {
+            minzoom: Some(6),
+            maxzoom: Some(10),
+            tolerance: 0.5,
+            preserve_topology: Some(true),
+        },
+    ]);
+    table_source.validate().unwrap();
+
+    let geom_query = table_source.get_geom_query(&Xyz { x: 0, y: 0, z: 0 });
+    assert!(geom_query.contains("ST_Simplify(ST_Transform"));
+    assert!(geom_query.contains(", 3857), 9783.93"));
+
+    let geom_query = table_source.get_geom_query(&Xyz { x: 0, y: 0, z: 8 });
+    assert!(geom_query.contains("ST_SimplifyPreserveTopology(ST_Transform"));
+
+    let geom_query = table_source.get_geom_query(&Xyz { x: 0, y: 0, z: 11 });
+    assert!(!geom_query.contains("ST_Simplify"));
+
+    table_source.simplification = Some(vec![Simplification {
+        minzoom: Some(6),
+        maxzoom: Some(5),
+        tolerance: 1.0,
+        preserve_topology: None,
+    }]);
+    assert!(table_source.validate().is_err());
+    table_source.simplification = Some(vec![Simplification {
+        minzoom: None,
+        maxzoom: None,
+        tolerance: 0.0,
+        preserve_topology: None,
+    }]);
+    assert!(table_source.validate().is_err());
+}
+
+#[test]
+fn table_source_extent_override() {
+    assert_eq!(parse_extent_override("8192").unwrap(), 8192);
+    assert_eq!(parse_extent_override("2048").unwrap(), 2048);
+    for extent in ["1024", "16384", "4096.0", "big"] {
+        let error = parse_extent_override(extent).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("Invalid extent '{extent}', expected one of: 2048, 4096, 8192")
+        );
+    }
+
+    let mut table_source = mock_default_table_sources()
+        .get("public.table_source")
+        .unwrap()
+        .deref()
+        .clone();
+    table_source.extent = Some(parse_extent_override("8192").unwrap());
+    let tile_query = table_source.get_tile_query(&Xyz { x: 0, y: 0, z: 0 });
+    assert!(tile_query.contains("8192, 64, true"), "{tile_query}");
+    assert!(!tile_query.contains("4096"), "{tile_query}");
+}
+
+#[test]
+fn table_source_geojson_query() {
+    let table_source = mock_default_table_sources()
+        .get("public.table_source")
+        .unwrap()
+        .deref()
+        .clone();
+
+    let query = table_source.build_geojson_query(&Xyz { x: 0, y: 0, z: 0 });
+    assert!(query.starts_with("WITH bounds AS"), "{query}");
+    assert!(
+        query.contains("ST_AsGeoJSON (features.*, 'geom')"),
+        "{query}"
+    );
+    assert!(query.contains("bounds.srid_4326"), "{query}");
+    assert!(!query.contains("ST_AsMVT"), "{query}");
+
+    let query = table_source.build_tile_query(&Xyz { x: 0, y: 0, z: 0 });
+    assert!(query.contains("ST_AsMVTGeom ("), "{query}");
+    assert!(!query.contains("ST_AsGeoJSON"), "{query}");
 }
 
 #[actix_rt::test]
@@ -69,11 +243,13 @@ async fn table_source_tile_ok() {
 
     let pool = make_pool().await;
     let mut connection = pool.get().await.unwrap();
-    let table_sources = get_table_sources(&mut connection, None).await.unwrap();
+    let table_sources = get_table_sources(&mut connection, None, false)
+        .await
+        .unwrap();
 
     let table_source = table_sources.get("public.table_source").unwrap();
     let tile = table_source
-        .get_tile(&mut connection, &Xyz { x: 0, y: 0, z: 0 }, &None)
+        .get_tile(Some(&mut connection), &Xyz { x: 0, y: 0, z: 0 }, &None)
         .await
         .unwrap();
 
@@ -86,7 +262,7 @@ async fn table_source_srid_ok() {
 
     let pool = make_pool().await;
     let mut connection = pool.get().await.unwrap();
-    let table_sources = get_table_sources(&mut connection, Some(900_913))
+    let table_sources = get_table_sources(&mut connection, Some(900_913), false)
         .await
         .unwrap();
 
@@ -107,13 +283,46 @@ async fn table_source_srid_ok() {
     assert_eq!(points_empty_srid.srid, 900_913);
 }
 
+#[actix_rt::test]
+async fn table_source_sampled_srid_ok() {
+    init();
+
+    let pool = make_pool().await;
+    let mut connection = pool.get().await.unwrap();
+
+    // Without sampling, tables with SRID 0 are skipped
+    let table_sources = get_table_sources(&mut connection, None, false)
+        .await
+        .unwrap();
+    assert!(!table_sources.contains_key("public.points_empty_srid"));
+    assert!(!table_sources.contains_key("public.points_mixed_srid"));
+
+    let table_sources = get_table_sources(&mut connection, None, true)
+        .await
+        .unwrap();
+    let points_empty_srid = table_sources.get("public.points_empty_srid").unwrap();
+    assert_eq!(points_empty_srid.srid, 900_913);
+    assert!(!table_sources.contains_key("public.points_mixed_srid"));
+
+    // Tables with mixed SRIDs fall back to the default SRID
+    let table_sources = get_table_sources(&mut connection, Some(4326), true)
+        .await
+        .unwrap();
+    let points_empty_srid = table_sources.get("public.points_empty_srid").unwrap();
+    assert_eq!(points_empty_srid.srid, 900_913);
+    let points_mixed_srid = table_sources.get("public.points_mixed_srid").unwrap();
+    assert_eq!(points_mixed_srid.srid, 4326);
+}
+
 #[actix_rt::test]
 async fn table_source_multiple_geom_ok() {
     init();
 
     let pool = make_pool().await;
     let mut connection = pool.get().await.unwrap();
-    let table_sources = get_table_sources(&mut connection, None).await.unwrap();
+    let table_sources = get_table_sources(&mut connection, None, false)
+        .await
+        .unwrap();
 
     assert!(table_sources.contains_key("public.table_source_multiple_geom"));
     let table_source_multiple_geom = table_sources
@@ -136,3 +345,507 @@ async fn table_source_multiple_geom_ok() {
 
     assert_eq!(table_source_multiple_geom2.geometry_column, "geom2");
 }
+
+#[actix_rt::test]
+async fn table_source_join_ok() {
+    init();
+
+    let pool = make_pool().await;
+    let mut connection = pool.get().await.unwrap();
+    let table_sources = get_table_sources(&mut connection, None, false)
+        .await
+        .unwrap();
+
+    let mut table_source = table_sources
+        .get("public.table_source_join")
+        .unwrap()
+        .deref()
+        .clone();
+    table_source.join = Some(TableJoin {
+        schema: None,
+        table: "table_source_join_attributes".to_owned(),
+        key: "gid".to_owned(),
+        join_key: None,
+        columns: vec!["name".to_owned(), "kind".to_owned()],
+    });
+    table_source.validate().unwrap();
+
+    let xyz = Xyz { x: 0, y: 0, z: 0 };
+    let geom_query = format!(
+        "{} SELECT * FROM ({}) AS tile",
+        get_bounds_cte(&get_srid_bounds(
+            table_source.srid,
+            &xyz,
+            TileMatrixSet::default()
+        )),
+        table_source.get_geom_query(&xyz)
+    );
+    let rows = connection.query(geom_query.as_str(), &[]).await.unwrap();
+    assert_eq!(rows.len(), 2);
+    let columns: Vec<&str> = rows[0].columns().iter().map(|c| c.name()).collect();
+    assert!(columns.contains(&"gid"));
+    assert!(columns.contains(&"name"));
+    assert!(columns.contains(&"kind"));
+
+    let tile = table_source
+        .get_tile(Some(&mut connection), &xyz, &None)
+        .await
+        .unwrap();
+    assert!(!tile.is_empty());
+}
+
+#[test]
+fn table_source_validate_zooms() {
+    let mut table_source = mock_default_table_sources()
+        .get("public.table_source")
+        .unwrap()
+        .deref()
+        .clone();
+    table_source.minzoom = Some(14);
+    table_source.maxzoom = Some(14);
+    table_source.validate().unwrap();
+
+    table_source.maxzoom = Some(10);
+    let error = table_source.validate().unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Source public.table_source has minzoom 14 greater than its maxzoom 10"
+    );
+
+    table_source.minzoom = Some(31);
+    table_source.maxzoom = None;
+    let error = table_source.validate().unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Source public.table_source has minzoom 31, must be between 0 and 30"
+    );
+}
+
+#[test]
+fn table_source_layers_tile_query() {
+    let mut table_source = mock_default_table_sources()
+        .get("public.table_source")
+        .unwrap()
+        .deref()
+        .clone();
+    table_source.extra_geometry_columns = Some(vec![GeometryColumn {
+        column: "geom2".to_owned(),
+        srid: None,
+        layer: Some("centroids".to_owned()),
+    }]);
+    let xyz = Xyz { x: 0, y: 0, z: 0 };
+    let layers = |layers: &[&str]| -> Vec<String> {
+        layers.iter().map(|layer| (*layer).to_owned()).collect()
+    };
+
+    let query = table_source.get_tile_query(&xyz);
+    assert!(query.contains("ST_AsMVT (tile, 'public.table_source',"));
+    assert!(query.contains("ST_AsMVT (tile, 'centroids',"));
+
+    let query = table_source.get_layers_tile_query(&xyz, Some(&layers(&["centroids"])));
+    assert!(!query.contains("ST_AsMVT (tile, 'public.table_source',"));
+    assert!(query.contains("ST_AsMVT (tile, 'centroids',"));
+    assert!(query.contains(r#""geom2""#));
+
+    let subset = layers(&["public.table_source", "centroids", "other.layer"]);
+    assert_eq!(
+        table_source.get_layers_tile_query(&xyz, Some(&subset)),
+        table_source.get_tile_query(&xyz)
+    );
+
+    let query = table_source.get_layers_tile_query(&xyz, Some(&layers(&["other.layer"])));
+    assert_eq!(query, "SELECT ''::bytea AS st_asmvt");
+}
+
+#[test]
+fn table_source_skip_out_of_bounds() {
+    let mut table_source = mock_default_table_sources()
+        .get("public.table_source")
+        .unwrap()
+        .deref()
+        .clone();
+    // Tiles 2/0/1 and 2/3/1 are on either side of the antimeridian, 2/2/1 is over Europe
+    let (west, europe, east) = (
+        Xyz { z: 2, x: 0, y: 1 },
+        Xyz { z: 2, x: 2, y: 1 },
+        Xyz { z: 2, x: 3, y: 1 },
+    );
+    table_source.bounds = Some(Bounds::new(170.0, 0.0, -170.0, 60.0));
+    assert!(!table_source.is_out_of_bounds(&europe));
+
+    table_source.skip_out_of_bounds = Some(true);
+    assert!(!table_source.is_out_of_bounds(&west));
+    assert!(table_source.is_out_of_bounds(&europe));
+    assert!(!table_source.is_out_of_bounds(&east));
+    assert!(table_source.is_out_of_bounds(&Xyz { z: 2, x: 3, y: 3 }));
+
+    table_source.tile_scheme = Some(TileScheme::Tms);
+    assert!(!table_source.is_out_of_bounds(&Xyz { z: 2, x: 3, y: 2 }));
+    assert!(table_source.is_out_of_bounds(&Xyz { z: 2, x: 3, y: 0 }));
+
+    table_source.tile_scheme = None;
+    table_source.bounds = Some(Bounds::new(100.0, 20.0, 110.0, 30.0));
+    assert!(!table_source.is_out_of_bounds(&east));
+    assert!(table_source.is_out_of_bounds(&europe));
+    table_source.bounds = None;
+    assert!(!table_source.is_out_of_bounds(&europe));
+}
+
+#[actix_rt::test]
+async fn table_source_extra_geometry_columns_ok() {
+    init();
+
+    let pool = make_pool().await;
+    let mut connection = pool.get().await.unwrap();
+    let table_sources = get_table_sources(&mut connection, None, false)
+        .await
+        .unwrap();
+
+    let mut table_source = table_sources
+        .get("public.table_source_multiple_geom.geom1")
+        .unwrap()
+        .deref()
+        .clone();
+    table_source.extra_geometry_columns = Some(vec![GeometryColumn {
+        column: "geom2".to_owned(),
+        srid: None,
+        layer: None,
+    }]);
+    table_source.validate().unwrap();
+
+    let xyz = Xyz { x: 0, y: 0, z: 0 };
+    let tile = table_source
+        .get_tile(Some(&mut connection), &xyz, &None)
+        .await
+        .unwrap();
+
+    let layers: Vec<String> = summarize_mvt(&tile)
+        .unwrap()
+        .layers
+        .into_iter()
+        .map(|layer| layer.name)
+        .collect();
+    assert_eq!(
+        layers,
+        vec![
+            "public.table_source_multiple_geom.geom1",
+            "public.table_source_multiple_geom.geom1.geom2",
+        ]
+    );
+}
+
+#[actix_rt::test]
+async fn table_source_tms_scheme_ok() {
+    init();
+
+    let table_sources = mock_default_table_sources();
+    let xyz_source = table_sources.get("public.table_source").unwrap();
+    let mut tms_source = xyz_source.deref().clone();
+    tms_source.tile_scheme = Some(TileScheme::Tms);
+
+    // The northern tiles of zoom 1 are rows 0 in XYZ, and rows 1 in TMS
+    let xyz = Xyz { z: 1, x: 0, y: 0 };
+    let tms = Xyz { z: 1, x: 0, y: 1 };
+    assert_eq!(
+        tms_source.build_tile_query(&tms),
+        xyz_source.build_tile_query(&xyz)
+    );
+    assert_ne!(
+        tms_source.build_tile_query(&xyz),
+        xyz_source.build_tile_query(&xyz)
+    );
+
+    let tilejson = tms_source.get_tilejson().await.unwrap();
+    assert_eq!(tilejson.scheme, Some("tms".to_owned()));
+    let tilejson = xyz_source.get_tilejson().await.unwrap();
+    assert_eq!(tilejson.scheme, Some("xyz".to_owned()));
+}
+
+#[actix_rt::test]
+async fn table_source_crs84_tile_matrix_set_ok() {
+    init();
+
+    let table_sources = mock_default_table_sources();
+    let mut table_source = table_sources.get("public.points1").unwrap().deref().clone();
+    table_source.tile_matrix_set = Some(TileMatrixSet::WorldCrs84Quad);
+
+    // The eastern hemisphere is the second tile of zoom 0
+    let query = table_source.build_tile_query(&Xyz { z: 0, x: 1, y: 0 });
+    assert!(query.contains("ST_Transform(ST_MakeEnvelope(0, -90, 180, 90, 4326), 4326)"));
+    assert!(query.contains("ST_CurveToLine(\"points1\".\"geom\"), 4326)"));
+
+    let tilejson = table_source.get_tilejson().await.unwrap();
+    assert_eq!(
+        tilejson.other.get("crs"),
+        Some(&serde_json::Value::from("EPSG:4326"))
+    );
+    let tilejson = table_sources["public.points1"]
+        .get_tilejson()
+        .await
+        .unwrap();
+    assert_eq!(tilejson.other.get("crs"), None);
+
+    let pool = make_pool().await;
+    let mut connection = pool.get().await.unwrap();
+    let tile = table_source
+        .get_tile(Some(&mut connection), &Xyz { z: 0, x: 1, y: 0 }, &None)
+        .await
+        .unwrap();
+    assert!(!tile.is_empty());
+}
+
+#[actix_rt::test]
+async fn table_source_sql_filter_ok() {
+    init();
+
+    let table_sources = mock_default_table_sources();
+    let mut table_source = table_sources
+        .get("public.table_source")
+        .unwrap()
+        .deref()
+        .clone();
+    table_source.sql_filter = Some("gid IN (1, 2) OR gid = 3".to_owned());
+    table_source.validate().unwrap();
+
+    let xyz = Xyz { x: 0, y: 0, z: 0 };
+    let geom_query = table_source.get_geom_query(&xyz);
+    assert!(geom_query.contains("AND (gid IN (1, 2) OR gid = 3)"));
+
+    let pool = make_pool().await;
+    let connection = pool.get().await.unwrap();
+    let query = format!(
+        "{} SELECT * FROM ({geom_query}) AS tile",
+        get_bounds_cte(&get_srid_bounds(
+            table_source.srid,
+            &xyz,
+            TileMatrixSet::default()
+        )),
+    );
+    let rows = connection.query(query.as_str(), &[]).await.unwrap();
+    assert_eq!(rows.len(), 3);
+
+    table_source.sql_filter = Some("true; DROP TABLE table_source".to_owned());
+    assert!(table_source.validate().is_err());
+    table_source.sql_filter = Some(" ".to_owned());
+    assert!(table_source.validate().is_err());
+}
+
+#[actix_rt::test]
+async fn table_source_max_feature_count_ok() {
+    init();
+
+    let table_sources = mock_default_table_sources();
+    let mut table_source = table_sources
+        .get("public.table_source")
+        .unwrap()
+        .deref()
+        .clone();
+    table_source.max_feature_count = Some(2);
+    table_source.validate().unwrap();
+
+    let xyz = Xyz { x: 0, y: 0, z: 0 };
+    let geom_query = table_source.get_geom_query(&xyz);
+    assert!(geom_query.trim_end().ends_with("LIMIT 2"));
+    assert!(!geom_query.contains("ORDER BY"));
+
+    table_source.id_column = Some("gid".to_owned());
+    let geom_query = table_source.get_geom_query(&xyz);
+    assert!(geom_query.contains(r#"ORDER BY "table_source"."gid" LIMIT 2"#));
+
+    let pool = make_pool().await;
+    let mut connection = pool.get().await.unwrap();
+    let tile = table_source
+        .get_tile(Some(&mut connection), &xyz, &None)
+        .await
+        .unwrap();
+    let summary = summarize_mvt(&tile).unwrap();
+    assert_eq!(summary.layers[0].features, 2);
+
+    table_source.max_feature_count = Some(0);
+    assert!(table_source.validate().is_err());
+}
+
+#[actix_rt::test]
+async fn table_source_layer_name_ok() {
+    init();
+
+    let table_sources = mock_default_table_sources();
+    let mut table_source = table_sources
+        .get("public.table_source")
+        .unwrap()
+        .deref()
+        .clone();
+    let xyz = Xyz { x: 0, y: 0, z: 0 };
+    assert!(table_source
+        .get_tile_query(&xyz)
+        .contains("ST_AsMVT (tile, 'public.table_source',"));
+
+    table_source.layer_name = Some("roads".to_owned());
+    table_source.validate().unwrap();
+    assert!(table_source
+        .get_tile_query(&xyz)
+        .contains("ST_AsMVT (tile, 'roads',"));
+    assert_eq!(table_source.get_vector_layers()[0].id, "roads");
+
+    for layer_name in ["", " ", "o'clock"] {
+        let mut table_source = table_source.clone();
+        table_source.layer_name = Some(layer_name.to_owned());
+        assert!(table_source.validate().is_err(), "{layer_name}");
+    }
+
+    let pool = make_pool().await;
+    let mut connection = pool.get().await.unwrap();
+    let tile = table_source
+        .get_tile(Some(&mut connection), &xyz, &None)
+        .await
+        .unwrap();
+    let summary = summarize_mvt(&tile).unwrap();
+    assert_eq!(summary.layers[0].name, "roads");
+}
+
+#[actix_rt::test]
+async fn table_source_make_valid_ok() {
+    init();
+
+    let table_sources = mock_default_table_sources();
+    let mut table_source = table_sources
+        .get("public.table_source")
+        .unwrap()
+        .deref()
+        .clone();
+    let xyz = Xyz { x: 0, y: 0, z: 0 };
+    assert!(!table_source.get_geom_query(&xyz).contains("ST_MakeValid"));
+
+    // Repaired inside the clipping, after the simplification
+    table_source.make_valid = Some(true);
+    table_source.simplification = Some(vec![Simplification {
+        minzoom: None,
+        maxzoom: None,
+        tolerance: 1.0,
+        preserve_topology: None,
+    }]);
+    let geom_query = table_source.get_geom_query(&xyz);
+    assert!(
+        geom_query.contains("ST_AsMVTGeom (ST_MakeValid(ST_Simplify(ST_Transform ("),
+        "{geom_query}"
+    );
+
+    let pool = make_pool().await;
+    let mut connection = pool.get().await.unwrap();
+    let table_sources = get_table_sources(&mut connection, None, false)
+        .await
+        .unwrap();
+    let mut table_source = table_sources
+        .get("public.table_source_invalid")
+        .unwrap()
+        .deref()
+        .clone();
+    table_source.make_valid = Some(true);
+    let tile = table_source
+        .get_tile(Some(&mut connection), &xyz, &None)
+        .await
+        .unwrap();
+    let summary = summarize_mvt(&tile).unwrap();
+    assert_eq!(summary.layers[0].features, 1);
+}
+
+#[actix_rt::test]
+async fn table_source_property_aliases_ok() {
+    init();
+
+    let table_sources = mock_default_table_sources();
+    let mut table_source = table_sources
+        .get("public.table_source")
+        .unwrap()
+        .deref()
+        .clone();
+    table_source.properties = HashMap::from([("gid".to_owned(), "int4".to_owned())]);
+    table_source.property_aliases = Some(HashMap::from([(
+        "gid".to_owned(),
+        "feature_number".to_owned(),
+    )]));
+    table_source.validate().unwrap();
+
+    let xyz = Xyz { x: 0, y: 0, z: 0 };
+    let geom_query = table_source.get_geom_query(&xyz);
+    assert!(geom_query.contains(r#""table_source"."gid" AS "feature_number""#));
+    let layers = table_source.get_vector_layers();
+    assert!(layers[0].fields.contains_key("feature_number"));
+    assert!(!layers[0].fields.contains_key("gid"));
+
+    for (column, alias) in [("missing", "name"), ("gid", ""), ("gid", "geom")] {
+        let mut table_source = table_source.clone();
+        table_source.property_aliases =
+            Some(HashMap::from([(column.to_owned(), alias.to_owned())]));
+        assert!(table_source.validate().is_err(), "{column}: {alias}");
+    }
+
+    let mut invalid = table_source.clone();
+    invalid.id_column = Some("gid".to_owned());
+    let error = invalid.validate().unwrap_err();
+    assert!(error.to_string().contains("can't rename its id_column gid"));
+
+    let pool = make_pool().await;
+    let mut connection = pool.get().await.unwrap();
+    let tile = table_source
+        .get_tile(Some(&mut connection), &xyz, &None)
+        .await
+        .unwrap();
+    let layer = &summarize_mvt(&tile).unwrap().layers[0];
+    assert_eq!(layer.keys, vec!["feature_number".to_owned()]);
+}
+
+#[actix_rt::test]
+async fn table_source_id_column_ok() {
+    init();
+
+    let pool = make_pool().await;
+    let mut connection = pool.get().await.unwrap();
+    let table_sources = get_table_sources(&mut connection, None, false)
+        .await
+        .unwrap();
+    let table_source = table_sources
+        .get("public.table_source_ids")
+        .unwrap()
+        .deref()
+        .clone();
+    let xyz = Xyz { x: 0, y: 0, z: 0 };
+
+    for (id_column, type_name) in [("gid", "int4"), ("big_id", "int8")] {
+        let mut table_source = table_source.clone();
+        table_source.id_column = Some(id_column.to_owned());
+        assert_eq!(table_source.get_id_column_warning(Some(type_name)), None);
+        table_source.check_id_column(&mut connection).await.unwrap();
+        assert_eq!(table_source.id_column, Some(id_column.to_owned()));
+
+        let tile = table_source
+            .get_tile(Some(&mut connection), &xyz, &None)
+            .await
+            .unwrap();
+        let layer = &summarize_mvt(&tile).unwrap().layers[0];
+        assert_eq!(layer.features, 2);
+        // The id column is encoded as the feature id, not as a property
+        assert!(!layer.keys.contains(&id_column.to_owned()), "{id_column}");
+    }
+
+    let mut table_source = table_source.clone();
+    table_source.id_column = Some("uid".to_owned());
+    let warning = table_source.get_id_column_warning(Some("uuid")).unwrap();
+    assert!(warning.contains("id column uid has type uuid"), "{warning}");
+    table_source.check_id_column(&mut connection).await.unwrap();
+    assert_eq!(table_source.id_column, None);
+
+    // The column is still served as a property
+    let tile = table_source
+        .get_tile(Some(&mut connection), &xyz, &None)
+        .await
+        .unwrap();
+    let layer = &summarize_mvt(&tile).unwrap().layers[0];
+    assert_eq!(layer.features, 2);
+    assert!(layer.keys.contains(&"uid".to_owned()));
+
+    let mut table_source = table_source.clone();
+    table_source.id_column = Some("missing".to_owned());
+    table_source.check_id_column(&mut connection).await.unwrap();
+    assert_eq!(table_source.id_column, None);
+}
@@ -151,3 +151,18 @@ test_pbf fnc_0_0_0  http://localhost:3000/rpc/public.function_source/0/0/0.pbf
 test_pbf fnc2_0_0_0 http://localhost:3000/rpc/public.function_source_query_params/0/0/0.pbf?token=martin
 
 kill_process $PROCESS_ID
+
+
+echo "------------------------------------------------------------------------------------------------------------------------"
+echo "Test saving the resolved config"
+TEST_OUT_DIR="$(dirname "$0")/output/save_config"
+mkdir -p "$TEST_OUT_DIR"
+
+set -x
+$MARTIN_BIN --save-config - --default-srid 900913 "$DATABASE_URL" > "$TEST_OUT_DIR/stdout.yaml"
+$MARTIN_BIN --save-config "$TEST_OUT_DIR/file.yaml" --default-srid 900913 "$DATABASE_URL"
+{ set +x; } 2> /dev/null
+
+grep -q "public.table_source" "$TEST_OUT_DIR/stdout.yaml"
+grep -q "public.function_source" "$TEST_OUT_DIR/stdout.yaml"
+diff "$TEST_OUT_DIR/stdout.yaml" <(cat "$TEST_OUT_DIR/file.yaml"; echo)